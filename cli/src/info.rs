@@ -0,0 +1,297 @@
+//! `boltpm info`: a summary of a package's registry metadata, or selected
+//! fields of it for scripts.
+
+use crate::registry;
+use semver::Version;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeSet;
+
+/// Flatten registry metadata into one document for `version` (a version,
+/// range or dist-tag; `latest` if `None`): package-wide fields such as
+/// `dist-tags`, `versions` and `time`, plus the chosen version's own fields.
+pub fn view(meta: &Value, version: Option<&str>) -> Result<Value, String> {
+    let name = meta["name"].as_str().unwrap_or_default();
+    let versions = meta["versions"].as_object().cloned().unwrap_or_default();
+    // An exact version is shown even if it is yanked.
+    let chosen = match version {
+        Some(v) if versions.contains_key(v) => v.to_string(),
+        _ => registry::resolve_version(meta, version).ok_or_else(|| match version {
+            Some(v) => format!("No version of {name} matches {v}"),
+            None => format!("{name} has no versions"),
+        })?,
+    };
+    let mut sorted: Vec<&String> = versions.keys().collect();
+    sorted.sort_by_key(|v| Version::parse(v).ok());
+    let time: Map<String, Value> = sorted
+        .iter()
+        .filter_map(|v| Some(((*v).clone(), versions[*v].get("published_at")?.clone())))
+        .collect();
+    let maintainers: BTreeSet<&str> = versions
+        .values()
+        .filter_map(|v| v["published_by"].as_str())
+        .collect();
+    let yanked: Vec<&String> = sorted
+        .iter()
+        .copied()
+        .filter(|v| registry::is_yanked(meta, v))
+        .collect();
+    let deprecations: Map<String, Value> = sorted
+        .iter()
+        .filter_map(|v| Some(((*v).clone(), registry::deprecation(meta, v)?.into())))
+        .collect();
+
+    let mut doc = match &versions[&chosen] {
+        Value::Object(fields) => fields.clone(),
+        _ => Map::new(),
+    };
+    // Registry metadata keeps the deprecation flag and message apart; show
+    // the message as `deprecated`, as npm does.
+    doc.remove("deprecation_message");
+    match deprecations.get(&chosen) {
+        Some(message) => doc.insert("deprecated".into(), message.clone()),
+        None => doc.remove("deprecated"),
+    };
+    doc.insert("name".into(), name.into());
+    doc.insert("version".into(), chosen.into());
+    doc.insert("dist-tags".into(), meta["dist-tags"].clone());
+    doc.insert("versions".into(), json!(sorted));
+    doc.insert("time".into(), time.into());
+    doc.insert("yanked_versions".into(), json!(yanked));
+    doc.insert("deprecated_versions".into(), deprecations.into());
+    doc.insert("maintainers".into(), json!(maintainers));
+    if let Some(weekly) = meta.get("weekly_downloads") {
+        doc.insert("weekly_downloads".into(), weekly.clone());
+    }
+    Ok(Value::Object(doc))
+}
+
+/// Look up a dotted field path such as `dist.tarball` or `dependencies.foo`.
+/// Keys may themselves contain dots (`time.1.0.0`), so the longest matching
+/// key wins at each level.
+pub fn field<'a>(doc: &'a Value, path: &str) -> Option<&'a Value> {
+    if path.is_empty() {
+        return Some(doc);
+    }
+    let map = doc.as_object()?;
+    if let Some(value) = map.get(path) {
+        return Some(value);
+    }
+    path.char_indices()
+        .rev()
+        .filter(|(_, c)| *c == '.')
+        .find_map(|(i, _)| field(map.get(&path[..i])?, &path[i + 1..]))
+}
+
+/// Print the requested fields: a single field on its own (strings bare, the
+/// rest as JSON), several as `field = value` lines, or all of them as one
+/// JSON object with `json`.
+pub fn format_fields(doc: &Value, fields: &[String], json: bool) -> Result<String, String> {
+    let mut selected = Map::new();
+    for path in fields {
+        let value = field(doc, path).ok_or_else(|| format!("No field '{path}'"))?;
+        selected.insert(path.clone(), value.clone());
+    }
+    let render = |value: &Value| match value {
+        Value::String(s) if !json => s.clone(),
+        _ => serde_json::to_string_pretty(value).unwrap(),
+    };
+    Ok(match fields {
+        [] => render(doc),
+        [only] => render(&selected[only]),
+        _ if json => render(&Value::Object(selected)),
+        _ => fields
+            .iter()
+            .map(|path| format!("{path} = {}", render(&selected[path])))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    })
+}
+
+/// The human-readable summary.
+pub fn format_info(doc: &Value) -> String {
+    let text = |key: &str| doc[key].as_str().unwrap_or_default();
+    let map = |key: &str| doc[key].as_object().cloned().unwrap_or_default();
+    let deps = map("dependencies");
+    let versions: Vec<&str> = doc["versions"]
+        .as_array()
+        .map(|v| v.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+
+    let mut header = vec![format!("{}@{}", text("name"), text("version"))];
+    if let Some(license) = doc["license"].as_str() {
+        header.push(license.to_string());
+    }
+    header.push(format!("deps: {}", deps.len()));
+    header.push(format!("versions: {}", versions.len()));
+    let mut out = header.join(" | ") + "\n";
+    if !text("description").is_empty() {
+        out += &format!("{}\n", text("description"));
+    }
+    if let Some(message) = doc["deprecated"].as_str() {
+        out += &format!("DEPRECATED: {message}\n");
+    }
+    if doc["yanked"] == Value::Bool(true) {
+        out += "YANKED: this version should not be installed\n";
+    }
+
+    let section = |out: &mut String, title: &str, entries: Map<String, Value>| {
+        if entries.is_empty() {
+            return;
+        }
+        *out += &format!("\n{title}:\n");
+        for (key, value) in entries {
+            *out += &format!("  {key}: {}\n", value.as_str().unwrap_or_default());
+        }
+    };
+    section(&mut out, "dist-tags", map("dist-tags"));
+    section(&mut out, "dependencies", deps);
+    section(&mut out, "peer dependencies", map("peer_dependencies"));
+    section(
+        &mut out,
+        "optional dependencies",
+        map("optional_dependencies"),
+    );
+
+    if let Some(dist) = doc["dist"].as_object() {
+        out += "\ndist:\n";
+        for key in ["tarball", "shasum", "integrity"] {
+            if let Some(value) = dist.get(key).and_then(Value::as_str) {
+                out += &format!("  {key}: {value}\n");
+            }
+        }
+    }
+
+    out += "\nversions:\n";
+    let width = versions.iter().map(|v| v.len()).max().unwrap_or_default();
+    for version in &versions {
+        // RFC 3339 down to the day.
+        let published = doc["time"][*version].as_str().unwrap_or_default();
+        let mut line = format!(
+            "  {version:width$}  {:10}",
+            published.get(..10).unwrap_or("")
+        );
+        let mut notes = Vec::new();
+        if *version == text("version") {
+            notes.push("shown".to_string());
+        }
+        if doc["yanked_versions"]
+            .as_array()
+            .is_some_and(|y| y.iter().any(|v| v == version))
+        {
+            notes.push("yanked".to_string());
+        }
+        if let Some(message) = doc["deprecated_versions"][*version].as_str() {
+            notes.push(format!("deprecated: {message}"));
+        }
+        if !notes.is_empty() {
+            line += &format!("  ({})", notes.join(", "));
+        }
+        out += line.trim_end();
+        out.push('\n');
+    }
+
+    let maintainers: Vec<&str> = doc["maintainers"]
+        .as_array()
+        .map(|m| m.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    if !maintainers.is_empty() {
+        out += &format!("\nmaintainers: {}\n", maintainers.join(", "));
+    }
+    if let Some(weekly) = doc["weekly_downloads"].as_u64() {
+        out += &format!("weekly downloads: {weekly}\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> Value {
+        json!({
+            "name": "foo",
+            "dist-tags": {"latest": "1.2.0", "next": "2.0.0-rc.1"},
+            "weekly_downloads": 42,
+            "versions": {
+                "1.10.0": {"description": "Foo", "yanked": true, "deprecated": false,
+                           "published_at": "2024-05-04T00:00:00Z"},
+                "1.2.0": {"description": "Foo things", "yanked": false, "deprecated": true,
+                          "deprecation_message": "Use 2.x", "license": "MIT",
+                          "dependencies": {"bar": "^1.0.0"},
+                          "dist": {"tarball": "http://localhost:4000/v1/foo/1.2.0/", "shasum": "abc"},
+                          "published_at": "2024-05-03T12:00:00Z", "published_by": "ci"},
+                "2.0.0-rc.1": {"description": "Foo", "yanked": false, "deprecated": false,
+                               "published_by": "alice"}
+            }
+        })
+    }
+
+    #[test]
+    fn test_view_selects_version() {
+        let doc = view(&meta(), None).unwrap();
+        assert_eq!(doc["version"], "1.2.0");
+        assert_eq!(doc["deprecated"], "Use 2.x");
+        assert_eq!(doc["versions"], json!(["1.2.0", "1.10.0", "2.0.0-rc.1"]));
+        assert_eq!(doc["maintainers"], json!(["alice", "ci"]));
+        assert_eq!(
+            view(&meta(), Some("next")).unwrap()["version"],
+            "2.0.0-rc.1"
+        );
+        let yanked = view(&meta(), Some("1.10.0")).unwrap();
+        assert_eq!(yanked["yanked"], true);
+        assert!(yanked.get("deprecated").is_none());
+        assert!(view(&meta(), Some("^3")).is_err());
+    }
+
+    #[test]
+    fn test_fields() {
+        let doc = view(&meta(), None).unwrap();
+        assert_eq!(field(&doc, "dependencies.bar"), Some(&json!("^1.0.0")));
+        assert_eq!(
+            field(&doc, "time.1.2.0"),
+            Some(&json!("2024-05-03T12:00:00Z"))
+        );
+        assert_eq!(field(&doc, "dist-tags.next"), Some(&json!("2.0.0-rc.1")));
+        assert_eq!(
+            format_fields(&doc, &["version".into()], false).unwrap(),
+            "1.2.0"
+        );
+        assert_eq!(
+            format_fields(&doc, &["version".into()], true).unwrap(),
+            "\"1.2.0\""
+        );
+        assert_eq!(
+            format_fields(&doc, &["version".into(), "license".into()], false).unwrap(),
+            "version = 1.2.0\nlicense = MIT"
+        );
+        let both: Value = serde_json::from_str(
+            &format_fields(&doc, &["version".into(), "weekly_downloads".into()], true).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(both, json!({"version": "1.2.0", "weekly_downloads": 42}));
+        assert!(format_fields(&doc, &["nope".into()], false).is_err());
+    }
+
+    #[test]
+    fn test_format_info() {
+        let doc = view(&meta(), None).unwrap();
+        assert_eq!(
+            format_info(&doc),
+            "foo@1.2.0 | MIT | deps: 1 | versions: 3\n\
+             Foo things\n\
+             DEPRECATED: Use 2.x\n\
+             \n\
+             dist-tags:\n  latest: 1.2.0\n  next: 2.0.0-rc.1\n\
+             \n\
+             dependencies:\n  bar: ^1.0.0\n\
+             \n\
+             dist:\n  tarball: http://localhost:4000/v1/foo/1.2.0/\n  shasum: abc\n\
+             \n\
+             versions:\n  1.2.0       2024-05-03  (shown, deprecated: Use 2.x)\n  \
+             1.10.0      2024-05-04  (yanked)\n  2.0.0-rc.1\n\
+             \n\
+             maintainers: alice, ci\n\
+             weekly downloads: 42\n"
+        );
+    }
+}
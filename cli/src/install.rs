@@ -0,0 +1,395 @@
+//! Installing and updating dependencies. An install plans the whole
+//! dependency graph first, from `bolt.lock` and registry metadata, then
+//! fetches and extracts each newly resolved package into `.boltpm/cache`.
+//! Plugins hear about each step once; see [`plugin_api::Hook`] for the order.
+
+use crate::plugin::Lifecycle;
+use crate::{registry, BoltLock, BoltLockEntry};
+use log::{error, info, warn};
+use plugin_api::{Hook, Payload, PlannedPackage};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+
+/// Where planning gets package metadata: [`registry::fetch_metadata`], or a
+/// stand-in in tests.
+type FetchMetadata<'a> = dyn Fn(&str) -> reqwest::Result<Result<Value, StatusCode>> + 'a;
+
+pub struct Options {
+    /// Resolve every package again instead of using `bolt.lock`.
+    pub refresh: bool,
+    /// Fail instead of warning when a version is deprecated.
+    pub strict_deprecations: bool,
+}
+
+/// Install `requested`, a map of package names to ranges (empty for the
+/// latest version), recording what was installed in `lock`. Returns whether
+/// `lock` changed.
+///
+/// Packages whose metadata or tarball can't be fetched are reported, with
+/// `onError`, and skipped. Ranges nothing matches, and plugin failures or
+/// vetoes, stop the install.
+pub fn install(
+    lifecycle: &Lifecycle,
+    requested: &BTreeMap<String, String>,
+    lock: &mut BoltLock,
+    options: &Options,
+) -> Result<bool, String> {
+    let install = Payload::Install {
+        requested: requested.clone(),
+    };
+    lifecycle
+        .fire(Hook::PreInstall, install.clone())
+        .map_err(|e| format!("Preinstall plugin failed: {e}"))?;
+    if requested.is_empty() {
+        info!("No dependencies to install.");
+    }
+    let plan = plan(
+        lifecycle,
+        requested,
+        lock,
+        options,
+        &registry::fetch_metadata,
+    )?;
+    lifecycle
+        .fire(
+            Hook::PostResolve,
+            Payload::Graph {
+                packages: plan.clone(),
+            },
+        )
+        .map_err(|e| format!("Postresolve plugin failed: {e}"))?;
+    let mut changed = false;
+    for (name, package) in plan.iter().filter(|(_, package)| !package.pinned) {
+        if let Some(resolved) = fetch(lifecycle, name, package)? {
+            lock.packages.insert(
+                name.clone(),
+                BoltLockEntry {
+                    version: package.version.clone(),
+                    resolved,
+                    dependencies: (!package.dependencies.is_empty())
+                        .then(|| package.dependencies.clone()),
+                },
+            );
+            changed = true;
+            info!("Install complete: {name}@{}", package.version);
+        }
+    }
+    lifecycle
+        .fire(Hook::PostInstall, install)
+        .map_err(|e| format!("Postinstall plugin failed: {e}"))?;
+    Ok(changed)
+}
+
+/// The packages `requested` and their dependencies, breadth first: the first
+/// range asked of a package is the one resolved. A package whose metadata
+/// can't be fetched is reported once and left out, however many ask for it.
+fn plan(
+    lifecycle: &Lifecycle,
+    requested: &BTreeMap<String, String>,
+    lock: &BoltLock,
+    options: &Options,
+    fetch_metadata: &FetchMetadata,
+) -> Result<BTreeMap<String, PlannedPackage>, String> {
+    let mut plan = BTreeMap::new();
+    let mut failed = BTreeSet::new();
+    let mut queue: VecDeque<_> = requested.clone().into_iter().collect();
+    while let Some((name, range)) = queue.pop_front() {
+        if plan.contains_key(&name) || failed.contains(&name) {
+            continue;
+        }
+        let package = match lock.packages.get(&name).filter(|_| !options.refresh) {
+            Some(entry) => pinned(&name, entry, options, fetch_metadata)?,
+            None => match resolve(lifecycle, &name, &range, options, fetch_metadata)? {
+                Some(package) => package,
+                None => {
+                    failed.insert(name);
+                    continue;
+                }
+            },
+        };
+        queue.extend(package.dependencies.clone());
+        plan.insert(name, package);
+    }
+    Ok(plan)
+}
+
+/// A package as `bolt.lock` pins it. Pins are kept even if yanked, but say
+/// so. Without metadata (e.g. offline) the pin is used as is.
+fn pinned(
+    name: &str,
+    entry: &BoltLockEntry,
+    options: &Options,
+    fetch_metadata: &FetchMetadata,
+) -> Result<PlannedPackage, String> {
+    info!("Using {name}@{} from lockfile", entry.version);
+    if let Ok(Ok(meta)) = fetch_metadata(name) {
+        if registry::is_yanked(&meta, &entry.version) {
+            warn!(
+                "{name}@{} in bolt.lock has been yanked; run `boltpm update {name}` to move off it",
+                entry.version
+            );
+        }
+        check_deprecation(&meta, name, &entry.version, options.strict_deprecations)?;
+    }
+    Ok(PlannedPackage {
+        version: entry.version.clone(),
+        tarball: entry.resolved.clone(),
+        dependencies: entry.dependencies.clone().unwrap_or_default(),
+        pinned: true,
+    })
+}
+
+/// Resolve `range` of `name` against the registry, after `preresolve`
+/// plugins have had a chance to rewrite it. `None` if the metadata can't be
+/// fetched.
+fn resolve(
+    lifecycle: &Lifecycle,
+    name: &str,
+    range: &str,
+    options: &Options,
+    fetch_metadata: &FetchMetadata,
+) -> Result<Option<PlannedPackage>, String> {
+    let rewrites = lifecycle
+        .fire(
+            Hook::PreResolve,
+            Payload::Resolve {
+                name: name.to_string(),
+                range: range.to_string(),
+            },
+        )
+        .map_err(|e| format!("Preresolve plugin failed: {e}"))?;
+    let range = rewrites.range.as_deref().unwrap_or(range);
+    let spec = Some(range).filter(|range| !range.is_empty());
+    info!("Fetching metadata from {}", registry::package_url(name));
+    let meta = match fetch_metadata(name) {
+        Ok(Ok(meta)) => meta,
+        Ok(Err(status)) => {
+            report(
+                lifecycle,
+                name,
+                format!("Failed to fetch metadata: {status}"),
+            );
+            return Ok(None);
+        }
+        Err(e) => {
+            report(lifecycle, name, format!("Error fetching metadata: {e}"));
+            return Ok(None);
+        }
+    };
+    let Some(version) = registry::resolve_version(&meta, spec) else {
+        return Err(no_matching_version(&meta, name, spec));
+    };
+    check_deprecation(&meta, name, &version, options.strict_deprecations)?;
+    let dependencies = meta["versions"][&version]["dependencies"]
+        .as_object()
+        .map(|deps| {
+            deps.iter()
+                .map(|(dep, range)| (dep.clone(), range.as_str().unwrap_or("").to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(Some(PlannedPackage {
+        tarball: registry::tarball_url(name, &version),
+        version,
+        dependencies,
+        pinned: false,
+    }))
+}
+
+/// Download and extract a newly resolved package, returning the URL it came
+/// from, or `None` if that failed.
+fn fetch(
+    lifecycle: &Lifecycle,
+    name: &str,
+    package: &PlannedPackage,
+) -> Result<Option<String>, String> {
+    let version = &package.version;
+    let rewrites = lifecycle
+        .fire(
+            Hook::PreFetch,
+            Payload::Fetch {
+                name: name.to_string(),
+                version: version.clone(),
+                url: package.tarball.clone(),
+            },
+        )
+        .map_err(|e| format!("Prefetch plugin failed: {e}"))?;
+    let url = rewrites.tarball.unwrap_or_else(|| package.tarball.clone());
+    info!("Downloading tarball from {url}");
+    let bytes = match reqwest::blocking::get(&url)
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.bytes())
+    {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            report(lifecycle, name, format!("Failed to download tarball: {e}"));
+            return Ok(None);
+        }
+    };
+    let cache_dir = format!(".boltpm/cache/{name}-{version}");
+    let archive = fs::create_dir_all(&cache_dir)
+        .and_then(|_| fs::write(format!("{cache_dir}/package.tgz"), &bytes))
+        .and_then(|_| {
+            tar::Archive::new(flate2::read::GzDecoder::new(&bytes[..])).unpack(&cache_dir)
+        });
+    if let Err(e) = archive {
+        report(lifecycle, name, format!("Extraction failed: {e}"));
+        return Ok(None);
+    }
+    info!("Extracted to {cache_dir}");
+    lifecycle
+        .fire(
+            Hook::PostExtract,
+            Payload::Package {
+                name: name.to_string(),
+                version: version.clone(),
+                path: cache_dir,
+            },
+        )
+        .map_err(|e| format!("Postextract plugin failed: {e}"))?;
+    Ok(Some(url))
+}
+
+/// Log a failure to install `package` and tell `onError` plugins, whose own
+/// failures are only logged.
+fn report(lifecycle: &Lifecycle, package: &str, message: String) {
+    error!("{package}: {message}");
+    let payload = Payload::Error {
+        message,
+        package: Some(package.to_string()),
+    };
+    if let Err(e) = lifecycle.fire(Hook::OnError, payload) {
+        error!("onError plugin failed: {e}");
+    }
+}
+
+/// Warn that `pkg@version` is deprecated, with the author's message, or fail
+/// with `--strict-deprecations`.
+fn check_deprecation(meta: &Value, pkg: &str, version: &str, strict: bool) -> Result<(), String> {
+    let Some(message) = registry::deprecation(meta, version) else {
+        return Ok(());
+    };
+    if strict {
+        return Err(format!(
+            "{pkg}@{version} is deprecated: {message}. Aborting due to --strict-deprecations."
+        ));
+    }
+    warn!("{pkg}@{version} is deprecated: {message}");
+    Ok(())
+}
+
+/// Why a spec resolved to nothing, saying so when it names a yanked version.
+fn no_matching_version(meta: &Value, pkg: &str, spec: Option<&str>) -> String {
+    match spec {
+        Some(spec) if registry::is_yanked(meta, spec) => {
+            format!("{pkg}@{spec} has been yanked and can't be newly installed")
+        }
+        _ => format!("No version of {pkg} matches '{}'", spec.unwrap_or("latest")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::cell::RefCell;
+
+    fn entry(version: &str, dependencies: &[(&str, &str)]) -> BoltLockEntry {
+        BoltLockEntry {
+            version: version.to_string(),
+            resolved: format!("http://localhost:4000/v1/pkg/{version}/"),
+            dependencies: (!dependencies.is_empty()).then(|| {
+                dependencies
+                    .iter()
+                    .map(|(dep, range)| (dep.to_string(), range.to_string()))
+                    .collect()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_plan_from_lockfile() {
+        let mut lock = BoltLock::default();
+        lock.packages.insert(
+            "app-utils".to_string(),
+            entry("1.2.0", &[("left-pad", "^1")]),
+        );
+        lock.packages.insert(
+            "left-pad".to_string(),
+            entry("1.3.0", &[("app-utils", "^1")]),
+        );
+        let requested = BTreeMap::from([("app-utils".to_string(), "^1".to_string())]);
+        let options = Options {
+            refresh: false,
+            strict_deprecations: false,
+        };
+        // Everything is pinned, so no plugin is asked to resolve anything,
+        // and the cycle back to app-utils ends. Metadata is only consulted
+        // for yanks and deprecations.
+        let fetched = RefCell::new(Vec::new());
+        let fetch_metadata = |name: &str| {
+            fetched.borrow_mut().push(name.to_string());
+            Ok(Ok(json!({"versions": {"1.3.0": {"yanked": true}}})))
+        };
+        let lifecycle = Lifecycle::without_plugins("app", "1.0.0");
+        let plan = plan(&lifecycle, &requested, &lock, &options, &fetch_metadata).unwrap();
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan["left-pad"].version, "1.3.0");
+        assert!(plan["left-pad"].pinned);
+        assert_eq!(plan["app-utils"].dependencies["left-pad"], "^1");
+        assert_eq!(*fetched.borrow(), ["app-utils", "left-pad"]);
+    }
+
+    #[test]
+    fn test_plan_reports_missing_packages_once() {
+        let mut lock = BoltLock::default();
+        lock.packages
+            .insert("a".to_string(), entry("1.0.0", &[("missing", "^1")]));
+        lock.packages
+            .insert("b".to_string(), entry("1.0.0", &[("missing", "^2")]));
+        let requested = BTreeMap::from([
+            ("a".to_string(), String::new()),
+            ("b".to_string(), String::new()),
+            ("missing".to_string(), String::new()),
+        ]);
+        let options = Options {
+            refresh: false,
+            strict_deprecations: false,
+        };
+        let fetched = RefCell::new(Vec::new());
+        let fetch_metadata = |name: &str| {
+            fetched.borrow_mut().push(name.to_string());
+            Ok(Err(StatusCode::NOT_FOUND))
+        };
+        let lifecycle = Lifecycle::without_plugins("app", "1.0.0");
+        let plan = plan(&lifecycle, &requested, &lock, &options, &fetch_metadata).unwrap();
+        assert_eq!(plan.keys().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(*fetched.borrow(), ["a", "b", "missing"]);
+    }
+
+    #[test]
+    fn test_version_errors() {
+        let meta = json!({
+            "versions": {
+                "1.0.0": {"yanked": true},
+                "1.1.0": {"deprecated": true, "deprecation_message": "use 2.x"}
+            }
+        });
+        assert_eq!(
+            no_matching_version(&meta, "left-pad", Some("1.0.0")),
+            "left-pad@1.0.0 has been yanked and can't be newly installed"
+        );
+        assert_eq!(
+            no_matching_version(&meta, "left-pad", None),
+            "No version of left-pad matches 'latest'"
+        );
+        assert!(check_deprecation(&meta, "left-pad", "1.1.0", false).is_ok());
+        assert!(check_deprecation(&meta, "left-pad", "1.0.0", true).is_ok());
+        assert_eq!(
+            check_deprecation(&meta, "left-pad", "1.1.0", true).unwrap_err(),
+            "left-pad@1.1.0 is deprecated: use 2.x. Aborting due to --strict-deprecations."
+        );
+    }
+}
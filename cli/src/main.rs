@@ -1,12 +1,17 @@
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
+mod info;
+mod install;
 mod lockfile;
 use std::path::Path;
+mod pack;
 mod plugin;
-use crate::plugin::run_plugins;
+mod registry;
+use crate::plugin::Lifecycle;
 use log::{error, info};
-use plugin_api::PluginContext;
+use plugin_api::{Hook, Payload};
 
 #[derive(Parser)]
 #[command(name = "boltpm")]
@@ -16,6 +21,9 @@ struct Cli {
     command: Commands,
     #[arg(long, default_value_t = false)]
     frozen_lockfile: bool,
+    /// Fail instead of warning when installing a deprecated version
+    #[arg(long, default_value_t = false)]
+    strict_deprecations: bool,
     #[arg(long, default_value = "info")]
     log_level: String,
 }
@@ -51,10 +59,131 @@ enum Commands {
         version: String,
         message: Option<String>,
     },
+    /// Remove a version from the registry for good: `boltpm unpublish foo@1.2.0`.
+    /// The version number can't be reused afterwards.
+    Unpublish {
+        package: String,
+    },
     Search {
         query: String,
+        /// Number of results to show
+        #[arg(long, default_value_t = 20)]
+        size: usize,
+        /// Number of results to skip
+        #[arg(long, default_value_t = 0)]
+        from: usize,
+    },
+    /// Show a package's registry metadata: `boltpm info foo@1.2.0`, or
+    /// `boltpm info foo versions --json` for selected fields
+    #[command(visible_alias = "view")]
+    Info {
+        /// Package name, optionally with @version, @range or @tag
+        package: String,
+        /// Fields to print, with dots for nested ones (e.g. `dist.tarball`)
+        fields: Vec<String>,
+        /// Print JSON
+        #[arg(long)]
+        json: bool,
     },
     Lock, // <-- add this
+    /// Pack the current directory and publish it to the registry
+    Publish {
+        /// Dist-tag to point at the new version instead of `latest`
+        #[arg(long)]
+        tag: Option<String>,
+    },
+    /// Manage a package's dist-tags
+    DistTag {
+        #[command(subcommand)]
+        command: DistTagCommand,
+    },
+    /// Manage webhooks the registry calls when packages change
+    Hook {
+        #[command(subcommand)]
+        command: HookCommand,
+    },
+    /// Registry administration
+    Registry {
+        #[command(subcommand)]
+        command: RegistryCommand,
+    },
+    /// Manage the project's plugins in `.boltpm/plugins`
+    Plugin {
+        #[command(subcommand)]
+        command: PluginCommand,
+    },
+    /// Run one request for an untrusted native plugin; spawned by boltpm itself
+    #[command(name = "plugin-host", hide = true)]
+    PluginHost {
+        library: std::path::PathBuf,
+        #[arg(long)]
+        memory_limit_mb: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum DistTagCommand {
+    /// Point a tag at a version: `boltpm dist-tag add foo@1.2.0 next`
+    Add { package: String, tag: String },
+    /// Remove a tag from a package
+    Rm { package: String, tag: String },
+    /// List a package's tags
+    Ls { package: String },
+}
+
+#[derive(Subcommand)]
+enum HookCommand {
+    /// Call `url` when a package (or every package in `@scope/*`) changes:
+    /// `boltpm hook add @acme/* https://ci.example.com/hook <secret>`
+    Add {
+        package: String,
+        url: String,
+        /// Key for the `X-Boltpm-Signature` HMAC of each payload
+        secret: String,
+        /// Only deliver these events (publish, unpublish, yank, unyank,
+        /// deprecate, undeprecate, dist-tag-add, dist-tag-remove)
+        #[arg(long = "event")]
+        events: Vec<String>,
+    },
+    /// List the registry's webhooks
+    Ls,
+    /// Remove a webhook
+    Rm { id: String },
+    /// Show recent delivery attempts for a webhook, newest first
+    Deliveries {
+        id: String,
+        /// Number of attempts to show
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum PluginCommand {
+    /// Install a plugin from a directory with a `plugin.toml`, a library, or
+    /// the registry: `boltpm plugin add license-check@^1`
+    Add { source: String },
+    /// Uninstall a plugin
+    Remove { name: String },
+    /// Show each plugin's version, hooks, ABI, trust level and status
+    List,
+    /// Run a disabled plugin again
+    Enable { name: String },
+    /// Stop running a plugin without uninstalling it
+    Disable { name: String },
+}
+
+#[derive(Subcommand)]
+enum RegistryCommand {
+    /// Show who published, yanked, deprecated or retagged what, newest first
+    AuditLog {
+        /// Only show changes to this package
+        #[arg(long)]
+        package: Option<String>,
+        /// Number of entries to show
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -89,6 +218,50 @@ fn write_lockfile(lock: &BoltLock) {
     fs::write("bolt.lock", s).expect("Failed to write bolt.lock");
 }
 
+/// What an install or update was asked for: `package` (`name@range`), or
+/// else the project's dependencies, by name, with their ranges.
+fn requested(package: Option<&str>, pj: &PackageJson) -> BTreeMap<String, String> {
+    if let Some(package) = package {
+        let (name, spec) = registry::parse_spec(package);
+        return BTreeMap::from([(name.to_string(), spec.unwrap_or("").to_string())]);
+    }
+    pj.dependencies
+        .as_ref()
+        .and_then(|deps| deps.as_object())
+        .map(|deps| {
+            deps.iter()
+                .map(|(dep, ver)| (dep.clone(), ver.as_str().unwrap_or("").to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Hooks for the project in the current directory, named after its
+/// `package.json` if it has one.
+fn project_lifecycle() -> Lifecycle {
+    let pj: serde_json::Value = fs::read_to_string("package.json")
+        .ok()
+        .and_then(|pj| serde_json::from_str(&pj).ok())
+        .unwrap_or_default();
+    Lifecycle::new(
+        pj["name"].as_str().unwrap_or_default(),
+        pj["version"].as_str().unwrap_or_default(),
+    )
+}
+
+/// Log a failed install or update, tell `onError` plugins, and exit.
+fn install_failed(lifecycle: &Lifecycle, message: String) -> ! {
+    error!("{message}");
+    let payload = Payload::Error {
+        message,
+        package: None,
+    };
+    if let Err(e) = lifecycle.fire(Hook::OnError, payload) {
+        error!("onError plugin failed: {e}");
+    }
+    std::process::exit(1);
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -121,23 +294,6 @@ fn main() {
             let pj: PackageJson = serde_json::from_str(&pj_str).expect("Invalid package.json");
             info!("Parsed package.json: {pj:?}");
             let mut lock = read_lockfile();
-            let mut changed = false;
-            // Plugin context setup
-            let ctx = PluginContext {
-                hook: "preinstall".to_string(),
-                package_name: pj.name.clone(),
-                package_version: pj.version.clone(),
-                install_path: std::env::current_dir()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string(),
-                env: std::env::vars().collect(),
-            };
-            // Always run preinstall plugins, even if no dependencies or fetch fails
-            if let Err(e) = run_plugins("preinstall", &ctx) {
-                error!("Preinstall plugin failed: {e}");
-                std::process::exit(1);
-            }
             // Check for frozen lockfile mismatch
             if cli.frozen_lockfile {
                 let mut mismatched = false;
@@ -166,358 +322,139 @@ fn main() {
                     std::process::exit(1);
                 }
             }
-            fn install_pkg(pkg: &str, lock: &mut BoltLock, changed: &mut bool) {
-                // Plugin context for this package
-                let ctx = PluginContext {
-                    hook: "preinstall".to_string(),
-                    package_name: pkg.to_string(),
-                    package_version: "unknown".to_string(),
-                    install_path: std::env::current_dir()
-                        .unwrap()
-                        .to_string_lossy()
-                        .to_string(),
-                    env: std::env::vars().collect(),
-                };
-                // Run preinstall plugins for this package
-                if let Err(e) = run_plugins("preinstall", &ctx) {
-                    error!("Preinstall plugin failed: {e}");
-                    std::process::exit(1);
+            let lifecycle = Lifecycle::new(&pj.name, &pj.version);
+            let options = install::Options {
+                refresh: false,
+                strict_deprecations: cli.strict_deprecations,
+            };
+            let requested = requested(package.as_deref(), &pj);
+            match install::install(&lifecycle, &requested, &mut lock, &options) {
+                Ok(true) => {
+                    write_lockfile(&lock);
+                    info!("bolt.lock updated.");
                 }
-                // If already in lockfile, use pinned version
-                if let Some(entry) = lock.packages.get(pkg) {
-                    info!(
-                        "Using {pkg}@{version} from lockfile",
-                        version = entry.version
-                    );
-                    // Run postinstall plugins for this package
-                    let ctx_post = PluginContext {
-                        hook: "postinstall".to_string(),
-                        package_name: pkg.to_string(),
-                        package_version: entry.version.clone(),
-                        install_path: entry.resolved.clone(),
-                        env: std::env::vars().collect(),
-                    };
-                    if let Err(e) = run_plugins("postinstall", &ctx_post) {
-                        error!("Postinstall plugin failed: {e}");
-                        std::process::exit(1);
-                    }
+                Ok(false) => info!("No changes to bolt.lock."),
+                Err(e) => install_failed(&lifecycle, e),
+            }
+        }
+        Commands::Remove { package } => {
+            info!("Removing package: {package}");
+            let mut lock = read_lockfile();
+            // The package and, recursively, its dependencies, by name, with
+            // their versions
+            fn collect(pkg: &str, lock: &BoltLock, removed: &mut BTreeMap<String, String>) {
+                if removed.contains_key(pkg) {
                     return;
                 }
-                let url = format!("http://localhost:4000/v1/{pkg}/");
-                info!("Fetching metadata from {url}");
-                let meta_resp = reqwest::blocking::get(&url);
-                match meta_resp {
-                    Ok(resp) => {
-                        if resp.status().is_success() {
-                            let meta: serde_json::Value = resp.json().unwrap();
-                            let versions = meta["versions"].as_object().unwrap();
-                            let latest = versions.keys().next_back().unwrap();
-                            let tarball_url = format!("http://localhost:4000/v1/{pkg}/{latest}/");
-                            info!("Downloading tarball from {tarball_url}");
-                            let tarball_resp = reqwest::blocking::get(&tarball_url).unwrap();
-                            if tarball_resp.status().is_success() {
-                                let bytes = tarball_resp.bytes().unwrap();
-                                let cache_dir = format!(".boltpm/cache/{pkg}-{latest}");
-                                fs::create_dir_all(&cache_dir).unwrap();
-                                let tarball_path = format!("{cache_dir}/package.tgz");
-                                fs::write(&tarball_path, &bytes).unwrap();
-                                // PluginContext for hooks
-                                let ctx = PluginContext {
-                                    hook: "preinstall".to_string(),
-                                    package_name: pkg.to_string(),
-                                    package_version: latest.to_string(),
-                                    install_path: cache_dir.clone(),
-                                    env: std::env::vars().collect(),
-                                };
-                                // Call preinstall plugin hook
-                                if let Err(e) = run_plugins("preinstall", &ctx) {
-                                    error!("Preinstall plugin failed: {e}");
-                                    std::process::exit(1);
-                                }
-                                // Extract tarball
-                                let tar_gz = fs::File::open(&tarball_path).unwrap();
-                                let decompressed = flate2::read::GzDecoder::new(tar_gz);
-                                let mut archive = tar::Archive::new(decompressed);
-                                if let Err(e) = archive.unpack(&cache_dir) {
-                                    println!("Extraction failed: {e}");
-                                    let _ = run_plugins("onError", &ctx);
-                                    return;
-                                }
-                                info!("Extracted to {cache_dir}");
-                                // Recursively install dependencies if package.json exists in extracted dir
-                                let extracted_pj = format!("{cache_dir}/package.json");
-                                let mut dependencies = std::collections::BTreeMap::new();
-                                if let Ok(dep_pj_str) = fs::read_to_string(&extracted_pj) {
-                                    if let Ok(dep_pj) =
-                                        serde_json::from_str::<PackageJson>(&dep_pj_str)
-                                    {
-                                        if let Some(deps) = dep_pj.dependencies {
-                                            if let Some(map) = deps.as_object() {
-                                                for (dep, ver) in map {
-                                                    dependencies.insert(
-                                                        dep.clone(),
-                                                        ver.as_str().unwrap_or("").to_string(),
-                                                    );
-                                                    install_pkg(dep, lock, changed);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                // Update lockfile
-                                lock.packages.insert(
-                                    pkg.to_string(),
-                                    BoltLockEntry {
-                                        version: latest.to_string(),
-                                        resolved: tarball_url.clone(),
-                                        dependencies: if dependencies.is_empty() {
-                                            None
-                                        } else {
-                                            Some(dependencies)
-                                        },
-                                    },
-                                );
-                                *changed = true;
-                                // Call postinstall plugin hook
-                                if let Err(e) = run_plugins("postinstall", &ctx) {
-                                    error!("Postinstall plugin failed: {e}");
-                                    std::process::exit(1);
-                                }
-                                info!("Install complete: {pkg}@{latest}");
-                            } else {
-                                error!(
-                                    "Failed to download tarball: {status}",
-                                    status = tarball_resp.status()
-                                );
-                                let ctx = PluginContext {
-                                    hook: "preinstall".to_string(),
-                                    package_name: pkg.to_string(),
-                                    package_version: "unknown".to_string(),
-                                    install_path: std::path::PathBuf::from("")
-                                        .to_string_lossy()
-                                        .to_string(),
-                                    env: std::env::vars().collect(),
-                                };
-                                let _ = run_plugins("onError", &ctx);
-                            }
-                        } else {
-                            error!("Failed to fetch metadata: {status}", status = resp.status());
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error fetching metadata: {e}");
+                if let Some(entry) = lock.packages.get(pkg) {
+                    removed.insert(pkg.to_string(), entry.version.clone());
+                    for dep in entry.dependencies.iter().flat_map(|deps| deps.keys()) {
+                        collect(dep, lock, removed);
                     }
                 }
-                // Always run postinstall plugins for this package
-                let ctx_post = PluginContext {
-                    hook: "postinstall".to_string(),
-                    package_name: pkg.to_string(),
-                    package_version: "unknown".to_string(),
-                    install_path: std::env::current_dir()
-                        .unwrap()
-                        .to_string_lossy()
-                        .to_string(),
-                    env: std::env::vars().collect(),
+            }
+            let mut removed = BTreeMap::new();
+            collect(&package, &lock, &mut removed);
+            let lifecycle = project_lifecycle();
+            for (name, version) in &removed {
+                let payload = Payload::Package {
+                    name: name.clone(),
+                    version: version.clone(),
+                    path: format!(".boltpm/cache/{name}-{version}"),
                 };
-                if let Err(e) = run_plugins("postinstall", &ctx_post) {
-                    error!("Postinstall plugin failed: {e}");
+                if let Err(e) = lifecycle.fire(Hook::PreUninstall, payload) {
+                    error!("Preuninstall plugin failed: {e}");
                     std::process::exit(1);
                 }
             }
-            if let Some(pkg) = package {
-                install_pkg(&pkg, &mut lock, &mut changed);
-            } else {
-                // Install all dependencies from package.json
-                if let Some(deps) = pj.dependencies {
-                    if let Some(map) = deps.as_object() {
-                        for (dep, _ver) in map {
-                            install_pkg(dep, &mut lock, &mut changed);
-                        }
-                    }
-                } else {
-                    info!("No dependencies to install.");
-                }
-            }
-            // After install, run postinstall plugins (always)
-            let ctx_post = PluginContext {
-                hook: "postinstall".to_string(),
-                package_name: pj.name.clone(),
-                package_version: pj.version.clone(),
-                install_path: std::env::current_dir()
-                    .unwrap()
-                    .to_string_lossy()
-                    .to_string(),
-                env: std::env::vars().collect(),
-            };
-            if let Err(e) = run_plugins("postinstall", &ctx_post) {
-                error!("Postinstall plugin failed: {e}");
-                std::process::exit(1);
-            }
-            if changed {
-                write_lockfile(&lock);
-                info!("bolt.lock updated.");
-            } else {
-                info!("No changes to bolt.lock.");
+            for name in removed.keys() {
+                lock.packages.remove(name);
             }
-        }
-        Commands::Remove { package } => {
-            info!("Removing package: {package}");
-            let mut lock = read_lockfile();
-            // Remove the package and its dependencies recursively from lockfile
-            fn remove_pkg(pkg: &str, lock: &mut BoltLock) {
-                if let Some(entry) = lock.packages.remove(pkg) {
-                    if let Some(deps) = entry.dependencies {
-                        for dep in deps.keys() {
-                            remove_pkg(dep, lock);
-                        }
-                    }
-                }
-            }
-            remove_pkg(&package, &mut lock);
             write_lockfile(&lock);
             info!("Removed {package} and its dependencies from bolt.lock.");
             // TODO: Remove from node_modules, filesystem, etc.
-            // TODO: Call onError plugin hook if needed
         }
         Commands::Update { package } => {
             info!("Updating package: {package:?}");
             let pj_str = fs::read_to_string("package.json").expect("No package.json found");
             let pj: PackageJson = serde_json::from_str(&pj_str).expect("Invalid package.json");
             let mut lock = read_lockfile();
-            let mut changed = false;
-            fn update_pkg(pkg: &str, lock: &mut BoltLock, changed: &mut bool) {
-                // Remove old entry if exists
-                lock.packages.remove(pkg);
-                // Reinstall to get latest and update lockfile
-                // (reuse install_pkg logic from install command)
-                // For now, duplicate logic for clarity
-                let url = format!("http://localhost:4000/v1/{pkg}/");
-                info!("Fetching metadata from {url}");
-                let meta_resp = reqwest::blocking::get(&url);
-                match meta_resp {
-                    Ok(resp) => {
-                        if resp.status().is_success() {
-                            let meta: serde_json::Value = resp.json().unwrap();
-                            let versions = meta["versions"].as_object().unwrap();
-                            let latest = versions.keys().next_back().unwrap();
-                            let tarball_url = format!("http://localhost:4000/v1/{pkg}/{latest}/");
-                            info!("Downloading tarball from {tarball_url}");
-                            let tarball_resp = reqwest::blocking::get(&tarball_url).unwrap();
-                            if tarball_resp.status().is_success() {
-                                let bytes = tarball_resp.bytes().unwrap();
-                                let cache_dir = format!(".boltpm/cache/{pkg}-{latest}");
-                                fs::create_dir_all(&cache_dir).unwrap();
-                                let tarball_path = format!("{cache_dir}/package.tgz");
-                                fs::write(&tarball_path, &bytes).unwrap();
-                                // PluginContext for hooks
-                                let ctx = PluginContext {
-                                    hook: "preinstall".to_string(),
-                                    package_name: pkg.to_string(),
-                                    package_version: latest.to_string(),
-                                    install_path: cache_dir.clone(),
-                                    env: std::env::vars().collect(),
-                                };
-                                let _ = run_plugins("preinstall", &ctx);
-                                let tar_gz = fs::File::open(&tarball_path).unwrap();
-                                let decompressed = flate2::read::GzDecoder::new(tar_gz);
-                                let mut archive = tar::Archive::new(decompressed);
-                                if let Err(e) = archive.unpack(&cache_dir) {
-                                    println!("Extraction failed: {e}");
-                                    let _ = run_plugins("onError", &ctx);
-                                    return;
-                                }
-                                info!("Extracted to {cache_dir}");
-                                let extracted_pj = format!("{cache_dir}/package.json");
-                                let mut dependencies = std::collections::BTreeMap::new();
-                                if let Ok(dep_pj_str) = fs::read_to_string(&extracted_pj) {
-                                    if let Ok(dep_pj) =
-                                        serde_json::from_str::<PackageJson>(&dep_pj_str)
-                                    {
-                                        if let Some(deps) = dep_pj.dependencies {
-                                            if let Some(map) = deps.as_object() {
-                                                for (dep, ver) in map {
-                                                    dependencies.insert(
-                                                        dep.clone(),
-                                                        ver.as_str().unwrap_or("").to_string(),
-                                                    );
-                                                    update_pkg(dep, lock, changed);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                lock.packages.insert(
-                                    pkg.to_string(),
-                                    BoltLockEntry {
-                                        version: latest.to_string(),
-                                        resolved: tarball_url.clone(),
-                                        dependencies: if dependencies.is_empty() {
-                                            None
-                                        } else {
-                                            Some(dependencies)
-                                        },
-                                    },
-                                );
-                                *changed = true;
-                                let _ = run_plugins("postinstall", &ctx);
-                                info!("Update complete: {pkg}@{latest}");
-                            } else {
-                                error!(
-                                    "Failed to download tarball: {status}",
-                                    status = tarball_resp.status()
-                                );
-                                let ctx = PluginContext {
-                                    hook: "preinstall".to_string(),
-                                    package_name: pkg.to_string(),
-                                    package_version: latest.to_string(),
-                                    install_path: std::path::PathBuf::from("")
-                                        .to_string_lossy()
-                                        .to_string(),
-                                    env: std::env::vars().collect(),
-                                };
-                                let _ = run_plugins("onError", &ctx);
-                            }
-                        } else {
-                            error!("Failed to fetch metadata: {status}", status = resp.status());
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error fetching metadata: {e}");
-                    }
+            let lifecycle = Lifecycle::new(&pj.name, &pj.version);
+            // Resolve everything reachable from what is updated again
+            let options = install::Options {
+                refresh: true,
+                strict_deprecations: cli.strict_deprecations,
+            };
+            let requested = requested(package.as_deref(), &pj);
+            match install::install(&lifecycle, &requested, &mut lock, &options) {
+                Ok(true) => {
+                    write_lockfile(&lock);
+                    info!("bolt.lock updated.");
                 }
+                Ok(false) => info!("No changes to bolt.lock."),
+                Err(e) => install_failed(&lifecycle, e),
             }
-            if let Some(pkg) = package {
-                update_pkg(&pkg, &mut lock, &mut changed);
+        }
+        Commands::Run { script } => {
+            let pj_str = fs::read_to_string("package.json").expect("No package.json found");
+            let pj: serde_json::Value =
+                serde_json::from_str(&pj_str).expect("Invalid package.json");
+            let Some(command) = pj["scripts"][&script].as_str() else {
+                error!("No script '{script}' in package.json");
+                std::process::exit(1);
+            };
+            let lifecycle = Lifecycle::new(
+                pj["name"].as_str().unwrap_or_default(),
+                pj["version"].as_str().unwrap_or_default(),
+            );
+            let payload = Payload::Script {
+                name: script.clone(),
+                command: command.to_string(),
+            };
+            if let Err(e) = lifecycle.fire(Hook::PreRun, payload.clone()) {
+                error!("Prerun plugin failed: {e}");
+                std::process::exit(1);
+            }
+            info!("Running script {script}: {command}");
+            let mut shell = if cfg!(windows) {
+                let mut shell = std::process::Command::new("cmd");
+                shell.arg("/C");
+                shell
             } else {
-                // Update all dependencies from package.json
-                if let Some(deps) = pj.dependencies {
-                    if let Some(map) = deps.as_object() {
-                        for (dep, _ver) in map {
-                            update_pkg(dep, &mut lock, &mut changed);
-                        }
-                    }
-                } else {
-                    info!("No dependencies to update.");
+                let mut shell = std::process::Command::new("sh");
+                shell.arg("-c");
+                shell
+            };
+            match shell.arg(command).status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    error!("Script {script} failed: {status}");
+                    std::process::exit(status.code().unwrap_or(1));
+                }
+                Err(e) => {
+                    error!("Failed to run script {script}: {e}");
+                    std::process::exit(1);
                 }
             }
-            if changed {
-                write_lockfile(&lock);
-                info!("bolt.lock updated.");
-            } else {
-                info!("No changes to bolt.lock.");
+            if let Err(e) = lifecycle.fire(Hook::PostRun, payload) {
+                error!("Postrun plugin failed: {e}");
+                std::process::exit(1);
             }
         }
-        Commands::Run { script } => {
-            info!("Running script: {script} (stub)");
-            // TODO: Run script from package.json
-        }
         Commands::Link { path } => {
+            let payload = Payload::Link {
+                path: path.clone().unwrap_or_else(|| ".".to_string()),
+            };
+            if let Err(e) = project_lifecycle().fire(Hook::PreLink, payload) {
+                error!("Prelink plugin failed: {e}");
+                std::process::exit(1);
+            }
             info!("Linking path: {path:?} (stub)");
             // TODO: Link local package
         }
         Commands::Yank { package, version } => {
             let url = format!("http://localhost:4000/v1/{package}/{version}/yank");
-            let resp = reqwest::blocking::Client::new().post(&url).send();
+            let resp = registry::client().post(&url).send();
             match resp {
                 Ok(r) => println!("{}", r.text().unwrap()),
                 Err(e) => println!("Error: {e}"),
@@ -525,7 +462,7 @@ fn main() {
         }
         Commands::Unyank { package, version } => {
             let url = format!("http://localhost:4000/v1/{package}/{version}/unyank");
-            let resp = reqwest::blocking::Client::new().post(&url).send();
+            let resp = registry::client().post(&url).send();
             match resp {
                 Ok(r) => println!("{}", r.text().unwrap()),
                 Err(e) => println!("Error: {e}"),
@@ -538,29 +475,271 @@ fn main() {
         } => {
             let url = format!("http://localhost:4000/v1/{package}/{version}/deprecate");
             let body = serde_json::json!({ "message": message });
-            let resp = reqwest::blocking::Client::new()
-                .post(&url)
-                .json(&body)
-                .send();
+            let resp = registry::client().post(&url).json(&body).send();
             match resp {
                 Ok(r) => println!("{}", r.text().unwrap()),
                 Err(e) => println!("Error: {e}"),
             }
         }
-        Commands::Search { query } => {
+        Commands::Unpublish { package } => {
+            let (name, version) = registry::parse_spec(&package);
+            let Some(version) = version else {
+                error!("Usage: boltpm unpublish <pkg>@<version>");
+                std::process::exit(1);
+            };
+            match registry::client()
+                .delete(registry::tarball_url(name, version))
+                .send()
+            {
+                Ok(r) if r.status().is_success() => println!("{}", r.text().unwrap()),
+                Ok(r) => {
+                    error!("unpublish failed ({}): {}", r.status(), r.text().unwrap());
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("Error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Search { query, size, from } => {
             let url = format!(
-                "http://localhost:4000/v1/search?q={}",
+                "{}/v1/search?q={}&size={size}&from={from}",
+                registry::REGISTRY_URL,
                 urlencoding::encode(&query)
             );
-            let resp = reqwest::blocking::get(&url);
+            let resp = reqwest::blocking::get(&url).and_then(|r| r.error_for_status());
+            match resp.and_then(|r| r.json::<serde_json::Value>()) {
+                Ok(page) => print!("{}", registry::format_search_results(&page)),
+                Err(e) => println!("Error: {e}"),
+            }
+        }
+        Commands::Publish { tag } => {
+            let pj_str = fs::read_to_string("package.json").expect("No package.json found");
+            let pj: serde_json::Value =
+                serde_json::from_str(&pj_str).expect("Invalid package.json");
+            let (Some(name), Some(version)) = (pj["name"].as_str(), pj["version"].as_str()) else {
+                error!("package.json must have a name and version to publish");
+                std::process::exit(1);
+            };
+            let tarball = pack::pack(Path::new(".")).expect("Failed to pack package");
+            info!("Packed {name}@{version} ({} bytes)", tarball.len());
+            let payload = Payload::Publish {
+                name: name.to_string(),
+                version: version.to_string(),
+                tag: tag.clone(),
+                size: tarball.len(),
+            };
+            if let Err(e) = Lifecycle::new(name, version).fire(Hook::PrePublish, payload) {
+                error!("Prepublish plugin failed: {e}");
+                std::process::exit(1);
+            }
+            let mut form = reqwest::blocking::multipart::Form::new()
+                .text("version", version.to_string())
+                .text(
+                    "description",
+                    pj["description"].as_str().unwrap_or_default().to_string(),
+                )
+                .part(
+                    "tarball",
+                    reqwest::blocking::multipart::Part::bytes(tarball).file_name("package.tgz"),
+                );
+            if let Some(tag) = tag {
+                form = form.text("tag", tag);
+            }
+            let resp = registry::client()
+                .put(registry::package_url(name))
+                .multipart(form)
+                .send();
             match resp {
+                Ok(r) if r.status().is_success() => println!("{}", r.text().unwrap()),
                 Ok(r) => {
-                    let text = r.text().unwrap();
-                    println!("Search results: {text}");
+                    error!("Publish failed ({}): {}", r.status(), r.text().unwrap());
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("Error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::DistTag { command } => {
+            let client = registry::client();
+            let tag_url =
+                |pkg: &str, tag: &str| format!("{}dist-tags/{}", registry::package_url(pkg), tag);
+            let resp = match &command {
+                DistTagCommand::Add { package, tag } => {
+                    let (name, version) = registry::parse_spec(package);
+                    let Some(version) = version else {
+                        error!("Usage: boltpm dist-tag add <pkg>@<version> <tag>");
+                        std::process::exit(1);
+                    };
+                    client.put(tag_url(name, tag)).json(&version).send()
+                }
+                DistTagCommand::Rm { package, tag } => client.delete(tag_url(package, tag)).send(),
+                DistTagCommand::Ls { package } => client
+                    .get(format!("{}dist-tags", registry::package_url(package)))
+                    .send(),
+            };
+            match resp {
+                Ok(r) if r.status().is_success() => {
+                    if let DistTagCommand::Ls { .. } = command {
+                        let tags: std::collections::BTreeMap<String, String> = r.json().unwrap();
+                        for (tag, version) in tags {
+                            println!("{tag}: {version}");
+                        }
+                    } else {
+                        println!("{}", r.text().unwrap());
+                    }
+                }
+                Ok(r) => {
+                    error!("dist-tag failed ({}): {}", r.status(), r.text().unwrap());
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("Error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Info {
+            package,
+            fields,
+            json,
+        } => {
+            let (name, spec) = registry::parse_spec(&package);
+            let meta = match registry::fetch_metadata(name) {
+                Ok(Ok(meta)) => meta,
+                Ok(Err(status)) if status == reqwest::StatusCode::NOT_FOUND => {
+                    error!("Package {name} not found");
+                    std::process::exit(1);
+                }
+                Ok(Err(status)) => {
+                    error!("Failed to fetch {name}: {status}");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("Error: {e}");
+                    std::process::exit(1);
+                }
+            };
+            let output = info::view(&meta, spec).and_then(|doc| {
+                if fields.is_empty() && !json {
+                    Ok(info::format_info(&doc))
+                } else {
+                    info::format_fields(&doc, &fields, json).map(|out| out + "\n")
+                }
+            });
+            match output {
+                Ok(output) => print!("{output}"),
+                Err(e) => {
+                    error!("{e}");
+                    std::process::exit(1);
                 }
-                Err(e) => println!("Error: {e}"),
             }
         }
+        Commands::Hook { command } => {
+            let client = registry::client();
+            let hooks_url = format!("{}/v1/-/hooks", registry::REGISTRY_URL);
+            let resp = match &command {
+                HookCommand::Add {
+                    package,
+                    url,
+                    secret,
+                    events,
+                } => client
+                    .post(&hooks_url)
+                    .json(&serde_json::json!({
+                        "package": package,
+                        "url": url,
+                        "secret": secret,
+                        "events": events,
+                    }))
+                    .send(),
+                HookCommand::Ls => client.get(&hooks_url).send(),
+                HookCommand::Rm { id } => client.delete(format!("{hooks_url}/{id}")).send(),
+                HookCommand::Deliveries { id, limit } => client
+                    .get(format!("{hooks_url}/{id}/deliveries?limit={limit}"))
+                    .send(),
+            };
+            match resp {
+                Ok(r) if r.status().is_success() => match &command {
+                    HookCommand::Add { package, .. } => match r.json::<serde_json::Value>() {
+                        Ok(hook) => println!(
+                            "Added webhook {} for {package}",
+                            hook["id"].as_str().unwrap_or_default()
+                        ),
+                        Err(e) => error!("Invalid webhook response: {e}"),
+                    },
+                    HookCommand::Ls => match r.json::<serde_json::Value>() {
+                        Ok(hooks) => print!("{}", registry::format_webhooks(&hooks)),
+                        Err(e) => error!("Invalid webhook response: {e}"),
+                    },
+                    HookCommand::Rm { .. } => println!("{}", r.text().unwrap()),
+                    HookCommand::Deliveries { .. } => match r.json::<serde_json::Value>() {
+                        Ok(deliveries) => print!("{}", registry::format_deliveries(&deliveries)),
+                        Err(e) => error!("Invalid webhook response: {e}"),
+                    },
+                },
+                Ok(r) => {
+                    error!("hook failed ({}): {}", r.status(), r.text().unwrap());
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("Error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Registry {
+            command: RegistryCommand::AuditLog { package, limit },
+        } => {
+            let mut url = format!("{}/v1/-/audit?limit={limit}", registry::REGISTRY_URL);
+            if let Some(package) = &package {
+                url += &format!("&package={}", urlencoding::encode(package));
+            }
+            match registry::client().get(&url).send() {
+                Ok(r) if r.status().is_success() => match r.json::<serde_json::Value>() {
+                    Ok(entries) => print!("{}", registry::format_audit_log(&entries)),
+                    Err(e) => error!("Invalid audit log response: {e}"),
+                },
+                Ok(r) => {
+                    error!("audit-log failed ({}): {}", r.status(), r.text().unwrap());
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("Error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Plugin { command } => {
+            let project = Path::new(".");
+            let result = match command {
+                PluginCommand::Add { source } => plugin::manage::add(project, &source)
+                    .map(|(name, version)| println!("Added plugin {name}@{version}")),
+                PluginCommand::Remove { name } => plugin::manage::remove(project, &name)
+                    .map(|_| println!("Removed plugin {name}")),
+                PluginCommand::List => plugin::manage::list(project)
+                    .map(|plugins| print!("{}", plugin::manage::format_list(&plugins))),
+                PluginCommand::Enable { name } => plugin::manage::set_enabled(project, &name, true)
+                    .map(|_| println!("Enabled plugin {name}")),
+                PluginCommand::Disable { name } => {
+                    plugin::manage::set_enabled(project, &name, false)
+                        .map(|_| println!("Disabled plugin {name}"))
+                }
+            };
+            if let Err(e) = result {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::PluginHost {
+            library,
+            memory_limit_mb,
+        } => {
+            std::process::exit(plugin::serve_plugin_host(&library, memory_limit_mb));
+        }
         Commands::Lock => {
             // Read package.json (for now, just get the name)
             let pj_str = fs::read_to_string("package.json").expect("No package.json found");
@@ -0,0 +1,65 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Directories never included in a published tarball.
+const IGNORED: &[&str] = &[".git", ".boltpm", "node_modules", "target"];
+
+/// Pack `dir` into a gzipped tarball with every file under `package/`, the
+/// layout the registry and npm expect.
+pub fn pack(dir: &Path) -> io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    builder.follow_symlinks(false);
+    append_dir(&mut builder, dir, Path::new("package"))?;
+    builder.into_inner()?.finish()
+}
+
+fn append_dir(
+    builder: &mut tar::Builder<GzEncoder<Vec<u8>>>,
+    dir: &Path,
+    prefix: &Path,
+) -> io::Result<()> {
+    // Sort entries so the same sources always produce the same tarball.
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        if IGNORED.iter().any(|ignored| name == *ignored) {
+            continue;
+        }
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            append_dir(builder, &path, &prefix.join(&name))?;
+        } else if file_type.is_file() {
+            builder.append_path_with_name(&path, prefix.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_skips_ignored_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("package.json"), "{}").unwrap();
+        fs::create_dir_all(dir.path().join("lib")).unwrap();
+        fs::write(dir.path().join("lib/index.js"), "").unwrap();
+        fs::create_dir_all(dir.path().join("node_modules/dep")).unwrap();
+        fs::write(dir.path().join("node_modules/dep/index.js"), "").unwrap();
+
+        let bytes = pack(dir.path()).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&bytes[..]));
+        let paths: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(paths, ["package/lib/index.js", "package/package.json"]);
+    }
+}
@@ -1,9 +1,29 @@
+mod approval;
+mod callbacks;
+mod host;
+pub mod manage;
+mod manifest;
+mod settings;
+#[cfg(feature = "wasm_plugins")]
+mod wasm;
+
+use approval::Approvals;
 use libloading::{Library, Symbol};
-use log::{debug, error, info};
-use plugin_api::PluginContext;
+use log::{debug, error, info, warn};
+use manifest::{run_order, Capabilities, PluginManifest, MANIFEST_FILE};
+use plugin_api::{
+    FreeFn, Hook, Outcome, Payload, PluginContext, RunFn, ABI_VERSION, ABI_VERSION_SYMBOL, EXIT_OK,
+    EXIT_PANICKED, FREE_SYMBOL, HOOKS_SYMBOL, RUN_SYMBOL,
+};
+use serde::{Deserialize, Serialize};
+use settings::{ProjectConfig, PROJECT_CONFIG};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+pub use host::serve as serve_plugin_host;
 
 #[derive(Debug)]
 pub enum PluginError {
@@ -12,6 +32,60 @@ pub enum PluginError {
     Serialization(serde_json::Error),
     Execution(i32),
     Panic,
+    /// A `plugin.toml` that is invalid, or doesn't identify one library, or
+    /// an invalid `boltpm.toml`.
+    Manifest(PathBuf, String),
+    /// Settings in `boltpm.toml` that the plugin's manifest doesn't allow.
+    Config {
+        plugin: String,
+        message: String,
+    },
+    /// No installed plugin has this name.
+    NotInstalled(String),
+    /// What `boltpm plugin add` was given isn't a plugin.
+    NotAPlugin(PathBuf),
+    /// A plugin package couldn't be fetched from the registry.
+    Download(String),
+    /// A plugin built against a `plugin_api` this boltpm doesn't provide.
+    IncompatibleApi {
+        plugin: String,
+        required: String,
+    },
+    /// The installed plugins' ordering constraints can't be satisfied.
+    Ordering(String),
+    /// A library built for another plugin ABI, or not built with
+    /// `plugin_api::declare_plugin!` at all (`found` is `None`).
+    IncompatibleAbi {
+        path: PathBuf,
+        found: Option<u32>,
+    },
+    /// A WASM plugin whose capabilities the user hasn't approved.
+    Unapproved {
+        plugin: String,
+        capabilities: Vec<String>,
+    },
+    /// A plugin that ran past its deadline and was stopped.
+    Timeout,
+    /// An isolated plugin's process died, e.g. from a segfault or abort.
+    Crashed {
+        path: PathBuf,
+        reason: String,
+    },
+    /// An error an isolated plugin's process reported.
+    Host(String),
+    /// A plugin returned an outcome that isn't valid JSON, or can't be
+    /// applied.
+    Outcome(PathBuf, String),
+    /// A plugin stopped the operation.
+    Vetoed {
+        plugin: String,
+        reason: String,
+    },
+    /// A WASM module that failed to compile, link or run.
+    #[cfg(feature = "wasm_plugins")]
+    Wasm(String),
+    #[cfg(feature = "wasm_plugins")]
+    OutOfFuel,
 }
 
 impl fmt::Display for PluginError {
@@ -22,6 +96,60 @@ impl fmt::Display for PluginError {
             PluginError::Serialization(e) => write!(f, "Serialization error: {e}"),
             PluginError::Execution(code) => write!(f, "Plugin execution failed with code: {code}"),
             PluginError::Panic => write!(f, "Plugin panicked during execution"),
+            PluginError::Manifest(path, e) => write!(f, "Invalid {}: {e}", path.display()),
+            PluginError::Config { plugin, message } => {
+                write!(f, "Invalid config for plugin {plugin} in {PROJECT_CONFIG}: {message}")
+            }
+            PluginError::NotInstalled(name) => write!(f, "No plugin named {name} is installed"),
+            PluginError::NotAPlugin(path) => write!(
+                f,
+                "{} is not a plugin: expected a directory with a {MANIFEST_FILE}, or a plugin library",
+                path.display()
+            ),
+            PluginError::Download(e) => write!(f, "{e}"),
+            PluginError::IncompatibleApi { plugin, required } => write!(
+                f,
+                "Plugin {plugin} requires plugin_api {required} but this boltpm provides {}",
+                plugin_api::VERSION
+            ),
+            PluginError::Ordering(e) => write!(f, "{e}"),
+            PluginError::IncompatibleAbi { path, found: None } => write!(
+                f,
+                "{} is not a boltpm plugin: it doesn't export {ABI_VERSION_SYMBOL} (build it with plugin_api::declare_plugin!)",
+                path.display()
+            ),
+            PluginError::IncompatibleAbi {
+                path,
+                found: Some(found),
+            } => write!(
+                f,
+                "Plugin {} was built for plugin ABI v{found} but this boltpm supports v{ABI_VERSION}; rebuild it against plugin_api {}",
+                path.display(),
+                plugin_api::VERSION
+            ),
+            PluginError::Unapproved {
+                plugin,
+                capabilities,
+            } => write!(
+                f,
+                "Plugin {plugin} needs approval to {}. Run boltpm in a terminal to approve it, or see BOLTPM_PLUGIN_APPROVALS for CI",
+                capabilities.join(", "),
+            ),
+            PluginError::Timeout => write!(f, "Plugin timed out"),
+            PluginError::Crashed { path, reason } => {
+                write!(f, "Plugin {} crashed: {reason}", path.display())
+            }
+            PluginError::Host(e) => write!(f, "{e}"),
+            PluginError::Outcome(path, e) => {
+                write!(f, "Plugin {} returned an invalid outcome: {e}", path.display())
+            }
+            PluginError::Vetoed { plugin, reason } => write!(f, "Plugin {plugin} vetoed: {reason}"),
+            #[cfg(feature = "wasm_plugins")]
+            PluginError::Wasm(e) => write!(f, "WASM plugin error: {e}"),
+            #[cfg(feature = "wasm_plugins")]
+            PluginError::OutOfFuel => {
+                write!(f, "WASM plugin exceeded its instruction budget")
+            }
         }
     }
 }
@@ -46,39 +174,607 @@ impl From<serde_json::Error> for PluginError {
 
 impl std::error::Error for PluginError {}
 
-pub fn run_plugins(_hook: &str, ctx: &PluginContext) -> Result<(), PluginError> {
-    let cwd = std::env::current_dir().map_err(|e| {
-        PluginError::Io(std::io::Error::other(format!(
-            "Failed to get current working directory: {e}"
-        )))
-    })?;
-    let plugins_dir = cwd.join(".boltpm/plugins");
-    debug!("Searching for plugins in: {}", plugins_dir.display());
-
-    if !plugins_dir.exists() {
-        debug!(
-            "Plugins directory does not exist: {}",
-            plugins_dir.display()
+/// A plugin found in `.boltpm/plugins`, kept loaded for the rest of the run.
+struct LoadedPlugin {
+    path: PathBuf,
+    backend: Backend,
+    /// The plugin's `plugin.toml`, or what is assumed of a bare library.
+    manifest: PluginManifest,
+    /// The plugin's config, from its defaults and `boltpm.toml`.
+    config: BTreeMap<String, serde_json::Value>,
+}
+
+impl LoadedPlugin {
+    /// Load a plugin directory holding `manifest`. Its API requirement and
+    /// the approval of its capabilities (only `env`, for a native library)
+    /// are checked before the library is loaded.
+    fn load_dir(
+        dir: &Path,
+        manifest: PluginManifest,
+        loader: &Loader,
+    ) -> Result<Self, PluginError> {
+        if !manifest.supports_api(plugin_api::VERSION) {
+            return Err(PluginError::IncompatibleApi {
+                plugin: manifest.name,
+                required: manifest.api_version,
+            });
+        }
+        let path = library_path(dir, &manifest)?;
+        if is_wasm_file(&path) {
+            approval::check(
+                &loader.approvals,
+                &manifest.name,
+                &path,
+                &manifest.capabilities,
+                approval::ask_user,
+            )?;
+        } else {
+            if !manifest.capabilities.only_env() {
+                warn!(
+                    "Plugin {} declares capabilities, but is a native library: only `env` applies to it",
+                    manifest.name
+                );
+            }
+            let env = Capabilities {
+                env: manifest.capabilities.env.clone(),
+                ..Capabilities::default()
+            };
+            approval::check(
+                &loader.approvals,
+                &manifest.name,
+                &path,
+                &env,
+                approval::ask_user,
+            )?;
+        }
+        let backend = Backend::open(&path, &manifest.name, &manifest.capabilities, loader)?;
+        Ok(LoadedPlugin {
+            path,
+            backend,
+            manifest,
+            config: BTreeMap::new(),
+        })
+    }
+
+    /// Load a library installed without a manifest. A native library runs
+    /// on the hooks its `boltpm_plugin_hooks` export lists, or on every hook;
+    /// a WASM module on every hook.
+    fn load_bare(path: &Path, loader: &Loader) -> Result<Self, PluginError> {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let backend = Backend::open(path, &name, &Capabilities::bare(), loader)?;
+        let exported = match &backend {
+            Backend::Native(library) => exported_hooks(library),
+            Backend::Isolated { hooks } => *hooks,
+            #[cfg(feature = "wasm_plugins")]
+            Backend::Wasm(_) => None,
+        };
+        let mask = exported.unwrap_or_else(|| {
+            debug!(
+                "Plugin {} doesn't export {HOOKS_SYMBOL}; running it on every hook",
+                path.display()
+            );
+            u32::MAX
+        });
+        Ok(LoadedPlugin {
+            path: path.to_path_buf(),
+            backend,
+            manifest: PluginManifest::bare(&name, hooks_in(mask)),
+            config: BTreeMap::new(),
+        })
+    }
+
+    fn subscribes(&self, hook: Hook) -> bool {
+        self.manifest.hook_mask() & hook.bit() != 0
+    }
+}
+
+/// How a plugin's code is run.
+enum Backend {
+    /// A native library loaded into this process, with full trust.
+    Native(Library),
+    /// A native library run in a `boltpm plugin-host` process each time.
+    Isolated {
+        /// What the library's `boltpm_plugin_hooks` export returned.
+        hooks: Option<u32>,
+    },
+    #[cfg(feature = "wasm_plugins")]
+    Wasm(wasm::WasmPlugin),
+}
+
+impl Backend {
+    /// Load a plugin's code. A WASM module can use only the host functions
+    /// `capabilities` grant. A native library is loaded in-process if the
+    /// user trusts it, and otherwise checked in a child process.
+    #[cfg_attr(not(feature = "wasm_plugins"), allow(unused_variables))]
+    fn open(
+        path: &Path,
+        name: &str,
+        capabilities: &Capabilities,
+        loader: &Loader,
+    ) -> Result<Self, PluginError> {
+        #[cfg(feature = "wasm_plugins")]
+        if is_wasm_file(path) {
+            return Ok(Backend::Wasm(wasm::WasmPlugin::load(
+                path,
+                capabilities.clone(),
+            )?));
+        }
+        if loader.isolates(name, path)? {
+            info!(
+                "Plugin {} is an untrusted native library; running it in a separate process",
+                path.display()
+            );
+            let probe = host::describe(path)?;
+            check_abi(path, probe.abi)?;
+            return Ok(Backend::Isolated { hooks: probe.hooks });
+        }
+        info!(
+            "Plugin {} is a native library, with full trust",
+            path.display()
         );
-        return Ok(()); // No plugins to run
+        Ok(Backend::Native(open_library(path)?))
+    }
+}
+
+/// How untrusted native plugins run, set with `BOLTPM_PLUGIN_ISOLATION`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Isolation {
+    /// Plugins the user hasn't trusted run in a child process. The default.
+    Untrusted,
+    /// All native plugins run in a child process.
+    All,
+    /// All native plugins run in-process.
+    None,
+}
+
+impl Isolation {
+    fn from_env() -> Self {
+        match std::env::var("BOLTPM_PLUGIN_ISOLATION").as_deref() {
+            Ok("all") => Isolation::All,
+            Ok("none") => Isolation::None,
+            Ok("untrusted") | Err(_) => Isolation::Untrusted,
+            Ok(other) => {
+                warn!("Unknown BOLTPM_PLUGIN_ISOLATION '{other}'; expected all, untrusted or none");
+                Isolation::Untrusted
+            }
+        }
+    }
+}
+
+/// What plugins are loaded with, for the project in one directory.
+struct Loader {
+    approvals: Approvals,
+    isolation: Isolation,
+    settings: ProjectConfig,
+    /// Whether the user may be asked to trust plugins.
+    interactive: bool,
+}
+
+impl Loader {
+    fn new(project: &Path, isolation: Isolation) -> Result<Self, PluginError> {
+        Ok(Loader {
+            approvals: Approvals::new(project),
+            isolation,
+            settings: ProjectConfig::load(&project.join(PROJECT_CONFIG))?,
+            interactive: true,
+        })
+    }
+
+    /// Whether the native plugin `name` in `library` runs in a child
+    /// process.
+    fn isolates(&self, name: &str, library: &Path) -> Result<bool, PluginError> {
+        match self.isolation {
+            Isolation::All => Ok(true),
+            Isolation::None => Ok(false),
+            Isolation::Untrusted => Ok(!approval::check_trust(
+                &self.approvals,
+                name,
+                library,
+                |plugin| {
+                    if self.interactive {
+                        approval::ask_trust(plugin)
+                    } else {
+                        None
+                    }
+                },
+            )?),
+        }
+    }
+}
+
+/// Where a project keeps its plugins.
+fn plugins_dir(project: &Path) -> PathBuf {
+    project.join(".boltpm").join("plugins")
+}
+
+/// What a native library says about itself, read without calling `run`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Probe {
+    /// Its `boltpm_plugin_abi_version`, if it exports one.
+    abi: Option<u32>,
+    /// Its `boltpm_plugin_hooks`, if it exports one.
+    hooks: Option<u32>,
+}
+
+impl Probe {
+    fn of(library: &Library) -> Self {
+        let abi =
+            match unsafe { library.get::<extern "C" fn() -> u32>(ABI_VERSION_SYMBOL.as_bytes()) } {
+                Ok(abi_version) => Some(abi_version()),
+                Err(_) => None,
+            };
+        Probe {
+            abi,
+            hooks: exported_hooks(library),
+        }
     }
+}
 
-    let entries = fs::read_dir(&plugins_dir)?;
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path();
-        debug!("Found file: {}", path.display());
+/// The hooks set in `mask`.
+fn hooks_in(mask: u32) -> Vec<Hook> {
+    Hook::ALL
+        .into_iter()
+        .filter(|hook| mask & hook.bit() != 0)
+        .collect()
+}
 
-        if is_plugin_file(&path) {
-            debug!("Attempting to load plugin: {}", path.display());
-            load_and_run_plugin(&path, ctx)?;
+/// What a library's `boltpm_plugin_hooks` export returns, if it has one.
+fn exported_hooks(library: &Library) -> Option<u32> {
+    unsafe {
+        library
+            .get::<extern "C" fn() -> u32>(HOOKS_SYMBOL.as_bytes())
+            .map(|hooks| hooks())
+            .ok()
+    }
+}
+
+/// Load a library without checking it is a plugin.
+fn load_library(path: &Path) -> Result<Library, PluginError> {
+    // SAFETY: loading a library runs its initializers; plugins in the
+    // project's plugin directory are trusted to be well-behaved.
+    Ok(unsafe { Library::new(path)? })
+}
+
+/// Check a library was built for this host's ABI, so that its `run` has the
+/// signature it will be called with.
+fn check_abi(path: &Path, found: Option<u32>) -> Result<(), PluginError> {
+    if found != Some(ABI_VERSION) {
+        return Err(PluginError::IncompatibleAbi {
+            path: path.to_path_buf(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+/// Load a library and check its ABI.
+fn open_library(path: &Path) -> Result<Library, PluginError> {
+    let library = load_library(path)?;
+    check_abi(path, Probe::of(&library).abi)?;
+    Ok(library)
+}
+
+/// The library a plugin directory's manifest identifies.
+fn library_path(dir: &Path, manifest: &PluginManifest) -> Result<PathBuf, PluginError> {
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let path = match &manifest.library {
+        Some(library) => dir.join(library),
+        None => {
+            let mut libraries = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if is_plugin_file(&path) {
+                    libraries.push(path);
+                }
+            }
+            if libraries.len() != 1 {
+                return Err(PluginError::Manifest(
+                    manifest_path,
+                    format!(
+                        "found {} plugin libraries next to it; set `library` to pick one",
+                        libraries.len()
+                    ),
+                ));
+            }
+            libraries.remove(0)
+        }
+    };
+    if is_wasm_file(&path) && !cfg!(feature = "wasm_plugins") {
+        return Err(PluginError::Manifest(
+            manifest_path,
+            "this boltpm was built without the wasm_plugins feature".to_string(),
+        ));
+    }
+    Ok(path)
+}
+
+/// A plugin in `.boltpm/plugins`, before its code is loaded.
+struct Installed {
+    /// The plugin's directory, or a bare library.
+    path: PathBuf,
+    /// The directory's `plugin.toml`; `None` for a bare library.
+    manifest: Option<PluginManifest>,
+}
+
+impl Installed {
+    /// The name the plugin goes by in `boltpm.toml`, approvals and the
+    /// `boltpm plugin` commands: its manifest's, or a bare library's file
+    /// stem.
+    fn name(&self) -> String {
+        match &self.manifest {
+            Some(manifest) => manifest.name.clone(),
+            None => self
+                .path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        }
+    }
+}
+
+/// Read and validate a plugin directory's `plugin.toml`.
+fn read_manifest(dir: &Path) -> Result<PluginManifest, PluginError> {
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let raw = fs::read_to_string(&manifest_path)?;
+    PluginManifest::parse(&raw).map_err(|e| PluginError::Manifest(manifest_path, e))
+}
+
+/// The plugins in `dir`, sorted by path. A plugin is either a directory with
+/// a `plugin.toml`, or a bare library.
+fn installed(dir: &Path) -> Result<Vec<Installed>, PluginError> {
+    debug!("Searching for plugins in: {}", dir.display());
+    if !dir.exists() {
+        debug!("Plugins directory does not exist: {}", dir.display());
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        paths.push(entry?.path());
+    }
+    paths.sort();
+    let mut plugins = Vec::new();
+    for path in paths {
+        if path.join(MANIFEST_FILE).is_file() {
+            let manifest = read_manifest(&path)?;
+            plugins.push(Installed {
+                path,
+                manifest: Some(manifest),
+            });
+        } else if is_plugin_file(&path) {
+            plugins.push(Installed {
+                path,
+                manifest: None,
+            });
+        } else if is_wasm_file(&path) {
+            warn!(
+                "Skipping WASM plugin {}: this boltpm was built without the wasm_plugins feature",
+                path.display()
+            );
+        } else {
+            debug!("Skipping non-plugin file: {}", path.display());
+        }
+    }
+    Ok(plugins)
+}
+
+/// Plugins are discovered and loaded on the first hook, then reused.
+static PLUGINS: OnceLock<Vec<LoadedPlugin>> = OnceLock::new();
+
+/// Load the enabled plugins in `dir`, in the order they run, with their
+/// config.
+fn discover(dir: &Path, loader: &Loader) -> Result<Vec<LoadedPlugin>, PluginError> {
+    let mut plugins = Vec::new();
+    for plugin in installed(dir)? {
+        let name = plugin.name();
+        let settings = loader.settings.plugin(&name);
+        if !settings.enabled {
+            info!("Plugin {name} is disabled in {PROJECT_CONFIG}");
+            continue;
+        }
+        let mut loaded = match plugin.manifest {
+            Some(manifest) => LoadedPlugin::load_dir(&plugin.path, manifest, loader)?,
+            None => LoadedPlugin::load_bare(&plugin.path, loader)?,
+        };
+        loaded.config = loaded
+            .manifest
+            .config(&settings.config)
+            .map_err(|message| PluginError::Config {
+                plugin: name,
+                message,
+            })?;
+        plugins.push(loaded);
+    }
+    let manifests: Vec<_> = plugins.iter().map(|p| p.manifest.clone()).collect();
+    let order = run_order(&manifests).map_err(PluginError::Ordering)?;
+    let mut plugins: Vec<_> = plugins.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .filter_map(|i| plugins[i].take())
+        .collect())
+}
+
+/// What plugins rewrote.
+#[derive(Debug, Default, PartialEq)]
+pub struct Rewrites {
+    /// On `preresolve`, the range to resolve instead of the one requested.
+    pub range: Option<String>,
+    /// On `prefetch`, the URL to download the package from instead.
+    pub tarball: Option<String>,
+}
+
+/// Fires hooks for the project in the current directory.
+pub struct Lifecycle {
+    name: String,
+    version: String,
+}
+
+impl Lifecycle {
+    /// Hooks for the project whose package is `name@version`.
+    pub fn new(name: &str, version: &str) -> Self {
+        Lifecycle {
+            name: name.to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    /// Hooks that run no plugins, for unit tests, rather than whatever is
+    /// installed where the tests run.
+    #[cfg(test)]
+    pub fn without_plugins(name: &str, version: &str) -> Self {
+        let _ = PLUGINS.set(Vec::new());
+        Lifecycle::new(name, version)
+    }
+
+    /// Run the plugins subscribed to `hook` with `payload`, stopping at the
+    /// first failure or veto.
+    pub fn fire(&self, hook: Hook, payload: Payload) -> Result<Rewrites, PluginError> {
+        let (package_name, package_version) = match &payload {
+            Payload::Resolve { name, .. }
+            | Payload::Error {
+                package: Some(name),
+                ..
+            } => (name.as_str(), ""),
+            Payload::Fetch { name, version, .. }
+            | Payload::Package { name, version, .. }
+            | Payload::Publish { name, version, .. } => (name.as_str(), version.as_str()),
+            _ => (self.name.as_str(), self.version.as_str()),
+        };
+        let ctx = PluginContext {
+            hook,
+            package_name: package_name.to_string(),
+            package_version: package_version.to_string(),
+            install_path: std::env::current_dir()?.to_string_lossy().to_string(),
+            env: std::env::vars().collect(),
+            config: Default::default(),
+            payload: Some(payload),
+        };
+        run_plugins(hook, &ctx)
+    }
+}
+
+/// Run the plugins subscribed to `hook`, stopping at the first failure or
+/// veto. They get `ctx` with its `hook` set to `hook` and `config` set to
+/// their own, and see the payload as rewritten by plugins before them.
+fn run_plugins(hook: Hook, ctx: &PluginContext) -> Result<Rewrites, PluginError> {
+    let mut ctx = PluginContext {
+        hook,
+        ..ctx.clone()
+    };
+    let plugins = match PLUGINS.get() {
+        Some(plugins) => plugins,
+        None => {
+            let cwd = std::env::current_dir().map_err(|e| {
+                PluginError::Io(std::io::Error::other(format!(
+                    "Failed to get current working directory: {e}"
+                )))
+            })?;
+            let loader = Loader::new(&cwd, Isolation::from_env())?;
+            let found = discover(&plugins_dir(&cwd), &loader)?;
+            PLUGINS.get_or_init(|| found)
+        }
+    };
+    let mut rewrites = Rewrites::default();
+    for plugin in plugins {
+        if plugin.subscribes(ctx.hook) {
+            let plugin_ctx = PluginContext {
+                config: plugin.config.clone(),
+                env: granted_env(&ctx.env, &plugin.manifest.capabilities),
+                ..ctx.clone()
+            };
+            let outcome = run_plugin(plugin, &plugin_ctx)?;
+            apply_outcome(
+                &plugin.manifest.name,
+                &plugin.path,
+                &mut ctx,
+                outcome,
+                &mut rewrites,
+            )?;
         } else {
             debug!(
-                "Skipping file with unsupported extension: {}",
-                path.display()
+                "Plugin {} is not subscribed to {}",
+                plugin.path.display(),
+                ctx.hook
             );
         }
     }
+    Ok(rewrites)
+}
+
+/// The variables of `env` that `capabilities` grant, which is all a plugin
+/// gets in its context. WASM and isolated native plugins can't see any
+/// others, but a native plugin trusted to run in-process can read the whole
+/// environment of boltpm.
+fn granted_env(
+    env: &HashMap<String, String>,
+    capabilities: &Capabilities,
+) -> HashMap<String, String> {
+    env.iter()
+        .filter(|(var, _)| capabilities.env.contains(var))
+        .map(|(var, value)| (var.clone(), value.clone()))
+        .collect()
+}
+
+/// Show `outcome`'s messages, and record its rewrites in `rewrites` and
+/// `ctx`'s payload. Rewrites on hooks that don't take them are ignored.
+fn apply_outcome(
+    plugin: &str,
+    path: &Path,
+    ctx: &mut PluginContext,
+    outcome: Outcome,
+    rewrites: &mut Rewrites,
+) -> Result<(), PluginError> {
+    for message in &outcome.messages {
+        info!("[{plugin}] {message}");
+    }
+    for warning in &outcome.warnings {
+        warn!("[{plugin}] {warning}");
+    }
+    if let Some(reason) = outcome.veto {
+        return Err(PluginError::Vetoed {
+            plugin: plugin.to_string(),
+            reason,
+        });
+    }
+    let invalid = |e: String| Err(PluginError::Outcome(path.to_path_buf(), e));
+    if let Some(range) = outcome.range {
+        match &mut ctx.payload {
+            Some(Payload::Resolve {
+                name,
+                range: requested,
+            }) => {
+                if range.trim().is_empty() {
+                    return invalid("the rewritten range is empty".to_string());
+                }
+                info!(
+                    "Plugin {plugin} rewrote the range of {name} from '{requested}' to '{range}'"
+                );
+                *requested = range.clone();
+                rewrites.range = Some(range);
+            }
+            _ => warn!(
+                "Plugin {plugin} rewrote a range on {}, which doesn't resolve one; ignoring it",
+                ctx.hook
+            ),
+        }
+    }
+    if let Some(tarball) = outcome.tarball {
+        match &mut ctx.payload {
+            Some(Payload::Fetch { name, url, .. }) => {
+                match reqwest::Url::parse(&tarball) {
+                    Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                    _ => return invalid(format!("tarball '{tarball}' is not an http(s) URL")),
+                }
+                info!("Plugin {plugin} substituted the tarball of {name} with {tarball}");
+                *url = tarball.clone();
+                rewrites.tarball = Some(tarball);
+            }
+            _ => warn!(
+                "Plugin {plugin} substituted a tarball on {}, which doesn't fetch one; ignoring it",
+                ctx.hook
+            ),
+        }
+    }
     Ok(())
 }
 
@@ -91,39 +787,83 @@ fn is_plugin_file(path: &Path) -> bool {
             ext == "so" || ext == "dylib" || ext == "dll"
         })
         .unwrap_or(false)
+        || (cfg!(feature = "wasm_plugins") && is_wasm_file(path))
 }
 
-/// Load and execute a plugin with proper error handling
-fn load_and_run_plugin(path: &Path, ctx: &PluginContext) -> Result<(), PluginError> {
-    // Serialize context to JSON bytes for FFI-safe passing
-    let ctx_json = serde_json::to_string(ctx)?;
-    let ctx_bytes = ctx_json.as_bytes();
+fn is_wasm_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("wasm"))
+}
 
+/// Execute a plugin with proper error handling, returning its outcome
+fn run_plugin(plugin: &LoadedPlugin, ctx: &PluginContext) -> Result<Outcome, PluginError> {
+    let path = &plugin.path;
+    let name = &plugin.manifest.name;
+    let (result, outcome) = match &plugin.backend {
+        Backend::Native(library) => run_native(library, path, name, ctx)?,
+        Backend::Isolated { .. } => host::run(path, name, ctx)?,
+        #[cfg(feature = "wasm_plugins")]
+        Backend::Wasm(module) => module.run(path, ctx).inspect_err(|e| {
+            error!("Plugin {} failed: {e}", path.display());
+        })?,
+    };
+    if result != EXIT_OK {
+        error!("Plugin {} failed with code {}", path.display(), result);
+        return Err(PluginError::Execution(result));
+    }
+    info!("Plugin {} executed successfully", path.display());
+    Ok(outcome)
+}
+
+/// Call a native plugin's `run` with `ctx` and the host's callbacks,
+/// returning its exit code and outcome.
+fn run_native(
+    library: &Library,
+    path: &Path,
+    name: &str,
+    ctx: &PluginContext,
+) -> Result<(i32, Outcome), PluginError> {
+    // Serialize context to JSON bytes for FFI-safe passing
+    let ctx_bytes = serde_json::to_vec(ctx)?;
+    let callbacks = callbacks::Callbacks::new(name, ctx);
+    let vtable = callbacks.vtable();
     unsafe {
-        let lib = Library::new(path)?;
-        let func: Result<Symbol<unsafe extern "C" fn(*const u8, usize) -> i32>, _> =
-            lib.get(b"run");
+        let func: Result<Symbol<RunFn>, _> = library.get(RUN_SYMBOL.as_bytes());
+        let free: Symbol<FreeFn> = library.get(FREE_SYMBOL.as_bytes())?;
+        let mut out_ptr = std::ptr::null_mut();
+        let mut out_len = 0;
 
         match func {
             Ok(func) => {
                 use std::panic;
 
                 // Note: catch_unwind only catches Rust panics, not segmentation faults
-                // or aborts from FFI code. For untrusted plugins, consider isolating
-                // them in a subprocess for better security.
-                let call_result = panic::catch_unwind(|| func(ctx_bytes.as_ptr(), ctx_bytes.len()));
+                // or aborts from FFI code. Untrusted plugins run this in a
+                // `plugin-host` process (see host.rs), so those end only the child.
+                let call_result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    func(
+                        ctx_bytes.as_ptr(),
+                        ctx_bytes.len(),
+                        &vtable,
+                        &mut out_ptr,
+                        &mut out_len,
+                    )
+                }));
 
                 match call_result {
-                    Ok(result) => {
-                        if result != 0 {
-                            error!("Plugin {} failed with code {}", path.display(), result);
-                            return Err(PluginError::Execution(result));
-                        }
-                        info!("Plugin {} executed successfully", path.display());
-                    }
-                    Err(_) => {
+                    Ok(EXIT_PANICKED) | Err(_) => {
                         error!("Plugin {} panicked during execution", path.display());
-                        return Err(PluginError::Panic);
+                        Err(PluginError::Panic)
+                    }
+                    Ok(result) if out_ptr.is_null() => Ok((result, Outcome::default())),
+                    Ok(result) => {
+                        let outcome =
+                            serde_json::from_slice(std::slice::from_raw_parts(out_ptr, out_len));
+                        free(out_ptr, out_len);
+                        let outcome = outcome
+                            .map_err(|e| PluginError::Outcome(path.to_path_buf(), e.to_string()))?;
+                        Ok((result, outcome))
                     }
                 }
             }
@@ -133,9 +873,185 @@ fn load_and_run_plugin(path: &Path, ctx: &PluginContext) -> Result<(), PluginErr
                     path.display(),
                     e
                 );
-                return Err(PluginError::Loading(e));
+                Err(PluginError::Loading(e))
             }
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discover_in(dir: &Path) -> Result<Vec<LoadedPlugin>, PluginError> {
+        discover(dir, &Loader::new(dir, Isolation::None)?)
+    }
+
+    #[test]
+    fn test_granted_env() {
+        let env: HashMap<String, String> = [("CI", "1"), ("BOLTPM_TOKEN", "s3cret")]
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .into();
+        assert!(granted_env(&env, &Capabilities::default()).is_empty());
+        let capabilities = Capabilities {
+            env: vec!["CI".to_string(), "HOME".to_string()],
+            ..Capabilities::default()
+        };
+        let granted = granted_env(&env, &capabilities);
+        assert_eq!(granted.len(), 1);
+        assert_eq!(granted["CI"], "1");
+    }
+
+    #[test]
+    fn test_discover_skips_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(discover_in(&dir.path().join("missing")).unwrap().is_empty());
+        fs::write(dir.path().join("README.md"), "not a plugin").unwrap();
+        fs::write(dir.path().join("plugin.rs"), "fn main() {}").unwrap();
+        #[cfg(not(feature = "wasm_plugins"))]
+        fs::write(dir.path().join("plugin.wasm"), "not loaded").unwrap();
+        assert!(discover_in(dir.path()).unwrap().is_empty());
+        // Anything with a library extension is loaded, and must load.
+        fs::write(dir.path().join("broken.so"), "not a library").unwrap();
+        assert!(matches!(
+            discover_in(dir.path()),
+            Err(PluginError::Loading(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_outcome() {
+        let path = Path::new("license-check.so");
+        let context = |hook, payload| PluginContext {
+            hook,
+            package_name: "left-pad".to_string(),
+            package_version: String::new(),
+            install_path: String::new(),
+            env: Default::default(),
+            config: Default::default(),
+            payload: Some(payload),
+        };
+        let mut ctx = context(
+            Hook::PreResolve,
+            Payload::Resolve {
+                name: "left-pad".to_string(),
+                range: "^1".to_string(),
+            },
+        );
+        let mut rewrites = Rewrites::default();
+        let outcome = Outcome {
+            messages: vec!["checked".to_string()],
+            range: Some("^1.3".to_string()),
+            // Not fetching yet, so ignored.
+            tarball: Some("https://mirror.example.com/left-pad-1.3.0.tgz".to_string()),
+            ..Outcome::default()
+        };
+        apply_outcome("license-check", path, &mut ctx, outcome, &mut rewrites).unwrap();
+        // Later plugins see the new range.
+        assert_eq!(
+            ctx.payload,
+            Some(Payload::Resolve {
+                name: "left-pad".to_string(),
+                range: "^1.3".to_string(),
+            })
+        );
+        assert_eq!(
+            rewrites,
+            Rewrites {
+                range: Some("^1.3".to_string()),
+                tarball: None,
+            }
+        );
+
+        let err = apply_outcome(
+            "license-check",
+            path,
+            &mut ctx,
+            Outcome::veto("GPL-3.0 is not allowed"),
+            &mut rewrites,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Plugin license-check vetoed: GPL-3.0 is not allowed"
+        );
+
+        let mut ctx = context(
+            Hook::PreFetch,
+            Payload::Fetch {
+                name: "left-pad".to_string(),
+                version: "1.3.0".to_string(),
+                url: "http://localhost:4000/v1/left-pad/1.3.0/".to_string(),
+            },
+        );
+        let mut rewrites = Rewrites::default();
+        let mirror = Outcome {
+            tarball: Some("https://mirror.example.com/left-pad-1.3.0.tgz".to_string()),
+            ..Outcome::default()
+        };
+        apply_outcome("license-check", path, &mut ctx, mirror, &mut rewrites).unwrap();
+        assert_eq!(
+            rewrites.tarball.as_deref(),
+            Some("https://mirror.example.com/left-pad-1.3.0.tgz")
+        );
+        let local = Outcome {
+            tarball: Some("file:///tmp/left-pad.tgz".to_string()),
+            ..Outcome::default()
+        };
+        assert!(matches!(
+            apply_outcome("license-check", path, &mut ctx, local, &mut rewrites),
+            Err(PluginError::Outcome(..))
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_open_rejects_non_plugins() {
+        // A real library that isn't a plugin, so has no ABI version.
+        let err = open_library(Path::new("libc.so.6")).err().unwrap();
+        assert!(matches!(
+            err,
+            PluginError::IncompatibleAbi { found: None, .. }
+        ));
+        assert!(err.to_string().contains("is not a boltpm plugin"));
+    }
+
+    #[test]
+    fn test_discover_checks_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let plugin = dir.path().join("license-check");
+        fs::create_dir(&plugin).unwrap();
+        let manifest = "name = \"license-check\"\nversion = \"1.0.0\"\nhooks = [\"preinstall\"]\n";
+
+        fs::write(
+            plugin.join(MANIFEST_FILE),
+            format!("{manifest}api_version = \"^99\""),
+        )
+        .unwrap();
+        let err = discover_in(dir.path()).err().unwrap();
+        assert!(matches!(err, PluginError::IncompatibleApi { .. }));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Plugin license-check requires plugin_api ^99 but this boltpm provides {}",
+                plugin_api::VERSION
+            )
+        );
+
+        fs::write(
+            plugin.join(MANIFEST_FILE),
+            format!("{manifest}api_version = \"*\""),
+        )
+        .unwrap();
+        assert!(matches!(
+            discover_in(dir.path()),
+            Err(PluginError::Manifest(_, e)) if e.contains("found 0 plugin libraries")
+        ));
+
+        fs::write(plugin.join(MANIFEST_FILE), "name = \"license-check\"").unwrap();
+        assert!(matches!(
+            discover_in(dir.path()),
+            Err(PluginError::Manifest(..))
+        ));
+    }
 }
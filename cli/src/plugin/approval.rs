@@ -0,0 +1,345 @@
+//! The capabilities the user has approved for each WASM plugin, and whether
+//! they trust each native plugin to run in-process. Answers are recorded
+//! against the SHA-256 of the plugin's library, so a different library
+//! installed under the same name is asked about afresh.
+//!
+//! They are kept in the user's `~/.boltpm/plugin-approvals.json`, outside
+//! any project, so a cloned repository can't approve its own plugins. CI has
+//! nobody to ask, so with `BOLTPM_PLUGIN_APPROVALS=project` a committed
+//! `.boltpm/plugin-approvals.json` in the same format is read as well.
+
+use super::manifest::Capabilities;
+use super::PluginError;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+pub const APPROVALS_FILE: &str = "plugin-approvals.json";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Approval {
+    /// The plugin's name when it was approved, for whoever reads the file.
+    #[serde(default)]
+    plugin: String,
+    /// Whether a native plugin may run in-process; `None` until asked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    full_trust: Option<bool>,
+    #[serde(flatten)]
+    capabilities: Capabilities,
+}
+
+/// Where approvals are read from and recorded.
+pub struct Approvals {
+    /// The user's own file, where answers are recorded; `None` without a
+    /// home directory, when nothing is remembered.
+    user: Option<PathBuf>,
+    /// A project's committed file, only read.
+    project: Option<PathBuf>,
+}
+
+impl Approvals {
+    /// The user's approvals, and those committed to `project` if
+    /// `BOLTPM_PLUGIN_APPROVALS=project` opts in to them.
+    pub fn new(project: &Path) -> Self {
+        let project = match std::env::var("BOLTPM_PLUGIN_APPROVALS").as_deref() {
+            Ok("project") => Some(project.join(".boltpm").join(APPROVALS_FILE)),
+            Ok("user") | Err(_) => None,
+            Ok(other) => {
+                warn!("Unknown BOLTPM_PLUGIN_APPROVALS '{other}'; expected user or project");
+                None
+            }
+        };
+        Approvals {
+            user: home_dir().map(|home| home.join(".boltpm").join(APPROVALS_FILE)),
+            project,
+        }
+    }
+
+    /// What the user and, if opted in to, the project recorded for the
+    /// library `key`, the user's answers first.
+    fn recorded(&self, key: &str) -> Result<Vec<Approval>, PluginError> {
+        let mut found = Vec::new();
+        for file in [&self.user, &self.project].into_iter().flatten() {
+            if let Some(approval) = load(file)?.remove(key) {
+                found.push(approval);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Update the user's record for the library `key`.
+    fn record(
+        &self,
+        key: &str,
+        plugin: &str,
+        update: impl FnOnce(&mut Approval),
+    ) -> Result<(), PluginError> {
+        let Some(file) = &self.user else {
+            return Ok(());
+        };
+        let mut approvals = load(file)?;
+        let approval = approvals.entry(key.to_string()).or_default();
+        approval.plugin = plugin.to_string();
+        update(approval);
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(file, serde_json::to_string_pretty(&approvals)?)?;
+        Ok(())
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var_os(var)
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+fn load(file: &Path) -> Result<BTreeMap<String, Approval>, PluginError> {
+    match fs::read(file) {
+        Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What approvals of `library` are recorded under: `sha256:<hex>` of its
+/// contents.
+fn library_key(library: &Path) -> Result<String, PluginError> {
+    Ok(format!("sha256:{:x}", Sha256::digest(fs::read(library)?)))
+}
+
+/// Check what the plugin `plugin` in `library` requests against its
+/// approvals. Anything not approved yet is put to `ask`, and a yes is
+/// recorded.
+pub fn check(
+    approvals: &Approvals,
+    plugin: &str,
+    library: &Path,
+    requested: &Capabilities,
+    ask: impl FnOnce(&str, &[String]) -> bool,
+) -> Result<(), PluginError> {
+    if Capabilities::default().covers(requested) {
+        return Ok(());
+    }
+    let key = library_key(library)?;
+    let mut approved = Capabilities::default();
+    for approval in approvals.recorded(&key)? {
+        approved.merge(&approval.capabilities);
+    }
+    if approved.covers(requested) {
+        return Ok(());
+    }
+    let capabilities = requested.describe();
+    if !ask(plugin, &capabilities) {
+        return Err(PluginError::Unapproved {
+            plugin: plugin.to_string(),
+            capabilities,
+        });
+    }
+    approvals.record(&key, plugin, |approval| {
+        approval.capabilities.merge(requested)
+    })
+}
+
+/// Whether the native plugin `plugin` in `library` is trusted to run
+/// in-process, asking with `ask` the first time. `ask` gives `None` when it
+/// can't ask, and the plugin is then untrusted without recording anything.
+pub fn check_trust(
+    approvals: &Approvals,
+    plugin: &str,
+    library: &Path,
+    ask: impl FnOnce(&str) -> Option<bool>,
+) -> Result<bool, PluginError> {
+    let key = library_key(library)?;
+    let recorded = approvals.recorded(&key)?;
+    if let Some(trusted) = recorded.iter().find_map(|approval| approval.full_trust) {
+        return Ok(trusted);
+    }
+    let Some(trusted) = ask(plugin) else {
+        return Ok(false);
+    };
+    approvals.record(&key, plugin, |approval| approval.full_trust = Some(trusted))?;
+    Ok(trusted)
+}
+
+/// Ask on the terminal. Without one there is nobody to ask, so the answer
+/// is no.
+pub fn ask_user(plugin: &str, capabilities: &[String]) -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    eprintln!("Plugin {plugin} asks to:");
+    for capability in capabilities {
+        eprintln!("  - {capability}");
+    }
+    eprint!("Allow? [y/N] ");
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Ask on the terminal whether to trust a native plugin.
+pub fn ask_trust(plugin: &str) -> Option<bool> {
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    eprint!(
+        "Plugin {plugin} is a native library. Trust it to run inside boltpm? \
+         Otherwise it runs in a separate, limited process. [y/N] "
+    );
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).ok()?;
+    Some(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Approvals recorded in `dir`, optionally reading a project's file too.
+    fn approvals(dir: &Path, project: Option<&Path>) -> Approvals {
+        Approvals {
+            user: Some(dir.join(APPROVALS_FILE)),
+            project: project.map(Path::to_path_buf),
+        }
+    }
+
+    /// A library file holding `contents`.
+    fn library(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let approvals = approvals(dir.path(), None);
+        let lint = library(dir.path(), "lint.wasm", "v1");
+        let requested = Capabilities {
+            env: vec!["CI".to_string()],
+            ..Capabilities::bare()
+        };
+        // Output alone never asks.
+        check(
+            &approvals,
+            "lint",
+            &lint,
+            &Capabilities::bare(),
+            |_, _| unreachable!(),
+        )
+        .unwrap();
+
+        let err = check(&approvals, "lint", &lint, &requested, |_, _| false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Plugin lint needs approval to read environment variables CI. Run boltpm in a terminal to approve it, or see BOLTPM_PLUGIN_APPROVALS for CI"
+        );
+        assert!(!dir.path().join(APPROVALS_FILE).exists());
+
+        check(
+            &approvals,
+            "lint",
+            &lint,
+            &requested,
+            |plugin, capabilities| {
+                assert_eq!(plugin, "lint");
+                assert_eq!(capabilities, ["read environment variables CI"]);
+                true
+            },
+        )
+        .unwrap();
+        // Remembered, for this library only.
+        check(&approvals, "lint", &lint, &requested, |_, _| unreachable!()).unwrap();
+        let other = library(dir.path(), "other.wasm", "other");
+        assert!(check(&approvals, "other", &other, &requested, |_, _| false).is_err());
+        let more = Capabilities {
+            read_project: true,
+            ..requested.clone()
+        };
+        assert!(check(&approvals, "lint", &lint, &more, |_, _| false).is_err());
+        // A different library installed under the same name is asked about.
+        fs::write(&lint, "v2").unwrap();
+        assert!(check(&approvals, "lint", &lint, &requested, |_, _| false).is_err());
+    }
+
+    #[test]
+    fn test_check_trust() {
+        let dir = tempfile::tempdir().unwrap();
+        let approvals = approvals(dir.path(), None);
+        let native = library(dir.path(), "libnative.so", "native");
+        let other = library(dir.path(), "libother.so", "other");
+        // Nobody to ask: untrusted, and asked again next time.
+        assert!(!check_trust(&approvals, "native", &native, |_| None).unwrap());
+        assert!(check_trust(&approvals, "native", &native, |_| Some(true)).unwrap());
+        assert!(check_trust(&approvals, "native", &native, |_| unreachable!()).unwrap());
+        assert!(!check_trust(&approvals, "other", &other, |_| Some(false)).unwrap());
+        assert!(!check_trust(&approvals, "other", &other, |_| unreachable!()).unwrap());
+        // Trust doesn't pass to another library under a trusted name.
+        assert!(!check_trust(&approvals, "native", &other, |_| unreachable!()).unwrap());
+
+        // Trust and capabilities are kept side by side, under the library's
+        // hash.
+        let requested = Capabilities {
+            env: vec!["CI".to_string()],
+            ..Capabilities::default()
+        };
+        check(&approvals, "native", &native, &requested, |_, _| true).unwrap();
+        assert!(check_trust(&approvals, "native", &native, |_| unreachable!()).unwrap());
+        let saved: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.path().join(APPROVALS_FILE)).unwrap())
+                .unwrap();
+        let saved = &saved[library_key(&native).unwrap()];
+        assert_eq!(saved["plugin"], "native");
+        assert_eq!(saved["full_trust"], true);
+        assert_eq!(saved["env"], serde_json::json!(["CI"]));
+    }
+
+    #[test]
+    fn test_project_approvals() {
+        let user = tempfile::tempdir().unwrap();
+        let project = tempfile::tempdir().unwrap();
+        let native = library(project.path(), "libnative.so", "native");
+        let committed = project.path().join(APPROVALS_FILE);
+        let approval = serde_json::json!({
+            library_key(&native).unwrap(): {
+                "plugin": "native", "full_trust": true, "env": ["CI"]
+            }
+        });
+        fs::write(&committed, approval.to_string()).unwrap();
+        let requested = Capabilities {
+            env: vec!["CI".to_string()],
+            ..Capabilities::default()
+        };
+
+        // Ignored unless opted in to.
+        let approvals = approvals(user.path(), None);
+        assert!(!check_trust(&approvals, "native", &native, |_| None).unwrap());
+        assert!(check(&approvals, "native", &native, &requested, |_, _| false).is_err());
+
+        let approvals = self::approvals(user.path(), Some(&committed));
+        assert!(check_trust(&approvals, "native", &native, |_| unreachable!()).unwrap());
+        check(
+            &approvals,
+            "native",
+            &native,
+            &requested,
+            |_, _| unreachable!(),
+        )
+        .unwrap();
+        // The user's own answer wins, and the project's file isn't written.
+        let before = fs::read_to_string(&committed).unwrap();
+        let approvals = self::approvals(user.path(), None);
+        assert!(!check_trust(&approvals, "native", &native, |_| Some(false)).unwrap());
+        let approvals = self::approvals(user.path(), Some(&committed));
+        assert!(!check_trust(&approvals, "native", &native, |_| unreachable!()).unwrap());
+        assert_eq!(fs::read_to_string(&committed).unwrap(), before);
+    }
+}
@@ -0,0 +1,175 @@
+//! The [`HostVtable`] a native plugin gets in `run`. Its log lines and
+//! progress go into boltpm's log under its name, and it can read its config
+//! and the project's `package.json`.
+
+use log::{info, warn};
+use plugin_api::host::Level;
+use plugin_api::{HostVtable, PluginContext};
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::path::Path;
+
+/// What the callbacks answer from during one `run` call.
+pub struct Callbacks<'a> {
+    plugin: &'a str,
+    ctx: &'a PluginContext,
+    /// Strings handed to the plugin, kept until `run` returns.
+    returned: RefCell<Vec<Box<[u8]>>>,
+}
+
+impl<'a> Callbacks<'a> {
+    pub fn new(plugin: &'a str, ctx: &'a PluginContext) -> Self {
+        Callbacks {
+            plugin,
+            ctx,
+            returned: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The vtable to pass to `run`, valid while `self` is.
+    pub fn vtable(&self) -> HostVtable {
+        HostVtable {
+            size: std::mem::size_of::<HostVtable>(),
+            host: self as *const Self as *mut c_void,
+            log,
+            config,
+            progress,
+            project,
+        }
+    }
+
+    fn give(&self, bytes: Vec<u8>, out: *mut *const u8, out_len: *mut usize) {
+        let bytes = bytes.into_boxed_slice();
+        // SAFETY: the plugin passes pointers to its own locals.
+        unsafe {
+            *out = bytes.as_ptr();
+            *out_len = bytes.len();
+        }
+        // Moving the box doesn't move its contents.
+        self.returned.borrow_mut().push(bytes);
+    }
+}
+
+/// # Safety
+/// `host` must be the `Callbacks` a vtable was made from, still alive.
+unsafe fn callbacks<'a>(host: *mut c_void) -> &'a Callbacks<'a> {
+    &*(host as *const Callbacks)
+}
+
+/// # Safety
+/// `ptr` must be valid for reads of `len` bytes, or `len` 0.
+unsafe fn text(ptr: *const u8, len: usize) -> String {
+    if len == 0 {
+        return String::new();
+    }
+    String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len)).into_owned()
+}
+
+unsafe extern "C" fn log(host: *mut c_void, level: u32, message: *const u8, len: usize) {
+    let level = match Level::from_u32(level) {
+        Some(Level::Error) => log::Level::Error,
+        Some(Level::Warn) => log::Level::Warn,
+        Some(Level::Info) | None => log::Level::Info,
+        Some(Level::Debug) => log::Level::Debug,
+        Some(Level::Trace) => log::Level::Trace,
+    };
+    let callbacks = callbacks(host);
+    log::log!(level, "[{}] {}", callbacks.plugin, text(message, len));
+}
+
+unsafe extern "C" fn config(
+    host: *mut c_void,
+    key: *const u8,
+    key_len: usize,
+    out: *mut *const u8,
+    out_len: *mut usize,
+) -> bool {
+    let callbacks = callbacks(host);
+    match callbacks.ctx.config.get(&text(key, key_len)) {
+        Some(value) => {
+            callbacks.give(value.to_string().into_bytes(), out, out_len);
+            true
+        }
+        None => false,
+    }
+}
+
+unsafe extern "C" fn progress(
+    host: *mut c_void,
+    done: u64,
+    total: u64,
+    message: *const u8,
+    len: usize,
+) {
+    let callbacks = callbacks(host);
+    let message = text(message, len);
+    match total {
+        0 => info!("[{}] {message} ({done})", callbacks.plugin),
+        _ => info!("[{}] {message} ({done}/{total})", callbacks.plugin),
+    }
+}
+
+unsafe extern "C" fn project(host: *mut c_void, out: *mut *const u8, out_len: *mut usize) -> bool {
+    let callbacks = callbacks(host);
+    let path = Path::new(&callbacks.ctx.install_path).join("package.json");
+    let Ok(raw) = std::fs::read(&path) else {
+        return false;
+    };
+    if let Err(e) = serde_json::from_slice::<serde_json::Value>(&raw) {
+        warn!(
+            "[{}] asked for {}, which is invalid: {e}",
+            callbacks.plugin,
+            path.display()
+        );
+        return false;
+    }
+    callbacks.give(raw, out, out_len);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin_api::Hook;
+
+    #[test]
+    fn test_callbacks() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = PluginContext {
+            hook: Hook::PreInstall,
+            package_name: "app".to_string(),
+            package_version: "1.0.0".to_string(),
+            install_path: dir.path().to_string_lossy().to_string(),
+            env: Default::default(),
+            config: [("allowed".to_string(), serde_json::json!(["MIT"]))].into(),
+            payload: None,
+        };
+        let callbacks = Callbacks::new("license-check", &ctx);
+        let vtable = callbacks.vtable();
+        let read = |found: bool, out: *const u8, out_len: usize| {
+            found.then(|| unsafe { text(out, out_len) })
+        };
+        let config = |key: &str| unsafe {
+            let (mut out, mut out_len) = (std::ptr::null(), 0);
+            let found =
+                (vtable.config)(vtable.host, key.as_ptr(), key.len(), &mut out, &mut out_len);
+            read(found, out, out_len)
+        };
+        let project = || unsafe {
+            let (mut out, mut out_len) = (std::ptr::null(), 0);
+            let found = (vtable.project)(vtable.host, &mut out, &mut out_len);
+            read(found, out, out_len)
+        };
+        assert_eq!(config("allowed").as_deref(), Some(r#"["MIT"]"#));
+        assert_eq!(config("missing"), None);
+        assert_eq!(project(), None);
+        std::fs::write(dir.path().join("package.json"), "{\"name\": \"app\"}").unwrap();
+        assert_eq!(project().as_deref(), Some("{\"name\": \"app\"}"));
+        std::fs::write(dir.path().join("package.json"), "{").unwrap();
+        assert_eq!(project(), None);
+        unsafe {
+            (vtable.log)(vtable.host, 99, "unknown levels log as info".as_ptr(), 26);
+            (vtable.progress)(vtable.host, 1, 0, std::ptr::null(), 0);
+        }
+    }
+}
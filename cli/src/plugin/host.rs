@@ -0,0 +1,385 @@
+//! Running native plugins in a `boltpm plugin-host` child process, so a
+//! plugin that crashes, hangs or exhausts memory takes down only the child.
+//!
+//! The parent writes one JSON [`Request`] to the child's stdin. The child
+//! loads the library, handles the request, and writes its JSON [`Response`]
+//! as the last line of stdout, after [`MARKER`]. Anything the plugin itself
+//! prints comes before it and is passed through. The child's address space
+//! is capped with `RLIMIT_AS`, and the parent kills it after [`TIMEOUT`],
+//! which also bounds waiting for its output.
+//! The child's environment holds only the variables the plugin was granted.
+
+use super::{load_library, open_library, run_native, PluginError, Probe};
+use plugin_api::{Outcome, PluginContext, EXIT_PANICKED};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+pub const TIMEOUT: Duration = Duration::from_secs(60);
+pub const MEMORY_LIMIT_MB: u64 = 1024;
+/// Precedes the response on the child's stdout.
+const MARKER: &str = "\u{1e}boltpm-plugin-host:";
+/// How often the child is checked on while waiting for it.
+const POLL: Duration = Duration::from_millis(5);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    /// Report the library's ABI version and the hooks it exports.
+    Describe,
+    Run {
+        /// The plugin's name, for attributing its log lines.
+        plugin: String,
+        context: Box<PluginContext>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+    Described(Probe),
+    Ran {
+        code: i32,
+        #[serde(default)]
+        outcome: Outcome,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// Handle a request for `library` in a child process.
+pub fn call(library: &Path, request: &Request) -> Result<Response, PluginError> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        // So the plugin's log lines are filtered as this process's are.
+        .arg("--log-level")
+        .arg(log::max_level().to_string())
+        .arg("plugin-host")
+        .arg(library)
+        .arg("--memory-limit-mb")
+        .arg(MEMORY_LIMIT_MB.to_string());
+    limit_env(&mut command, request);
+    exchange(command, library, request, TIMEOUT)
+}
+
+/// Give the child only the environment a run's context was granted, and
+/// none at all for probing the library.
+fn limit_env(command: &mut Command, request: &Request) {
+    let env = match request {
+        Request::Run { context, .. } => context.env.clone(),
+        Request::Describe => HashMap::new(),
+    };
+    command.env_clear().envs(env);
+}
+
+fn exchange(
+    mut command: Command,
+    library: &Path,
+    request: &Request,
+    timeout: Duration,
+) -> Result<Response, PluginError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A child that died early shows up in its exit status.
+        let _ = stdin.write_all(&serde_json::to_vec(request)?);
+    }
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let (chunks, received) = mpsc::channel();
+    // Stdout only closes once everything holding it has, which a process
+    // the plugin left running in the background may never do, so the
+    // output is passed on as it comes rather than waited for.
+    std::thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            match stdout.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if chunks.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    });
+    let deadline = Instant::now() + timeout;
+    let mut out = Vec::new();
+    let mut closed = false;
+    let mut exited = None;
+    let status = loop {
+        if exited.is_none() {
+            exited = child.try_wait()?;
+        }
+        // A child that exited successfully wrote its whole response first.
+        if let Some(status) = exited {
+            if closed || !status.success() || responded(&out) {
+                break status;
+            }
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(PluginError::Timeout);
+        }
+        if closed {
+            std::thread::sleep(POLL);
+            continue;
+        }
+        match received.recv_timeout(POLL) {
+            Ok(chunk) => out.extend_from_slice(&chunk),
+            Err(RecvTimeoutError::Disconnected) => closed = true,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    };
+    let out = String::from_utf8_lossy(&out);
+    let (printed, response) = match out.rfind(MARKER) {
+        Some(i) => (&out[..i], Some(&out[i + MARKER.len()..])),
+        None => (&out[..], None),
+    };
+    print!("{}", printed.strip_suffix('\n').unwrap_or(printed));
+    let crashed = |reason: String| PluginError::Crashed {
+        path: library.to_path_buf(),
+        reason,
+    };
+    if !status.success() {
+        return Err(crashed(describe_exit(status)));
+    }
+    match response {
+        Some(response) => Ok(serde_json::from_str(response.trim())?),
+        None => Err(crashed("it exited without responding".to_string())),
+    }
+}
+
+/// Whether `out` holds the whole response line.
+fn responded(out: &[u8]) -> bool {
+    let marker = MARKER.as_bytes();
+    out.windows(marker.len())
+        .rposition(|window| window == marker)
+        .is_some_and(|i| out[i..].contains(&b'\n'))
+}
+
+/// Run the plugin `name` in `library` for `ctx` in a child process,
+/// returning its exit code and outcome.
+pub fn run(library: &Path, name: &str, ctx: &PluginContext) -> Result<(i32, Outcome), PluginError> {
+    let request = Request::Run {
+        plugin: name.to_string(),
+        context: Box::new(ctx.clone()),
+    };
+    match call(library, &request)? {
+        Response::Ran { code, .. } if code == EXIT_PANICKED => Err(PluginError::Panic),
+        Response::Ran { code, outcome } => Ok((code, outcome)),
+        Response::Failed { error } => Err(PluginError::Host(error)),
+        Response::Described(_) => Err(PluginError::Host("unexpected response".to_string())),
+    }
+}
+
+/// Probe `library` in a child process. Its ABI isn't checked.
+pub fn describe(library: &Path) -> Result<Probe, PluginError> {
+    match call(library, &Request::Describe)? {
+        Response::Described(probe) => Ok(probe),
+        Response::Failed { error } => Err(PluginError::Host(error)),
+        Response::Ran { .. } => Err(PluginError::Host("unexpected response".to_string())),
+    }
+}
+
+/// The child's side: handle the request on stdin, returning the process's
+/// exit code.
+pub fn serve(library: &Path, memory_limit_mb: Option<u64>) -> i32 {
+    if let Some(limit) = memory_limit_mb {
+        limit_memory(limit);
+    }
+    let mut input = Vec::new();
+    let response = match std::io::stdin().read_to_end(&mut input) {
+        Ok(_) => match serde_json::from_slice(&input) {
+            Ok(request) => handle(library, request).unwrap_or_else(|e| Response::Failed {
+                error: e.to_string(),
+            }),
+            Err(e) => Response::Failed {
+                error: format!("Invalid request: {e}"),
+            },
+        },
+        Err(e) => Response::Failed {
+            error: format!("Failed to read request: {e}"),
+        },
+    };
+    let mut stdout = std::io::stdout().lock();
+    let written = serde_json::to_string(&response)
+        .map_err(std::io::Error::from)
+        .and_then(|json| writeln!(stdout, "\n{MARKER}{json}"))
+        .and_then(|_| stdout.flush());
+    if written.is_ok() {
+        0
+    } else {
+        1
+    }
+}
+
+fn handle(path: &Path, request: Request) -> Result<Response, PluginError> {
+    match request {
+        Request::Describe => Ok(Response::Described(Probe::of(&load_library(path)?))),
+        Request::Run { plugin, context } => {
+            let library = open_library(path)?;
+            match run_native(&library, path, &plugin, &context) {
+                Ok((code, outcome)) => Ok(Response::Ran { code, outcome }),
+                Err(PluginError::Panic) => Ok(Response::Ran {
+                    code: EXIT_PANICKED,
+                    outcome: Outcome::default(),
+                }),
+                Err(e) => Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn limit_memory(limit_mb: u64) {
+    let bytes = (limit_mb << 20) as libc::rlim_t;
+    let limit = libc::rlimit {
+        rlim_cur: bytes,
+        rlim_max: bytes,
+    };
+    // SAFETY: setrlimit only reads the struct passed to it.
+    if unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) } != 0 {
+        log::warn!(
+            "Failed to limit plugin host memory: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn limit_memory(_limit_mb: u64) {
+    log::debug!("Plugin host memory limits are only supported on Unix");
+}
+
+fn describe_exit(status: ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            let name = match signal {
+                libc::SIGSEGV => " (SIGSEGV)",
+                libc::SIGBUS => " (SIGBUS)",
+                libc::SIGABRT => " (SIGABRT)",
+                libc::SIGILL => " (SIGILL)",
+                libc::SIGFPE => " (SIGFPE)",
+                libc::SIGKILL => " (SIGKILL)",
+                _ => "",
+            };
+            return format!("it was killed by signal {signal}{name}");
+        }
+    }
+    match status.code() {
+        Some(code) => format!("it exited with status {code}"),
+        None => "it exited abnormally".to_string(),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A child running `script` under `sh`.
+    fn exchange_with(script: &str, timeout: Duration) -> Result<Response, PluginError> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        exchange(
+            command,
+            Path::new("libplugin.so"),
+            &Request::Describe,
+            timeout,
+        )
+    }
+
+    #[test]
+    fn test_exchange() {
+        let second = Duration::from_secs(1);
+        let reply = format!(
+            "cat > /dev/null; printf 'plugin output\\n{MARKER}{}\\n'",
+            r#"{"ran":{"code":0,"outcome":{"veto":"GPL"}}}"#
+        );
+        assert_eq!(
+            exchange_with(&reply, second).unwrap(),
+            Response::Ran {
+                code: 0,
+                outcome: Outcome::veto("GPL")
+            }
+        );
+        assert!(matches!(
+            exchange_with("exec sleep 5", Duration::from_millis(100)),
+            Err(PluginError::Timeout)
+        ));
+        let err = exchange_with("kill -SEGV $$", second).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Plugin libplugin.so crashed: it was killed by signal 11 (SIGSEGV)"
+        );
+        let err = exchange_with("exit 0", second).unwrap_err();
+        assert!(err.to_string().ends_with("it exited without responding"));
+        let err = exchange_with("exit 101", second).unwrap_err();
+        assert!(err.to_string().ends_with("it exited with status 101"));
+
+        // Something left running in the background keeps stdout open.
+        let started = Instant::now();
+        let reply = format!(
+            "cat > /dev/null; sleep 5 & printf '{MARKER}{}\n'",
+            r#"{"ran":{"code":0}}"#
+        );
+        assert_eq!(
+            exchange_with(&reply, second).unwrap(),
+            Response::Ran {
+                code: 0,
+                outcome: Outcome::default()
+            }
+        );
+        assert!(matches!(
+            exchange_with("sleep 5 & exit 0", Duration::from_millis(100)),
+            Err(PluginError::Timeout)
+        ));
+        assert!(started.elapsed() < second);
+    }
+
+    #[test]
+    fn test_limit_env() {
+        let request = Request::Run {
+            plugin: "lint".to_string(),
+            context: Box::new(PluginContext {
+                hook: plugin_api::Hook::PreInstall,
+                package_name: "left-pad".to_string(),
+                package_version: String::new(),
+                install_path: String::new(),
+                env: HashMap::from([("CI".to_string(), "true".to_string())]),
+                config: Default::default(),
+                payload: None,
+            }),
+        };
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(format!(
+            r#"printf '{MARKER}{{"failed":{{"error":"%s %s"}}}}\n' "${{HOME:-unset}}" "$CI""#
+        ));
+        limit_env(&mut command, &request);
+        let response = exchange(
+            command,
+            Path::new("libplugin.so"),
+            &request,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            Response::Failed {
+                error: "unset true".to_string()
+            }
+        );
+    }
+}
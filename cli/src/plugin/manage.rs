@@ -0,0 +1,433 @@
+//! `boltpm plugin`: installing, listing, enabling and removing the plugins in
+//! a project's `.boltpm/plugins`.
+//!
+//! Plugins can be added from a plugin directory, a bare library, or the
+//! registry, where a plugin is a package with the `boltpm-plugin` keyword
+//! whose tarball holds a `plugin.toml`. Either way the plugin is loaded
+//! before it is installed, so one this boltpm can't run is refused.
+
+use super::settings::{self, PROJECT_CONFIG};
+use super::{
+    approval, host, installed, is_plugin_file, is_wasm_file, library_path, load_library,
+    plugins_dir, read_manifest, run_order, Installed, Isolation, LoadedPlugin, Loader, PluginError,
+    PluginManifest, Probe, MANIFEST_FILE,
+};
+use crate::registry;
+use log::{info, warn};
+use plugin_api::{Hook, ABI_VERSION};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The keyword that marks a registry package as a boltpm plugin.
+const PLUGIN_KEYWORD: &str = "boltpm-plugin";
+
+/// An installed plugin, as `boltpm plugin list` shows it.
+#[derive(Debug)]
+pub struct PluginInfo {
+    pub name: String,
+    pub version: String,
+    /// The hooks it runs on, or `None` for every hook.
+    pub hooks: Option<Vec<Hook>>,
+    /// The plugin ABI a native library was built for, or `wasm`.
+    pub abi: String,
+    pub trust: String,
+    pub enabled: bool,
+    /// Why it can't run as installed.
+    pub problems: Vec<String>,
+}
+
+/// Install the plugin at `source`, a directory with a `plugin.toml` or a
+/// library, or else the registry package `source` names (`name@range`),
+/// replacing any installed plugin of the same name. Returns the plugin's
+/// name and version.
+pub fn add(project: &Path, source: &str) -> Result<(String, String), PluginError> {
+    let path = Path::new(source);
+    let from = if path.exists() {
+        path.to_path_buf()
+    } else {
+        download(project, source)?
+    };
+    let dir = plugins_dir(project);
+    let loader = Loader::new(project, Isolation::from_env())?;
+    let (manifest, target) = if from.join(MANIFEST_FILE).is_file() {
+        let manifest = read_manifest(&from)?;
+        let target = dir.join(&manifest.name);
+        (
+            LoadedPlugin::load_dir(&from, manifest, &loader)?.manifest,
+            target,
+        )
+    } else if is_plugin_file(&from) {
+        let target = dir.join(from.file_name().unwrap_or_default());
+        (LoadedPlugin::load_bare(&from, &loader)?.manifest, target)
+    } else {
+        return Err(PluginError::NotAPlugin(from));
+    };
+    let name = manifest.name.clone();
+    if let Err(message) = manifest.config(&loader.settings.plugin(&name).config) {
+        warn!("Plugin {name} needs configuring in {PROJECT_CONFIG} before it can run: {message}");
+    }
+
+    let mut others = Vec::new();
+    let mut replaced = Vec::new();
+    for plugin in installed(&dir)? {
+        if plugin.name() == name {
+            replaced.push(plugin.path);
+        } else {
+            others.push(manifest_of(&plugin));
+        }
+    }
+    if same_file(&from, &target) || replaced.iter().any(|path| same_file(path, &from)) {
+        info!("Plugin {name} is already installed");
+        return Ok((name, manifest.version));
+    }
+    others.push(manifest.clone());
+    run_order(&others).map_err(PluginError::Ordering)?;
+
+    for path in replaced {
+        info!("Replacing plugin {name} at {}", path.display());
+        remove_path(&path)?;
+    }
+    fs::create_dir_all(&dir)?;
+    if from.is_dir() {
+        copy_dir(&from, &target)?;
+    } else {
+        fs::copy(&from, &target)?;
+    }
+    Ok((name, manifest.version))
+}
+
+/// Uninstall the plugin `name`.
+pub fn remove(project: &Path, name: &str) -> Result<(), PluginError> {
+    let plugin = find(project, name)?;
+    remove_path(&plugin.path)
+}
+
+/// Enable or disable the plugin `name` in `boltpm.toml`.
+pub fn set_enabled(project: &Path, name: &str, enabled: bool) -> Result<(), PluginError> {
+    find(project, name)?;
+    settings::set_enabled(&project.join(PROJECT_CONFIG), name, enabled)
+}
+
+/// Describe the installed plugins, without asking the user anything or
+/// running any plugin. Untrusted native libraries are probed in a child
+/// process.
+pub fn list(project: &Path) -> Result<Vec<PluginInfo>, PluginError> {
+    let loader = Loader {
+        interactive: false,
+        ..Loader::new(project, Isolation::from_env())?
+    };
+    list_with(project, &loader)
+}
+
+fn list_with(project: &Path, loader: &Loader) -> Result<Vec<PluginInfo>, PluginError> {
+    let mut plugins = Vec::new();
+    for plugin in installed(&plugins_dir(project))? {
+        let name = plugin.name();
+        let settings = loader.settings.plugin(&name);
+        let mut problems = Vec::new();
+        let manifest = manifest_of(&plugin);
+        if !manifest.supports_api(plugin_api::VERSION) {
+            problems.push(
+                PluginError::IncompatibleApi {
+                    plugin: name.clone(),
+                    required: manifest.api_version.clone(),
+                }
+                .to_string(),
+            );
+        }
+        if let Err(message) = manifest.config(&settings.config) {
+            problems.push(format!("{PROJECT_CONFIG}: {message}"));
+        }
+        let library = match &plugin.manifest {
+            Some(manifest) => library_path(&plugin.path, manifest),
+            None => Ok(plugin.path.clone()),
+        };
+        let (abi, trust, exported) = match library {
+            Ok(library) if is_wasm_file(&library) => {
+                let approved = approval::check(
+                    &loader.approvals,
+                    &name,
+                    &library,
+                    &manifest.capabilities,
+                    |_, _| false,
+                );
+                let trust = match approved {
+                    Ok(()) => "sandboxed",
+                    Err(PluginError::Unapproved { .. }) => "sandboxed, unapproved",
+                    Err(e) => return Err(e),
+                };
+                ("wasm".to_string(), trust.to_string(), None)
+            }
+            Ok(library) => {
+                let isolated = loader.isolates(&name, &library)?;
+                let probe = if isolated {
+                    host::describe(&library)
+                } else {
+                    load_library(&library).map(|library| Probe::of(&library))
+                };
+                let trust = if isolated { "isolated" } else { "full trust" }.to_string();
+                match probe {
+                    Ok(probe) => {
+                        let abi = match probe.abi {
+                            Some(ABI_VERSION) => ABI_VERSION.to_string(),
+                            Some(abi) => format!("{abi} (incompatible)"),
+                            None => "none".to_string(),
+                        };
+                        if probe.abi != Some(ABI_VERSION) {
+                            problems.push(
+                                PluginError::IncompatibleAbi {
+                                    path: library,
+                                    found: probe.abi,
+                                }
+                                .to_string(),
+                            );
+                        }
+                        (abi, trust, probe.hooks)
+                    }
+                    Err(e) => {
+                        problems.push(e.to_string());
+                        ("?".to_string(), trust, None)
+                    }
+                }
+            }
+            Err(e) => {
+                problems.push(e.to_string());
+                ("?".to_string(), "?".to_string(), None)
+            }
+        };
+        let hooks = match (&plugin.manifest, exported) {
+            (Some(manifest), _) => Some(manifest.hooks.clone()),
+            (None, Some(mask)) => Some(super::hooks_in(mask)),
+            (None, None) => None,
+        };
+        plugins.push(PluginInfo {
+            name,
+            version: manifest.version,
+            hooks,
+            abi,
+            trust,
+            enabled: settings.enabled,
+            problems,
+        });
+    }
+    Ok(plugins)
+}
+
+/// Render `plugins` as a table, each followed by its problems.
+pub fn format_list(plugins: &[PluginInfo]) -> String {
+    if plugins.is_empty() {
+        return "No plugins installed.".to_string();
+    }
+    let rows: Vec<Vec<String>> = plugins
+        .iter()
+        .map(|p| {
+            let hooks = match &p.hooks {
+                Some(hooks) => hooks
+                    .iter()
+                    .map(|hook| hook.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                None => "all".to_string(),
+            };
+            vec![
+                p.name.clone(),
+                p.version.clone(),
+                hooks,
+                p.abi.clone(),
+                p.trust.clone(),
+                if p.enabled { "enabled" } else { "disabled" }.to_string(),
+            ]
+        })
+        .collect();
+    let mut out = registry::format_table(
+        &["NAME", "VERSION", "HOOKS", "ABI", "TRUST", "STATUS"],
+        &rows,
+    );
+    for plugin in plugins {
+        for problem in &plugin.problems {
+            out += &format!("{}: {problem}\n", plugin.name);
+        }
+    }
+    out
+}
+
+/// The installed plugin named `name`.
+fn find(project: &Path, name: &str) -> Result<Installed, PluginError> {
+    installed(&plugins_dir(project))?
+        .into_iter()
+        .find(|plugin| plugin.name() == name)
+        .ok_or_else(|| PluginError::NotInstalled(name.to_string()))
+}
+
+/// An installed plugin's manifest, or what is assumed of a bare library
+/// before it is loaded.
+fn manifest_of(plugin: &Installed) -> PluginManifest {
+    match &plugin.manifest {
+        Some(manifest) => manifest.clone(),
+        None => PluginManifest::bare(&plugin.name(), Vec::new()),
+    }
+}
+
+/// Download the registry plugin package `spec` into `.boltpm/cache`,
+/// returning the directory holding its `plugin.toml`.
+fn download(project: &Path, spec: &str) -> Result<PathBuf, PluginError> {
+    let (name, range) = registry::parse_spec(spec);
+    let failed = |e: String| PluginError::Download(format!("Failed to fetch plugin {name}: {e}"));
+    let meta = match registry::fetch_metadata(name) {
+        Ok(Ok(meta)) => meta,
+        Ok(Err(status)) => return Err(failed(status.to_string())),
+        Err(e) => return Err(failed(e.to_string())),
+    };
+    let version = registry::resolve_version(&meta, range).ok_or_else(|| {
+        PluginError::Download(format!(
+            "No version of {name} matches '{}'",
+            range.unwrap_or("latest")
+        ))
+    })?;
+    let is_plugin = meta["versions"][&version]["keywords"]
+        .as_array()
+        .is_some_and(|keywords| keywords.iter().any(|k| k == PLUGIN_KEYWORD));
+    if !is_plugin {
+        return Err(PluginError::Download(format!(
+            "{name}@{version} is not a boltpm plugin: its package.json doesn't have the \"{PLUGIN_KEYWORD}\" keyword"
+        )));
+    }
+    let url = registry::tarball_url(name, &version);
+    info!("Downloading plugin {name}@{version} from {url}");
+    let bytes = registry::client()
+        .get(&url)
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.bytes())
+        .map_err(|e| failed(e.to_string()))?;
+    let cache_dir = project
+        .join(".boltpm/cache")
+        .join(format!("{name}-{version}"));
+    let package = cache_dir.join("package");
+    if package.exists() {
+        fs::remove_dir_all(&package)?;
+    }
+    fs::create_dir_all(&cache_dir)?;
+    tar::Archive::new(flate2::read::GzDecoder::new(&bytes[..])).unpack(&cache_dir)?;
+    if !package.join(MANIFEST_FILE).is_file() {
+        return Err(PluginError::Download(format!(
+            "{name}@{version} is not a boltpm plugin: its tarball has no {MANIFEST_FILE}"
+        )));
+    }
+    Ok(package)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+fn remove_path(path: &Path) -> Result<(), PluginError> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A project with a bare library and a plugin directory, neither of
+    /// which loads.
+    fn project() -> tempfile::TempDir {
+        let project = tempfile::tempdir().unwrap();
+        let dir = plugins_dir(project.path());
+        fs::create_dir_all(dir.join("audit")).unwrap();
+        fs::write(dir.join("broken.so"), "not a library").unwrap();
+        fs::write(
+            dir.join("audit").join(MANIFEST_FILE),
+            "name = \"audit\"\nversion = \"1.2.0\"\napi_version = \"*\"\nhooks = [\"postresolve\"]\n\
+             [config.level]\ntype = \"string\"\nrequired = true\n",
+        )
+        .unwrap();
+        fs::write(dir.join("audit").join("libaudit.so"), "not a library").unwrap();
+        project
+    }
+
+    #[test]
+    fn test_enable_disable_remove() {
+        let project = project();
+        let project = project.path();
+        let loader = || Loader::new(project, Isolation::None).unwrap();
+        assert!(super::super::discover(&plugins_dir(project), &loader()).is_err());
+
+        // Disabled plugins aren't loaded, so broken ones can be switched off.
+        set_enabled(project, "broken", false).unwrap();
+        set_enabled(project, "audit", false).unwrap();
+        assert!(super::super::discover(&plugins_dir(project), &loader())
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            set_enabled(project, "lint", false),
+            Err(PluginError::NotInstalled(_))
+        ));
+
+        let plugins = list_with(project, &loader()).unwrap();
+        let names: Vec<_> = plugins.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["audit", "broken"]);
+        assert!(plugins.iter().all(|p| !p.enabled && p.abi == "?"));
+        assert_eq!(plugins[0].hooks, Some(vec![Hook::PostResolve]));
+        assert_eq!(plugins[0].trust, "full trust");
+        assert_eq!(plugins[0].problems[0], "boltpm.toml: 'level' is required");
+        assert_eq!(plugins[1].version, "0.0.0");
+        let table = format_list(&plugins);
+        assert!(table.starts_with("NAME    VERSION  HOOKS        ABI  TRUST       STATUS\n"));
+        assert!(table.contains("\naudit   1.2.0    postresolve  ?    full trust  disabled\n"));
+
+        remove(project, "broken").unwrap();
+        assert!(!plugins_dir(project).join("broken.so").exists());
+        assert!(matches!(
+            remove(project, "broken"),
+            Err(PluginError::NotInstalled(_))
+        ));
+        remove(project, "audit").unwrap();
+        assert_eq!(
+            format_list(&list_with(project, &loader()).unwrap()),
+            "No plugins installed."
+        );
+    }
+
+    #[test]
+    fn test_add_rejects_what_cannot_run() {
+        let project = tempfile::tempdir().unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let readme = source.path().join("README.md");
+        fs::write(&readme, "not a plugin").unwrap();
+        assert!(matches!(
+            add(project.path(), readme.to_str().unwrap()),
+            Err(PluginError::NotAPlugin(_))
+        ));
+
+        fs::write(
+            source.path().join(MANIFEST_FILE),
+            "name = \"lint\"\nversion = \"1.0.0\"\napi_version = \"^99\"\nhooks = [\"preinstall\"]\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            add(project.path(), source.path().to_str().unwrap()),
+            Err(PluginError::IncompatibleApi { .. })
+        ));
+        assert!(!plugins_dir(project.path()).exists());
+    }
+}
@@ -0,0 +1,534 @@
+//! `plugin.toml`: what a plugin is, which `plugin_api` it was built against,
+//! the hooks it runs on, where it runs relative to other plugins, the
+//! configuration it accepts, and what a WASM plugin may access.
+//!
+//! ```toml
+//! name = "license-check"
+//! version = "1.0.0"
+//! api_version = "^0.2"
+//! library = "liblicense_check.so"   # optional if the directory has one library
+//! hooks = ["preinstall"]
+//! priority = 10                      # lower runs first; default 0
+//! after = ["audit"]
+//!
+//! [config.allowed]
+//! type = "array"
+//! default = ["MIT", "Apache-2.0"]
+//! description = "Licenses that may be installed"
+//!
+//! [capabilities]
+//! read_project = true
+//! write_output = true
+//! env = ["CI"]
+//! http = ["spdx.org"]
+//! ```
+
+use plugin_api::Hook;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub const MANIFEST_FILE: &str = "plugin.toml";
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PluginManifest {
+    pub name: String,
+    pub version: String,
+    /// Requirement on the host's `plugin_api` version, e.g. `^0.2`.
+    pub api_version: String,
+    /// The plugin library, relative to the manifest.
+    #[serde(default)]
+    pub library: Option<String>,
+    pub hooks: Vec<Hook>,
+    #[serde(default)]
+    pub priority: i32,
+    /// Plugins this one must run before, if they are installed.
+    #[serde(default)]
+    pub before: Vec<String>,
+    /// Plugins this one must run after, if they are installed.
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Setting name -> its schema.
+    #[serde(default)]
+    pub config: BTreeMap<String, ConfigField>,
+    /// What a WASM plugin may access. Native plugins have full trust.
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+/// Access a WASM plugin requests through host functions. Everything but
+/// `write_output`, which only reaches `.boltpm/plugins_output`, needs the
+/// user's approval.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Capabilities {
+    /// Read files under the project directory.
+    pub read_project: bool,
+    /// Write files under `.boltpm/plugins_output`.
+    pub write_output: bool,
+    /// Environment variables the plugin may read.
+    pub env: Vec<String>,
+    /// Hosts the plugin may send HTTP requests to.
+    pub http: Vec<String>,
+}
+
+impl Capabilities {
+    /// What a WASM module installed without a manifest gets.
+    pub fn bare() -> Self {
+        Capabilities {
+            write_output: true,
+            ..Capabilities::default()
+        }
+    }
+
+    /// Whether these grant nothing but environment variables, the only
+    /// capability that applies to native plugins.
+    pub fn only_env(&self) -> bool {
+        *self
+            == Capabilities {
+                env: self.env.clone(),
+                ..Capabilities::default()
+            }
+    }
+
+    /// Whether `self`, as approved, covers everything `requested` needs
+    /// approval for.
+    pub fn covers(&self, requested: &Capabilities) -> bool {
+        (self.read_project || !requested.read_project)
+            && requested.env.iter().all(|var| self.env.contains(var))
+            && requested.http.iter().all(|host| self.http.contains(host))
+    }
+
+    /// The capabilities needing approval, for showing to the user.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.read_project {
+            lines.push("read files in the project".to_string());
+        }
+        if !self.env.is_empty() {
+            lines.push(format!(
+                "read environment variables {}",
+                self.env.join(", ")
+            ));
+        }
+        if !self.http.is_empty() {
+            lines.push(format!("send HTTP requests to {}", self.http.join(", ")));
+        }
+        lines
+    }
+
+    /// Add `other`'s capabilities to these.
+    pub fn merge(&mut self, other: &Capabilities) {
+        self.read_project |= other.read_project;
+        self.write_output |= other.write_output;
+        for var in &other.env {
+            if !self.env.contains(var) {
+                self.env.push(var.clone());
+            }
+        }
+        for host in &other.http {
+            if !self.http.contains(host) {
+                self.http.push(host.clone());
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigField {
+    #[serde(rename = "type")]
+    pub kind: ConfigType,
+    #[serde(default)]
+    pub default: Option<toml::Value>,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigType {
+    String,
+    Boolean,
+    Integer,
+    Float,
+    Array,
+    Table,
+}
+
+impl ConfigType {
+    pub fn matches(self, value: &toml::Value) -> bool {
+        matches!(
+            (self, value),
+            (ConfigType::String, toml::Value::String(_))
+                | (ConfigType::Boolean, toml::Value::Boolean(_))
+                | (ConfigType::Integer, toml::Value::Integer(_))
+                | (
+                    ConfigType::Float,
+                    toml::Value::Float(_) | toml::Value::Integer(_)
+                )
+                | (ConfigType::Array, toml::Value::Array(_))
+                | (ConfigType::Table, toml::Value::Table(_))
+        )
+    }
+}
+
+impl PluginManifest {
+    /// Parse and validate a manifest. Whether the host can run the plugin is
+    /// checked separately, with [`PluginManifest::supports_api`].
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let manifest: PluginManifest = toml::from_str(raw).map_err(|e| e.to_string())?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// What is assumed of a library installed without a manifest.
+    pub fn bare(name: &str, hooks: Vec<Hook>) -> Self {
+        PluginManifest {
+            name: name.to_string(),
+            version: "0.0.0".to_string(),
+            api_version: "*".to_string(),
+            library: None,
+            hooks,
+            priority: 0,
+            before: Vec::new(),
+            after: Vec::new(),
+            config: BTreeMap::new(),
+            capabilities: Capabilities::default(),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let valid_name = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(format!(
+                "Invalid plugin name '{}' (use lowercase letters, digits, '-' and '_')",
+                self.name
+            ));
+        }
+        Version::parse(&self.version)
+            .map_err(|e| format!("Invalid version '{}': {e}", self.version))?;
+        VersionReq::parse(&self.api_version)
+            .map_err(|e| format!("Invalid api_version '{}': {e}", self.api_version))?;
+        if self.hooks.is_empty() {
+            return Err("A plugin must subscribe to at least one hook".to_string());
+        }
+        if let Some(host) = self
+            .capabilities
+            .http
+            .iter()
+            .find(|host| host.is_empty() || host.contains(['/', ':', '@']))
+        {
+            return Err(format!(
+                "Invalid HTTP capability '{host}' (list host names, like 'example.com')"
+            ));
+        }
+        for (key, field) in &self.config {
+            match &field.default {
+                Some(_) if field.required => {
+                    return Err(format!(
+                        "Config '{key}' can't be both required and have a default"
+                    ))
+                }
+                Some(default) if !field.kind.matches(default) => {
+                    return Err(format!(
+                        "Default for config '{key}' is not a {:?}",
+                        field.kind
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a host providing `plugin_api` `host_version` can run this
+    /// plugin.
+    pub fn supports_api(&self, host_version: &str) -> bool {
+        match (
+            VersionReq::parse(&self.api_version),
+            Version::parse(host_version),
+        ) {
+            (Ok(req), Ok(version)) => req.matches(&version),
+            _ => false,
+        }
+    }
+
+    /// Bitmask of the manifest's hooks.
+    pub fn hook_mask(&self) -> u32 {
+        Hook::mask(&self.hooks)
+    }
+
+    /// The config passed to the plugin: its defaults, overridden by the
+    /// project's `settings` for it. Fails on settings the manifest doesn't
+    /// declare, of the wrong type, or required but missing.
+    pub fn config(
+        &self,
+        settings: &toml::Table,
+    ) -> Result<BTreeMap<String, serde_json::Value>, String> {
+        if let Some(key) = settings.keys().find(|key| !self.config.contains_key(*key)) {
+            return Err(format!("'{key}' is not a setting of this plugin"));
+        }
+        let mut config = BTreeMap::new();
+        for (key, field) in &self.config {
+            let value = match (settings.get(key), &field.default) {
+                (Some(value), _) if !field.kind.matches(value) => {
+                    return Err(format!("'{key}' must be a {:?}", field.kind))
+                }
+                (Some(value), _) | (None, Some(value)) => value,
+                (None, None) if field.required => return Err(format!("'{key}' is required")),
+                (None, None) => continue,
+            };
+            let value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+            config.insert(key.clone(), value);
+        }
+        Ok(config)
+    }
+}
+
+/// The order to run plugins in: every `before`/`after` constraint between
+/// installed plugins is honoured, and otherwise lower `priority` runs first,
+/// then by name. Fails on duplicate names and on cycles.
+pub fn run_order(manifests: &[PluginManifest]) -> Result<Vec<usize>, String> {
+    let mut index = HashMap::new();
+    for (i, manifest) in manifests.iter().enumerate() {
+        if index.insert(manifest.name.as_str(), i).is_some() {
+            return Err(format!(
+                "Two plugins are named '{}'; plugin names must be unique",
+                manifest.name
+            ));
+        }
+    }
+    // Edges from each plugin to those that must run after it.
+    let mut later: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); manifests.len()];
+    for (i, manifest) in manifests.iter().enumerate() {
+        for name in &manifest.before {
+            if let Some(&j) = index.get(name.as_str()) {
+                later[i].insert(j);
+            }
+        }
+        for name in &manifest.after {
+            if let Some(&j) = index.get(name.as_str()) {
+                later[j].insert(i);
+            }
+        }
+    }
+    let mut waiting_on = vec![0; manifests.len()];
+    for &j in later.iter().flatten() {
+        waiting_on[j] += 1;
+    }
+    let key = |i: usize| (manifests[i].priority, manifests[i].name.as_str(), i);
+    let mut ready: BTreeSet<_> = (0..manifests.len())
+        .filter(|&i| waiting_on[i] == 0)
+        .map(key)
+        .collect();
+    let mut order = Vec::with_capacity(manifests.len());
+    while let Some((_, _, i)) = ready.pop_first() {
+        order.push(i);
+        for &j in &later[i] {
+            waiting_on[j] -= 1;
+            if waiting_on[j] == 0 {
+                ready.insert(key(j));
+            }
+        }
+    }
+    if order.len() < manifests.len() {
+        let mut stuck: Vec<&str> = (0..manifests.len())
+            .filter(|&i| waiting_on[i] > 0)
+            .map(|i| manifests[i].name.as_str())
+            .collect();
+        stuck.sort();
+        return Err(format!(
+            "Plugins {} can't be ordered: their before/after constraints form a cycle",
+            stuck.join(", ")
+        ));
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(name: &str, priority: i32, before: &[&str], after: &[&str]) -> PluginManifest {
+        PluginManifest {
+            priority,
+            before: before.iter().map(|s| s.to_string()).collect(),
+            after: after.iter().map(|s| s.to_string()).collect(),
+            ..PluginManifest::bare(name, vec![Hook::PreInstall])
+        }
+    }
+
+    fn names(manifests: &[PluginManifest]) -> Result<Vec<&str>, String> {
+        Ok(run_order(manifests)?
+            .into_iter()
+            .map(|i| manifests[i].name.as_str())
+            .collect())
+    }
+
+    #[test]
+    fn test_parse() {
+        let manifest = PluginManifest::parse(
+            r#"
+            name = "license-check"
+            version = "1.0.0"
+            api_version = "^0.2"
+            hooks = ["preinstall", "onError"]
+            after = ["audit"]
+
+            [config.allowed]
+            type = "array"
+            default = ["MIT"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(manifest.hooks, [Hook::PreInstall, Hook::OnError]);
+        assert!(manifest.supports_api("0.2.0"));
+        assert!(!manifest.supports_api("0.3.0"));
+        assert_eq!(
+            manifest.config(&toml::Table::new()).unwrap()["allowed"],
+            serde_json::json!(["MIT"])
+        );
+
+        let invalid = [
+            "name = \"x\"\nversion = \"1\"\napi_version = \"*\"\nhooks = [\"preinstall\"]",
+            "name = \"X\"\nversion = \"1.0.0\"\napi_version = \"*\"\nhooks = [\"preinstall\"]",
+            "name = \"x\"\nversion = \"1.0.0\"\napi_version = \"^^1\"\nhooks = [\"preinstall\"]",
+            "name = \"x\"\nversion = \"1.0.0\"\napi_version = \"*\"\nhooks = []",
+            "name = \"x\"\nversion = \"1.0.0\"\napi_version = \"*\"\nhooks = [\"install\"]",
+            "name = \"x\"\nversion = \"1.0.0\"\napi_version = \"*\"\nhooks = [\"preinstall\"]\nextra = 1",
+            "name = \"x\"\nversion = \"1.0.0\"\napi_version = \"*\"\nhooks = [\"preinstall\"]\n\
+             [config.n]\ntype = \"integer\"\ndefault = \"one\"",
+        ];
+        for raw in invalid {
+            assert!(PluginManifest::parse(raw).is_err(), "{raw}");
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let manifest = PluginManifest::parse(
+            r#"
+            name = "license-check"
+            version = "1.0.0"
+            api_version = "*"
+            hooks = ["preinstall"]
+
+            [config.allowed]
+            type = "array"
+            default = ["MIT"]
+
+            [config.level]
+            type = "string"
+            required = true
+            "#,
+        )
+        .unwrap();
+        let settings = |raw: &str| raw.parse::<toml::Table>().unwrap();
+        assert_eq!(
+            manifest.config(&settings("level = \"warn\"")).unwrap(),
+            BTreeMap::from([
+                ("allowed".to_string(), serde_json::json!(["MIT"])),
+                ("level".to_string(), serde_json::json!("warn")),
+            ])
+        );
+        assert_eq!(
+            manifest
+                .config(&settings("level = \"warn\"\nallowed = [\"ISC\"]"))
+                .unwrap()["allowed"],
+            serde_json::json!(["ISC"])
+        );
+        assert_eq!(
+            manifest.config(&toml::Table::new()).unwrap_err(),
+            "'level' is required"
+        );
+        assert_eq!(
+            manifest.config(&settings("level = 1")).unwrap_err(),
+            "'level' must be a String"
+        );
+        assert_eq!(
+            manifest
+                .config(&settings("level = \"warn\"\nstrict = true"))
+                .unwrap_err(),
+            "'strict' is not a setting of this plugin"
+        );
+    }
+
+    #[test]
+    fn test_run_order() {
+        let plugins = [
+            manifest("c", 0, &[], &[]),
+            manifest("b", 0, &[], &[]),
+            manifest("a", 5, &[], &[]),
+        ];
+        assert_eq!(names(&plugins).unwrap(), ["b", "c", "a"]);
+
+        let plugins = [
+            manifest("lint", 0, &[], &["audit"]),
+            manifest("audit", 10, &[], &[]),
+            manifest("notify", -5, &[], &["lint"]),
+            manifest("setup", 20, &["audit"], &["missing"]),
+        ];
+        assert_eq!(
+            names(&plugins).unwrap(),
+            ["setup", "audit", "lint", "notify"]
+        );
+
+        let cycle = [
+            manifest("a", 0, &["b"], &[]),
+            manifest("b", 0, &["a"], &[]),
+            manifest("c", 0, &[], &[]),
+        ];
+        assert_eq!(
+            run_order(&cycle).unwrap_err(),
+            "Plugins a, b can't be ordered: their before/after constraints form a cycle"
+        );
+        let twins = [manifest("a", 0, &[], &[]), manifest("a", 1, &[], &[])];
+        assert!(run_order(&twins).is_err());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let requested = Capabilities {
+            read_project: true,
+            write_output: true,
+            env: vec!["CI".to_string()],
+            http: vec!["spdx.org".to_string()],
+        };
+        // Output is confined, so never needs approving.
+        assert!(Capabilities::default().covers(&Capabilities::bare()));
+        assert!(!Capabilities::bare().covers(&requested));
+
+        let mut approved = Capabilities {
+            env: vec!["CI".to_string(), "HOME".to_string()],
+            ..Capabilities::default()
+        };
+        assert!(!approved.covers(&requested));
+        approved.merge(&requested);
+        assert!(approved.covers(&requested));
+        assert_eq!(approved.env, ["CI", "HOME"]);
+        assert_eq!(
+            requested.describe(),
+            [
+                "read files in the project",
+                "read environment variables CI",
+                "send HTTP requests to spdx.org"
+            ]
+        );
+
+        let raw =
+            "name = \"x\"\nversion = \"1.0.0\"\napi_version = \"*\"\nhooks = [\"preinstall\"]\n\
+                   [capabilities]\n";
+        let parsed = PluginManifest::parse(&format!("{raw}env = [\"CI\"]")).unwrap();
+        assert_eq!(parsed.capabilities.env, ["CI"]);
+        assert!(PluginManifest::parse(&format!("{raw}http = [\"https://spdx.org\"]")).is_err());
+        assert!(PluginManifest::parse(&format!("{raw}network = true")).is_err());
+    }
+}
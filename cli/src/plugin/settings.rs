@@ -0,0 +1,139 @@
+//! The project's plugin settings, in `boltpm.toml` next to `package.json`:
+//! whether each installed plugin is enabled, and its config, checked against
+//! the schema in its `plugin.toml`.
+//!
+//! ```toml
+//! [plugins.license-check]
+//! enabled = false
+//!
+//! [plugins.license-check.config]
+//! allowed = ["MIT", "ISC"]
+//! ```
+
+use super::PluginError;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+pub const PROJECT_CONFIG: &str = "boltpm.toml";
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ProjectConfig {
+    #[serde(default)]
+    plugins: BTreeMap<String, PluginSettings>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PluginSettings {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub config: toml::Table,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Default for PluginSettings {
+    fn default() -> Self {
+        PluginSettings {
+            enabled: true,
+            config: toml::Table::new(),
+        }
+    }
+}
+
+impl ProjectConfig {
+    /// Read `file`; a missing file configures nothing.
+    pub fn load(file: &Path) -> Result<Self, PluginError> {
+        match fs::read_to_string(file) {
+            Ok(raw) => toml::from_str(&raw)
+                .map_err(|e| PluginError::Manifest(file.to_path_buf(), e.to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ProjectConfig::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The settings for `plugin`, or the defaults if it has none.
+    pub fn plugin(&self, plugin: &str) -> PluginSettings {
+        self.plugins.get(plugin).cloned().unwrap_or_default()
+    }
+}
+
+/// Enable or disable `plugin` in `file`, keeping the rest of the file,
+/// comments included, as it is.
+pub fn set_enabled(file: &Path, plugin: &str, enabled: bool) -> Result<(), PluginError> {
+    let raw = match fs::read_to_string(file) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let invalid = |e: String| PluginError::Manifest(file.to_path_buf(), e);
+    let mut doc: toml_edit::Document = raw
+        .parse()
+        .map_err(|e: toml_edit::TomlError| invalid(e.to_string()))?;
+    let plugins = doc
+        .entry("plugins")
+        .or_insert_with(|| {
+            let mut table = toml_edit::Table::new();
+            table.set_implicit(true);
+            toml_edit::Item::Table(table)
+        })
+        .as_table_mut()
+        .ok_or_else(|| invalid("`plugins` is not a table".to_string()))?;
+    let settings = plugins
+        .entry(plugin)
+        .or_insert_with(toml_edit::table)
+        .as_table_like_mut()
+        .ok_or_else(|| invalid(format!("`plugins.{plugin}` is not a table")))?;
+    settings.insert("enabled", toml_edit::value(enabled));
+    fs::write(file, doc.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join(PROJECT_CONFIG);
+        assert_eq!(
+            ProjectConfig::load(&file).unwrap().plugin("lint"),
+            PluginSettings::default()
+        );
+
+        set_enabled(&file, "lint", false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "[plugins.lint]\nenabled = false\n"
+        );
+
+        fs::write(
+            &file,
+            "# Checked in CI\n[plugins.lint]\nenabled = false\n\n[plugins.lint.config]\nstrict = true\n",
+        )
+        .unwrap();
+        set_enabled(&file, "lint", true).unwrap();
+        set_enabled(&file, "audit", false).unwrap();
+        let raw = fs::read_to_string(&file).unwrap();
+        assert!(raw.starts_with("# Checked in CI\n[plugins.lint]\nenabled = true\n"));
+        let config = ProjectConfig::load(&file).unwrap();
+        assert!(config.plugin("lint").enabled);
+        assert_eq!(
+            config.plugin("lint").config["strict"],
+            toml::Value::Boolean(true)
+        );
+        assert!(!config.plugin("audit").enabled);
+
+        fs::write(&file, "[plugins.lint]\nenable = false\n").unwrap();
+        assert!(matches!(
+            ProjectConfig::load(&file),
+            Err(PluginError::Manifest(..))
+        ));
+    }
+}
@@ -0,0 +1,592 @@
+//! WebAssembly plugins, run with wasmtime when boltpm is built with the
+//! `wasm_plugins` feature.
+//!
+//! A module exports its `memory` and `run(ctx_ptr: i32, ctx_len: i32) -> i32`,
+//! which returns 0 on success. For each call the host instantiates the module
+//! afresh, grows its memory and writes the JSON [`PluginContext`] into the new
+//! pages, so modules need no allocator export. Calls are bounded by a fuel
+//! (instruction) budget, a wall-clock deadline and a memory cap.
+//!
+//! A module may only import the host functions its [`Capabilities`] grant,
+//! and its context's `env` holds only the variables they list. Host
+//! functions, imported from `env`:
+//!
+//! - `host_log(ptr: i32, len: i32)`: log a UTF-8 message. Always available.
+//! - `host_set_result(ptr: i32, len: i32)`: return an [`Outcome`] as UTF-8
+//!   JSON. Always available.
+//! - `host_write_output(name_ptr: i32, name_len: i32, ptr: i32, len: i32)`:
+//!   write `.boltpm/plugins_output/<name>`. Needs `write_output`.
+//! - `host_write_file(ptr: i32, len: i32)`: write
+//!   `.boltpm/plugins_output/wasm_test_hook`, for modules built before
+//!   `host_write_output`. Needs `write_output`.
+//! - `host_read_file(path_ptr: i32, path_len: i32, buf_ptr: i32, buf_len: i32) -> i32`:
+//!   read a file, by its path relative to the project. Needs `read_project`.
+//! - `host_http_get(url_ptr: i32, url_len: i32, buf_ptr: i32, buf_len: i32) -> i32`:
+//!   GET a URL on one of the `http` hosts.
+//!
+//! Functions returning `i32` return the full length of the result, which is
+//! copied to the buffer only if it fits, or -1 if there is none. A host
+//! function given bad arguments traps the module.
+//!
+//! [`PluginContext`]: plugin_api::PluginContext
+//! [`Outcome`]: plugin_api::Outcome
+
+use super::manifest::Capabilities;
+use super::PluginError;
+use anyhow::{anyhow, bail};
+use log::info;
+use plugin_api::{Outcome, PluginContext};
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use wasmtime::{
+    Caller, Config, Engine, ExternType, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+    Trap, ValType,
+};
+
+/// Fuel given to each call; roughly one unit per instruction.
+const FUEL: u64 = if cfg!(test) { 1_000_000 } else { 2_000_000_000 };
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the engine's epoch advances, i.e. the deadline's resolution.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+const MAX_MEMORY: usize = 256 << 20;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REDIRECTS: usize = 10;
+const PAGE_SIZE: u64 = 64 << 10;
+const OUTPUT_DIR: &str = ".boltpm/plugins_output";
+/// Where `host_write_file` writes.
+const LEGACY_OUTPUT: &str = "wasm_test_hook";
+
+/// The engine shared by all WASM plugins, with a thread advancing its epoch.
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.consume_fuel(true).epoch_interruption(true);
+        let engine = Engine::new(&config).expect("wasmtime config is valid");
+        let ticker = engine.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            ticker.increment_epoch();
+        });
+        engine
+    })
+}
+
+struct HostState {
+    plugin: String,
+    project_dir: PathBuf,
+    output_dir: PathBuf,
+    capabilities: Capabilities,
+    limits: StoreLimits,
+    /// What the module passed to `host_set_result`.
+    outcome: Option<Vec<u8>>,
+}
+
+pub struct WasmPlugin {
+    module: Module,
+    capabilities: Capabilities,
+}
+
+impl WasmPlugin {
+    /// Compile a module and check it has the exports the host calls, and
+    /// imports only host functions `capabilities` grant.
+    pub fn load(path: &Path, capabilities: Capabilities) -> Result<Self, PluginError> {
+        let module = Module::from_file(engine(), path)
+            .map_err(|e| PluginError::Wasm(format!("{}: {e:#}", path.display())))?;
+        let has_run = match module.get_export("run") {
+            Some(ExternType::Func(ty)) => {
+                ty.params().eq([ValType::I32, ValType::I32]) && ty.results().eq([ValType::I32])
+            }
+            _ => false,
+        };
+        if !has_run {
+            return Err(PluginError::Wasm(format!(
+                "{} doesn't export run(i32, i32) -> i32",
+                path.display()
+            )));
+        }
+        if !matches!(module.get_export("memory"), Some(ExternType::Memory(_))) {
+            return Err(PluginError::Wasm(format!(
+                "{} doesn't export its memory",
+                path.display()
+            )));
+        }
+        for import in module.imports() {
+            let granted = match (import.module(), import.name()) {
+                ("env", "host_log" | "host_set_result") => None,
+                ("env", "host_write_output" | "host_write_file") => {
+                    Some(("write_output", capabilities.write_output))
+                }
+                ("env", "host_read_file") => Some(("read_project", capabilities.read_project)),
+                ("env", "host_http_get") => Some(("http", !capabilities.http.is_empty())),
+                (module, name) => {
+                    return Err(PluginError::Wasm(format!(
+                        "{} imports {module}.{name}, which boltpm doesn't provide",
+                        path.display()
+                    )))
+                }
+            };
+            if let Some((capability, false)) = granted {
+                return Err(PluginError::Wasm(format!(
+                    "{} imports env.{}, which needs the `{capability}` capability in its {}",
+                    path.display(),
+                    import.name(),
+                    super::MANIFEST_FILE
+                )));
+            }
+        }
+        Ok(WasmPlugin {
+            module,
+            capabilities,
+        })
+    }
+
+    /// Call the module's `run` with `ctx`, returning its code and outcome.
+    pub fn run(&self, path: &Path, ctx: &PluginContext) -> Result<(i32, Outcome), PluginError> {
+        let mut ctx = ctx.clone();
+        ctx.env.retain(|var, _| self.capabilities.env.contains(var));
+        let ctx_json = serde_json::to_vec(&ctx)?;
+        let project_dir = std::env::current_dir()?;
+        let mut store = Store::new(
+            engine(),
+            HostState {
+                plugin: path.display().to_string(),
+                output_dir: project_dir.join(OUTPUT_DIR),
+                project_dir,
+                capabilities: self.capabilities.clone(),
+                limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+                outcome: None,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.add_fuel(FUEL).map_err(run_error)?;
+        store.set_epoch_deadline((TIMEOUT.as_millis() / EPOCH_TICK.as_millis()) as u64);
+
+        let instance = linker(&self.capabilities)
+            .and_then(|linker| linker.instantiate(&mut store, &self.module))
+            .map_err(run_error)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| PluginError::Wasm("module doesn't export its memory".to_string()))?;
+        let run = instance
+            .get_typed_func::<(i32, i32), i32>(&mut store, "run")
+            .map_err(run_error)?;
+
+        let pages = (ctx_json.len() as u64).div_ceil(PAGE_SIZE);
+        let start = memory.grow(&mut store, pages).map_err(run_error)? * PAGE_SIZE;
+        let (ptr, len) = match (i32::try_from(start), i32::try_from(ctx_json.len())) {
+            (Ok(ptr), Ok(len)) => (ptr, len),
+            _ => {
+                return Err(PluginError::Wasm(
+                    "context doesn't fit in memory".to_string(),
+                ))
+            }
+        };
+        memory
+            .write(&mut store, start as usize, &ctx_json)
+            .map_err(|e| PluginError::Wasm(e.to_string()))?;
+        let code = run.call(&mut store, (ptr, len)).map_err(run_error)?;
+        let outcome = match store.data_mut().outcome.take() {
+            Some(json) => serde_json::from_slice(&json)
+                .map_err(|e| PluginError::Outcome(path.to_path_buf(), e.to_string()))?,
+            None => Outcome::default(),
+        };
+        Ok((code, outcome))
+    }
+}
+
+fn run_error(e: anyhow::Error) -> PluginError {
+    match e.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => PluginError::OutOfFuel,
+        Some(Trap::Interrupt) => PluginError::Timeout,
+        _ => PluginError::Wasm(format!("{e:#}")),
+    }
+}
+
+/// The host functions `capabilities` grant.
+fn linker(capabilities: &Capabilities) -> anyhow::Result<Linker<HostState>> {
+    let mut linker = Linker::new(engine());
+    linker.func_wrap(
+        "env",
+        "host_log",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            let message = String::from_utf8(read_guest(&mut caller, ptr, len)?)?;
+            info!("[{}] {message}", caller.data().plugin);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "host_set_result",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            let json = read_guest(&mut caller, ptr, len)?;
+            caller.data_mut().outcome = Some(json);
+            Ok(())
+        },
+    )?;
+    if capabilities.write_output {
+        linker.func_wrap(
+            "env",
+            "host_write_output",
+            |mut caller: Caller<'_, HostState>,
+             name_ptr: i32,
+             name_len: i32,
+             ptr: i32,
+             len: i32| {
+                let name = String::from_utf8(read_guest(&mut caller, name_ptr, name_len)?)?;
+                let contents = read_guest(&mut caller, ptr, len)?;
+                write_output(caller.data(), &name, &contents)
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "host_write_file",
+            |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                let contents = read_guest(&mut caller, ptr, len)?;
+                write_output(caller.data(), LEGACY_OUTPUT, &contents)
+            },
+        )?;
+    }
+    if capabilities.read_project {
+        linker.func_wrap(
+            "env",
+            "host_read_file",
+            |mut caller: Caller<'_, HostState>,
+             path_ptr: i32,
+             path_len: i32,
+             buf_ptr: i32,
+             buf_len: i32| {
+                let path = String::from_utf8(read_guest(&mut caller, path_ptr, path_len)?)?;
+                let contents = read_project_file(&caller.data().project_dir, &path)?;
+                write_guest(&mut caller, buf_ptr, buf_len, contents.as_deref())
+            },
+        )?;
+    }
+    if !capabilities.http.is_empty() {
+        linker.func_wrap(
+            "env",
+            "host_http_get",
+            |mut caller: Caller<'_, HostState>,
+             url_ptr: i32,
+             url_len: i32,
+             buf_ptr: i32,
+             buf_len: i32| {
+                let url = String::from_utf8(read_guest(&mut caller, url_ptr, url_len)?)?;
+                let body = http_get(&caller.data().capabilities, &url)?;
+                write_guest(&mut caller, buf_ptr, buf_len, body.as_deref())
+            },
+        )?;
+    }
+    Ok(linker)
+}
+
+/// Copy `len` bytes at `ptr` out of the calling module's memory.
+fn read_guest(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> anyhow::Result<Vec<u8>> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| anyhow!("module doesn't export its memory"))?;
+    let (Ok(ptr), Ok(len)) = (usize::try_from(ptr), usize::try_from(len)) else {
+        bail!("negative pointer or length");
+    };
+    let mut buf = vec![0; len];
+    memory.read(&caller, ptr, &mut buf)?;
+    Ok(buf)
+}
+
+/// Copy `data` to the `len`-byte buffer at `ptr` if it fits, returning its
+/// length, or -1 for no data.
+fn write_guest(
+    caller: &mut Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+    data: Option<&[u8]>,
+) -> anyhow::Result<i32> {
+    let Some(data) = data else {
+        return Ok(-1);
+    };
+    let (Ok(ptr), Ok(len)) = (usize::try_from(ptr), usize::try_from(len)) else {
+        bail!("negative pointer or length");
+    };
+    if data.len() <= len {
+        let memory = caller
+            .get_export("memory")
+            .and_then(|export| export.into_memory())
+            .ok_or_else(|| anyhow!("module doesn't export its memory"))?;
+        memory.write(&mut *caller, ptr, data)?;
+    }
+    Ok(i32::try_from(data.len())?)
+}
+
+/// Read a file by its path relative to the project, or `None` if it can't
+/// be read. Paths leading out of the project are refused.
+fn read_project_file(project_dir: &Path, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let relative = Path::new(path);
+    if path.is_empty()
+        || relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("'{path}' is not a path within the project");
+    }
+    // Symlinks may still lead out of it.
+    let (Ok(root), Ok(file)) = (
+        project_dir.canonicalize(),
+        project_dir.join(relative).canonicalize(),
+    ) else {
+        return Ok(None);
+    };
+    if !file.starts_with(&root) {
+        bail!("'{path}' is not a path within the project");
+    }
+    Ok(std::fs::read(file).ok())
+}
+
+/// Whether `url` is http(s) on one of the `allowed` hosts.
+fn allows_url(allowed: &[String], url: &reqwest::Url) -> bool {
+    let host = url.host_str().unwrap_or_default();
+    matches!(url.scheme(), "http" | "https") && allowed.iter().any(|allowed| allowed == host)
+}
+
+/// GET `url`, which must be on one of the allowed hosts, as must every
+/// redirect it leads to. Failed requests have no body.
+fn http_get(capabilities: &Capabilities, url: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let url = reqwest::Url::parse(url)?;
+    if !allows_url(&capabilities.http, &url) {
+        bail!("plugin may not send HTTP requests to {url}");
+    }
+    let allowed = capabilities.http.clone();
+    let redirects = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if allows_url(&allowed, attempt.url()) {
+            attempt.follow()
+        } else {
+            let refused = format!("redirect to {} is not allowed", attempt.url());
+            attempt.error(refused)
+        }
+    });
+    let body = reqwest::blocking::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .redirect(redirects)
+        .build()?
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes());
+    Ok(body.ok().map(|body| body.to_vec()))
+}
+
+/// Write a file in the output directory. Plugins can't name anything outside
+/// it.
+fn write_output(state: &HostState, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        bail!("invalid output file name '{name}'");
+    }
+    std::fs::create_dir_all(&state.output_dir)?;
+    std::fs::write(state.output_dir.join(name), contents)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin_api::Hook;
+
+    /// A module with `imports` running `body` as `run`, in the text format
+    /// wasmtime also accepts.
+    fn module(imports: &str, body: &str) -> String {
+        format!(
+            r#"(module
+                {imports}
+                (memory (export "memory") 1)
+                (data (i32.const 0) "outcontents")
+                (func (export "run") (param $ptr i32) (param $len i32) (result i32)
+                    {body}))"#
+        )
+    }
+
+    fn context() -> PluginContext {
+        PluginContext {
+            hook: Hook::PreInstall,
+            package_name: "left-pad".to_string(),
+            package_version: "1.0.0".to_string(),
+            install_path: String::new(),
+            env: [("CI", "1"), ("SECRET", "hunter2")]
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .into(),
+            config: Default::default(),
+            payload: None,
+        }
+    }
+
+    fn load(dir: &Path, wat: &str, capabilities: Capabilities) -> Result<WasmPlugin, PluginError> {
+        let path = dir.join("plugin.wasm");
+        std::fs::write(&path, wat).unwrap();
+        WasmPlugin::load(&path, capabilities)
+    }
+
+    fn run(dir: &Path, body: &str) -> Result<i32, PluginError> {
+        let imports = r#"(import "env" "host_write_output" (func $write (param i32 i32 i32 i32)))"#;
+        let plugin = load(dir, &module(imports, body), Capabilities::bare())?;
+        Ok(plugin.run(dir, &context())?.0)
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        // Returns the context's first byte, past the module's own page.
+        let body =
+            "(if (i32.lt_u (local.get $ptr) (i32.const 65536)) (then (return (i32.const -1))))
+                    (i32.load8_u (local.get $ptr))";
+        assert_eq!(run(dir.path(), body).unwrap(), b'{' as i32);
+        assert!(matches!(
+            run(dir.path(), "(loop $l (br $l)) (i32.const 0)"),
+            Err(PluginError::OutOfFuel | PluginError::Timeout)
+        ));
+        assert!(matches!(
+            run(dir.path(), "unreachable"),
+            Err(PluginError::Wasm(_))
+        ));
+        // Writing outside the output directory traps.
+        let write = |name_len: usize| {
+            format!(
+                "(call $write (i32.const 0) (i32.const {name_len}) (i32.const 3) (i32.const 8)) (i32.const 0)"
+            )
+        };
+        assert!(run(dir.path(), &write(0)).is_err());
+        std::fs::write(dir.path().join("bad.wasm"), "not wasm").unwrap();
+        assert!(WasmPlugin::load(&dir.path().join("bad.wasm"), Capabilities::bare()).is_err());
+    }
+
+    #[test]
+    fn test_outcome() {
+        let dir = tempfile::tempdir().unwrap();
+        let outcome = |json: &str| {
+            let wat = format!(
+                r#"(module
+                    (import "env" "host_set_result" (func $set (param i32 i32)))
+                    (memory (export "memory") 1)
+                    (data (i32.const 0) "{}")
+                    (func (export "run") (param i32 i32) (result i32)
+                        (call $set (i32.const 0) (i32.const {}))
+                        (i32.const 0)))"#,
+                json.replace('"', "\\\""),
+                json.len()
+            );
+            load(dir.path(), &wat, Capabilities::bare())?.run(dir.path(), &context())
+        };
+        assert_eq!(
+            outcome(r#"{"warnings":["old"],"range":"^2"}"#).unwrap(),
+            (
+                0,
+                Outcome {
+                    warnings: vec!["old".to_string()],
+                    range: Some("^2".to_string()),
+                    ..Outcome::default()
+                }
+            )
+        );
+        assert!(matches!(outcome("not json"), Err(PluginError::Outcome(..))));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let dir = tempfile::tempdir().unwrap();
+        let read = r#"(import "env" "host_read_file" (func (param i32 i32 i32 i32) (result i32)))"#;
+        let err = load(
+            dir.path(),
+            &module(read, "(i32.const 0)"),
+            Capabilities::bare(),
+        )
+        .err()
+        .unwrap();
+        assert!(err
+            .to_string()
+            .contains("imports env.host_read_file, which needs the `read_project` capability"));
+        let granted = Capabilities {
+            read_project: true,
+            ..Capabilities::default()
+        };
+        assert!(load(dir.path(), &module(read, "(i32.const 0)"), granted).is_ok());
+        let unknown = r#"(import "wasi" "fd_write" (func (param i32) (result i32)))"#;
+        assert!(load(
+            dir.path(),
+            &module(unknown, "(i32.const 0)"),
+            Capabilities::bare()
+        )
+        .is_err());
+
+        // Only granted variables reach the plugin, which returns the
+        // context's length.
+        let capabilities = Capabilities {
+            env: vec!["CI".to_string()],
+            ..Capabilities::default()
+        };
+        let plugin = load(dir.path(), &module("", "(local.get $len)"), capabilities).unwrap();
+        let mut expected = context();
+        expected.env.remove("SECRET");
+        assert_eq!(
+            plugin.run(dir.path(), &context()).unwrap().0 as usize,
+            serde_json::to_vec(&expected).unwrap().len()
+        );
+
+        let http = Capabilities {
+            http: vec!["spdx.org".to_string()],
+            ..Capabilities::default()
+        };
+        assert!(http_get(&http, "https://example.com/").is_err());
+        assert!(http_get(&http, "file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_http_get_checks_redirects() {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                let response = match request.split(' ').nth(1).unwrap_or_default() {
+                    "/inside" => format!("HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:{port}/ok\r\nContent-Length: 0\r\n\r\n"),
+                    "/outside" => format!("HTTP/1.1 302 Found\r\nLocation: http://localhost:{port}/ok\r\nContent-Length: 0\r\n\r\n"),
+                    _ => "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_string(),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        let http = Capabilities {
+            http: vec!["127.0.0.1".to_string()],
+            ..Capabilities::default()
+        };
+        let get = |path: &str| http_get(&http, &format!("http://127.0.0.1:{port}{path}")).unwrap();
+        assert_eq!(get("/inside").as_deref(), Some(&b"ok"[..]));
+        assert_eq!(get("/outside"), None);
+    }
+
+    #[test]
+    fn test_read_project_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = dir.path().join("project");
+        std::fs::create_dir(&project).unwrap();
+        std::fs::write(project.join("package.json"), "{}").unwrap();
+        std::fs::write(dir.path().join("secret"), "hunter2").unwrap();
+
+        assert_eq!(
+            read_project_file(&project, "./package.json").unwrap(),
+            Some(b"{}".to_vec())
+        );
+        assert_eq!(read_project_file(&project, "missing").unwrap(), None);
+        assert!(read_project_file(&project, "../secret").is_err());
+        assert!(read_project_file(&project, "/etc/passwd").is_err());
+        assert!(read_project_file(&project, "").is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path().join("secret"), project.join("link")).unwrap();
+            assert!(read_project_file(&project, "link").is_err());
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use reqwest::header::{
+    HeaderMap, HeaderValue, AUTHORIZATION, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::StatusCode;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+pub const REGISTRY_URL: &str = "http://localhost:4000";
+
+/// A client for talking to the registry, sending `BOLTPM_TOKEN` as a bearer
+/// token when it is set.
+pub fn client() -> reqwest::blocking::Client {
+    let mut headers = HeaderMap::new();
+    if let Ok(token) = std::env::var("BOLTPM_TOKEN") {
+        if let Ok(mut value) = HeaderValue::from_str(&format!("Bearer {token}")) {
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+    }
+    reqwest::blocking::Client::builder()
+        .default_headers(headers)
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+/// `/v1/<pkg>/` on the registry, with scoped names percent-encoded.
+pub fn package_url(pkg: &str) -> String {
+    format!("{REGISTRY_URL}/v1/{}/", urlencoding::encode(pkg))
+}
+
+pub fn tarball_url(pkg: &str, version: &str) -> String {
+    format!("{REGISTRY_URL}/v1/{}/{version}/", urlencoding::encode(pkg))
+}
+
+/// Where package metadata is cached between runs, keyed by package name.
+const METADATA_CACHE_DIR: &str = ".boltpm/cache/metadata";
+
+#[derive(Serialize, Deserialize)]
+struct CachedMetadata {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: Value,
+}
+
+/// Fetch a package's metadata, revalidating any cached copy with a
+/// conditional request so unchanged metadata isn't downloaded again.
+/// Non-success statuses are returned as `Err`.
+pub fn fetch_metadata(pkg: &str) -> reqwest::Result<Result<Value, StatusCode>> {
+    let cache_path =
+        Path::new(METADATA_CACHE_DIR).join(format!("{}.json", urlencoding::encode(pkg)));
+    fetch_cached(&client(), &package_url(pkg), &cache_path)
+}
+
+fn fetch_cached(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    cache_path: &Path,
+) -> reqwest::Result<Result<Value, StatusCode>> {
+    let cached: Option<CachedMetadata> = fs::read(cache_path)
+        .ok()
+        .and_then(|raw| serde_json::from_slice(&raw).ok());
+    let mut req = client.get(url);
+    if let Some(cached) = &cached {
+        if let Some(etag) = &cached.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let resp = req.send()?;
+    let status = resp.status();
+    if status == StatusCode::NOT_MODIFIED {
+        if let Some(cached) = cached {
+            return Ok(Ok(cached.body));
+        }
+    }
+    if !status.is_success() {
+        return Ok(Err(status));
+    }
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header(ETAG);
+    let last_modified = header(LAST_MODIFIED);
+    let body: Value = resp.json()?;
+    if etag.is_some() || last_modified.is_some() {
+        let entry = CachedMetadata {
+            etag,
+            last_modified,
+            body,
+        };
+        // A failed write only costs a full download next time.
+        if let Some(dir) = cache_path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = fs::write(cache_path, serde_json::to_vec(&entry).unwrap());
+        return Ok(Ok(entry.body));
+    }
+    Ok(Ok(body))
+}
+
+/// Split `name@spec` into the package name and the optional version, range or
+/// dist-tag, keeping the leading `@` of scoped names.
+pub fn parse_spec(input: &str) -> (&str, Option<&str>) {
+    let at = match input.strip_prefix('@') {
+        Some(rest) => rest.find('@').map(|i| i + 1),
+        None => input.find('@'),
+    };
+    match at {
+        Some(i) if i + 1 < input.len() => (&input[..i], Some(&input[i + 1..])),
+        Some(i) => (&input[..i], None),
+        None => (input, None),
+    }
+}
+
+/// Whether `version` of a package has been yanked, given its registry
+/// metadata.
+pub fn is_yanked(meta: &Value, version: &str) -> bool {
+    meta["versions"][version]["yanked"].as_bool() == Some(true)
+}
+
+/// The author's message if `version` is deprecated.
+pub fn deprecation<'a>(meta: &'a Value, version: &str) -> Option<&'a str> {
+    let ver_meta = &meta["versions"][version];
+    if ver_meta["deprecated"].as_bool() != Some(true) {
+        return None;
+    }
+    Some(
+        ver_meta["deprecation_message"]
+            .as_str()
+            .filter(|m| !m.is_empty())
+            .unwrap_or("This version is deprecated"),
+    )
+}
+
+/// Pick the version of a package that `spec` refers to, given its registry
+/// metadata.
+///
+/// `spec` may be a dist-tag, an exact version or a semver range. Without a
+/// spec the `latest` dist-tag is used, falling back to the highest release.
+/// Yanked versions are never picked; only an existing lockfile may still pin
+/// one.
+pub fn resolve_version(meta: &Value, spec: Option<&str>) -> Option<String> {
+    let versions = meta["versions"].as_object()?;
+    let installable = |v: &str| versions.contains_key(v) && !is_yanked(meta, v);
+    let spec = spec.map(str::trim).filter(|s| !s.is_empty());
+    let tag = spec.unwrap_or("latest");
+    if let Some(version) = meta["dist-tags"][tag].as_str() {
+        if installable(version) {
+            return Some(version.to_string());
+        }
+    }
+    let req = match spec {
+        // A bare version means exactly that version, as in npm.
+        Some(spec) if Version::parse(spec).is_ok() => {
+            return installable(spec).then(|| spec.to_string());
+        }
+        Some(spec) => VersionReq::parse(spec).ok()?,
+        None => VersionReq::STAR,
+    };
+    versions
+        .keys()
+        .filter(|v| installable(v))
+        .filter_map(|v| Version::parse(v).ok())
+        .filter(|v| req.matches(v))
+        .max()
+        .map(|v| v.to_string())
+}
+
+/// Render a `/v1/search` response as a table of name, version and
+/// description.
+pub fn format_search_results(page: &Value) -> String {
+    let results = page["results"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    if results.is_empty() {
+        return "No packages found.".to_string();
+    }
+    let rows: Vec<Vec<String>> = results
+        .iter()
+        .map(|r| {
+            let text = |key: &str| r[key].as_str().unwrap_or_default().to_string();
+            let mut description = text("description");
+            if description.chars().count() > 60 {
+                description = description.chars().take(59).collect::<String>() + "…";
+            }
+            if r["deprecated"].as_bool() == Some(true) {
+                description = format!("[deprecated] {description}");
+            }
+            vec![text("name"), text("version"), description]
+        })
+        .collect();
+    let mut out = format_table(&["NAME", "VERSION", "DESCRIPTION"], &rows);
+    let total = page["total"].as_u64().unwrap_or(rows.len() as u64);
+    if total > rows.len() as u64 {
+        out += &format!("({} of {total} results)\n", rows.len());
+    }
+    out
+}
+
+/// Render a `/v1/-/audit` response as a table, most recent first.
+pub fn format_audit_log(entries: &Value) -> String {
+    let entries = entries.as_array().map(Vec::as_slice).unwrap_or_default();
+    if entries.is_empty() {
+        return "No audit log entries.".to_string();
+    }
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|e| {
+            let text = |key: &str| e[key].as_str().unwrap_or_default().to_string();
+            let mut target = text("package");
+            if let Some(version) = e["version"].as_str() {
+                target = format!("{target}@{version}");
+            }
+            if let Some(tag) = e["tag"].as_str() {
+                target = format!("{target} ({tag})");
+            }
+            vec![
+                short_time(&text("timestamp")),
+                text("action"),
+                target,
+                text("actor"),
+                text("client_ip"),
+                text("request_id"),
+            ]
+        })
+        .collect();
+    format_table(
+        &[
+            "TIME",
+            "ACTION",
+            "PACKAGE",
+            "ACTOR",
+            "CLIENT IP",
+            "REQUEST ID",
+        ],
+        &rows,
+    )
+}
+
+/// Render the registry's webhooks (`GET /v1/-/hooks`) as a table.
+pub fn format_webhooks(hooks: &Value) -> String {
+    let hooks = hooks.as_array().map(Vec::as_slice).unwrap_or_default();
+    if hooks.is_empty() {
+        return "No webhooks.".to_string();
+    }
+    let rows: Vec<Vec<String>> = hooks
+        .iter()
+        .map(|h| {
+            let text = |key: &str| h[key].as_str().unwrap_or_default().to_string();
+            let events: Vec<&str> = h["events"]
+                .as_array()
+                .map(|e| e.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+            vec![
+                text("id"),
+                text("package"),
+                text("url"),
+                if events.is_empty() {
+                    "all".to_string()
+                } else {
+                    events.join(",")
+                },
+                text("created_by"),
+            ]
+        })
+        .collect();
+    format_table(&["ID", "PACKAGE", "URL", "EVENTS", "CREATED BY"], &rows)
+}
+
+/// Render a webhook's delivery attempts (`GET /v1/-/hooks/:id/deliveries`)
+/// as a table.
+pub fn format_deliveries(deliveries: &Value) -> String {
+    let deliveries = deliveries.as_array().map(Vec::as_slice).unwrap_or_default();
+    if deliveries.is_empty() {
+        return "No deliveries.".to_string();
+    }
+    let rows: Vec<Vec<String>> = deliveries
+        .iter()
+        .map(|d| {
+            let text = |key: &str| d[key].as_str().unwrap_or_default().to_string();
+            let mut target = text("package");
+            if let Some(version) = d["version"].as_str() {
+                target = format!("{target}@{version}");
+            }
+            let result = match (d["status"].as_u64(), d["error"].as_str()) {
+                (_, Some(error)) => error.to_string(),
+                (Some(status), None) => status.to_string(),
+                (None, None) => String::new(),
+            };
+            vec![
+                short_time(&text("timestamp")),
+                text("id"),
+                text("event"),
+                target,
+                d["attempt"].to_string(),
+                result,
+            ]
+        })
+        .collect();
+    format_table(
+        &["TIME", "DELIVERY", "EVENT", "PACKAGE", "ATTEMPT", "RESULT"],
+        &rows,
+    )
+}
+
+/// RFC 3339 to the second, e.g. `2024-05-01 12:00:00`.
+fn short_time(timestamp: &str) -> String {
+    timestamp.get(..19).unwrap_or(timestamp).replace('T', " ")
+}
+
+/// Lay `rows` out in columns under `headers`.
+pub fn format_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let widths: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .map(|(col, header)| {
+            rows.iter()
+                .map(|row| row[col].chars().count())
+                .chain([header.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let mut out = String::new();
+    let header_row: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    for row in [&header_row].into_iter().chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{cell:width$}"))
+            .collect();
+        out += cells.join("  ").trim_end();
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> Value {
+        serde_json::json!({
+            "name": "foo",
+            "versions": {"1.0.0": {}, "1.2.0": {}, "2.0.0-rc.1": {}, "0.9.0": {}},
+            "dist-tags": {"latest": "1.0.0", "next": "2.0.0-rc.1"}
+        })
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec("foo"), ("foo", None));
+        assert_eq!(parse_spec("foo@next"), ("foo", Some("next")));
+        assert_eq!(parse_spec("foo@"), ("foo", None));
+        assert_eq!(parse_spec("@scope/foo"), ("@scope/foo", None));
+        assert_eq!(
+            parse_spec("@scope/foo@^1.0.0"),
+            ("@scope/foo", Some("^1.0.0"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_version() {
+        let meta = meta();
+        assert_eq!(resolve_version(&meta, None).as_deref(), Some("1.0.0"));
+        assert_eq!(
+            resolve_version(&meta, Some("next")).as_deref(),
+            Some("2.0.0-rc.1")
+        );
+        assert_eq!(
+            resolve_version(&meta, Some("^1.0.0")).as_deref(),
+            Some("1.2.0")
+        );
+        assert_eq!(
+            resolve_version(&meta, Some("0.9.0")).as_deref(),
+            Some("0.9.0")
+        );
+        assert_eq!(resolve_version(&meta, Some("3.0.0")), None);
+        assert_eq!(resolve_version(&meta, Some("beta")), None);
+
+        let untagged = serde_json::json!({"versions": {"1.0.0": {}, "1.1.0": {}}});
+        assert_eq!(resolve_version(&untagged, None).as_deref(), Some("1.1.0"));
+    }
+
+    #[test]
+    fn test_resolve_version_skips_yanked() {
+        let mut meta = meta();
+        meta["versions"]["1.2.0"]["yanked"] = true.into();
+        meta["versions"]["1.0.0"]["yanked"] = true.into();
+        // `latest` points at a yanked version, so fall back to the highest
+        // remaining release.
+        assert_eq!(resolve_version(&meta, None).as_deref(), Some("0.9.0"));
+        assert_eq!(resolve_version(&meta, Some("^1.0.0")).as_deref(), None);
+        assert_eq!(resolve_version(&meta, Some("1.2.0")), None);
+        assert!(is_yanked(&meta, "1.2.0"));
+        assert!(!is_yanked(&meta, "0.9.0"));
+    }
+
+    #[test]
+    fn test_deprecation() {
+        let mut meta = meta();
+        assert_eq!(deprecation(&meta, "1.0.0"), None);
+        meta["versions"]["1.0.0"]["deprecated"] = true.into();
+        assert_eq!(
+            deprecation(&meta, "1.0.0"),
+            Some("This version is deprecated")
+        );
+        meta["versions"]["1.0.0"]["deprecation_message"] = "Use 2.x".into();
+        assert_eq!(deprecation(&meta, "1.0.0"), Some("Use 2.x"));
+    }
+
+    #[test]
+    fn test_format_search_results() {
+        let page = serde_json::json!({
+            "total": 3,
+            "results": [
+                {"name": "react", "version": "18.2.0", "description": "UI library"},
+                {"name": "react-old", "version": "1.0.0", "description": "", "deprecated": true}
+            ]
+        });
+        assert_eq!(
+            format_search_results(&page),
+            "NAME       VERSION  DESCRIPTION\n\
+             react      18.2.0   UI library\n\
+             react-old  1.0.0    [deprecated]\n\
+             (2 of 3 results)\n"
+        );
+        let empty = serde_json::json!({"total": 0, "results": []});
+        assert_eq!(format_search_results(&empty), "No packages found.");
+    }
+
+    #[test]
+    fn test_format_audit_log() {
+        let entries = serde_json::json!([
+            {"timestamp": "2024-05-01T12:00:00.123Z", "actor": "ci", "action": "dist-tag-add",
+             "package": "foo", "version": "1.0.0", "tag": "next", "request_id": "r2"},
+            {"timestamp": "2024-05-01T11:59:00Z", "actor": "anonymous", "action": "publish",
+             "package": "foo", "version": "1.0.0", "client_ip": "10.0.0.1", "request_id": "r1"}
+        ]);
+        assert_eq!(
+            format_audit_log(&entries),
+            "TIME                 ACTION        PACKAGE           ACTOR      CLIENT IP  REQUEST ID\n\
+             2024-05-01 12:00:00  dist-tag-add  foo@1.0.0 (next)  ci                    r2\n\
+             2024-05-01 11:59:00  publish       foo@1.0.0         anonymous  10.0.0.1   r1\n"
+        );
+        assert_eq!(
+            format_audit_log(&serde_json::json!([])),
+            "No audit log entries."
+        );
+    }
+
+    #[test]
+    fn test_format_webhooks() {
+        let hooks = serde_json::json!([
+            {"id": "a1b2c3", "package": "@acme/*", "url": "https://ci.test/hook",
+             "events": ["publish", "yank"], "created_by": "ci"},
+            {"id": "d4e5f6", "package": "foo", "url": "http://localhost:9000/",
+             "created_by": "anonymous"}
+        ]);
+        assert_eq!(
+            format_webhooks(&hooks),
+            "ID      PACKAGE  URL                     EVENTS        CREATED BY\n\
+             a1b2c3  @acme/*  https://ci.test/hook    publish,yank  ci\n\
+             d4e5f6  foo      http://localhost:9000/  all           anonymous\n"
+        );
+        assert_eq!(format_webhooks(&serde_json::json!([])), "No webhooks.");
+    }
+
+    #[test]
+    fn test_format_deliveries() {
+        let deliveries = serde_json::json!([
+            {"id": "d1", "hook_id": "a1b2c3", "event": "publish", "package": "foo",
+             "version": "1.0.0", "attempt": 2, "timestamp": "2024-05-01T12:00:02Z",
+             "status": 204, "duration_ms": 12},
+            {"id": "d1", "hook_id": "a1b2c3", "event": "publish", "package": "foo",
+             "version": "1.0.0", "attempt": 1, "timestamp": "2024-05-01T12:00:00Z",
+             "status": 500, "error": "Receiver returned 500", "duration_ms": 30}
+        ]);
+        assert_eq!(
+            format_deliveries(&deliveries),
+            "TIME                 DELIVERY  EVENT    PACKAGE    ATTEMPT  RESULT\n\
+             2024-05-01 12:00:02  d1        publish  foo@1.0.0  2        204\n\
+             2024-05-01 12:00:00  d1        publish  foo@1.0.0  1        Receiver returned 500\n"
+        );
+    }
+
+    #[test]
+    fn test_fetch_cached_sends_conditional_requests() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut conditional = vec![];
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut lines = BufReader::new(&stream).lines();
+                let mut if_none_match = None;
+                for line in lines.by_ref() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_lowercase().strip_prefix("if-none-match: ") {
+                        if_none_match = Some(value.to_string());
+                    }
+                }
+                let resp = if if_none_match.as_deref() == Some("\"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nContent-Length: 0\r\n\r\n"
+                        .to_string()
+                } else {
+                    let body = r#"{"name":"foo"}"#;
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\n\r\n{body}",
+                        body.len()
+                    )
+                };
+                stream.write_all(resp.as_bytes()).unwrap();
+                conditional.push(if_none_match.is_some());
+            }
+            conditional
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("metadata/foo.json");
+        let client = reqwest::blocking::Client::new();
+        let url = format!("http://{addr}/v1/foo/");
+        for _ in 0..2 {
+            let meta = fetch_cached(&client, &url, &cache_path).unwrap().unwrap();
+            assert_eq!(meta["name"], "foo");
+        }
+        assert_eq!(server.join().unwrap(), [false, true]);
+    }
+}
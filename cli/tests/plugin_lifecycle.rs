@@ -57,11 +57,13 @@ fn setup_test_plugin(success: bool) {
     };
     println!("[DEBUG] Copying plugin from: {}", src);
     fs::create_dir_all(&plugins_dir).unwrap();
-    fs::copy(src, plugins_dir.join(dest_name)).expect(&format!(
-        "Failed to copy plugin from {} to {}",
-        src,
-        plugins_dir.join(dest_name).display()
-    ));
+    fs::copy(src, plugins_dir.join(dest_name)).unwrap_or_else(|_| {
+        panic!(
+            "Failed to copy plugin from {} to {}",
+            src,
+            plugins_dir.join(dest_name).display()
+        )
+    });
     // Print plugins after copy for debug
     if let Ok(entries) = fs::read_dir(&plugins_dir) {
         println!("[DEBUG] Plugins after setup:");
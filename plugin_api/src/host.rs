@@ -0,0 +1,202 @@
+//! Calling back into boltpm from a native plugin's `run`: logging into the
+//! CLI's output under the plugin's name, reading the plugin's config and the
+//! project's `package.json`, and reporting progress.
+//!
+//! ```
+//! use plugin_api::{host, PluginContext, PluginResult};
+//!
+//! fn scan(ctx: PluginContext) -> PluginResult {
+//!     let allowed = host::config("allowed").unwrap_or_default();
+//!     let deps = host::project()
+//!         .and_then(|pj| pj["dependencies"].as_object().cloned())
+//!         .unwrap_or_default();
+//!     for (i, name) in deps.keys().enumerate() {
+//!         host::progress(i as u64 + 1, deps.len() as u64, &format!("checking {name}"));
+//!     }
+//!     host::info(&format!("{} is allowed to use {allowed}", ctx.package_name));
+//!     Ok(())
+//! }
+//! # scan(serde_json::from_str(r#"{"hook":"preinstall","package_name":"app",
+//! #     "package_version":"1.0.0","install_path":".","env":{}}"#).unwrap()).unwrap();
+//! ```
+//!
+//! The host's callbacks are only reachable from the thread running `run`,
+//! while it runs. Elsewhere, such as in a plugin's own unit tests, logging
+//! goes to stderr, progress is dropped, and `config` and `project` return
+//! `None`.
+
+use std::cell::Cell;
+use std::ffi::c_void;
+
+/// The callbacks the host passes to [`crate::RunFn`], valid for the duration
+/// of the call. Strings are UTF-8 pointer and length pairs. Strings the host
+/// returns stay valid until `run` returns.
+#[repr(C)]
+#[derive(Debug)]
+pub struct HostVtable {
+    /// `size_of::<HostVtable>()` as the host built it, so callbacks can be
+    /// appended without a new ABI version. Plugins don't call callbacks that
+    /// end past it, as if there were no host.
+    pub size: usize,
+    /// Passed back as the first argument of every callback.
+    pub host: *mut c_void,
+    /// Log `message` at `level`, a [`Level`].
+    pub log: unsafe extern "C" fn(host: *mut c_void, level: u32, message: *const u8, len: usize),
+    /// Point `out`/`out_len` at the JSON value of the plugin's config
+    /// setting `key`, returning false if it isn't set.
+    pub config: unsafe extern "C" fn(
+        host: *mut c_void,
+        key: *const u8,
+        key_len: usize,
+        out: *mut *const u8,
+        out_len: *mut usize,
+    ) -> bool,
+    /// Report that `done` of `total` steps are done (`total` is 0 when
+    /// unknown), with what is being done.
+    pub progress: unsafe extern "C" fn(
+        host: *mut c_void,
+        done: u64,
+        total: u64,
+        message: *const u8,
+        len: usize,
+    ),
+    /// Point `out`/`out_len` at the project's `package.json`, returning false
+    /// if it has none.
+    pub project:
+        unsafe extern "C" fn(host: *mut c_void, out: *mut *const u8, out_len: *mut usize) -> bool,
+}
+
+/// How important a log message is, numbered as in the `log` crate.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    /// The level numbered `n`, if any.
+    pub fn from_u32(n: u32) -> Option<Self> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .into_iter()
+        .find(|level| *level as u32 == n)
+    }
+}
+
+thread_local! {
+    static HOST: Cell<*const HostVtable> = const { Cell::new(std::ptr::null()) };
+}
+
+/// Make `vtable` what this module calls while `f` runs.
+///
+/// # Safety
+/// `vtable` must be null or valid until `f` returns.
+pub(crate) unsafe fn with<R>(vtable: *const HostVtable, f: impl FnOnce() -> R) -> R {
+    struct Reset(*const HostVtable);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            HOST.with(|host| host.set(self.0));
+        }
+    }
+    let _reset = Reset(HOST.with(|host| host.replace(vtable)));
+    f()
+}
+
+/// The current host's `host` pointer and its `$field` callback, if there is
+/// a host and its vtable is recent enough to have that callback. Only the
+/// fields within the host's `size` are read.
+macro_rules! callback {
+    ($field:ident) => {{
+        let vtable = HOST.with(Cell::get);
+        let end = std::mem::offset_of!(HostVtable, $field) + std::mem::size_of::<*const ()>();
+        // SAFETY: `with` only installs vtables valid until it returns, and
+        // resets them after. `size` is always there; other fields only
+        // within it.
+        unsafe {
+            (!vtable.is_null() && (*vtable).size >= end).then(|| ((*vtable).host, (*vtable).$field))
+        }
+    }};
+}
+
+/// Read a string the host returned.
+///
+/// # Safety
+/// `ptr` must be valid for reads of `len` bytes.
+unsafe fn returned(ptr: *const u8, len: usize) -> Option<serde_json::Value> {
+    serde_json::from_slice(std::slice::from_raw_parts(ptr, len)).ok()
+}
+
+/// Whether this is running inside boltpm's `run` call, so calls reach it.
+pub fn is_available() -> bool {
+    !HOST.with(Cell::get).is_null()
+}
+
+/// Log `message` in boltpm's output, attributed to this plugin.
+pub fn log(level: Level, message: &str) {
+    match callback!(log) {
+        Some((host, log)) => unsafe { log(host, level as u32, message.as_ptr(), message.len()) },
+        None => eprintln!("[{level:?}] {message}"),
+    }
+}
+
+pub fn error(message: &str) {
+    log(Level::Error, message)
+}
+
+pub fn warn(message: &str) {
+    log(Level::Warn, message)
+}
+
+pub fn info(message: &str) {
+    log(Level::Info, message)
+}
+
+pub fn debug(message: &str) {
+    log(Level::Debug, message)
+}
+
+/// The plugin's config setting `key`, as `boltpm.toml` or the manifest's
+/// default sets it.
+pub fn config(key: &str) -> Option<serde_json::Value> {
+    let (host, config) = callback!(config)?;
+    let mut out = std::ptr::null();
+    let mut out_len = 0;
+    unsafe {
+        if config(host, key.as_ptr(), key.len(), &mut out, &mut out_len) {
+            returned(out, out_len)
+        } else {
+            None
+        }
+    }
+}
+
+/// Report that `done` of `total` steps are done; pass a `total` of 0 when it
+/// isn't known.
+pub fn progress(done: u64, total: u64, message: &str) {
+    if let Some((host, progress)) = callback!(progress) {
+        unsafe { progress(host, done, total, message.as_ptr(), message.len()) }
+    }
+}
+
+/// The project's `package.json`.
+pub fn project() -> Option<serde_json::Value> {
+    let (host, project) = callback!(project)?;
+    let mut out = std::ptr::null();
+    let mut out_len = 0;
+    unsafe {
+        if project(host, &mut out, &mut out_len) {
+            returned(out, out_len)
+        } else {
+            None
+        }
+    }
+}
@@ -1,13 +1,441 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+pub mod host;
+
+pub use host::HostVtable;
+
+/// The version of this crate. Plugin manifests declare a requirement on it
+/// as `api_version`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A point in the package manager's lifecycle at which plugins run. Each
+/// hook fires once per event, with the [`Payload`] it names.
+///
+/// `boltpm install` and `boltpm update` plan the whole dependency graph
+/// before fetching anything:
+///
+/// 1. `preinstall` ([`Payload::Install`]), once.
+/// 2. `preresolve` ([`Payload::Resolve`]) for each range resolved against
+///    the registry. Packages pinned in `bolt.lock` aren't resolved again.
+/// 3. `postresolve` ([`Payload::Graph`]), once, with the planned graph.
+/// 4. For each newly resolved package, `prefetch` ([`Payload::Fetch`])
+///    before it is downloaded and `postextract` ([`Payload::Package`]) once
+///    it is extracted.
+/// 5. `postinstall` ([`Payload::Install`]), once.
+///
+/// `onError` ([`Payload::Error`]) fires for each package that fails to
+/// resolve, download or extract. Other commands fire `prerun` and `postrun`
+/// around a script ([`Payload::Script`]), `prelink` ([`Payload::Link`]),
+/// `prepublish` ([`Payload::Publish`]) and, for each package removed,
+/// `preuninstall` ([`Payload::Package`]).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hook {
+    #[serde(rename = "preinstall")]
+    PreInstall,
+    #[serde(rename = "postinstall")]
+    PostInstall,
+    #[serde(rename = "onError")]
+    OnError,
+    #[serde(rename = "preresolve")]
+    PreResolve,
+    #[serde(rename = "postresolve")]
+    PostResolve,
+    #[serde(rename = "prefetch")]
+    PreFetch,
+    #[serde(rename = "postextract")]
+    PostExtract,
+    #[serde(rename = "prelink")]
+    PreLink,
+    #[serde(rename = "prerun")]
+    PreRun,
+    #[serde(rename = "postrun")]
+    PostRun,
+    #[serde(rename = "prepublish")]
+    PrePublish,
+    #[serde(rename = "preuninstall")]
+    PreUninstall,
+}
+
+impl Hook {
+    pub const ALL: [Hook; 12] = [
+        Hook::PreInstall,
+        Hook::PostInstall,
+        Hook::OnError,
+        Hook::PreResolve,
+        Hook::PostResolve,
+        Hook::PreFetch,
+        Hook::PostExtract,
+        Hook::PreLink,
+        Hook::PreRun,
+        Hook::PostRun,
+        Hook::PrePublish,
+        Hook::PreUninstall,
+    ];
+
+    /// The name plugins see in [`PluginContext::hook`].
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Hook::PreInstall => "preinstall",
+            Hook::PostInstall => "postinstall",
+            Hook::OnError => "onError",
+            Hook::PreResolve => "preresolve",
+            Hook::PostResolve => "postresolve",
+            Hook::PreFetch => "prefetch",
+            Hook::PostExtract => "postextract",
+            Hook::PreLink => "prelink",
+            Hook::PreRun => "prerun",
+            Hook::PostRun => "postrun",
+            Hook::PrePublish => "prepublish",
+            Hook::PreUninstall => "preuninstall",
+        }
+    }
+
+    /// This hook's bit in the mask returned by a plugin's
+    /// `boltpm_plugin_hooks` export. Bits are never reused.
+    pub const fn bit(self) -> u32 {
+        match self {
+            Hook::PreInstall => 1 << 0,
+            Hook::PostInstall => 1 << 1,
+            Hook::OnError => 1 << 2,
+            Hook::PreResolve => 1 << 3,
+            Hook::PostResolve => 1 << 4,
+            Hook::PreFetch => 1 << 5,
+            Hook::PostExtract => 1 << 6,
+            Hook::PreLink => 1 << 7,
+            Hook::PreRun => 1 << 8,
+            Hook::PostRun => 1 << 9,
+            Hook::PrePublish => 1 << 10,
+            Hook::PreUninstall => 1 << 11,
+        }
+    }
+
+    /// The mask subscribing to `hooks`.
+    pub const fn mask(hooks: &[Hook]) -> u32 {
+        let mut mask = 0;
+        let mut i = 0;
+        while i < hooks.len() {
+            mask |= hooks[i].bit();
+            i += 1;
+        }
+        mask
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Hook::ALL
+            .into_iter()
+            .find(|hook| hook.as_str() == s)
+            .ok_or_else(|| format!("Unknown plugin hook '{s}'"))
+    }
+}
+
+/// Name of the optional export listing the hooks a plugin subscribes to, as
+/// `extern "C" fn() -> u32` returning a [`Hook::mask`]. Plugins without it
+/// run on every hook. Use [`export_hooks!`] to define it.
+pub const HOOKS_SYMBOL: &str = "boltpm_plugin_hooks";
+
+/// Subscribe a plugin to the given hooks only:
+///
+/// ```
+/// plugin_api::export_hooks!(plugin_api::Hook::PostInstall);
+/// assert_eq!(boltpm_plugin_hooks(), plugin_api::Hook::PostInstall.bit());
+/// ```
+#[macro_export]
+macro_rules! export_hooks {
+    ($($hook:expr),+ $(,)?) => {
+        #[no_mangle]
+        pub extern "C" fn boltpm_plugin_hooks() -> u32 {
+            const MASK: u32 = $crate::Hook::mask(&[$($hook),+]);
+            MASK
+        }
+    };
+}
+
+/// Version of the native plugin ABI: the symbols below, their signatures,
+/// the [`HostVtable`], and the encoding of the context and outcome. Bumped
+/// on any incompatible change; the host refuses plugins built for another
+/// version.
+pub const ABI_VERSION: u32 = 3;
+
+/// Name of the required `extern "C" fn() -> u32` export returning the
+/// [`ABI_VERSION`] a plugin was built for. The host checks it before calling
+/// anything else.
+pub const ABI_VERSION_SYMBOL: &str = "boltpm_plugin_abi_version";
+
+/// Name of the plugin entry point, a [`RunFn`].
+pub const RUN_SYMBOL: &str = "run";
+
+/// The plugin entry point. It gets a [`PluginContext`] as UTF-8 JSON and
+/// the host's callbacks, both valid for the duration of the call, and
+/// returns one of the `EXIT_*` codes. It may set `*out_ptr` and `*out_len`
+/// to an [`Outcome`] as UTF-8 JSON, which the host hands back to
+/// [`FREE_SYMBOL`] once it has read it.
+pub type RunFn = unsafe extern "C" fn(
+    ctx_ptr: *const u8,
+    ctx_len: usize,
+    host: *const HostVtable,
+    out_ptr: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32;
+
+/// Name of the required export freeing the outcome `run` returned, a
+/// [`FreeFn`].
+pub const FREE_SYMBOL: &str = "boltpm_plugin_free";
+
+pub type FreeFn = unsafe extern "C" fn(ptr: *mut u8, len: usize);
+
+pub const EXIT_OK: i32 = 0;
+/// The plugin returned an error.
+pub const EXIT_FAILED: i32 = 1;
+/// The context couldn't be decoded.
+pub const EXIT_BAD_CONTEXT: i32 = 2;
+/// The plugin panicked.
+pub const EXIT_PANICKED: i32 = 3;
+
+/// Define a plugin's exports around a `fn(PluginContext) -> PluginResult`,
+/// or a `fn(PluginContext) -> PluginResult<Outcome>` to tell the host more:
+///
+/// ```
+/// use plugin_api::{Outcome, PluginContext, PluginResult};
+///
+/// fn check(ctx: PluginContext) -> PluginResult<Outcome> {
+///     if ctx.package_name.is_empty() {
+///         return Err("no package".to_string());
+///     }
+///     if ctx.package_name.starts_with("gpl-") {
+///         return Ok(Outcome::veto("GPL packages are not allowed"));
+///     }
+///     Ok(Outcome::default())
+/// }
+///
+/// plugin_api::declare_plugin!(check);
+/// assert_eq!(boltpm_plugin_abi_version(), plugin_api::ABI_VERSION);
+/// ```
+///
+/// While the handler runs, [`host`] reaches the host. Errors are logged
+/// through it. Panics are caught rather than unwinding into the host.
+#[macro_export]
+macro_rules! declare_plugin {
+    ($handler:path) => {
+        #[no_mangle]
+        pub extern "C" fn boltpm_plugin_abi_version() -> u32 {
+            $crate::ABI_VERSION
+        }
+
+        /// # Safety
+        /// `ctx_ptr` must be valid for reads of `ctx_len` bytes, `host`
+        /// valid or null, and `out_ptr` and `out_len` valid for writes or
+        /// null.
+        #[no_mangle]
+        pub unsafe extern "C" fn run(
+            ctx_ptr: *const u8,
+            ctx_len: usize,
+            host: *const $crate::HostVtable,
+            out_ptr: *mut *mut u8,
+            out_len: *mut usize,
+        ) -> i32 {
+            $crate::__run(ctx_ptr, ctx_len, host, out_ptr, out_len, $handler)
+        }
+
+        /// # Safety
+        /// `ptr` and `len` must be an outcome `run` returned, freed once.
+        #[no_mangle]
+        pub unsafe extern "C" fn boltpm_plugin_free(ptr: *mut u8, len: usize) {
+            $crate::__free(ptr, len)
+        }
+    };
+}
+
+/// The body of [`declare_plugin!`]'s `run`.
+///
+/// # Safety
+/// `ctx_ptr` must be valid for reads of `ctx_len` bytes, `host` valid or
+/// null, and `out_ptr` and `out_len` valid for writes or null.
+#[doc(hidden)]
+pub unsafe fn __run<T: Into<Outcome>>(
+    ctx_ptr: *const u8,
+    ctx_len: usize,
+    host: *const HostVtable,
+    out_ptr: *mut *mut u8,
+    out_len: *mut usize,
+    handler: fn(PluginContext) -> PluginResult<T>,
+) -> i32 {
+    host::with(host, || {
+        run_handler(ctx_ptr, ctx_len, out_ptr, out_len, handler)
+    })
+}
+
+/// # Safety
+/// As for [`__run`].
+unsafe fn run_handler<T: Into<Outcome>>(
+    ctx_ptr: *const u8,
+    ctx_len: usize,
+    out_ptr: *mut *mut u8,
+    out_len: *mut usize,
+    handler: fn(PluginContext) -> PluginResult<T>,
+) -> i32 {
+    let bytes = std::slice::from_raw_parts(ctx_ptr, ctx_len);
+    let ctx: PluginContext = match serde_json::from_slice(bytes) {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            host::error(&format!("Plugin received an invalid context: {e}"));
+            return EXIT_BAD_CONTEXT;
+        }
+    };
+    match std::panic::catch_unwind(|| handler(ctx)) {
+        Ok(Ok(outcome)) => {
+            let outcome = outcome.into();
+            if outcome != Outcome::default() && !out_ptr.is_null() && !out_len.is_null() {
+                let json = serde_json::to_vec(&outcome)
+                    .expect("outcomes serialize")
+                    .into_boxed_slice();
+                *out_len = json.len();
+                *out_ptr = Box::into_raw(json).cast();
+            }
+            EXIT_OK
+        }
+        Ok(Err(e)) => {
+            host::error(&e);
+            EXIT_FAILED
+        }
+        Err(_) => EXIT_PANICKED,
+    }
+}
+
+/// The body of [`declare_plugin!`]'s `boltpm_plugin_free`.
+///
+/// # Safety
+/// `ptr` and `len` must be an outcome [`__run`] returned, freed once.
+#[doc(hidden)]
+pub unsafe fn __free(ptr: *mut u8, len: usize) {
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)));
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PluginContext {
-    pub hook: String,
+    pub hook: Hook,
+    /// The package the hook is about, or else the project's own package.
+    /// The version is empty for a package not resolved yet.
     pub package_name: String,
     pub package_version: String,
+    /// The project directory.
     pub install_path: String,
     pub env: HashMap<String, String>,
+    /// The plugin's settings: defaults from its manifest, overridden by the
+    /// project's `boltpm.toml`.
+    #[serde(default)]
+    pub config: BTreeMap<String, serde_json::Value>,
+    /// What the hook is about; see [`Hook`] for which payload each gets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Payload>,
+}
+
+/// The details of the event a hook fires for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Payload {
+    /// The packages an install or update was asked for, by name, with the
+    /// ranges requested.
+    Install { requested: BTreeMap<String, String> },
+    /// A range about to be resolved against the registry. A plugin can
+    /// rewrite it with [`Outcome::range`].
+    Resolve { name: String, range: String },
+    /// The dependency graph an install will leave, by package name.
+    Graph {
+        packages: BTreeMap<String, PlannedPackage>,
+    },
+    /// A package about to be downloaded. A plugin can substitute the URL
+    /// with [`Outcome::tarball`].
+    Fetch {
+        name: String,
+        version: String,
+        url: String,
+    },
+    /// A package extracted into, or about to be removed from, `path`.
+    Package {
+        name: String,
+        version: String,
+        path: String,
+    },
+    /// A `package.json` script about to run, or that has run.
+    Script { name: String, command: String },
+    /// A local package about to be linked.
+    Link { path: String },
+    /// The project's package about to be published, packed into a tarball
+    /// of `size` bytes.
+    Publish {
+        name: String,
+        version: String,
+        tag: Option<String>,
+        size: usize,
+    },
+    /// Something that failed, and the package it failed for if any.
+    Error {
+        message: String,
+        package: Option<String>,
+    },
+}
+
+/// A package in a planned dependency graph.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannedPackage {
+    pub version: String,
+    /// Where it is downloaded from.
+    pub tarball: String,
+    /// Its dependencies, by name, with the ranges it requests.
+    pub dependencies: BTreeMap<String, String>,
+    /// Whether the version is pinned by `bolt.lock`, rather than newly
+    /// resolved.
+    pub pinned: bool,
+}
+
+/// What a plugin tells the host, beyond success or failure.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Outcome {
+    /// Shown to the user.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<String>,
+    /// Shown to the user as warnings.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    /// Stop the operation, for this reason.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub veto: Option<String>,
+    /// On `preresolve`, resolve this range instead of the one requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+    /// On `prefetch`, download the package from this URL instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tarball: Option<String>,
+}
+
+impl Outcome {
+    /// Stop the operation, for `reason`.
+    pub fn veto(reason: impl Into<String>) -> Self {
+        Outcome {
+            veto: Some(reason.into()),
+            ..Outcome::default()
+        }
+    }
+}
+
+impl From<()> for Outcome {
+    fn from(_: ()) -> Self {
+        Outcome::default()
+    }
 }
 
-pub type PluginResult = Result<(), String>;
+pub type PluginResult<T = ()> = Result<T, String>;
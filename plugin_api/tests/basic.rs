@@ -1,16 +1,265 @@
 #[test]
 fn test_plugin_context_fields() {
-    use plugin_api::PluginContext;
+    use plugin_api::{Hook, PluginContext};
     use std::collections::HashMap;
     let ctx = PluginContext {
-        hook: "testhook".to_string(),
+        hook: Hook::PreInstall,
         package_name: "testpkg".to_string(),
         package_version: "0.1.0".to_string(),
         install_path: "/tmp/testpkg".to_string(),
         env: HashMap::new(),
+        config: Default::default(),
+        payload: None,
     };
-    assert_eq!(ctx.hook, "testhook");
+    assert_eq!(ctx.hook, Hook::PreInstall);
     assert_eq!(ctx.package_name, "testpkg");
     assert_eq!(ctx.package_version, "0.1.0");
     assert_eq!(ctx.install_path, "/tmp/testpkg");
 }
+
+#[test]
+fn test_hook_names() {
+    use plugin_api::{Hook, PluginContext};
+    use std::collections::HashMap;
+    for hook in Hook::ALL {
+        assert_eq!(hook.as_str().parse::<Hook>(), Ok(hook));
+    }
+    assert!("postInstall".parse::<Hook>().is_err());
+    // Plugins read the hook as a plain string.
+    let ctx = PluginContext {
+        hook: Hook::OnError,
+        package_name: String::new(),
+        package_version: String::new(),
+        install_path: String::new(),
+        env: HashMap::new(),
+        config: Default::default(),
+        payload: None,
+    };
+    let json = serde_json::to_value(&ctx).unwrap();
+    assert_eq!(json["hook"], "onError");
+}
+
+#[test]
+fn test_hook_mask() {
+    use plugin_api::Hook;
+    let mask = Hook::mask(&[Hook::PreInstall, Hook::OnError]);
+    assert_ne!(mask & Hook::PreInstall.bit(), 0);
+    assert_eq!(mask & Hook::PostInstall.bit(), 0);
+    assert_ne!(mask & Hook::OnError.bit(), 0);
+}
+
+fn fail_on_empty(ctx: plugin_api::PluginContext) -> plugin_api::PluginResult<plugin_api::Outcome> {
+    match ctx.package_name.as_str() {
+        "" => Err("no package".to_string()),
+        "panic" => panic!("plugin bug"),
+        "gpl-thing" => Ok(plugin_api::Outcome::veto("GPL")),
+        _ => Ok(().into()),
+    }
+}
+
+plugin_api::declare_plugin!(fail_on_empty);
+
+#[test]
+fn test_declare_plugin() {
+    use plugin_api::{Hook, Outcome, PluginContext};
+    assert_eq!(boltpm_plugin_abi_version(), plugin_api::ABI_VERSION);
+    let call_with_outcome = |json: &str| unsafe {
+        let mut out_ptr = std::ptr::null_mut();
+        let mut out_len = 0;
+        let code = run(
+            json.as_ptr(),
+            json.len(),
+            std::ptr::null(),
+            &mut out_ptr,
+            &mut out_len,
+        );
+        if out_ptr.is_null() {
+            return (code, None);
+        }
+        let outcome: Outcome =
+            serde_json::from_slice(std::slice::from_raw_parts(out_ptr, out_len)).unwrap();
+        boltpm_plugin_free(out_ptr, out_len);
+        (code, Some(outcome))
+    };
+    let call = |json: &str| call_with_outcome(json).0;
+    let ctx = |name: &str| {
+        serde_json::to_string(&PluginContext {
+            hook: Hook::PreInstall,
+            package_name: name.to_string(),
+            package_version: String::new(),
+            install_path: String::new(),
+            env: Default::default(),
+            config: Default::default(),
+            payload: None,
+        })
+        .unwrap()
+    };
+    assert_eq!(call(&ctx("left-pad")), plugin_api::EXIT_OK);
+    assert_eq!(call(&ctx("")), plugin_api::EXIT_FAILED);
+    assert_eq!(call(&ctx("panic")), plugin_api::EXIT_PANICKED);
+    assert_eq!(call("{\"hook\": 1}"), plugin_api::EXIT_BAD_CONTEXT);
+    // Only outcomes with something in them are returned.
+    assert_eq!(call_with_outcome(&ctx("left-pad")).1, None);
+    assert_eq!(
+        call_with_outcome(&ctx("gpl-thing")),
+        (plugin_api::EXIT_OK, Some(Outcome::veto("GPL")))
+    );
+}
+
+#[test]
+fn test_payloads() {
+    use plugin_api::{Hook, Payload};
+    let bits: std::collections::HashSet<_> = Hook::ALL.iter().map(|hook| hook.bit()).collect();
+    assert_eq!(bits.len(), Hook::ALL.len());
+    // Plugins see the payload keyed by its kind.
+    let payload = Payload::Fetch {
+        name: "left-pad".to_string(),
+        version: "1.3.0".to_string(),
+        url: "http://localhost:4000/v1/left-pad/1.3.0/".to_string(),
+    };
+    let json = serde_json::to_value(&payload).unwrap();
+    assert_eq!(json["fetch"]["version"], "1.3.0");
+    let error: Payload =
+        serde_json::from_str(r#"{"error": {"message": "offline", "package": null}}"#).unwrap();
+    assert_eq!(
+        error,
+        Payload::Error {
+            message: "offline".to_string(),
+            package: None
+        }
+    );
+}
+
+#[test]
+fn test_host_callbacks() {
+    use plugin_api::{host, HostVtable, PluginContext, PluginResult};
+    use std::cell::RefCell;
+    use std::ffi::c_void;
+
+    /// What the fake host was told, and the strings it returned.
+    #[derive(Default)]
+    struct Calls {
+        log: Vec<String>,
+        returned: Vec<Vec<u8>>,
+    }
+    unsafe fn calls<'a>(host: *mut c_void) -> &'a RefCell<Calls> {
+        &*(host as *const RefCell<Calls>)
+    }
+    unsafe fn text(ptr: *const u8, len: usize) -> String {
+        String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len)).to_string()
+    }
+    unsafe fn give(host: *mut c_void, json: &str, out: *mut *const u8, out_len: *mut usize) {
+        let mut calls = calls(host).borrow_mut();
+        calls.returned.push(json.as_bytes().to_vec());
+        let json = calls.returned.last().unwrap();
+        *out = json.as_ptr();
+        *out_len = json.len();
+    }
+    unsafe extern "C" fn log(host: *mut c_void, level: u32, message: *const u8, len: usize) {
+        let line = format!("{:?} {}", host::Level::from_u32(level), text(message, len));
+        calls(host).borrow_mut().log.push(line);
+    }
+    unsafe extern "C" fn config(
+        host: *mut c_void,
+        key: *const u8,
+        key_len: usize,
+        out: *mut *const u8,
+        out_len: *mut usize,
+    ) -> bool {
+        if text(key, key_len) != "allowed" {
+            return false;
+        }
+        give(host, r#"["MIT"]"#, out, out_len);
+        true
+    }
+    unsafe extern "C" fn progress(
+        host: *mut c_void,
+        done: u64,
+        total: u64,
+        message: *const u8,
+        len: usize,
+    ) {
+        let line = format!("{done}/{total} {}", text(message, len));
+        calls(host).borrow_mut().log.push(line);
+    }
+    unsafe extern "C" fn project(
+        host: *mut c_void,
+        out: *mut *const u8,
+        out_len: *mut usize,
+    ) -> bool {
+        give(host, r#"{"name": "app"}"#, out, out_len);
+        true
+    }
+
+    fn scan(_ctx: PluginContext) -> PluginResult {
+        assert!(host::is_available());
+        let project = host::project().unwrap();
+        host::progress(1, 2, "checking left-pad");
+        host::info(&format!(
+            "{} allows {}",
+            project["name"],
+            host::config("allowed").unwrap()
+        ));
+        assert_eq!(host::config("missing"), None);
+        Err("left-pad is GPL".to_string())
+    }
+
+    let state = RefCell::new(Calls::default());
+    let vtable = HostVtable {
+        size: std::mem::size_of::<HostVtable>(),
+        host: &state as *const _ as *mut c_void,
+        log,
+        config,
+        progress,
+        project,
+    };
+    let ctx = r#"{"hook": "preinstall", "package_name": "app", "package_version": "1.0.0",
+                  "install_path": ".", "env": {}}"#;
+    let (mut out, mut out_len) = (std::ptr::null_mut(), 0);
+    let code = unsafe {
+        plugin_api::__run(
+            ctx.as_ptr(),
+            ctx.len(),
+            &vtable,
+            &mut out,
+            &mut out_len,
+            scan,
+        )
+    };
+    assert_eq!(code, plugin_api::EXIT_FAILED);
+    // Errors are logged through the host too.
+    assert_eq!(
+        state.borrow().log,
+        [
+            "1/2 checking left-pad",
+            "Some(Info) \"app\" allows [\"MIT\"]",
+            "Some(Error) left-pad is GPL",
+        ]
+    );
+    // Outside `run`, nothing reaches the host.
+    assert!(!host::is_available());
+    assert_eq!(host::project(), None);
+
+    // A host whose vtable ends before `project` isn't asked for it.
+    fn older_host(_ctx: PluginContext) -> PluginResult {
+        assert_eq!(host::project(), None);
+        host::info("no project");
+        Ok(())
+    }
+    let older = HostVtable {
+        size: std::mem::offset_of!(HostVtable, project),
+        ..vtable
+    };
+    let code = unsafe {
+        plugin_api::__run(
+            ctx.as_ptr(),
+            ctx.len(),
+            &older,
+            &mut out,
+            &mut out_len,
+            older_host,
+        )
+    };
+    assert_eq!(code, plugin_api::EXIT_OK);
+    assert_eq!(state.borrow().log.last().unwrap(), "Some(Info) no project");
+}
@@ -1,4 +1,4 @@
-use plugin_api::PluginContext;
+use plugin_api::{Hook, PluginContext};
 use std::collections::HashMap;
 
 #[test]
@@ -7,59 +7,82 @@ fn test_sample_plugin_builds() {
 }
 
 #[test]
-#[ignore]
 fn test_sample_plugin_run() {
     use libloading::{Library, Symbol};
-    use serde_json;
-    use std::env;
+    use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
     use std::path::PathBuf;
     let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     let workspace_root = manifest_dir
         .join("../../")
         .canonicalize()
         .expect("Failed to canonicalize workspace root");
+    let file = format!("{DLL_PREFIX}sample_plugin{DLL_SUFFIX}");
     let candidates = [
-        workspace_root.join("target/debug/libsample_plugin.dylib"),
-        workspace_root.join("target/debug/deps/libsample_plugin.dylib"),
-        workspace_root.join("test_project/.boltpm/plugins/libsample_plugin.dylib"),
+        workspace_root.join("target/debug").join(&file),
+        workspace_root.join("target/debug/deps").join(&file),
     ];
-    println!("Checking for libsample_plugin.dylib at the following locations:");
-    for path in &candidates {
-        println!("  {}", path.display());
-    }
-    let lib_path = candidates.iter().find(|p| p.exists());
-    let lib = match lib_path {
+    let lib = match candidates.iter().find(|p| p.exists()) {
         Some(path) => unsafe { Library::new(path).unwrap() },
-        None => {
-            let cwd = env::current_dir().unwrap();
-            panic!("Could not find libsample_plugin.dylib in any known location. Current working directory: {}. Tried: {:?}", cwd.display(), candidates);
-        }
+        None => panic!("Could not find {file}. Tried: {candidates:?}"),
     };
-    let func: Symbol<unsafe extern "C" fn(*const u8, usize) -> i32> =
-        unsafe { lib.get(b"run").unwrap() };
+    let run: Symbol<plugin_api::RunFn> =
+        unsafe { lib.get(plugin_api::RUN_SYMBOL.as_bytes()).unwrap() };
+    let free: Symbol<plugin_api::FreeFn> =
+        unsafe { lib.get(plugin_api::FREE_SYMBOL.as_bytes()).unwrap() };
+    let hooks: Symbol<extern "C" fn() -> u32> =
+        unsafe { lib.get(plugin_api::HOOKS_SYMBOL.as_bytes()).unwrap() };
+    assert_eq!(hooks(), Hook::PostInstall.bit());
+    let abi_version: Symbol<extern "C" fn() -> u32> =
+        unsafe { lib.get(plugin_api::ABI_VERSION_SYMBOL.as_bytes()).unwrap() };
+    assert_eq!(abi_version(), plugin_api::ABI_VERSION);
+    let install_path = std::env::temp_dir().join(format!("sample_plugin-{}", std::process::id()));
     let ctx = PluginContext {
-        hook: "testhook".to_string(),
+        hook: Hook::PostInstall,
         package_name: "sample".to_string(),
         package_version: "1.2.3".to_string(),
-        install_path: "/tmp/sample".to_string(),
+        install_path: install_path.to_string_lossy().to_string(),
         env: HashMap::new(),
+        config: Default::default(),
+        payload: None,
     };
     let ctx_json = serde_json::to_string(&ctx).unwrap();
-    let ctx_bytes = ctx_json.as_bytes();
-    let result = unsafe { func(ctx_bytes.as_ptr(), ctx_bytes.len()) };
-    assert_eq!(result, 0);
+    let mut out_ptr = std::ptr::null_mut();
+    let mut out_len = 0;
+    let code = unsafe {
+        run(
+            ctx_json.as_ptr(),
+            ctx_json.len(),
+            std::ptr::null(),
+            &mut out_ptr,
+            &mut out_len,
+        )
+    };
+    let outcome = (!out_ptr.is_null()).then(|| unsafe {
+        let outcome: plugin_api::Outcome =
+            serde_json::from_slice(std::slice::from_raw_parts(out_ptr, out_len)).unwrap();
+        free(out_ptr, out_len);
+        outcome
+    });
+    let written = std::fs::read_to_string(install_path.join(".boltpm/plugins_output/PLUGIN_TEST"));
+    let _ = std::fs::remove_dir_all(&install_path);
+    assert_eq!(code, plugin_api::EXIT_OK);
+    // A plain `Ok(())` has nothing to tell the host.
+    assert_eq!(outcome, None);
+    assert_eq!(written.unwrap(), "plugin ran");
 }
 
 #[test]
 fn test_plugin_context_fields() {
     let ctx = PluginContext {
-        hook: "testhook".to_string(),
+        hook: Hook::PostInstall,
         package_name: "sample".to_string(),
         package_version: "1.2.3".to_string(),
         install_path: "/tmp/sample".to_string(),
         env: HashMap::new(),
+        config: Default::default(),
+        payload: None,
     };
-    assert_eq!(ctx.hook, "testhook");
+    assert_eq!(ctx.hook, Hook::PostInstall);
     assert_eq!(ctx.package_name, "sample");
     assert_eq!(ctx.package_version, "1.2.3");
     assert_eq!(ctx.install_path, "/tmp/sample");
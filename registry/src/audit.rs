@@ -0,0 +1,327 @@
+//! Append-only log of every change made to the registry: who did what, to
+//! which package, when and from where.
+
+use crate::error::ApiError;
+use crate::AppState;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Query, Request, State},
+    http::{request::Parts, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Entries returned by the audit endpoint when no limit is given.
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditAction {
+    Publish,
+    Unpublish,
+    Yank,
+    Unyank,
+    Deprecate,
+    Undeprecate,
+    DistTagAdd,
+    DistTagRemove,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub actor: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    pub action: AuditAction,
+    pub package: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// The dist-tag, for dist-tag changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    pub request_id: String,
+}
+
+/// Who is making a request, set by [`crate::auth::require_token`] once the
+/// token is checked.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub actor: String,
+    pub token_id: Option<String>,
+    pub admin: bool,
+}
+
+impl Identity {
+    pub fn anonymous() -> Self {
+        Identity {
+            actor: "anonymous".to_string(),
+            token_id: None,
+            admin: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Tag every request with an id, taken from the client's `X-Request-Id` when
+/// it sends a usable one, and echo it in the response.
+pub(crate) async fn request_id(mut req: Request, next: Next) -> Response {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            format!(
+                "{:x}-{:x}",
+                Utc::now().timestamp_micros(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            )
+        });
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let mut resp = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    resp
+}
+
+/// The who and where of a request that changes the registry, for recording
+/// with [`AppState::audit`].
+pub struct AuditContext {
+    identity: Identity,
+    client_ip: Option<String>,
+    request_id: String,
+}
+
+/// The address a request came from. Behind a trusted reverse proxy the peer
+/// is the proxy, so this is the nearest address in `X-Forwarded-For` that
+/// isn't another trusted proxy; anyone else's `X-Forwarded-For` is ignored,
+/// since clients can send whatever they like.
+fn client_ip(parts: &Parts, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let forwarded: Vec<&str> = parts
+        .headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    let mut client = peer;
+    for hop in forwarded.iter().rev() {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuditContext {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(AuditContext {
+            identity: parts
+                .extensions
+                .get::<Identity>()
+                .cloned()
+                .unwrap_or_else(Identity::anonymous),
+            client_ip: client_ip(parts, &state.config.trusted_proxies).map(|ip| ip.to_string()),
+            request_id: parts
+                .extensions
+                .get::<RequestId>()
+                .map(|RequestId(id)| id.clone())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl AuditContext {
+    /// Who made the request, if they presented a token.
+    pub fn actor(&self) -> Option<&str> {
+        self.identity
+            .token_id
+            .is_some()
+            .then_some(self.identity.actor.as_str())
+    }
+
+    /// Whether the request was made with an admin token.
+    pub fn is_admin(&self) -> bool {
+        self.identity.admin
+    }
+
+    pub fn entry(&self, action: AuditAction, package: &str, version: Option<&str>) -> AuditEntry {
+        AuditEntry {
+            timestamp: Utc::now(),
+            actor: self.identity.actor.clone(),
+            token_id: self.identity.token_id.clone(),
+            action,
+            package: package.to_string(),
+            version: version.map(str::to_string),
+            tag: None,
+            client_ip: self.client_ip.clone(),
+            request_id: self.request_id.clone(),
+        }
+    }
+}
+
+impl AppState {
+    /// Record a change that has already been made, and tell webhooks about
+    /// it. A failure to write the log is logged rather than failing a
+    /// request whose change is stored.
+    pub fn audit(&self, entry: AuditEntry) {
+        if let Err(e) = self.storage.append_audit(&entry) {
+            error!(
+                "Failed to record {:?} of {} in the audit log: {e}",
+                entry.action, entry.package
+            );
+        }
+        self.notify_webhooks(&entry);
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AuditQuery {
+    package: Option<String>,
+    limit: Option<usize>,
+}
+
+/// `GET /v1/-/audit?package=&limit=`: the most recent entries first. Reading
+/// the log needs an admin token.
+pub(crate) async fn get_audit_log(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    if !ctx.is_admin() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "Reading the audit log needs an admin token",
+        ));
+    }
+    let mut entries = state.storage.list_audit(query.package.as_deref())?;
+    entries.reverse();
+    entries.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+    Ok(Json(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthToken;
+    use crate::tests::{publish_request, send, test_registry_with};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+
+    #[tokio::test]
+    async fn test_changes_are_audited() {
+        let registry = test_registry_with(|config| {
+            let admin = AuthToken {
+                admin: true,
+                .."ops:adm1n".parse::<AuthToken>().unwrap()
+            };
+            config.auth.tokens = vec!["ci:s3cret".parse().unwrap(), admin];
+            config.trusted_proxies =
+                vec!["127.0.0.1".parse().unwrap(), "10.0.0.1".parse().unwrap()];
+        });
+        let from = |mut req: Request<Body>, peer: &str, token: &str| {
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::new(peer.parse().unwrap(), 40000)));
+            req.headers_mut()
+                .insert("authorization", format!("Bearer {token}").parse().unwrap());
+            req.headers_mut()
+                .insert("x-forwarded-for", "203.0.113.7, 10.0.0.1".parse().unwrap());
+            req
+        };
+        let with_token = |req| from(req, "127.0.0.1", "s3cret");
+        let as_admin = |req| from(req, "127.0.0.1", "adm1n");
+        let manifest = r#"{"name":"foo","version":"1.0.0"}"#;
+        let mut req = with_token(publish_request("foo", "1.0.0", manifest));
+        req.headers_mut()
+            .insert(REQUEST_ID_HEADER, "req-1".parse().unwrap());
+        assert_eq!(send(&registry.app, req).await.0, StatusCode::OK);
+        let req = Request::post("/v1/foo/1.0.0/yank")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&registry.app, with_token(req)).await.0, StatusCode::OK);
+        let req = Request::put("/v1/bar/").body(Body::empty()).unwrap();
+        // Rejected requests change nothing and aren't logged.
+        assert_ne!(send(&registry.app, with_token(req)).await.0, StatusCode::OK);
+
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let (status, _) = send(&registry.app, get("/v1/-/audit")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&registry.app, with_token(get("/v1/-/audit"))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = send(&registry.app, as_admin(get("/v1/-/audit?package=foo"))).await;
+        assert_eq!(status, StatusCode::OK);
+        let entries: Vec<AuditEntry> = serde_json::from_str(&body).unwrap();
+        let actions: Vec<_> = entries.iter().map(|e| e.action).collect();
+        assert_eq!(actions, [AuditAction::Yank, AuditAction::Publish]);
+        let publish = &entries[1];
+        assert_eq!(publish.actor, "ci");
+        assert_eq!(publish.version.as_deref(), Some("1.0.0"));
+        assert_eq!(publish.client_ip.as_deref(), Some("203.0.113.7"));
+        assert_eq!(publish.request_id, "req-1");
+        assert_eq!(publish.token_id.as_ref().map(String::len), Some(12));
+
+        let (_, body) = send(&registry.app, get("/v1/foo/")).await;
+        let meta: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(meta["versions"]["1.0.0"]["published_by"], "ci");
+
+        let (_, body) = send(&registry.app, as_admin(get("/v1/-/audit?package=bar"))).await;
+        assert_eq!(body, "[]");
+        let (_, body) = send(&registry.app, as_admin(get("/v1/-/audit?limit=1"))).await;
+        assert_eq!(
+            serde_json::from_str::<Vec<AuditEntry>>(&body)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // A client that isn't a trusted proxy can't choose its address.
+        let req = Request::post("/v1/foo/1.0.0/unyank")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(&registry.app, from(req, "198.51.100.9", "s3cret")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = send(&registry.app, as_admin(get("/v1/-/audit?limit=1"))).await;
+        let entries: Vec<AuditEntry> = serde_json::from_str(&body).unwrap();
+        assert_eq!(entries[0].client_ip.as_deref(), Some("198.51.100.9"));
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_needs_admin_token() {
+        let registry = test_registry_with(|_| {});
+        let req = Request::get("/v1/-/audit").body(Body::empty()).unwrap();
+        assert_eq!(send(&registry.app, req).await.0, StatusCode::FORBIDDEN);
+    }
+}
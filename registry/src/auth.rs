@@ -0,0 +1,102 @@
+use crate::audit::Identity;
+use crate::config::AuthToken;
+use crate::error::ApiError;
+use crate::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Paths under which reads need a token too.
+const PRIVATE_PATHS: [&str; 2] = ["/v1/-/audit", "/v1/-/hooks"];
+
+/// Require one of the configured bearer tokens for every request that can
+/// change the registry, and for reading the audit log and webhooks. Other
+/// reads stay anonymous, and with no tokens configured everything is open.
+///
+/// The token's [`Identity`] is attached to the request for the audit log.
+pub(crate) async fn require_token(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let tokens = &state.config.auth.tokens;
+    let path = req.uri().path();
+    let private = PRIVATE_PATHS.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    });
+    let read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) && !private;
+    if read || tokens.is_empty() {
+        req.extensions_mut().insert(Identity::anonymous());
+        return Ok(next.run(req).await);
+    }
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+    match token.as_deref().and_then(|token| find_token(tokens, token)) {
+        Some(token) => {
+            req.extensions_mut().insert(Identity {
+                actor: token.name.clone(),
+                token_id: Some(token.id()),
+                admin: token.admin,
+            });
+            Ok(next.run(req).await)
+        }
+        None if token.is_some() => Err(ApiError::new(StatusCode::FORBIDDEN, "Invalid token")),
+        None => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "This registry requires a token for this request",
+        )),
+    }
+}
+
+/// The configured token whose secret is `presented`. Secrets are compared
+/// by their SHA-256 digests in constant time, and every token is checked, so
+/// timing reveals neither a secret's length nor how much of it matched.
+fn find_token<'a>(tokens: &'a [AuthToken], presented: &str) -> Option<&'a AuthToken> {
+    let presented = Sha256::digest(presented);
+    let mut found = None;
+    for token in tokens {
+        if bool::from(Sha256::digest(&token.secret).ct_eq(&presented)) {
+            found = Some(token);
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{publish_request, send, test_registry_with};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+
+    #[tokio::test]
+    async fn test_writes_require_token() {
+        let registry =
+            test_registry_with(|config| config.auth.tokens = vec!["ci:s3cret".parse().unwrap()]);
+        let manifest = r#"{"name":"foo","version":"1.0.0"}"#;
+        let (status, _) = send(&registry.app, publish_request("foo", "1.0.0", manifest)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let mut req = publish_request("foo", "1.0.0", manifest);
+        req.headers_mut()
+            .insert("authorization", "Bearer wrong".parse().unwrap());
+        assert_eq!(send(&registry.app, req).await.0, StatusCode::FORBIDDEN);
+
+        let mut req = publish_request("foo", "1.0.0", manifest);
+        req.headers_mut()
+            .insert("authorization", "Bearer s3cret".parse().unwrap());
+        assert_eq!(send(&registry.app, req).await.0, StatusCode::OK);
+
+        let req = Request::get("/v1/foo/").body(Body::empty()).unwrap();
+        assert_eq!(send(&registry.app, req).await.0, StatusCode::OK);
+    }
+}
@@ -0,0 +1,776 @@
+use axum::http::HeaderValue;
+use clap::Parser;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    Fs,
+    Sqlite,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fs" | "filesystem" => Ok(StorageBackend::Fs),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            other => Err(format!(
+                "Unknown storage backend '{other}' (expected 'fs' or 'sqlite')"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Root directory for `fs`, database file for `sqlite`.
+    pub path: PathBuf,
+}
+
+impl StorageConfig {
+    fn default_path(backend: StorageBackend) -> PathBuf {
+        match backend {
+            StorageBackend::Fs => PathBuf::from("packages"),
+            StorageBackend::Sqlite => PathBuf::from("packages/registry.db"),
+        }
+    }
+}
+
+/// Which package names may be fetched from the uplink. Patterns are an exact
+/// name, `@scope/*` or `*`; a leading `!` denies instead of allows.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyRule {
+    pub allow: bool,
+    pub pattern: String,
+}
+
+impl FromStr for ProxyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (allow, pattern) = match s.strip_prefix('!') {
+            Some(rest) => (false, rest),
+            None => (true, s),
+        };
+        let valid = pattern == "*"
+            || match pattern.strip_suffix("/*") {
+                Some(scope) => scope.starts_with('@') && scope.len() > 1 && !scope.contains('/'),
+                None => !pattern.is_empty() && !pattern.contains('*'),
+            };
+        if !valid {
+            return Err(format!(
+                "Invalid uplink package rule '{s}' (expected a name, '@scope/*' or '*')"
+            ));
+        }
+        Ok(ProxyRule {
+            allow,
+            pattern: pattern.to_string(),
+        })
+    }
+}
+
+impl ProxyRule {
+    fn matches(&self, name: &str) -> bool {
+        if self.pattern == "*" {
+            return true;
+        }
+        match self.pattern.strip_suffix("/*") {
+            Some(scope) => name
+                .strip_prefix(scope)
+                .is_some_and(|rest| rest.starts_with('/')),
+            None => self.pattern == name,
+        }
+    }
+}
+
+/// Default time a package fetched from the uplink is served before refetching.
+pub const DEFAULT_UPLINK_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct UplinkConfig {
+    /// Base URL of an npm-compatible registry, e.g. `https://registry.npmjs.org`.
+    pub url: String,
+    pub ttl: Duration,
+    pub rules: Vec<ProxyRule>,
+}
+
+impl UplinkConfig {
+    /// Whether `name` may be fetched from the uplink. The first matching rule
+    /// wins and names matching no rule are not proxied.
+    pub fn proxies(&self, name: &str) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(name))
+            .is_some_and(|rule| rule.allow)
+    }
+}
+
+/// Default limit for an uploaded tarball, in bytes.
+pub const DEFAULT_MAX_UPLOAD_SIZE: usize = 50 * 1024 * 1024;
+
+pub const DEFAULT_BIND: &str = "0.0.0.0:4000";
+
+/// Config file read from the working directory when none is given.
+pub const DEFAULT_CONFIG_FILE: &str = "registry.toml";
+
+/// Default time after publishing during which a version may be unpublished
+/// without an admin token.
+pub const DEFAULT_UNPUBLISH_WINDOW: Duration = Duration::from_secs(72 * 60 * 60);
+
+/// A bearer token, written `name:secret`. The name is who the audit log says
+/// made changes with it; the secret, everything after the first `:`, is what
+/// clients send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthToken {
+    pub name: String,
+    pub secret: String,
+    /// Admin tokens may also do what the registry's policies otherwise
+    /// forbid, such as unpublishing old versions.
+    pub admin: bool,
+}
+
+impl FromStr for AuthToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // A bare secret containing `:` would read as a name and a shorter
+        // secret, so names are required rather than guessed at. The value
+        // isn't echoed, since it is a secret.
+        match s.split_once(':') {
+            Some((name, secret)) if !name.trim().is_empty() && !secret.is_empty() => {
+                Ok(AuthToken {
+                    name: name.to_string(),
+                    secret: secret.to_string(),
+                    admin: false,
+                })
+            }
+            _ => Err("Auth tokens must be written `name:secret`".to_string()),
+        }
+    }
+}
+
+impl AuthToken {
+    /// A fingerprint that identifies the token without revealing it.
+    pub fn id(&self) -> String {
+        format!("{:x}", Sha256::digest(&self.secret))[..12].to_string()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+    /// Bearer tokens accepted for writes, admin tokens included. Empty leaves
+    /// writes open.
+    pub tokens: Vec<AuthToken>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub storage: StorageConfig,
+    /// Origins allowed by CORS; `*` allows any.
+    pub cors_origins: Vec<String>,
+    pub max_upload_size: usize,
+    pub uplink: Option<UplinkConfig>,
+    pub auth: AuthConfig,
+    /// Reverse proxies whose `X-Forwarded-For` is believed. Requests from
+    /// anywhere else are recorded with their peer address.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// How long after publishing a version may be unpublished without an
+    /// admin token.
+    pub unpublish_window: Duration,
+    /// An `env_logger` filter such as `info` or `boltpm_registry=debug`.
+    pub log_level: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: DEFAULT_BIND.parse().unwrap(),
+            storage: StorageConfig {
+                backend: StorageBackend::Fs,
+                path: StorageConfig::default_path(StorageBackend::Fs),
+            },
+            cors_origins: vec!["*".to_string()],
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            uplink: None,
+            auth: AuthConfig::default(),
+            trusted_proxies: Vec::new(),
+            unpublish_window: DEFAULT_UNPUBLISH_WINDOW,
+            log_level: "info".to_string(),
+        }
+    }
+}
+
+/// Command-line flags. These override the environment, which overrides the
+/// config file.
+#[derive(Parser, Debug, Default)]
+#[command(name = "boltpm-registry", about = "BoltPM package registry")]
+pub struct Args {
+    /// Config file [default: registry.toml if present] [env: BOLTPM_REGISTRY_CONFIG]
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Validate the configuration, print it and exit
+    #[arg(long)]
+    pub config_check: bool,
+    /// Address to listen on [default: 0.0.0.0:4000]
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+    /// Storage backend: fs or sqlite
+    #[arg(long)]
+    pub storage: Option<StorageBackend>,
+    /// Storage directory (fs) or database file (sqlite)
+    #[arg(long)]
+    pub storage_path: Option<PathBuf>,
+    /// Allowed CORS origin; repeat for several, or `*` for any
+    #[arg(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+    /// Largest accepted tarball, in bytes
+    #[arg(long)]
+    pub max_upload_size: Option<usize>,
+    /// URL of an upstream registry to proxy packages from
+    #[arg(long)]
+    pub uplink: Option<String>,
+    /// Seconds to cache uplink metadata
+    #[arg(long)]
+    pub uplink_ttl: Option<u64>,
+    /// Rule for which packages may be proxied; repeat for several
+    #[arg(long = "uplink-package")]
+    pub uplink_packages: Vec<ProxyRule>,
+    /// Address of a reverse proxy whose X-Forwarded-For to believe; repeat
+    /// for several
+    #[arg(long = "trusted-proxy")]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Hours after publishing during which a version may be unpublished
+    /// without an admin token [default: 72]
+    #[arg(long)]
+    pub unpublish_window_hours: Option<u64>,
+    /// Log level, or `module=level` directives, e.g. `info,boltpm_registry=debug`
+    #[arg(long)]
+    pub log_level: Option<String>,
+}
+
+/// The contents of `registry.toml`.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    log_level: Option<String>,
+    max_upload_size: Option<usize>,
+    cors_origins: Option<Vec<String>>,
+    trusted_proxies: Option<Vec<String>>,
+    unpublish_window_hours: Option<u64>,
+    #[serde(default)]
+    storage: FileStorage,
+    #[serde(default)]
+    uplink: FileUplink,
+    #[serde(default)]
+    auth: FileAuth,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileStorage {
+    backend: Option<String>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileUplink {
+    url: Option<String>,
+    ttl: Option<u64>,
+    packages: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileAuth {
+    tokens: Option<Vec<String>>,
+    admin_tokens: Option<Vec<String>>,
+}
+
+/// One source of settings; later sources override earlier ones field by field.
+#[derive(Default, Debug)]
+struct Settings {
+    bind: Option<SocketAddr>,
+    backend: Option<StorageBackend>,
+    storage_path: Option<PathBuf>,
+    cors_origins: Option<Vec<String>>,
+    max_upload_size: Option<usize>,
+    uplink_url: Option<String>,
+    uplink_ttl: Option<u64>,
+    uplink_packages: Option<Vec<ProxyRule>>,
+    tokens: Option<Vec<String>>,
+    admin_tokens: Option<Vec<String>>,
+    trusted_proxies: Option<Vec<IpAddr>>,
+    unpublish_window_hours: Option<u64>,
+    log_level: Option<String>,
+}
+
+fn list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn rules(rules: &[String]) -> Result<Vec<ProxyRule>, String> {
+    rules.iter().map(|r| r.parse()).collect()
+}
+
+fn ips(ips: &[String]) -> Result<Vec<IpAddr>, String> {
+    ips.iter()
+        .map(|ip| {
+            ip.parse()
+                .map_err(|e| format!("Invalid trusted proxy '{ip}': {e}"))
+        })
+        .collect()
+}
+
+impl Settings {
+    fn merge(&mut self, other: Settings) {
+        macro_rules! take {
+            ($($field:ident),*) => {
+                $(if other.$field.is_some() {
+                    self.$field = other.$field;
+                })*
+            };
+        }
+        take!(
+            bind,
+            backend,
+            storage_path,
+            cors_origins,
+            max_upload_size,
+            uplink_url,
+            uplink_ttl,
+            uplink_packages,
+            tokens,
+            admin_tokens,
+            trusted_proxies,
+            unpublish_window_hours,
+            log_level
+        );
+    }
+
+    /// Read `registry.toml`, resolving relative paths against its directory.
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+        let file: FileConfig =
+            toml::from_str(&raw).map_err(|e| format!("Invalid {}: {e}", path.display()))?;
+        let err = |e: String| format!("Invalid {}: {e}", path.display());
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Ok(Settings {
+            bind: file
+                .bind
+                .map(|b| b.parse().map_err(|e| format!("bind '{b}': {e}")))
+                .transpose()
+                .map_err(err)?,
+            backend: file
+                .storage
+                .backend
+                .map(|b| b.parse())
+                .transpose()
+                .map_err(err)?,
+            storage_path: file.storage.path.map(|p| dir.join(p)),
+            cors_origins: file.cors_origins,
+            max_upload_size: file.max_upload_size,
+            uplink_url: file.uplink.url,
+            uplink_ttl: file.uplink.ttl,
+            uplink_packages: file
+                .uplink
+                .packages
+                .map(|p| rules(&p))
+                .transpose()
+                .map_err(err)?,
+            tokens: file.auth.tokens,
+            admin_tokens: file.auth.admin_tokens,
+            trusted_proxies: file
+                .trusted_proxies
+                .map(|p| ips(&p))
+                .transpose()
+                .map_err(err)?,
+            unpublish_window_hours: file.unpublish_window_hours,
+            log_level: file.log_level,
+        })
+    }
+
+    /// Read the `BOLTPM_REGISTRY_*` environment variables through `var`.
+    fn from_env(var: &dyn Fn(&str) -> Option<String>) -> Result<Self, String> {
+        fn parse<T: FromStr>(name: &str, value: Option<String>) -> Result<Option<T>, String>
+        where
+            T::Err: fmt::Display,
+        {
+            value
+                .map(|s| s.parse().map_err(|e| format!("Invalid {name} '{s}': {e}")))
+                .transpose()
+        }
+        let get = |name: &str| var(name).filter(|v| !v.is_empty());
+        Ok(Settings {
+            bind: parse("BOLTPM_REGISTRY_BIND", get("BOLTPM_REGISTRY_BIND"))?,
+            backend: parse("BOLTPM_REGISTRY_STORAGE", get("BOLTPM_REGISTRY_STORAGE"))?,
+            storage_path: get("BOLTPM_REGISTRY_STORAGE_PATH").map(PathBuf::from),
+            cors_origins: get("BOLTPM_REGISTRY_CORS_ORIGINS").map(|s| list(&s)),
+            max_upload_size: parse(
+                "BOLTPM_REGISTRY_MAX_UPLOAD_SIZE",
+                get("BOLTPM_REGISTRY_MAX_UPLOAD_SIZE"),
+            )?,
+            uplink_url: get("BOLTPM_REGISTRY_UPLINK"),
+            uplink_ttl: parse(
+                "BOLTPM_REGISTRY_UPLINK_TTL",
+                get("BOLTPM_REGISTRY_UPLINK_TTL"),
+            )?,
+            uplink_packages: get("BOLTPM_REGISTRY_UPLINK_PACKAGES")
+                .map(|s| rules(&list(&s)))
+                .transpose()?,
+            tokens: get("BOLTPM_REGISTRY_TOKENS").map(|s| list(&s)),
+            admin_tokens: get("BOLTPM_REGISTRY_ADMIN_TOKENS").map(|s| list(&s)),
+            trusted_proxies: get("BOLTPM_REGISTRY_TRUSTED_PROXIES")
+                .map(|s| ips(&list(&s)))
+                .transpose()?,
+            unpublish_window_hours: parse(
+                "BOLTPM_REGISTRY_UNPUBLISH_WINDOW_HOURS",
+                get("BOLTPM_REGISTRY_UNPUBLISH_WINDOW_HOURS"),
+            )?,
+            log_level: get("BOLTPM_REGISTRY_LOG"),
+        })
+    }
+
+    fn from_args(args: &Args) -> Self {
+        fn non_empty<T: Clone>(v: &[T]) -> Option<Vec<T>> {
+            (!v.is_empty()).then(|| v.to_vec())
+        }
+        Settings {
+            bind: args.bind,
+            backend: args.storage,
+            storage_path: args.storage_path.clone(),
+            cors_origins: non_empty(&args.cors_origins),
+            max_upload_size: args.max_upload_size,
+            uplink_url: args.uplink.clone(),
+            uplink_ttl: args.uplink_ttl,
+            uplink_packages: non_empty(&args.uplink_packages),
+            tokens: None,
+            admin_tokens: None,
+            trusted_proxies: non_empty(&args.trusted_proxies),
+            unpublish_window_hours: args.unpublish_window_hours,
+            log_level: args.log_level.clone(),
+        }
+    }
+
+    fn into_config(self) -> Result<Config, String> {
+        let defaults = Config::default();
+        let backend = self.backend.unwrap_or(defaults.storage.backend);
+        let cors_origins = self.cors_origins.unwrap_or(defaults.cors_origins);
+        for origin in &cors_origins {
+            let valid = origin == "*"
+                || ((origin.starts_with("http://") || origin.starts_with("https://"))
+                    && HeaderValue::from_str(origin).is_ok());
+            if !valid {
+                return Err(format!("Invalid CORS origin '{origin}'"));
+            }
+        }
+        let uplink = match self.uplink_url {
+            Some(url) => {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return Err(format!("Uplink URL '{url}' must be http:// or https://"));
+                }
+                Some(UplinkConfig {
+                    url: url.trim_end_matches('/').to_string(),
+                    ttl: self
+                        .uplink_ttl
+                        .map(Duration::from_secs)
+                        .unwrap_or(DEFAULT_UPLINK_TTL),
+                    rules: self
+                        .uplink_packages
+                        .unwrap_or_else(|| vec!["*".parse().unwrap()]),
+                })
+            }
+            None if self.uplink_ttl.is_some() || self.uplink_packages.is_some() => {
+                return Err("Uplink settings are given but no uplink URL".to_string());
+            }
+            None => None,
+        };
+        let log_level = self.log_level.unwrap_or(defaults.log_level);
+        // A level, or `module=level` directives separated by commas.
+        let valid_level = log_level.split(',').map(str::trim).all(|directive| {
+            let level = match directive.split_once('=') {
+                Some((module, level)) if !module.is_empty() => level,
+                Some(_) => return false,
+                None => directive,
+            };
+            log::LevelFilter::from_str(level).is_ok()
+        });
+        if !valid_level {
+            return Err(format!("Invalid log level '{log_level}'"));
+        }
+        let unpublish_window = match self.unpublish_window_hours {
+            Some(hours) => hours
+                .checked_mul(60 * 60)
+                .map(Duration::from_secs)
+                .ok_or_else(|| format!("Unpublish window of {hours} hours is too long"))?,
+            None => defaults.unpublish_window,
+        };
+        let mut tokens = Vec::new();
+        for (list, admin) in [(self.tokens, false), (self.admin_tokens, true)] {
+            for token in list.unwrap_or_default() {
+                tokens.push(AuthToken {
+                    admin,
+                    ..token.parse()?
+                });
+            }
+        }
+        Ok(Config {
+            bind: self.bind.unwrap_or(defaults.bind),
+            storage: StorageConfig {
+                backend,
+                path: self
+                    .storage_path
+                    .unwrap_or_else(|| StorageConfig::default_path(backend)),
+            },
+            cors_origins,
+            max_upload_size: self.max_upload_size.unwrap_or(defaults.max_upload_size),
+            uplink,
+            auth: AuthConfig { tokens },
+            trusted_proxies: self.trusted_proxies.unwrap_or_default(),
+            unpublish_window,
+            log_level,
+        })
+    }
+}
+
+impl Config {
+    /// Build the configuration from defaults, then the config file, then
+    /// `BOLTPM_REGISTRY_*` environment variables, then `args`.
+    ///
+    /// The config file is `--config`, `BOLTPM_REGISTRY_CONFIG`, or
+    /// `registry.toml` in the working directory if it exists.
+    pub fn load(args: &Args, var: &dyn Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut settings = Settings::default();
+        let path = args
+            .config
+            .clone()
+            .or_else(|| var("BOLTPM_REGISTRY_CONFIG").map(PathBuf::from));
+        match path {
+            Some(path) => settings.merge(Settings::from_file(&path)?),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                settings.merge(Settings::from_file(Path::new(DEFAULT_CONFIG_FILE))?)
+            }
+            None => {}
+        }
+        settings.merge(Settings::from_env(var)?);
+        settings.merge(Settings::from_args(args));
+        settings.into_config()
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "bind = {}", self.bind)?;
+        writeln!(
+            f,
+            "storage = {:?} at {}",
+            self.storage.backend,
+            self.storage.path.display()
+        )?;
+        writeln!(f, "cors_origins = {}", self.cors_origins.join(", "))?;
+        writeln!(f, "max_upload_size = {}", self.max_upload_size)?;
+        match &self.uplink {
+            Some(uplink) => {
+                let rules: Vec<String> = uplink
+                    .rules
+                    .iter()
+                    .map(|r| format!("{}{}", if r.allow { "" } else { "!" }, r.pattern))
+                    .collect();
+                writeln!(
+                    f,
+                    "uplink = {} (ttl {}s, packages {})",
+                    uplink.url,
+                    uplink.ttl.as_secs(),
+                    rules.join(", ")
+                )?;
+            }
+            None => writeln!(f, "uplink = none")?,
+        }
+        let admins = self.auth.tokens.iter().filter(|t| t.admin).count();
+        writeln!(
+            f,
+            "auth tokens = {} ({admins} admin)",
+            self.auth.tokens.len()
+        )?;
+        let proxies: Vec<String> = self.trusted_proxies.iter().map(|p| p.to_string()).collect();
+        writeln!(f, "trusted_proxies = {}", proxies.join(", "))?;
+        writeln!(
+            f,
+            "unpublish_window = {}h",
+            self.unpublish_window.as_secs() / 3600
+        )?;
+        write!(f, "log_level = {}", self.log_level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uplink_rules() {
+        let uplink = UplinkConfig {
+            url: "http://upstream".to_string(),
+            ttl: DEFAULT_UPLINK_TTL,
+            rules: ["!@acme/*", "!internal-tool", "*"]
+                .iter()
+                .map(|r| r.parse().unwrap())
+                .collect(),
+        };
+        assert!(uplink.proxies("left-pad"));
+        assert!(uplink.proxies("@other/pkg"));
+        assert!(uplink.proxies("@acme-corp/pkg"));
+        assert!(!uplink.proxies("@acme/widget"));
+        assert!(!uplink.proxies("internal-tool"));
+
+        let scoped_only = UplinkConfig {
+            rules: vec!["@types/*".parse().unwrap()],
+            ..uplink
+        };
+        assert!(scoped_only.proxies("@types/node"));
+        assert!(!scoped_only.proxies("left-pad"));
+
+        assert!("@acme*".parse::<ProxyRule>().is_err());
+        assert!("@/*".parse::<ProxyRule>().is_err());
+        assert!("!".parse::<ProxyRule>().is_err());
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: std::collections::HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_config_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("registry.toml");
+        std::fs::write(
+            &file,
+            r#"
+            bind = "127.0.0.1:5000"
+            max_upload_size = 1024
+            cors_origins = ["https://example.com"]
+
+            [storage]
+            backend = "sqlite"
+
+            [uplink]
+            url = "https://registry.npmjs.org/"
+            packages = ["!@acme/*", "*"]
+
+            [auth]
+            tokens = ["ci:secret"]
+            admin_tokens = ["ops:root"]
+            "#,
+        )
+        .unwrap();
+        let args = Args {
+            config: Some(file.clone()),
+            log_level: Some("debug".to_string()),
+            ..Default::default()
+        };
+        let vars = env(&[
+            ("BOLTPM_REGISTRY_BIND", "127.0.0.1:6000"),
+            (
+                "BOLTPM_REGISTRY_STORAGE_PATH",
+                "/var/lib/boltpm/registry.db",
+            ),
+            ("BOLTPM_REGISTRY_LOG", "warn"),
+            ("BOLTPM_REGISTRY_UNPUBLISH_WINDOW_HOURS", "24"),
+            ("BOLTPM_REGISTRY_TRUSTED_PROXIES", "10.0.0.1, ::1"),
+        ]);
+        let config = Config::load(&args, &vars).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:6000".parse().unwrap());
+        assert_eq!(config.storage.backend, StorageBackend::Sqlite);
+        assert_eq!(
+            config.storage.path,
+            PathBuf::from("/var/lib/boltpm/registry.db")
+        );
+        assert_eq!(config.max_upload_size, 1024);
+        assert_eq!(config.cors_origins, ["https://example.com"]);
+        assert_eq!(config.auth.tokens[0].name, "ci");
+        assert_eq!(config.auth.tokens[0].secret, "secret");
+        assert!(!config.auth.tokens[0].admin);
+        assert!(config.auth.tokens[1].admin);
+        assert_eq!(config.unpublish_window, Duration::from_secs(24 * 60 * 60));
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(
+            config.trusted_proxies,
+            [
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        let uplink = config.uplink.unwrap();
+        assert_eq!(uplink.url, "https://registry.npmjs.org");
+        assert!(!uplink.proxies("@acme/widget"));
+
+        // Relative storage paths in the file are relative to the file.
+        std::fs::write(&file, "[storage]\npath = \"data\"\n").unwrap();
+        let config = Config::load(&args, &env(&[])).unwrap();
+        assert_eq!(config.storage.path, dir.path().join("data"));
+        assert_eq!(config.bind, DEFAULT_BIND.parse().unwrap());
+        assert_eq!(config.unpublish_window, DEFAULT_UNPUBLISH_WINDOW);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("registry.toml");
+        let load = |contents: &str| {
+            std::fs::write(&file, contents).unwrap();
+            let args = Args {
+                config: Some(file.clone()),
+                ..Default::default()
+            };
+            Config::load(&args, &env(&[]))
+        };
+        assert!(load("").is_ok());
+        assert!(load("bnid = \"0.0.0.0:1\"").is_err(), "unknown key");
+        assert!(load("bind = \"localhost\"").is_err());
+        assert!(load("[storage]\nbackend = \"postgres\"").is_err());
+        assert!(load("cors_origins = [\"example.com\"]").is_err());
+        assert!(load("log_level = \"verbose\"").is_err());
+        assert!(load("log_level = \"info,boltpm_registry=trace\"").is_ok());
+        assert!(load("[uplink]\nttl = 60").is_err(), "uplink without url");
+        assert!(load("[uplink]\nurl = \"ftp://mirror\"").is_err());
+        assert!(load("[auth]\ntokens = [\" \"]").is_err());
+        assert!(load("[auth]\ntokens = [\"bare-secret\"]").is_err());
+        assert!(load("[auth]\ntokens = [\":secret\"]").is_err());
+        assert!(load("[auth]\ntokens = [\"ci:\"]").is_err());
+        let token: AuthToken = "ci:se:cret".parse().unwrap();
+        assert_eq!(
+            (token.name.as_str(), token.secret.as_str()),
+            ("ci", "se:cret")
+        );
+        assert!(load("trusted_proxies = [\"10.0.0.0/8\"]").is_err());
+        assert!(load(&format!("unpublish_window_hours = {}", u64::MAX / 60)).is_err());
+
+        let args = Args {
+            config: Some(dir.path().join("missing.toml")),
+            ..Default::default()
+        };
+        assert!(Config::load(&args, &env(&[])).is_err());
+        let vars = env(&[("BOLTPM_REGISTRY_MAX_UPLOAD_SIZE", "lots")]);
+        assert!(Config::load(&Args::default(), &vars).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Download counts per package, version and day.
+//!
+//! Downloads are counted in memory and written to storage in batches every
+//! [`FLUSH_INTERVAL`], so serving a tarball never waits on a write.
+
+use crate::error::ApiError;
+use crate::storage::StorageError;
+use crate::{validate, AppState};
+use axum::extract::{Json, Path, Query, State};
+use chrono::{Days, NaiveDate, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often counted downloads are written to storage.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The longest period a report may cover, about 18 months.
+const MAX_PERIOD_DAYS: i64 = 550;
+
+/// Day -> version -> downloads.
+pub type DailyDownloads = BTreeMap<NaiveDate, BTreeMap<String, u64>>;
+
+/// Downloads counted since the last flush, and the day weekly counts were
+/// last computed for.
+pub struct Downloads {
+    pending: Mutex<HashMap<String, DailyDownloads>>,
+    day: Mutex<NaiveDate>,
+}
+
+impl Default for Downloads {
+    fn default() -> Self {
+        Downloads {
+            pending: Mutex::default(),
+            day: Mutex::new(today()),
+        }
+    }
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+/// Downloads over the seven full days before `today`. Today's count is left
+/// out so the figure only changes once a day.
+pub fn weekly(downloads: &DailyDownloads, today: NaiveDate) -> u64 {
+    let start = today - Days::new(7);
+    downloads
+        .range(start..today)
+        .flat_map(|(_, versions)| versions.values())
+        .sum()
+}
+
+fn merge(into: &mut DailyDownloads, from: DailyDownloads) {
+    for (day, versions) in from {
+        let day = into.entry(day).or_default();
+        for (version, count) in versions {
+            *day.entry(version).or_default() += count;
+        }
+    }
+}
+
+impl Downloads {
+    pub fn record(&self, pkg: &str, version: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending
+            .entry(pkg.to_string())
+            .or_default()
+            .entry(today())
+            .or_default()
+            .entry(version.to_string())
+            .or_default() += 1;
+    }
+
+    /// Counts for `pkg` that haven't been flushed yet.
+    fn pending(&self, pkg: &str) -> DailyDownloads {
+        self.pending
+            .lock()
+            .unwrap()
+            .get(pkg)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Write pending downloads to storage, and when the day has changed bring
+/// the weekly counts in search results and metadata up to date.
+pub(crate) fn flush(state: &AppState) {
+    let pending = std::mem::take(&mut *state.downloads.pending.lock().unwrap());
+    for (pkg, counts) in pending {
+        if let Err(e) = state.storage.add_downloads(&pkg, &counts) {
+            error!("Failed to record downloads of {pkg}: {e}");
+            // Keep them for the next flush rather than losing them.
+            let mut pending = state.downloads.pending.lock().unwrap();
+            merge(pending.entry(pkg).or_default(), counts);
+        }
+    }
+    let today = today();
+    let mut day = state.downloads.day.lock().unwrap();
+    if *day != today {
+        *day = today;
+        drop(day);
+        if let Err(e) = refresh_weekly(state, today) {
+            error!("Failed to update weekly download counts: {e}");
+        }
+    }
+}
+
+fn refresh_weekly(state: &AppState, today: NaiveDate) -> Result<(), StorageError> {
+    let mut counts = Vec::new();
+    for pkg in state.storage.list_packages()? {
+        let downloads = state.storage.get_downloads(&pkg.name)?;
+        counts.push((pkg.name, weekly(&downloads, today)));
+    }
+    let mut search = state.search.write().unwrap();
+    for (name, weekly) in counts {
+        search.set_downloads(&name, weekly);
+    }
+    state.metadata_cache.clear();
+    Ok(())
+}
+
+pub(crate) async fn flush_periodically(state: AppState) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let state = state.clone();
+        // Flushing touches storage, which blocks.
+        let _ = tokio::task::spawn_blocking(move || flush(&state)).await;
+    }
+}
+
+/// An inclusive range of days: `last-day`, `last-week`, `last-month` or
+/// `last-year` (each ending yesterday), or `YYYY-MM-DD:YYYY-MM-DD`.
+fn parse_period(period: &str, today: NaiveDate) -> Result<(NaiveDate, NaiveDate), String> {
+    let yesterday = today - Days::new(1);
+    let days = match period {
+        "last-day" => 1,
+        "last-week" => 7,
+        "last-month" => 30,
+        "last-year" => 365,
+        _ => {
+            let invalid = || format!("Invalid period '{period}'");
+            let (start, end) = period.split_once(':').ok_or_else(invalid)?;
+            let start: NaiveDate = start.parse().map_err(|_| invalid())?;
+            let end: NaiveDate = end.parse().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            if (end - start).num_days() >= MAX_PERIOD_DAYS {
+                return Err(format!("Periods are limited to {MAX_PERIOD_DAYS} days"));
+            }
+            return Ok((start, end));
+        }
+    };
+    Ok((today - Days::new(days), yesterday))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DownloadsQuery {
+    period: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DayDownloads {
+    pub day: NaiveDate,
+    pub downloads: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DownloadsReport {
+    pub package: String,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub downloads: u64,
+    pub versions: BTreeMap<String, u64>,
+    /// Every day of the period, including those without downloads.
+    pub days: Vec<DayDownloads>,
+}
+
+/// `GET /v1/-/downloads/:pkg?period=last-week`
+pub(crate) async fn get_downloads(
+    State(state): State<AppState>,
+    Path(pkg): Path<String>,
+    Query(query): Query<DownloadsQuery>,
+) -> Result<Json<DownloadsReport>, ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    let period = query.period.as_deref().unwrap_or("last-week");
+    let (start, end) = parse_period(period, today()).map_err(ApiError::bad_request)?;
+    let mut downloads = state.storage.get_downloads(&pkg)?;
+    merge(&mut downloads, state.downloads.pending(&pkg));
+    let mut report = DownloadsReport {
+        package: pkg,
+        start,
+        end,
+        downloads: 0,
+        versions: BTreeMap::new(),
+        days: Vec::new(),
+    };
+    for day in start.iter_days().take_while(|day| *day <= end) {
+        let versions = downloads.get(&day);
+        let total = versions.map(|v| v.values().sum()).unwrap_or_default();
+        for (version, count) in versions.into_iter().flatten() {
+            *report.versions.entry(version.clone()).or_default() += count;
+        }
+        report.downloads += total;
+        report.days.push(DayDownloads {
+            day,
+            downloads: total,
+        });
+    }
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{publish_request, send, test_registry};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+
+    fn day(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_period() {
+        let today = day("2024-05-10");
+        assert_eq!(
+            parse_period("last-week", today),
+            Ok((day("2024-05-03"), day("2024-05-09")))
+        );
+        assert_eq!(
+            parse_period("last-day", today),
+            Ok((day("2024-05-09"), day("2024-05-09")))
+        );
+        assert_eq!(
+            parse_period("2024-01-01:2024-01-31", today),
+            Ok((day("2024-01-01"), day("2024-01-31")))
+        );
+        assert!(parse_period("2024-02-01:2024-01-01", today).is_err());
+        assert!(parse_period("fortnight", today).is_err());
+        assert!(parse_period("2000-01-01:2024-01-01", today).is_err());
+    }
+
+    #[test]
+    fn test_weekly_skips_today() {
+        let mut downloads = DailyDownloads::new();
+        for (d, count) in [("2024-05-02", 100), ("2024-05-03", 1), ("2024-05-09", 2)] {
+            downloads.insert(day(d), BTreeMap::from([("1.0.0".to_string(), count)]));
+        }
+        downloads.insert(
+            day("2024-05-10"),
+            BTreeMap::from([("2.0.0".to_string(), 5)]),
+        );
+        assert_eq!(weekly(&downloads, day("2024-05-10")), 3);
+    }
+
+    #[tokio::test]
+    async fn test_downloads_are_counted() {
+        let registry = test_registry();
+        let manifest = r#"{"name":"foo","version":"1.0.0"}"#;
+        let (status, _) = send(&registry.app, publish_request("foo", "1.0.0", manifest)).await;
+        assert_eq!(status, StatusCode::OK);
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        for uri in ["/v1/foo/1.0.0/", "/foo/-/foo-1.0.0.tgz", "/v1/foo/9.9.9/"] {
+            send(&registry.app, get(uri)).await;
+        }
+        let range = Request::get("/v1/foo/1.0.0/")
+            .header("range", "bytes=10-")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            send(&registry.app, range).await.0,
+            StatusCode::PARTIAL_CONTENT
+        );
+
+        let period = format!("/v1/-/downloads/foo?period={0}:{0}", today());
+        let (status, body) = send(&registry.app, get(&period)).await;
+        assert_eq!(status, StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(report["downloads"], 2, "pending downloads are included");
+
+        flush(&registry.state);
+        let stored = registry.state.storage.get_downloads("foo").unwrap();
+        assert_eq!(stored[&today()]["1.0.0"], 2);
+        let (_, body) = send(&registry.app, get(&period)).await;
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(report["versions"]["1.0.0"], 2);
+        assert_eq!(report["days"].as_array().unwrap().len(), 1);
+
+        let (status, _) = send(&registry.app, get("/v1/-/downloads/foo?period=soon")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_weekly_downloads_are_served() {
+        let registry = test_registry();
+        let manifest = r#"{"name":"foo","version":"1.0.0"}"#;
+        send(&registry.app, publish_request("foo", "1.0.0", manifest)).await;
+        let yesterday = today() - Days::new(1);
+        let counts = DailyDownloads::from([(yesterday, BTreeMap::from([("1.0.0".into(), 7)]))]);
+        registry
+            .state
+            .storage
+            .add_downloads("foo", &counts)
+            .unwrap();
+        refresh_weekly(&registry.state, today()).unwrap();
+
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let (_, body) = send(&registry.app, get("/v1/foo/")).await;
+        let meta: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(meta["weekly_downloads"], 7);
+        let (_, body) = send(&registry.app, get("/v1/search?q=foo")).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["results"][0]["downloads"], 7);
+    }
+}
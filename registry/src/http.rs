@@ -0,0 +1,350 @@
+//! Conditional requests, ranges and caching for metadata and tarball
+//! responses.
+
+use crate::error::ApiError;
+use crate::meta::PackageMeta;
+use crate::storage::TarballData;
+use crate::{uplink, AppState};
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+/// Published tarballs never change, so clients may cache them for a year.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Rendered metadata is revalidated on every use so new versions show up
+/// immediately; the ETag makes that cheap.
+const REVALIDATE: &str = "public, max-age=0, must-revalidate";
+
+/// Upper bound on cached packages before the cache is cleared.
+const MAX_CACHED_PACKAGES: usize = 10_000;
+
+/// A rendered metadata document with its validators.
+pub struct Rendered {
+    pub body: Bytes,
+    pub content_type: &'static str,
+    pub etag: String,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Rendered {
+    pub fn new(
+        body: Vec<u8>,
+        content_type: &'static str,
+        last_modified: Option<DateTime<Utc>>,
+    ) -> Self {
+        Rendered {
+            etag: format!("\"{:x}\"", Sha1::digest(&body)),
+            body: body.into(),
+            content_type,
+            last_modified,
+        }
+    }
+}
+
+/// Rendered metadata documents per package and variant (API, base URL and
+/// format), dropped whenever the package changes.
+#[derive(Default)]
+pub struct MetadataCache {
+    inner: RwLock<CacheInner>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    /// Bumped on every invalidation, so a render that raced with an update
+    /// isn't stored.
+    generation: u64,
+    packages: HashMap<String, HashMap<String, Arc<Rendered>>>,
+}
+
+impl MetadataCache {
+    fn generation(&self) -> u64 {
+        self.inner.read().unwrap().generation
+    }
+
+    fn get(&self, pkg: &str, variant: &str) -> Option<Arc<Rendered>> {
+        self.inner
+            .read()
+            .unwrap()
+            .packages
+            .get(pkg)?
+            .get(variant)
+            .cloned()
+    }
+
+    fn insert(&self, pkg: &str, variant: &str, generation: u64, rendered: Arc<Rendered>) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.generation != generation {
+            return;
+        }
+        if inner.packages.len() >= MAX_CACHED_PACKAGES && !inner.packages.contains_key(pkg) {
+            inner.packages.clear();
+        }
+        inner
+            .packages
+            .entry(pkg.to_string())
+            .or_default()
+            .insert(variant.to_string(), rendered);
+    }
+
+    /// Drop everything, for changes that affect every package.
+    pub fn clear(&self) {
+        let mut inner = self.inner.write().unwrap();
+        inner.generation += 1;
+        inner.packages.clear();
+    }
+
+    pub fn invalidate(&self, pkg: &str) {
+        let mut inner = self.inner.write().unwrap();
+        inner.generation += 1;
+        inner.packages.remove(pkg);
+    }
+}
+
+/// When the package last changed, for `Last-Modified`.
+fn last_modified(meta: &PackageMeta) -> Option<DateTime<Utc>> {
+    meta.modified
+        .or_else(|| meta.versions.values().filter_map(|v| v.published_at).max())
+}
+
+/// Serve a package's metadata rendered by `render`, reusing the cached
+/// rendering of `variant` when the package hasn't changed. Copies proxied
+/// from the uplink aren't cached here, since they expire by TTL instead.
+pub(crate) async fn metadata_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    pkg: &str,
+    variant: &str,
+    content_type: &'static str,
+    render: impl FnOnce(PackageMeta) -> Vec<u8>,
+) -> Result<Response, ApiError> {
+    let rendered = match state.metadata_cache.get(pkg, variant) {
+        Some(rendered) => rendered,
+        None => {
+            let generation = state.metadata_cache.generation();
+            let meta = uplink::get_package(state, pkg)
+                .await?
+                .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Package not found"))?;
+            let proxied = meta.uplink.is_some();
+            let modified = last_modified(&meta);
+            let rendered = Arc::new(Rendered::new(render(meta), content_type, modified));
+            if !proxied {
+                state
+                    .metadata_cache
+                    .insert(pkg, variant, generation, rendered.clone());
+            }
+            rendered
+        }
+    };
+    let mut builder = Response::builder()
+        .header(header::ETAG, &rendered.etag)
+        .header(header::CACHE_CONTROL, REVALIDATE);
+    if let Some(modified) = rendered.last_modified {
+        builder = builder.header(header::LAST_MODIFIED, http_date(modified));
+    }
+    if is_fresh(headers, &rendered.etag, rendered.last_modified) {
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+    Ok(builder
+        .header(header::CONTENT_TYPE, rendered.content_type)
+        .body(Body::from(rendered.body.clone()))
+        .unwrap())
+}
+
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether the client's cached copy is still current, per `If-None-Match`
+/// or, failing that, `If-Modified-Since`.
+fn is_fresh(headers: &HeaderMap, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    if let Some(if_none_match) = header(header::IF_NONE_MATCH) {
+        return etag_matches(if_none_match, etag);
+    }
+    match (header(header::IF_MODIFIED_SINCE), last_modified) {
+        (Some(since), Some(modified)) => DateTime::parse_from_rfc2822(since)
+            .is_ok_and(|since| modified.timestamp() <= since.timestamp()),
+        _ => false,
+    }
+}
+
+/// Weak comparison of `etag` against an `If-None-Match` or `If-Range` list.
+fn etag_matches(list: &str, etag: &str) -> bool {
+    let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    list.split(',')
+        .any(|tag| tag.trim() == "*" || strip(tag) == strip(etag))
+}
+
+/// A satisfiable byte range, inclusive of `end`.
+#[derive(Debug, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+/// Parse a single-range `Range` header against a body of `len` bytes.
+/// `Ok(None)` means serve the whole body (no header, or one we don't
+/// support such as multiple ranges); `Err` means unsatisfiable.
+fn parse_range(value: &str, len: u64) -> Result<Option<ByteRange>, ()> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=-N: the last N bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Err(());
+            }
+            ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len.wrapping_sub(1),
+            }
+        }
+        (Ok(start), Err(_)) if end.is_empty() => ByteRange {
+            start,
+            end: len.wrapping_sub(1),
+        },
+        (Ok(start), Ok(end)) if start <= end => ByteRange {
+            start,
+            end: end.min(len.wrapping_sub(1)),
+        },
+        _ => return Ok(None),
+    };
+    if len == 0 || range.start >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+/// Serve a tarball with immutable caching, conditional requests and byte
+/// ranges, streaming it when it is stored as a file.
+pub(crate) async fn tarball_response(
+    headers: &HeaderMap,
+    data: TarballData,
+) -> Result<Response, ApiError> {
+    let io_error = |e: std::io::Error| ApiError::from(crate::storage::StorageError::Io(e));
+    let (len, etag, last_modified) = match &data {
+        TarballData::File(file) => {
+            let metadata = file.metadata().map_err(io_error)?;
+            let modified = metadata.modified().ok();
+            let mtime = modified
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos())
+                .unwrap_or_default();
+            let etag = format!("\"{:x}-{mtime:x}\"", metadata.len());
+            (metadata.len(), etag, modified.map(DateTime::<Utc>::from))
+        }
+        TarballData::Bytes(bytes) => (
+            bytes.len() as u64,
+            format!("\"{:x}\"", Sha1::digest(bytes)),
+            None,
+        ),
+    };
+    let mut builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, IMMUTABLE)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(modified) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, http_date(modified));
+    }
+    if is_fresh(headers, &etag, last_modified) {
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    // A stale If-Range means the client's partial copy is of something else.
+    let if_range_ok = header(header::IF_RANGE).is_none_or(|tag| etag_matches(tag, &etag));
+    let range = match header(header::RANGE).filter(|_| if_range_ok) {
+        Some(value) => match parse_range(value, len) {
+            Ok(range) => range,
+            Err(()) => {
+                return Ok(builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        },
+        None => None,
+    };
+    builder = builder.header(header::CONTENT_TYPE, "application/octet-stream");
+    let (start, count) = match &range {
+        Some(range) => {
+            builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{len}", range.start, range.end),
+            );
+            (range.start, range.end - range.start + 1)
+        }
+        None => (0, len),
+    };
+    let builder = builder.header(header::CONTENT_LENGTH, count);
+    let body = match data {
+        TarballData::File(file) => {
+            let mut file = tokio::fs::File::from_std(file);
+            file.seek(SeekFrom::Start(start)).await.map_err(io_error)?;
+            Body::from_stream(ReaderStream::new(file.take(count)))
+        }
+        TarballData::Bytes(bytes) => {
+            let end = (start + count) as usize;
+            Body::from(Bytes::from(bytes).slice(start as usize..end))
+        }
+    };
+    Ok(builder.body(body).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        let range = |start, end| Ok(Some(ByteRange { start, end }));
+        assert_eq!(parse_range("bytes=0-99", 1000), range(0, 99));
+        assert_eq!(parse_range("bytes=900-", 1000), range(900, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), range(900, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), range(0, 999));
+        assert_eq!(parse_range("bytes=500-5000", 1000), range(500, 999));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=5-1", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("W/\"abc\", \"def\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abcd\"", "\"abc\""));
+    }
+}
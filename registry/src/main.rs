@@ -1,225 +1,694 @@
 use axum::{
     body::Body,
-    extract::{Json, Multipart, Path},
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    routing::{get, post, put},
+    extract::{DefaultBodyLimit, Json, Multipart, Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::Response,
+    routing::{delete, get, post, put},
     Router,
 };
+use base64::prelude::*;
+use chrono::Utc;
+use clap::Parser;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs;
-use std::net::SocketAddr;
-use std::path::Path as FsPath;
-use std::sync::{Arc, Mutex};
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
 use tokio::net::TcpListener;
-use tower_http::cors::CorsLayer;
-
-#[derive(Serialize, Deserialize, Clone, Default)]
-struct VersionMeta {
-    description: String,
-    yanked: bool,
-    deprecated: bool,
-    deprecation_message: Option<String>,
-}
+use tower_http::compression::predicate::{NotForContentType, Predicate};
+use tower_http::compression::{CompressionLayer, DefaultPredicate};
+use tower_http::cors::{Any, CorsLayer};
+
+mod audit;
+mod auth;
+mod config;
+mod downloads;
+mod error;
+mod http;
+mod meta;
+mod npm;
+mod search;
+mod storage;
+mod uplink;
+mod validate;
+mod webhooks;
+
+use crate::audit::{AuditAction, AuditContext, AuditEntry};
+use crate::config::{Args, Config};
+use crate::downloads::Downloads;
+use crate::error::ApiError;
+use crate::http::MetadataCache;
+use crate::meta::{Dist, PackageMeta, VersionMeta};
+use crate::search::{SearchIndex, SearchPage};
+use crate::storage::{Storage, StorageError, TarballData, UpdateFn};
+use crate::uplink::Uplink;
 
-#[derive(Serialize, Deserialize, Clone, Default)]
-struct PackageMeta {
-    name: String,
-    versions: HashMap<String, VersionMeta>, // version -> VersionMeta
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pub config: Arc<Config>,
+    pub storage: Arc<dyn Storage>,
+    pub uplink: Option<Arc<Uplink>>,
+    pub search: Arc<RwLock<SearchIndex>>,
+    pub metadata_cache: Arc<MetadataCache>,
+    pub downloads: Arc<Downloads>,
+    /// Delivers to internal webhooks; others get a client pinned to their
+    /// checked addresses.
+    pub webhook_client: reqwest::Client,
 }
 
-type Registry = Arc<Mutex<HashMap<String, PackageMeta>>>;
-
-fn migrate_meta(meta_raw: HashMap<String, PackageMeta>) -> HashMap<String, PackageMeta> {
-    use serde_json::Value;
-    let mut new_meta = HashMap::new();
-    for (pkg_name, pkg) in meta_raw {
-        let mut new_versions = HashMap::new();
-        // Deserialize versions as Value for migration
-        let versions_val: HashMap<String, Value> =
-            serde_json::from_value(serde_json::to_value(pkg.versions).unwrap()).unwrap();
-        for (ver, val) in versions_val {
-            if let Value::String(desc) = val {
-                new_versions.insert(
-                    ver,
-                    VersionMeta {
-                        description: desc,
-                        yanked: false,
-                        deprecated: false,
-                        deprecation_message: None,
-                    },
-                );
-            } else if let Ok(vm) = serde_json::from_value::<VersionMeta>(val) {
-                new_versions.insert(ver, vm);
-            }
+impl AppState {
+    pub fn new(config: Config, storage: Arc<dyn Storage>) -> Result<Self, StorageError> {
+        let uplink = config.uplink.clone().map(|c| Arc::new(Uplink::new(c)));
+        let packages = storage.list_packages()?;
+        let today = Utc::now().date_naive();
+        let weekly = packages
+            .iter()
+            .map(|pkg| {
+                let downloads = storage.get_downloads(&pkg.name)?;
+                Ok((pkg.name.clone(), downloads::weekly(&downloads, today)))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        let mut search = SearchIndex::build(packages);
+        for (name, weekly) in weekly {
+            search.set_downloads(&name, weekly);
         }
-        new_meta.insert(
-            pkg_name,
-            PackageMeta {
-                name: pkg.name,
-                versions: new_versions,
-            },
-        );
+        Ok(AppState {
+            config: Arc::new(config),
+            storage,
+            uplink,
+            search: Arc::new(RwLock::new(search)),
+            metadata_cache: Arc::default(),
+            downloads: Arc::default(),
+            webhook_client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("the webhook client builds"),
+        })
+    }
+
+    /// [`Storage::update_package`], stamping the modification time and
+    /// keeping the search index and metadata cache in step.
+    pub fn update_package(
+        &self,
+        name: &str,
+        f: &mut UpdateFn,
+    ) -> Result<Option<PackageMeta>, StorageError> {
+        let meta = self.storage.update_package(name, &mut |meta| {
+            f(meta)?;
+            if let Some(meta) = meta {
+                meta.modified = Some(Utc::now());
+            }
+            Ok(())
+        })?;
+        self.metadata_cache.invalidate(name);
+        self.search.write().unwrap().update(name, meta.as_ref());
+        Ok(meta)
     }
-    new_meta
 }
 
-#[tokio::main]
-async fn main() {
-    let _registry: Registry = Arc::new(Mutex::new(HashMap::new()));
-    let app = Router::new()
+fn app(state: AppState) -> Router {
+    // npm publishes tarballs base64-encoded inside JSON, so allow for 4/3 of
+    // the upload limit plus the surrounding manifest or multipart framing.
+    let body_limit = (state.config.max_upload_size / 3 * 4).saturating_add(64 * 1024);
+    Router::new()
         .route("/v1/:pkg/", put(publish_package).get(get_metadata))
-        .route("/v1/:pkg/:version/", get(get_tarball))
+        .route(
+            "/v1/:pkg/:version/",
+            get(get_tarball).delete(unpublish_version),
+        )
+        .route("/v1/:pkg/:version", delete(unpublish_version))
+        .route("/v1/:pkg/dist-tags", get(get_dist_tags))
+        .route(
+            "/v1/:pkg/dist-tags/:tag",
+            put(put_dist_tag).delete(delete_dist_tag),
+        )
         .route("/v1/:pkg/:version/yank", post(yank_version))
         .route("/v1/:pkg/:version/unyank", post(unyank_version))
         .route("/v1/:pkg/:version/deprecate", post(deprecate_version))
         .route("/v1/index", get(get_index))
         .route("/v1/search", get(search_packages))
-        .layer(CorsLayer::permissive());
-    println!("BoltPM Registry running on http://localhost:4000");
-    let addr = "0.0.0.0:4000".parse::<SocketAddr>().unwrap();
-    let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .route("/v1/-/audit", get(audit::get_audit_log))
+        .route("/v1/-/downloads/:pkg", get(downloads::get_downloads))
+        .route(
+            "/v1/-/hooks",
+            get(webhooks::list_webhooks).post(webhooks::add_webhook),
+        )
+        .route("/v1/-/hooks/:id", delete(webhooks::delete_webhook))
+        .route("/v1/-/hooks/:id/deliveries", get(webhooks::list_deliveries))
+        .merge(npm::routes())
+        .layer(DefaultBodyLimit::max(body_limit))
+        // Tarballs are already gzipped, so only compress everything else.
+        .layer(CompressionLayer::new().compress_when(
+            DefaultPredicate::new().and(NotForContentType::const_new("application/octet-stream")),
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_token,
+        ))
+        .layer(middleware::from_fn(audit::request_id))
+        .layer(cors_layer(&state.config.cors_origins))
+        .with_state(state)
+}
+
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    if origins.iter().any(|origin| origin == "*") {
+        return CorsLayer::permissive();
+    }
+    // Origins were validated when the config was loaded.
+    let origins: Vec<HeaderValue> = origins.iter().filter_map(|o| o.parse().ok()).collect();
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(Any)
+        .allow_headers(Any)
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let config = match Config::load(&args, &|name| std::env::var(name).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid registry configuration: {e}");
+            std::process::exit(1);
+        }
+    };
+    if args.config_check {
+        println!("{config}");
+        println!("Configuration OK");
+        return;
+    }
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+    let storage = match storage::open(&config.storage) {
+        Ok(storage) => storage,
+        Err(e) => {
+            error!(
+                "Failed to open {:?} storage at {}: {e}",
+                config.storage.backend,
+                config.storage.path.display()
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Some(uplink) = &config.uplink {
+        info!("Proxying packages from {}", uplink.url);
+    }
+    let bind = config.bind;
+    let state = match AppState::new(config, storage) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("Failed to index packages: {e}");
+            std::process::exit(1);
+        }
+    };
+    tokio::spawn(downloads::flush_periodically(state.clone()));
+    let app = app(state.clone());
+    let listener = match TcpListener::bind(bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind {bind}: {e}");
+            std::process::exit(1);
+        }
+    };
+    info!("BoltPM Registry running on http://{bind}");
+    // The peer address is recorded in the audit log.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+    // Record the download counts still waiting for the periodic flush.
+    downloads::flush(&state);
+    info!("BoltPM Registry stopped");
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there are signals.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+    info!("Shutting down");
 }
 
 async fn publish_package(
+    State(state): State<AppState>,
     Path(pkg): Path<String>,
+    ctx: AuditContext,
     mut multipart: Multipart,
-) -> (StatusCode, String) {
-    // TODO: Check token (stub)
+) -> Result<(StatusCode, String), ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
     let mut version = None;
     let mut desc = None;
+    let mut tag = None;
     let mut tarball_bytes = None;
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    while let Some(field) = multipart.next_field().await? {
         let name = field.name().unwrap_or("");
         match name {
-            "version" => version = Some(field.text().await.unwrap()),
-            "description" => desc = Some(field.text().await.unwrap()),
-            "tarball" => tarball_bytes = Some(field.bytes().await.unwrap()),
+            "version" => version = Some(field.text().await?),
+            "description" => desc = Some(field.text().await?),
+            "tag" => tag = Some(field.text().await?),
+            "tarball" => tarball_bytes = Some(field.bytes().await?),
             _ => {}
         }
     }
-    let version = version.unwrap_or("0.1.0".to_string());
-    let desc = desc.unwrap_or_default();
-    let tarball_bytes = match tarball_bytes {
-        Some(b) => b,
-        None => return (StatusCode::BAD_REQUEST, "Missing tarball".to_string()),
-    };
-    let pkg_dir = format!("packages/{pkg}/{version}");
-    fs::create_dir_all(&pkg_dir).ok();
-    fs::write(format!("{pkg_dir}/package.tgz"), &tarball_bytes).ok();
-    // Update packages.json
-    let meta_path = FsPath::new("packages/packages.json");
-    let mut meta: HashMap<String, PackageMeta> = if meta_path.exists() {
-        let raw = fs::read(meta_path).unwrap();
-        let meta: HashMap<String, PackageMeta> = serde_json::from_slice(&raw).unwrap();
-        migrate_meta(meta)
-    } else {
-        HashMap::new()
-    };
-    let entry = meta.entry(pkg.clone()).or_insert(PackageMeta {
-        name: pkg.clone(),
-        versions: HashMap::new(),
-    });
-    entry.versions.insert(
-        version.clone(),
-        VersionMeta {
-            description: desc.clone(),
-            yanked: false,
-            deprecated: false,
-            deprecation_message: None,
+    let version = version.ok_or_else(|| ApiError::bad_request("Missing version"))?;
+    let tarball = tarball_bytes.ok_or_else(|| ApiError::bad_request("Missing tarball"))?;
+    publish_version(
+        &state,
+        NewVersion {
+            name: pkg.clone(),
+            version: version.clone(),
+            description: desc,
+            tag,
+            tarball: tarball.to_vec(),
+            publisher: ctx.actor().map(str::to_string),
         },
-    );
-    fs::write(meta_path, serde_json::to_vec_pretty(&meta).unwrap()).ok();
-    (
+    )?;
+    state.audit(ctx.entry(AuditAction::Publish, &pkg, Some(&version)));
+    Ok((
         StatusCode::OK,
         format!("Published package: {pkg}@{version}"),
-    )
+    ))
 }
 
-async fn get_metadata(Path(pkg): Path<String>) -> impl IntoResponse {
-    let meta_path = FsPath::new("packages/packages.json");
-    if !meta_path.exists() {
-        return (StatusCode::NOT_FOUND, "No metadata".to_string());
+/// A version being published, whichever API it was uploaded through.
+pub(crate) struct NewVersion {
+    pub name: String,
+    pub version: String,
+    /// Overrides the package.json description when non-empty.
+    pub description: Option<String>,
+    pub tag: Option<String>,
+    pub tarball: Vec<u8>,
+    pub publisher: Option<String>,
+}
+
+/// Validate and store a new version, failing with 409 if it already exists.
+pub(crate) fn publish_version(state: &AppState, new: NewVersion) -> Result<(), ApiError> {
+    let NewVersion {
+        name: pkg,
+        version,
+        description,
+        tag,
+        tarball,
+        publisher,
+    } = new;
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    let semver = validate::validate_version(&version).map_err(ApiError::bad_request)?;
+    let tag = tag.filter(|t| !t.is_empty());
+    if let Some(tag) = &tag {
+        validate::validate_tag(tag).map_err(ApiError::bad_request)?;
     }
-    let raw = fs::read(meta_path).unwrap();
-    let meta: HashMap<String, PackageMeta> = serde_json::from_slice(&raw).unwrap();
-    let meta = migrate_meta(meta);
-    if let Some(pkg_meta) = meta.get(&pkg) {
-        (
-            StatusCode::OK,
-            serde_json::to_string_pretty(pkg_meta).unwrap(),
-        )
-    } else {
-        (StatusCode::NOT_FOUND, "Package not found".to_string())
+    if tarball.len() > state.config.max_upload_size {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Tarball is {} bytes, above the {} byte limit",
+                tarball.len(),
+                state.config.max_upload_size
+            ),
+        ));
     }
+    let info = validate::inspect_tarball(&tarball).map_err(ApiError::bad_request)?;
+    let package_json = &info.package_json;
+    if package_json["name"].as_str() != Some(pkg.as_str()) {
+        return Err(ApiError::bad_request(format!(
+            "package.json name {} does not match {pkg}",
+            package_json["name"]
+        )));
+    }
+    if package_json["version"].as_str() != Some(version.as_str()) {
+        return Err(ApiError::bad_request(format!(
+            "package.json version {} does not match {version}",
+            package_json["version"]
+        )));
+    }
+    let already_exists = || StorageError::Conflict(format!("{pkg}@{version} already exists"));
+    let was_unpublished = || {
+        StorageError::Conflict(format!(
+            "{pkg}@{version} was unpublished and can't be published again"
+        ))
+    };
+    if let Some(existing) = state.storage.get_package(&pkg)? {
+        match &existing.uplink {
+            None => {
+                if existing.versions.contains_key(&version) {
+                    return Err(already_exists().into());
+                }
+                if existing.unpublished.contains_key(&version) {
+                    return Err(was_unpublished().into());
+                }
+            }
+            // Publishing here takes the name over, so upstream tarballs
+            // cached under it must not be served as this package's.
+            Some(cache) => {
+                for cached in cache.tarballs.keys() {
+                    state.storage.delete_tarball(&pkg, cached)?;
+                }
+            }
+        }
+    }
+    let dist = Dist {
+        tarball: tarball_path(&pkg, &version),
+        shasum: format!("{:x}", Sha1::digest(&tarball)),
+        integrity: format!(
+            "sha512-{}",
+            BASE64_STANDARD.encode(Sha512::digest(&tarball))
+        ),
+        unpacked_size: info.unpacked_size,
+        file_count: info.file_count,
+    };
+    let mut version_meta = VersionMeta::from_package_json(package_json, dist);
+    if let Some(desc) = description.filter(|d| !d.is_empty()) {
+        version_meta.description = desc;
+    }
+    version_meta.published_at = Some(Utc::now());
+    version_meta.published_by = publisher;
+    // The tarball write is create-only, so of two concurrent publishes of the
+    // same version only one gets past this point.
+    state.storage.put_tarball(&pkg, &version, &tarball)?;
+    state.update_package(&pkg, &mut |meta| {
+        // Publishing here takes the name over from any cached uplink copy.
+        if meta.as_ref().is_some_and(|m| m.uplink.is_some()) {
+            *meta = None;
+        }
+        let meta = meta.get_or_insert_with(|| PackageMeta::new(&pkg));
+        if meta.versions.contains_key(&version) {
+            return Err(already_exists());
+        }
+        if meta.unpublished.contains_key(&version) {
+            return Err(was_unpublished());
+        }
+        meta.versions.insert(version.clone(), version_meta.clone());
+        meta.tag_published(&semver, tag.as_deref());
+        Ok(())
+    })?;
+    Ok(())
 }
 
-async fn get_tarball(Path((pkg, version)): Path<(String, String)>) -> Response {
-    let path = format!("packages/{pkg}/{version}/package.tgz");
-    if let Ok(bytes) = fs::read(&path) {
-        Response::builder()
-            .status(StatusCode::OK)
-            .body(Body::from(bytes))
-            .unwrap()
-    } else {
-        Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from("Tarball not found"))
-            .unwrap()
+async fn get_dist_tags(
+    State(state): State<AppState>,
+    Path(pkg): Path<String>,
+) -> Result<Json<BTreeMap<String, String>>, ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    match state.storage.get_package(&pkg)? {
+        Some(pkg_meta) => Ok(Json(pkg_meta.dist_tags)),
+        None => Err(StorageError::NotFound("Package".to_string()).into()),
     }
 }
 
-async fn get_index() -> impl IntoResponse {
-    let meta_path = FsPath::new("packages/packages.json");
-    if !meta_path.exists() {
-        return (StatusCode::OK, "{}".to_string());
+/// Point `tag` at a version. The body is the version as a JSON string, as
+/// with `npm dist-tag add`.
+async fn put_dist_tag(
+    State(state): State<AppState>,
+    Path((pkg, tag)): Path<(String, String)>,
+    ctx: AuditContext,
+    Json(version): Json<String>,
+) -> Result<(StatusCode, String), ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    validate::validate_tag(&tag).map_err(ApiError::bad_request)?;
+    state.update_package(&pkg, &mut |meta| {
+        let pkg_meta = meta
+            .as_mut()
+            .ok_or_else(|| StorageError::NotFound("Package".to_string()))?;
+        if !pkg_meta.versions.contains_key(&version) {
+            return Err(StorageError::NotFound(format!("{pkg}@{version}")));
+        }
+        pkg_meta.dist_tags.insert(tag.clone(), version.clone());
+        Ok(())
+    })?;
+    state.audit(AuditEntry {
+        tag: Some(tag.clone()),
+        ..ctx.entry(AuditAction::DistTagAdd, &pkg, Some(&version))
+    });
+    Ok((StatusCode::OK, format!("{tag}: {pkg}@{version}")))
+}
+
+async fn delete_dist_tag(
+    State(state): State<AppState>,
+    Path((pkg, tag)): Path<(String, String)>,
+    ctx: AuditContext,
+) -> Result<(StatusCode, String), ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    if tag == "latest" {
+        return Err(ApiError::bad_request(
+            "The latest dist-tag cannot be removed",
+        ));
     }
-    let raw = fs::read(meta_path).unwrap();
-    let meta: HashMap<String, PackageMeta> = serde_json::from_slice(&raw).unwrap();
-    let meta = migrate_meta(meta);
-    (StatusCode::OK, serde_json::to_string_pretty(&meta).unwrap())
+    state.update_package(&pkg, &mut |meta| {
+        let pkg_meta = meta
+            .as_mut()
+            .ok_or_else(|| StorageError::NotFound("Package".to_string()))?;
+        match pkg_meta.dist_tags.remove(&tag) {
+            Some(_) => Ok(()),
+            None => Err(StorageError::NotFound(format!("Dist-tag {tag}"))),
+        }
+    })?;
+    state.audit(AuditEntry {
+        tag: Some(tag.clone()),
+        ..ctx.entry(AuditAction::DistTagRemove, &pkg, None)
+    });
+    Ok((StatusCode::OK, format!("Removed dist-tag {tag} from {pkg}")))
 }
 
-async fn yank_version(Path((pkg, version)): Path<(String, String)>) -> impl IntoResponse {
-    let meta_path = FsPath::new("packages/packages.json");
-    let mut meta: HashMap<String, PackageMeta> = if meta_path.exists() {
-        serde_json::from_slice(&fs::read(meta_path).unwrap()).unwrap()
-    } else {
-        return (StatusCode::NOT_FOUND, "No metadata".to_string());
-    };
-    if let Some(pkg_meta) = meta.get_mut(&pkg) {
-        if let Some(ver_meta) = pkg_meta.versions.get_mut(&version) {
-            ver_meta.yanked = true;
-            fs::write(meta_path, serde_json::to_vec_pretty(&meta).unwrap()).ok();
-            return (StatusCode::OK, format!("Yanked {pkg}@{version}"));
+pub(crate) fn tarball_path(pkg: &str, version: &str) -> String {
+    format!("/v1/{}/{version}/", urlencoding::encode(pkg))
+}
+
+/// The scheme and host clients used to reach us, for building absolute URLs.
+pub(crate) fn base_url(headers: &HeaderMap) -> String {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let proto = header("x-forwarded-proto").unwrap_or("http");
+    let host = header("x-forwarded-host")
+        .or_else(|| header("host"))
+        .unwrap_or("localhost:4000");
+    format!("{proto}://{host}")
+}
+
+/// Package metadata as served, with usage figures that aren't stored with it.
+#[derive(Serialize)]
+struct MetadataDoc {
+    #[serde(flatten)]
+    meta: PackageMeta,
+    weekly_downloads: u64,
+}
+
+/// Turn the stored registry-relative tarball paths into absolute URLs.
+fn with_absolute_tarballs(mut pkg_meta: PackageMeta, base: &str) -> PackageMeta {
+    for ver_meta in pkg_meta.versions.values_mut() {
+        if let Some(dist) = &mut ver_meta.dist {
+            if dist.tarball.starts_with('/') {
+                dist.tarball = format!("{base}{}", dist.tarball);
+            }
         }
     }
-    (
-        StatusCode::NOT_FOUND,
-        "Package/version not found".to_string(),
+    pkg_meta
+}
+
+async fn get_metadata(
+    State(state): State<AppState>,
+    Path(pkg): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let base = base_url(&headers);
+    let variant = format!("v1 {base}");
+    http::metadata_response(
+        &state,
+        &headers,
+        &pkg,
+        &variant,
+        "application/json",
+        |meta| {
+            let doc = MetadataDoc {
+                weekly_downloads: state.search.read().unwrap().downloads(&meta.name),
+                meta: with_absolute_tarballs(meta, &base),
+            };
+            serde_json::to_vec_pretty(&doc).unwrap()
+        },
     )
+    .await
+}
+
+async fn get_tarball(
+    State(state): State<AppState>,
+    Path((pkg, version)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    tarball_response(&state, &headers, &pkg, &version).await
 }
 
-async fn unyank_version(Path((pkg, version)): Path<(String, String)>) -> impl IntoResponse {
-    let meta_path = FsPath::new("packages/packages.json");
-    let mut meta: HashMap<String, PackageMeta> = if meta_path.exists() {
-        serde_json::from_slice(&fs::read(meta_path).unwrap()).unwrap()
-    } else {
-        return (StatusCode::NOT_FOUND, "No metadata".to_string());
+pub(crate) async fn tarball_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    pkg: &str,
+    version: &str,
+) -> Result<Response, ApiError> {
+    // Only serve versions the package lists now, not tarballs left from
+    // another owner of the name.
+    let listed = uplink::get_package(state, pkg)
+        .await?
+        .is_some_and(|meta| meta.versions.contains_key(version));
+    let data = match listed {
+        false => None,
+        true => match state.storage.open_tarball(pkg, version)? {
+            Some(data) => Some(data),
+            None => uplink::get_tarball(state, pkg, version)
+                .await?
+                .map(TarballData::Bytes),
+        },
     };
-    if let Some(pkg_meta) = meta.get_mut(&pkg) {
-        if let Some(ver_meta) = pkg_meta.versions.get_mut(&version) {
-            ver_meta.yanked = false;
-            fs::write(meta_path, serde_json::to_vec_pretty(&meta).unwrap()).ok();
-            return (StatusCode::OK, format!("Unyanked {pkg}@{version}"));
+    match data {
+        Some(data) => {
+            let resp = http::tarball_response(headers, data).await?;
+            // Partial and not-modified responses are resumed or repeated
+            // downloads, so only whole ones count.
+            if resp.status() == StatusCode::OK {
+                state.downloads.record(pkg, version);
+            }
+            Ok(resp)
         }
+        None => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Tarball not found"))
+            .unwrap()),
     }
-    (
-        StatusCode::NOT_FOUND,
-        "Package/version not found".to_string(),
-    )
+}
+
+async fn get_index(State(state): State<AppState>) -> Result<(StatusCode, String), ApiError> {
+    let meta: HashMap<String, PackageMeta> = state
+        .storage
+        .list_packages()?
+        .into_iter()
+        .map(|pkg| (pkg.name.clone(), pkg))
+        .collect();
+    Ok((StatusCode::OK, serde_json::to_string_pretty(&meta).unwrap()))
+}
+
+/// Apply `f` to an existing version's metadata, failing with 404 if the
+/// package or version is unknown.
+fn update_version(
+    state: &AppState,
+    pkg: &str,
+    version: &str,
+    mut f: impl FnMut(&mut VersionMeta),
+) -> Result<(), StorageError> {
+    state.update_package(pkg, &mut |meta| match meta
+        .as_mut()
+        .and_then(|pkg_meta| pkg_meta.versions.get_mut(version))
+    {
+        Some(ver_meta) => {
+            f(ver_meta);
+            Ok(())
+        }
+        None => Err(StorageError::NotFound("Package/version".to_string())),
+    })?;
+    Ok(())
+}
+
+async fn yank_version(
+    State(state): State<AppState>,
+    Path((pkg, version)): Path<(String, String)>,
+    ctx: AuditContext,
+) -> Result<(StatusCode, String), ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    update_version(&state, &pkg, &version, |ver_meta| {
+        ver_meta.yanked = true;
+    })?;
+    state.audit(ctx.entry(AuditAction::Yank, &pkg, Some(&version)));
+    Ok((StatusCode::OK, format!("Yanked {pkg}@{version}")))
+}
+
+async fn unyank_version(
+    State(state): State<AppState>,
+    Path((pkg, version)): Path<(String, String)>,
+    ctx: AuditContext,
+) -> Result<(StatusCode, String), ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    update_version(&state, &pkg, &version, |ver_meta| {
+        ver_meta.yanked = false;
+    })?;
+    state.audit(ctx.entry(AuditAction::Unyank, &pkg, Some(&version)));
+    Ok((StatusCode::OK, format!("Unyanked {pkg}@{version}")))
+}
+
+/// `DELETE /v1/:pkg/:version`: remove a version and its tarball for good.
+/// Any token that can publish may unpublish within the configured window
+/// after publishing; after that it takes an admin token.
+async fn unpublish_version(
+    State(state): State<AppState>,
+    Path((pkg, version)): Path<(String, String)>,
+    ctx: AuditContext,
+) -> Result<(StatusCode, String), ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    let not_found = || StorageError::NotFound("Package/version".to_string());
+    let meta = state
+        .storage
+        .get_package(&pkg)?
+        .filter(|meta| meta.uplink.is_none())
+        .ok_or_else(not_found)?;
+    // Already unpublished: only finish removing the tarball, in case that
+    // failed the first time.
+    if !meta.unpublished.contains_key(&version) {
+        let ver_meta = meta.versions.get(&version).ok_or_else(not_found)?;
+        let window = state.config.unpublish_window;
+        let within_window = ver_meta.published_at.is_some_and(|at| {
+            (Utc::now() - at)
+                .to_std()
+                .map_or(true, |elapsed| elapsed <= window)
+        });
+        if !within_window && !ctx.is_admin() {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                format!(
+                    "{pkg}@{version} was published more than {} hours ago; \
+                     unpublishing it needs an admin token",
+                    window.as_secs() / 3600
+                ),
+            ));
+        }
+        let mut unpublished = false;
+        state.update_package(&pkg, &mut |meta| {
+            let meta = meta.as_mut().ok_or_else(not_found)?;
+            if meta.versions.contains_key(&version) {
+                meta.unpublish(&version, Utc::now());
+                unpublished = true;
+            } else if !meta.unpublished.contains_key(&version) {
+                return Err(not_found());
+            }
+            Ok(())
+        })?;
+        if unpublished {
+            state.audit(ctx.entry(AuditAction::Unpublish, &pkg, Some(&version)));
+        }
+    }
+    state.storage.delete_tarball(&pkg, &version)?;
+    Ok((StatusCode::OK, format!("Unpublished {pkg}@{version}")))
 }
 
 #[derive(Deserialize)]
@@ -228,56 +697,396 @@ struct DeprecateReq {
 }
 
 async fn deprecate_version(
+    State(state): State<AppState>,
     Path((pkg, version)): Path<(String, String)>,
+    ctx: AuditContext,
     Json(req): Json<DeprecateReq>,
-) -> impl IntoResponse {
-    let meta_path = FsPath::new("packages/packages.json");
-    let mut meta: HashMap<String, PackageMeta> = if meta_path.exists() {
-        serde_json::from_slice(&fs::read(meta_path).unwrap()).unwrap()
-    } else {
-        return (StatusCode::NOT_FOUND, "No metadata".to_string());
-    };
-    if let Some(pkg_meta) = meta.get_mut(&pkg) {
-        if let Some(ver_meta) = pkg_meta.versions.get_mut(&version) {
-            ver_meta.deprecated = true;
-            ver_meta.deprecation_message = req.message;
-            fs::write(meta_path, serde_json::to_vec_pretty(&meta).unwrap()).ok();
-            return (StatusCode::OK, format!("Deprecated {pkg}@{version}"));
-        }
-    }
-    (
-        StatusCode::NOT_FOUND,
-        "Package/version not found".to_string(),
-    )
+) -> Result<(StatusCode, String), ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    update_version(&state, &pkg, &version, |ver_meta| {
+        ver_meta.deprecated = true;
+        ver_meta.deprecation_message = req.message.clone();
+    })?;
+    state.audit(ctx.entry(AuditAction::Deprecate, &pkg, Some(&version)));
+    Ok((StatusCode::OK, format!("Deprecated {pkg}@{version}")))
 }
 
 async fn search_packages(
+    State(state): State<AppState>,
     axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
-) -> impl IntoResponse {
-    let q = params
-        .get("q")
-        .map(|s| s.to_lowercase())
-        .unwrap_or_default();
-    let meta_path = FsPath::new("packages/packages.json");
-    if !meta_path.exists() {
-        return (StatusCode::OK, "[]".to_string());
+) -> Json<SearchPage> {
+    let q = params.get("q").map(String::as_str).unwrap_or_default();
+    let param = |name: &str, default: usize| {
+        params
+            .get(name)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    };
+    let page = state.search.read().unwrap().search(
+        &search::Query::parse(q),
+        param("from", 0),
+        param("size", 20),
+    );
+    Json(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{StorageBackend, StorageConfig, UplinkConfig};
+    use crate::validate::tests::make_tarball;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    pub(crate) struct TestRegistry {
+        pub app: Router,
+        pub state: AppState,
+        _dir: tempfile::TempDir,
+    }
+
+    pub(crate) fn test_registry() -> TestRegistry {
+        test_registry_with(|_| {})
+    }
+
+    pub(crate) fn test_registry_with_uplink(uplink: Option<UplinkConfig>) -> TestRegistry {
+        test_registry_with(|config| config.uplink = uplink)
     }
-    let raw = fs::read(meta_path).unwrap();
-    let meta: HashMap<String, PackageMeta> = serde_json::from_slice(&raw).unwrap();
-    let meta = migrate_meta(meta);
-    let mut results = vec![];
-    for pkg in meta.values() {
-        if pkg.name.to_lowercase().contains(&q)
-            || pkg
-                .versions
-                .values()
-                .any(|v| v.description.to_lowercase().contains(&q))
-        {
-            results.push(pkg);
+
+    /// A registry on a temporary directory, with `configure` applied to its
+    /// config.
+    pub(crate) fn test_registry_with(configure: impl FnOnce(&mut Config)) -> TestRegistry {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            storage: StorageConfig {
+                backend: StorageBackend::Fs,
+                path: dir.path().to_path_buf(),
+            },
+            max_upload_size: 1024 * 1024,
+            ..Config::default()
+        };
+        configure(&mut config);
+        let storage = storage::open(&config.storage).unwrap();
+        let state = AppState::new(config, storage).unwrap();
+        TestRegistry {
+            app: app(state.clone()),
+            state,
+            _dir: dir,
         }
     }
-    (
-        StatusCode::OK,
-        serde_json::to_string_pretty(&results).unwrap(),
-    )
+
+    pub(crate) fn publish_request(pkg: &str, version: &str, manifest: &str) -> Request<Body> {
+        let tarball = make_tarball(&[("package/package.json", manifest)]);
+        let boundary = "boltpm-test-boundary";
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"version\"\r\n\r\n{version}\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"tarball\"; filename=\"package.tgz\"\r\n\
+             Content-Type: application/gzip\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(&tarball);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+        Request::put(format!("/v1/{}/", urlencoding::encode(pkg)))
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    /// Serve `app` on a local port, returning its base URL.
+    pub(crate) async fn serve(app: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+            axum::serve(listener, app).await.unwrap()
+        });
+        format!("http://{addr}")
+    }
+
+    pub(crate) async fn send(app: &Router, req: Request<Body>) -> (StatusCode, String) {
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_publish_extracts_manifest() {
+        let registry = test_registry();
+        let manifest = r#"{"name":"foo","version":"1.0.0","description":"Foo",
+            "dependencies":{"bar":"^2.0.0"},"license":"MIT"}"#;
+        let (status, body) = send(&registry.app, publish_request("foo", "1.0.0", manifest)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let req = Request::get("/v1/foo/")
+            .header("host", "registry.test")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&registry.app, req).await;
+        assert_eq!(status, StatusCode::OK);
+        let meta: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let ver = &meta["versions"]["1.0.0"];
+        assert_eq!(ver["description"], "Foo");
+        assert_eq!(ver["dependencies"]["bar"], "^2.0.0");
+        assert_eq!(ver["license"], "MIT");
+        assert_eq!(ver["dist"]["tarball"], "http://registry.test/v1/foo/1.0.0/");
+        assert!(ver["dist"]["integrity"]
+            .as_str()
+            .unwrap()
+            .starts_with("sha512-"));
+        assert_eq!(ver["dist"]["shasum"].as_str().unwrap().len(), 40);
+    }
+
+    #[tokio::test]
+    async fn test_unpublish_policy() {
+        let registry = test_registry_with(|config| {
+            let mut admin: config::AuthToken = "root:admin-s3cret".parse().unwrap();
+            admin.admin = true;
+            config.auth.tokens = vec!["dev:s3cret".parse().unwrap(), admin];
+        });
+        let as_token = |secret: &str, mut req: Request<Body>| {
+            let value = format!("Bearer {secret}").parse().unwrap();
+            req.headers_mut().insert("authorization", value);
+            req
+        };
+        let publish = |version: &str| {
+            let manifest = format!(r#"{{"name":"foo","version":"{version}"}}"#);
+            as_token("s3cret", publish_request("foo", version, &manifest))
+        };
+        let unpublish = |secret: &str, version: &str| {
+            as_token(
+                secret,
+                Request::delete(format!("/v1/foo/{version}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        for version in ["1.0.0", "1.1.0"] {
+            assert_eq!(
+                send(&registry.app, publish(version)).await.0,
+                StatusCode::OK
+            );
+        }
+
+        let (status, body) = send(&registry.app, unpublish("s3cret", "1.1.0")).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        let (status, _) = send(&registry.app, get("/v1/foo/1.1.0/")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, body) = send(&registry.app, get("/v1/foo/dist-tags")).await;
+        assert_eq!(body, r#"{"latest":"1.0.0"}"#);
+        let (status, body) = send(&registry.app, publish("1.1.0")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.contains("unpublished"), "{body}");
+        // Retrying is harmless.
+        let (status, _) = send(&registry.app, unpublish("s3cret", "1.1.0")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&registry.app, unpublish("s3cret", "9.9.9")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Outside the window only an admin may unpublish.
+        registry
+            .state
+            .update_package("foo", &mut |meta| {
+                let version = meta.as_mut().unwrap().versions.get_mut("1.0.0").unwrap();
+                version.published_at = Some(Utc::now() - chrono::Duration::days(30));
+                Ok(())
+            })
+            .unwrap();
+        let (status, _) = send(&registry.app, unpublish("s3cret", "1.0.0")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = send(&registry.app, unpublish("admin-s3cret", "1.0.0")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(registry
+            .state
+            .storage
+            .get_tarball("foo", "1.0.0")
+            .unwrap()
+            .is_none());
+
+        let actions: Vec<_> = registry
+            .state
+            .storage
+            .list_audit(Some("foo"))
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.action == AuditAction::Unpublish)
+            .map(|e| (e.actor, e.version.unwrap()))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("dev".to_string(), "1.1.0".to_string()),
+                ("root".to_string(), "1.0.0".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dist_tags() {
+        let registry = test_registry();
+        for version in ["1.0.0", "2.0.0-rc.1"] {
+            let manifest = format!(r#"{{"name":"foo","version":"{version}"}}"#);
+            let (status, body) =
+                send(&registry.app, publish_request("foo", version, &manifest)).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        let tags = || {
+            Request::get("/v1/foo/dist-tags")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (_, body) = send(&registry.app, tags()).await;
+        assert_eq!(body, r#"{"latest":"1.0.0"}"#);
+
+        let put_tag = |tag: &str, version: &str| {
+            Request::put(format!("/v1/foo/dist-tags/{tag}"))
+                .header("content-type", "application/json")
+                .body(Body::from(format!("\"{version}\"")))
+                .unwrap()
+        };
+        let (status, _) = send(&registry.app, put_tag("next", "2.0.0-rc.1")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&registry.app, put_tag("next", "9.9.9")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, body) = send(&registry.app, tags()).await;
+        assert_eq!(body, r#"{"latest":"1.0.0","next":"2.0.0-rc.1"}"#);
+
+        let delete_tag = |tag: &str| {
+            Request::delete(format!("/v1/foo/dist-tags/{tag}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (status, _) = send(&registry.app, delete_tag("latest")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(&registry.app, delete_tag("next")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = send(&registry.app, tags()).await;
+        assert_eq!(body, r#"{"latest":"1.0.0"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_publish_rejects_invalid_uploads() {
+        let registry = test_registry();
+        let manifest = r#"{"name":"foo","version":"1.0.0"}"#;
+        let (status, _) = send(&registry.app, publish_request("foo", "1.0.0", manifest)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&registry.app, publish_request("foo", "1.0.0", manifest)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = send(&registry.app, publish_request("foo", "1.0.1", manifest)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "version mismatch");
+        let (status, _) = send(&registry.app, publish_request("Foo", "1.0.0", manifest)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "invalid name");
+        let (status, _) = send(&registry.app, publish_request("foo", "1.0", manifest)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "invalid version");
+    }
+
+    #[tokio::test]
+    async fn test_search_reflects_changes() {
+        let registry = test_registry();
+        for (name, keywords) in [("http-client", r#"["http"]"#), ("fetchy", r#"["http"]"#)] {
+            let manifest = format!(
+                r#"{{"name":"{name}","version":"1.0.0","description":"HTTP requests","keywords":{keywords}}}"#
+            );
+            let (status, _) = send(&registry.app, publish_request(name, "1.0.0", &manifest)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let search = |q: &str| {
+            Request::get(format!("/v1/search?q={}", urlencoding::encode(q)))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let (_, body) = send(&registry.app, search("http")).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["results"][0]["name"], "http-client");
+        assert_eq!(page["results"][1]["version"], "1.0.0");
+
+        let req = Request::post("/v1/fetchy/1.0.0/deprecate")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"message":"use http-client"}"#))
+            .unwrap();
+        assert_eq!(send(&registry.app, req).await.0, StatusCode::OK);
+        let (_, body) = send(&registry.app, search("http not:deprecated")).await;
+        let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(page["total"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_and_range_requests() {
+        let registry = test_registry();
+        let manifest = r#"{"name":"foo","version":"1.0.0","description":"Cached"}"#;
+        let (status, _) = send(&registry.app, publish_request("foo", "1.0.0", manifest)).await;
+        assert_eq!(status, StatusCode::OK);
+        let get = |uri: &str, headers: &[(&str, &str)]| {
+            let mut req = Request::get(uri);
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            registry
+                .app
+                .clone()
+                .oneshot(req.body(Body::empty()).unwrap())
+        };
+
+        let resp = get("/v1/foo/", &[]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+        assert!(resp.headers().contains_key("last-modified"));
+        let resp = get("/v1/foo/", &[("if-none-match", &etag)]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        let resp = get("/v1/foo/", &[("accept-encoding", "gzip")])
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()["content-encoding"], "gzip");
+
+        let manifest = r#"{"name":"foo","version":"1.1.0"}"#;
+        send(&registry.app, publish_request("foo", "1.1.0", manifest)).await;
+        let resp = get("/v1/foo/", &[("if-none-match", &etag)]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK, "publish changes the ETag");
+
+        let resp = get("/v1/foo/1.0.0/", &[("accept-encoding", "gzip")])
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers()["cache-control"]
+            .to_str()
+            .unwrap()
+            .contains("immutable"));
+        assert!(!resp.headers().contains_key("content-encoding"));
+        let len = resp.headers()["content-length"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+        let resp = get("/v1/foo/1.0.0/", &[("if-none-match", &etag)])
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        let resp = get("/v1/foo/1.0.0/", &[("range", "bytes=10-")])
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let len: u64 = len.parse().unwrap();
+        assert_eq!(
+            resp.headers()["content-range"],
+            format!("bytes 10-{}/{len}", len - 1).as_str()
+        );
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body.len() as u64, len - 10);
+        let resp = get(
+            "/foo/-/foo-1.0.0.tgz",
+            &[("range", &format!("bytes={len}-"))],
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
 }
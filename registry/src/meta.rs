@@ -0,0 +1,371 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Where to download a version and how to verify it.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Dist {
+    /// Stored as a registry-relative path and made absolute when served.
+    pub tarball: String,
+    /// Hex SHA-1 of the tarball, as used by npm's `shasum`.
+    pub shasum: String,
+    /// Subresource Integrity string (`sha512-<base64>`).
+    pub integrity: String,
+    pub unpacked_size: u64,
+    pub file_count: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct VersionMeta {
+    pub description: String,
+    pub yanked: bool,
+    pub deprecated: bool,
+    pub deprecation_message: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub peer_dependencies: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub optional_dependencies: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub engines: BTreeMap<String, String>,
+    /// Command name -> script path, normalized from the string form of `bin`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bin: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    /// The author's name, from either the string or object form.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<Value>,
+    /// Missing for versions published before manifests were extracted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dist: Option<Dist>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<DateTime<Utc>>,
+    /// The name of the token that published the version, when publishing
+    /// needs one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_by: Option<String>,
+}
+
+fn string_map(value: &Value) -> BTreeMap<String, String> {
+    value
+        .as_object()
+        .map(|map| {
+            map.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl VersionMeta {
+    /// Build version metadata from a published `package.json`.
+    pub fn from_package_json(pj: &Value, dist: Dist) -> Self {
+        let bin = match &pj["bin"] {
+            // A bare string names a single command after the unscoped package.
+            Value::String(path) => {
+                let name = pj["name"].as_str().unwrap_or_default();
+                let cmd = name.rsplit('/').next().unwrap_or(name);
+                BTreeMap::from([(cmd.to_string(), path.clone())])
+            }
+            other => string_map(other),
+        };
+        let license = match &pj["license"] {
+            Value::String(license) => Some(license.clone()),
+            // Legacy `{"type": "MIT", "url": ...}` form.
+            other => other["type"].as_str().map(str::to_string),
+        };
+        let keywords = pj["keywords"]
+            .as_array()
+            .map(|k| {
+                k.iter()
+                    .filter_map(|k| Some(k.as_str()?.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let author = match &pj["author"] {
+            // "Name <email> (url)"
+            Value::String(author) => author.split(['<', '(']).next().map(str::trim),
+            other => other["name"].as_str(),
+        }
+        .filter(|name| !name.is_empty())
+        .map(str::to_string);
+        VersionMeta {
+            description: pj["description"].as_str().unwrap_or_default().to_string(),
+            dependencies: string_map(&pj["dependencies"]),
+            peer_dependencies: string_map(&pj["peerDependencies"]),
+            optional_dependencies: string_map(&pj["optionalDependencies"]),
+            engines: string_map(&pj["engines"]),
+            bin,
+            keywords,
+            author,
+            license,
+            repository: pj.get("repository").filter(|r| !r.is_null()).cloned(),
+            dist: Some(dist),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct PackageMeta {
+    pub name: String,
+    pub versions: HashMap<String, VersionMeta>, // version -> VersionMeta
+    /// Tag -> version, e.g. `latest`, `next`, `beta`.
+    #[serde(default, rename = "dist-tags")]
+    pub dist_tags: BTreeMap<String, String>,
+    /// Set when this is a cached copy of a package from the uplink rather
+    /// than one published here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uplink: Option<UplinkCache>,
+    /// When the metadata last changed; unset for packages last written
+    /// before this was tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Utc>>,
+    /// Version -> when it was unpublished. These versions can never be
+    /// published again, so a version number always means the same code.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unpublished: BTreeMap<String, DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct UplinkCache {
+    pub fetched_at: DateTime<Utc>,
+    /// Version -> the uplink's tarball URL, fetched on first download.
+    pub tarballs: BTreeMap<String, String>,
+}
+
+impl PackageMeta {
+    pub fn new(name: &str) -> Self {
+        PackageMeta {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The version `latest` points at, or the highest version if untagged.
+    pub fn latest_version(&self) -> Option<&str> {
+        if let Some(latest) = self.dist_tags.get("latest") {
+            if self.versions.contains_key(latest) {
+                return Some(latest);
+            }
+        }
+        self.versions
+            .keys()
+            .filter_map(|v| Some((semver::Version::parse(v).ok()?, v)))
+            .max()
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Update dist-tags after `version` has been published. An explicit `tag`
+    /// always points at the new version; otherwise `latest` moves to it if it
+    /// is a release newer than the current `latest`.
+    pub fn tag_published(&mut self, version: &semver::Version, tag: Option<&str>) {
+        if let Some(tag) = tag {
+            self.dist_tags.insert(tag.to_string(), version.to_string());
+            return;
+        }
+        if !version.pre.is_empty() {
+            return;
+        }
+        let current = self
+            .dist_tags
+            .get("latest")
+            .and_then(|v| semver::Version::parse(v).ok());
+        if current.is_none_or(|current| *version > current) {
+            self.dist_tags
+                .insert("latest".to_string(), version.to_string());
+        }
+    }
+
+    /// Remove an unpublished version and record it so it can't be published
+    /// again. Tags pointing at it are dropped, except `latest`, which moves
+    /// to the highest remaining release.
+    pub fn unpublish(&mut self, version: &str, at: DateTime<Utc>) {
+        self.versions.remove(version);
+        self.unpublished.insert(version.to_string(), at);
+        self.dist_tags.retain(|_, v| v != version);
+        if !self.dist_tags.contains_key("latest") {
+            if let Some(highest) = highest_release(self.versions.keys()) {
+                self.dist_tags
+                    .insert("latest".to_string(), highest.to_string());
+            }
+        }
+    }
+
+    /// Dist-tags as clients that never see yanked versions should get them.
+    /// Tags pointing at a yanked version are dropped, except `latest`, which
+    /// moves to the highest release that isn't yanked.
+    pub fn unyanked_dist_tags(&self) -> BTreeMap<String, String> {
+        let listed = |version: &str| self.versions.get(version).is_some_and(|v| !v.yanked);
+        let mut tags: BTreeMap<_, _> = self
+            .dist_tags
+            .iter()
+            .filter(|(_, version)| listed(version))
+            .map(|(tag, version)| (tag.clone(), version.clone()))
+            .collect();
+        if !tags.contains_key("latest") {
+            let unyanked = self.versions.keys().filter(|v| listed(v));
+            if let Some(highest) = highest_release(unyanked) {
+                tags.insert("latest".to_string(), highest.to_string());
+            }
+        }
+        tags
+    }
+}
+
+/// The highest of `versions` that is a release rather than a prerelease.
+fn highest_release<'a>(versions: impl Iterator<Item = &'a String>) -> Option<semver::Version> {
+    versions
+        .filter_map(|v| semver::Version::parse(v).ok())
+        .filter(|v| v.pre.is_empty())
+        .max()
+}
+
+/// Parse the legacy `packages.json` index, where a version could map to a bare
+/// description string instead of a `VersionMeta` object.
+pub fn migrate_meta(raw: &[u8]) -> serde_json::Result<HashMap<String, PackageMeta>> {
+    let meta_raw: HashMap<String, Value> = serde_json::from_slice(raw)?;
+    let mut new_meta = HashMap::new();
+    for (pkg_name, pkg) in meta_raw {
+        let name = pkg["name"].as_str().unwrap_or(&pkg_name).to_string();
+        let mut new_versions = HashMap::new();
+        if let Some(versions) = pkg["versions"].as_object() {
+            for (ver, val) in versions {
+                if let Value::String(desc) = val {
+                    new_versions.insert(
+                        ver.clone(),
+                        VersionMeta {
+                            description: desc.clone(),
+                            ..Default::default()
+                        },
+                    );
+                } else if let Ok(vm) = serde_json::from_value::<VersionMeta>(val.clone()) {
+                    new_versions.insert(ver.clone(), vm);
+                }
+            }
+        }
+        new_meta.insert(
+            pkg_name,
+            PackageMeta {
+                name,
+                versions: new_versions,
+                ..Default::default()
+            },
+        );
+    }
+    Ok(new_meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_legacy_string_versions() {
+        let raw = br#"{
+            "old": {"name": "old", "versions": {"1.0.0": "Old package"}},
+            "new": {"name": "new", "versions": {"2.0.0": {
+                "description": "New package", "yanked": true,
+                "deprecated": false, "deprecation_message": null
+            }}}
+        }"#;
+        let meta = migrate_meta(raw).unwrap();
+        assert_eq!(meta["old"].versions["1.0.0"].description, "Old package");
+        assert!(!meta["old"].versions["1.0.0"].yanked);
+        assert!(meta["new"].versions["2.0.0"].yanked);
+    }
+
+    #[test]
+    fn test_tag_published() {
+        let v = |s| semver::Version::parse(s).unwrap();
+        let mut meta = PackageMeta::new("foo");
+        meta.tag_published(&v("1.0.0"), None);
+        assert_eq!(meta.dist_tags["latest"], "1.0.0");
+        meta.tag_published(&v("2.0.0-beta.1"), None);
+        assert_eq!(meta.dist_tags["latest"], "1.0.0");
+        assert!(!meta.dist_tags.contains_key("beta"));
+        meta.tag_published(&v("2.0.0-beta.2"), Some("next"));
+        assert_eq!(meta.dist_tags["next"], "2.0.0-beta.2");
+        // Backports do not take `latest` from a newer release.
+        meta.tag_published(&v("1.1.0"), None);
+        meta.tag_published(&v("0.9.1"), None);
+        assert_eq!(meta.dist_tags["latest"], "1.1.0");
+    }
+
+    #[test]
+    fn test_unpublish() {
+        let v = |s| semver::Version::parse(s).unwrap();
+        let mut meta = PackageMeta::new("foo");
+        for version in ["1.0.0", "1.1.0", "2.0.0-rc.1"] {
+            meta.versions
+                .insert(version.to_string(), VersionMeta::default());
+            meta.tag_published(&v(version), None);
+        }
+        meta.dist_tags
+            .insert("stable".to_string(), "1.1.0".to_string());
+        meta.unpublish("1.1.0", Utc::now());
+        assert!(!meta.versions.contains_key("1.1.0"));
+        assert!(meta.unpublished.contains_key("1.1.0"));
+        assert_eq!(meta.dist_tags["latest"], "1.0.0");
+        assert!(!meta.dist_tags.contains_key("stable"));
+        meta.unpublish("1.0.0", Utc::now());
+        assert!(meta.dist_tags.is_empty(), "prereleases don't become latest");
+    }
+
+    #[test]
+    fn test_unyanked_dist_tags() {
+        let v = |s| semver::Version::parse(s).unwrap();
+        let mut meta = PackageMeta::new("foo");
+        for version in ["1.0.0", "1.1.0", "2.0.0-rc.1"] {
+            meta.versions
+                .insert(version.to_string(), VersionMeta::default());
+            meta.tag_published(&v(version), None);
+        }
+        meta.dist_tags
+            .insert("stable".to_string(), "1.1.0".to_string());
+        meta.dist_tags
+            .insert("next".to_string(), "2.0.0-rc.1".to_string());
+        assert_eq!(meta.unyanked_dist_tags(), meta.dist_tags);
+        meta.versions.get_mut("1.1.0").unwrap().yanked = true;
+        let tags = meta.unyanked_dist_tags();
+        assert_eq!(tags["latest"], "1.0.0");
+        assert_eq!(tags["next"], "2.0.0-rc.1");
+        assert!(!tags.contains_key("stable"));
+        assert_eq!(meta.dist_tags["latest"], "1.1.0", "stored tags are kept");
+    }
+
+    #[test]
+    fn test_version_meta_from_package_json() {
+        let pj = serde_json::json!({
+            "name": "@scope/tool",
+            "version": "1.0.0",
+            "description": "A tool",
+            "dependencies": {"left-pad": "^1.0.0"},
+            "peerDependencies": {"react": ">=17"},
+            "engines": {"node": ">=18"},
+            "bin": "cli.js",
+            "license": {"type": "MIT"},
+            "keywords": ["cli", "tool"],
+            "author": "Jane Doe <jane@example.com>",
+            "repository": "github:scope/tool"
+        });
+        let meta = VersionMeta::from_package_json(&pj, Dist::default());
+        assert_eq!(meta.description, "A tool");
+        assert_eq!(meta.dependencies["left-pad"], "^1.0.0");
+        assert_eq!(meta.peer_dependencies["react"], ">=17");
+        assert_eq!(meta.engines["node"], ">=18");
+        assert_eq!(meta.bin["tool"], "cli.js");
+        assert_eq!(meta.license.as_deref(), Some("MIT"));
+        assert_eq!(meta.keywords, ["cli", "tool"]);
+        assert_eq!(meta.author.as_deref(), Some("Jane Doe"));
+        assert_eq!(meta.repository, Some(Value::from("github:scope/tool")));
+        assert!(meta.optional_dependencies.is_empty());
+    }
+}
@@ -0,0 +1,496 @@
+//! The npm registry protocol, so npm, yarn and pnpm can use this registry
+//! alongside the BoltPM CLI's `/v1` API.
+
+use crate::audit::{AuditAction, AuditContext};
+use crate::error::ApiError;
+use crate::http;
+use crate::meta::{PackageMeta, VersionMeta};
+use crate::search;
+use crate::storage::StorageError;
+use crate::{base_url, publish_version, tarball_response, validate, AppState, NewVersion};
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+    routing::get,
+    Router,
+};
+use base64::prelude::*;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+const ABBREVIATED: &str = "application/vnd.npm.install-v1+json";
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/-/ping", get(ping))
+        .route("/-/v1/search", get(search))
+        .route("/:pkg", get(get_packument).put(put_package))
+        .route("/:pkg/-/:file", get(get_tarball))
+        .route("/:scope/:name/-/:file", get(get_scoped_tarball))
+}
+
+async fn ping() -> Json<Value> {
+    Json(json!({}))
+}
+
+/// npm's tarball URL: `/<name>/-/<unscoped-name>-<version>.tgz`.
+fn tarball_url(base: &str, pkg: &str, version: &str) -> String {
+    let unscoped = pkg.rsplit('/').next().unwrap_or(pkg);
+    format!("{base}/{pkg}/-/{unscoped}-{version}.tgz")
+}
+
+/// Build a version document in npm's shape. The abbreviated form keeps only
+/// what an installer needs.
+fn version_doc(
+    pkg: &str,
+    version: &str,
+    meta: &VersionMeta,
+    base: &str,
+    abbreviated: bool,
+) -> Value {
+    let mut doc = Map::new();
+    doc.insert("name".into(), pkg.into());
+    doc.insert("version".into(), version.into());
+    let mut maps = vec![
+        ("dependencies", &meta.dependencies),
+        ("peerDependencies", &meta.peer_dependencies),
+        ("optionalDependencies", &meta.optional_dependencies),
+        ("bin", &meta.bin),
+        ("engines", &meta.engines),
+    ];
+    maps.retain(|(_, map)| !map.is_empty());
+    for (key, map) in maps {
+        doc.insert(key.into(), json!(map));
+    }
+    if meta.deprecated {
+        let message = meta
+            .deprecation_message
+            .clone()
+            .filter(|m| !m.is_empty())
+            .unwrap_or_else(|| "This version is deprecated".to_string());
+        doc.insert("deprecated".into(), message.into());
+    }
+    let mut dist = Map::new();
+    dist.insert("tarball".into(), tarball_url(base, pkg, version).into());
+    if let Some(d) = &meta.dist {
+        dist.insert("shasum".into(), d.shasum.clone().into());
+        dist.insert("integrity".into(), d.integrity.clone().into());
+        dist.insert("unpackedSize".into(), d.unpacked_size.into());
+        dist.insert("fileCount".into(), d.file_count.into());
+    }
+    doc.insert("dist".into(), dist.into());
+    if !abbreviated {
+        doc.insert("_id".into(), format!("{pkg}@{version}").into());
+        doc.insert("description".into(), meta.description.clone().into());
+        if let Some(license) = &meta.license {
+            doc.insert("license".into(), license.clone().into());
+        }
+        if let Some(repository) = &meta.repository {
+            doc.insert("repository".into(), repository.clone());
+        }
+    }
+    Value::Object(doc)
+}
+
+/// Build the package document ("packument"). Yanked versions are left out so
+/// npm clients never resolve to them, though their tarballs stay reachable
+/// for existing lockfiles, and dist-tags are moved off them to match.
+fn packument(pkg_meta: &PackageMeta, base: &str, abbreviated: bool) -> Value {
+    let name = &pkg_meta.name;
+    let mut versions = Map::new();
+    let mut time = Map::new();
+    for (version, meta) in &pkg_meta.versions {
+        if meta.yanked {
+            continue;
+        }
+        versions.insert(
+            version.clone(),
+            version_doc(name, version, meta, base, abbreviated),
+        );
+        if let Some(published_at) = meta.published_at {
+            time.insert(version.clone(), published_at.to_rfc3339().into());
+        }
+    }
+    let published = pkg_meta.versions.values().filter_map(|v| v.published_at);
+    let created = published.clone().min();
+    let modified = pkg_meta
+        .modified
+        .or(published.max())
+        .unwrap_or_else(Utc::now)
+        .to_rfc3339();
+    let mut doc = Map::new();
+    doc.insert("name".into(), name.clone().into());
+    doc.insert("dist-tags".into(), json!(pkg_meta.unyanked_dist_tags()));
+    doc.insert("versions".into(), versions.into());
+    if abbreviated {
+        doc.insert("modified".into(), modified.into());
+    } else {
+        doc.insert("_id".into(), name.clone().into());
+        if let Some(latest) = pkg_meta.latest_version() {
+            doc.insert(
+                "description".into(),
+                pkg_meta.versions[latest].description.clone().into(),
+            );
+        }
+        if let Some(created) = created {
+            time.insert("created".into(), created.to_rfc3339().into());
+        }
+        time.insert("modified".into(), modified.into());
+        doc.insert("time".into(), time.into());
+        let maintainers: BTreeSet<&str> = pkg_meta
+            .versions
+            .values()
+            .filter_map(|v| v.published_by.as_deref())
+            .collect();
+        let maintainers: Vec<Value> = maintainers
+            .into_iter()
+            .map(|name| json!({ "name": name }))
+            .collect();
+        doc.insert("maintainers".into(), maintainers.into());
+    }
+    Value::Object(doc)
+}
+
+async fn get_packument(
+    State(state): State<AppState>,
+    Path(pkg): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let abbreviated = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains(ABBREVIATED));
+    let base = base_url(&headers);
+    let (variant, content_type) = if abbreviated {
+        (
+            format!("npm-abbreviated {base}"),
+            "application/vnd.npm.install-v1+json; charset=utf-8",
+        )
+    } else {
+        (format!("npm {base}"), "application/json")
+    };
+    let mut resp =
+        http::metadata_response(&state, &headers, &pkg, &variant, content_type, |meta| {
+            serde_json::to_vec(&packument(&meta, &base, abbreviated)).unwrap()
+        })
+        .await?;
+    resp.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("Accept"));
+    Ok(resp)
+}
+
+/// Parse `<unscoped-name>-<version>.tgz` back into the version.
+fn version_from_filename<'a>(pkg: &str, file: &'a str) -> Option<&'a str> {
+    let unscoped = pkg.rsplit('/').next().unwrap_or(pkg);
+    file.strip_suffix(".tgz")?
+        .strip_prefix(unscoped)?
+        .strip_prefix('-')
+}
+
+async fn get_tarball(
+    State(state): State<AppState>,
+    Path((pkg, file)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let version = version_from_filename(&pkg, &file)
+        .ok_or_else(|| StorageError::NotFound("Tarball".to_string()))?;
+    tarball_response(&state, &headers, &pkg, version).await
+}
+
+async fn get_scoped_tarball(
+    State(state): State<AppState>,
+    Path((scope, name, file)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    get_tarball(
+        State(state),
+        Path((format!("{scope}/{name}"), file)),
+        headers,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct Attachment {
+    data: String,
+}
+
+/// The document `npm publish` and `npm deprecate` send to `PUT /:pkg`.
+#[derive(Deserialize)]
+struct PublishDoc {
+    #[serde(default, rename = "dist-tags")]
+    dist_tags: BTreeMap<String, String>,
+    #[serde(default)]
+    versions: BTreeMap<String, Value>,
+    #[serde(default, rename = "_attachments")]
+    attachments: BTreeMap<String, Attachment>,
+}
+
+async fn put_package(
+    State(state): State<AppState>,
+    Path(pkg): Path<String>,
+    ctx: AuditContext,
+    Json(doc): Json<PublishDoc>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    validate::validate_name(&pkg).map_err(ApiError::bad_request)?;
+    if doc.attachments.is_empty() {
+        // Without a tarball this is `npm deprecate` re-sending the packument.
+        apply_deprecations(&state, &ctx, &pkg, &doc.versions)?;
+        return Ok((StatusCode::OK, Json(json!({ "ok": true }))));
+    }
+    let (version, _) = match doc.versions.iter().next() {
+        Some(entry) if doc.versions.len() == 1 => entry,
+        _ => {
+            return Err(ApiError::bad_request(
+                "Publish exactly one version at a time",
+            ))
+        }
+    };
+    let attachment = match doc.attachments.values().next() {
+        Some(a) if doc.attachments.len() == 1 => a,
+        _ => {
+            return Err(ApiError::bad_request(
+                "Expected exactly one tarball attachment",
+            ))
+        }
+    };
+    let tarball = BASE64_STANDARD
+        .decode(&attachment.data)
+        .map_err(|e| ApiError::bad_request(format!("Invalid tarball attachment: {e}")))?;
+    // npm always names a tag, defaulting to `latest`; leave that default to
+    // the registry's own rule so prereleases and backports don't take it.
+    let tag = doc
+        .dist_tags
+        .iter()
+        .find(|(tag, v)| *v == version && *tag != "latest")
+        .map(|(tag, _)| tag.clone());
+    publish_version(
+        &state,
+        NewVersion {
+            name: pkg.clone(),
+            version: version.clone(),
+            description: None,
+            tag,
+            tarball,
+            publisher: ctx.actor().map(str::to_string),
+        },
+    )?;
+    state.audit(ctx.entry(AuditAction::Publish, &pkg, Some(version)));
+    Ok((StatusCode::CREATED, Json(json!({ "ok": true, "id": pkg }))))
+}
+
+/// Apply the deprecation state of every version in `versions`, auditing the
+/// versions whose state changed.
+fn apply_deprecations(
+    state: &AppState,
+    ctx: &AuditContext,
+    pkg: &str,
+    versions: &BTreeMap<String, Value>,
+) -> Result<(), ApiError> {
+    let mut changed = Vec::new();
+    state.update_package(pkg, &mut |meta| {
+        let pkg_meta = meta
+            .as_mut()
+            .ok_or_else(|| StorageError::NotFound("Package".to_string()))?;
+        for (version, doc) in versions {
+            if let Some(ver_meta) = pkg_meta.versions.get_mut(version) {
+                // An empty message is how `npm deprecate pkg@ver ""` undeprecates.
+                let message = doc["deprecated"].as_str().filter(|m| !m.is_empty());
+                let message = message.map(str::to_string);
+                if ver_meta.deprecated != message.is_some()
+                    || ver_meta.deprecation_message != message
+                {
+                    let action = match message {
+                        Some(_) => AuditAction::Deprecate,
+                        None => AuditAction::Undeprecate,
+                    };
+                    changed.push((action, version.clone()));
+                }
+                ver_meta.deprecated = message.is_some();
+                ver_meta.deprecation_message = message;
+            }
+        }
+        Ok(())
+    })?;
+    for (action, version) in changed {
+        state.audit(ctx.entry(action, pkg, Some(&version)));
+    }
+    Ok(())
+}
+
+async fn search(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let text = params.get("text").map(String::as_str).unwrap_or_default();
+    let param = |name: &str, default: usize| {
+        params
+            .get(name)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    };
+    let page = state.search.read().unwrap().search(
+        &search::Query::parse(text),
+        param("from", 0),
+        param("size", 20),
+    );
+    let objects: Vec<Value> = page
+        .results
+        .into_iter()
+        .map(|result| {
+            json!({
+                "package": {
+                    "name": result.name,
+                    "version": result.version,
+                    "description": result.description,
+                    "keywords": result.keywords,
+                    "author": result.author.map(|name| json!({ "name": name })),
+                    "date": result.date.map(|t| t.to_rfc3339()),
+                    "links": {},
+                },
+                "score": {
+                    "final": 1.0,
+                    "detail": {"quality": 1.0, "popularity": 1.0, "maintenance": 1.0},
+                },
+                "downloads": {"weekly": result.downloads},
+                "searchScore": result.score,
+            })
+        })
+        .collect();
+    Json(json!({
+        "objects": objects,
+        "total": page.total,
+        "time": Utc::now().to_rfc3339(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{publish_request, send, test_registry};
+    use crate::validate::tests::make_tarball;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use base64::prelude::*;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_packument_and_tarball() {
+        let registry = test_registry();
+        let manifest = r#"{"name":"@acme/widget","version":"1.0.0","description":"Widgets",
+            "dependencies":{"left-pad":"^1.0.0"}}"#;
+        let (status, body) = send(
+            &registry.app,
+            publish_request("@acme/widget", "1.0.0", manifest),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let req = Request::get("/@acme%2fwidget")
+            .header("host", "registry.test")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&registry.app, req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let doc: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(doc["dist-tags"]["latest"], "1.0.0");
+        assert_eq!(doc["description"], "Widgets");
+        assert!(doc["time"]["1.0.0"].is_string());
+        let version = &doc["versions"]["1.0.0"];
+        assert_eq!(version["dependencies"]["left-pad"], "^1.0.0");
+        let tarball = version["dist"]["tarball"].as_str().unwrap();
+        assert_eq!(
+            tarball,
+            "http://registry.test/@acme/widget/-/widget-1.0.0.tgz"
+        );
+
+        let req = Request::get("/@acme%2fwidget")
+            .header("accept", "application/vnd.npm.install-v1+json")
+            .body(Body::empty())
+            .unwrap();
+        let (_, body) = send(&registry.app, req).await;
+        let doc: Value = serde_json::from_str(&body).unwrap();
+        assert!(doc["modified"].is_string());
+        assert!(doc.get("time").is_none());
+        assert!(doc["versions"]["1.0.0"].get("description").is_none());
+
+        let path = tarball.strip_prefix("http://registry.test").unwrap();
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        let (status, _) = send(&registry.app, req).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_packument_retags_yanked_latest() {
+        let registry = test_registry();
+        for version in ["1.0.0", "1.1.0"] {
+            let manifest = format!(r#"{{"name":"foo","version":"{version}"}}"#);
+            let (status, body) =
+                send(&registry.app, publish_request("foo", version, &manifest)).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        let req = Request::post("/v1/foo/1.1.0/yank")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&registry.app, req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let req = Request::get("/foo").body(Body::empty()).unwrap();
+        let (_, body) = send(&registry.app, req).await;
+        let doc: Value = serde_json::from_str(&body).unwrap();
+        assert!(doc["versions"].get("1.1.0").is_none());
+        assert_eq!(doc["dist-tags"], json!({"latest": "1.0.0"}));
+    }
+
+    #[tokio::test]
+    async fn test_npm_publish_and_deprecate() {
+        let registry = test_registry();
+        let tarball = make_tarball(&[(
+            "package/package.json",
+            r#"{"name":"foo","version":"1.0.0-beta.1"}"#,
+        )]);
+        let doc = json!({
+            "name": "foo",
+            "dist-tags": {"beta": "1.0.0-beta.1"},
+            "versions": {"1.0.0-beta.1": {"name": "foo", "version": "1.0.0-beta.1"}},
+            "_attachments": {"foo-1.0.0-beta.1.tgz": {
+                "content_type": "application/octet-stream",
+                "data": BASE64_STANDARD.encode(&tarball),
+                "length": tarball.len(),
+            }}
+        });
+        let put = |doc: &Value| {
+            Request::put("/foo")
+                .header("content-type", "application/json")
+                .body(Body::from(doc.to_string()))
+                .unwrap()
+        };
+        let (status, body) = send(&registry.app, put(&doc)).await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+        let (status, _) = send(&registry.app, put(&doc)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let deprecate = json!({
+            "name": "foo",
+            "versions": {"1.0.0-beta.1": {"deprecated": "use 1.0.0"}}
+        });
+        let (status, _) = send(&registry.app, put(&deprecate)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let req = Request::get("/foo").body(Body::empty()).unwrap();
+        let (_, body) = send(&registry.app, req).await;
+        let doc: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(doc["dist-tags"], json!({"beta": "1.0.0-beta.1"}));
+        assert_eq!(doc["versions"]["1.0.0-beta.1"]["deprecated"], "use 1.0.0");
+
+        let req = Request::get("/-/v1/search?text=foo&size=5")
+            .body(Body::empty())
+            .unwrap();
+        let (_, body) = send(&registry.app, req).await;
+        let results: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(results["total"], 1);
+        assert_eq!(results["objects"][0]["package"]["name"], "foo");
+    }
+}
@@ -0,0 +1,380 @@
+//! In-memory search index over the latest version of every package, kept up
+//! to date as packages change.
+
+use crate::meta::PackageMeta;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Scores for how a query term matched; for a single term an exact name
+/// always outranks a name prefix, which outranks a keyword, then description.
+const EXACT_NAME: u32 = 1000;
+const NAME_PREFIX: u32 = 100;
+const KEYWORD: u32 = 10;
+const DESCRIPTION: u32 = 1;
+
+/// The largest page a client may ask for.
+pub const MAX_PAGE_SIZE: usize = 250;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SearchResult {
+    pub name: String,
+    pub version: String,
+    pub description: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub deprecated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<DateTime<Utc>>,
+    /// Downloads over the last week.
+    pub downloads: u64,
+    pub score: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchPage {
+    /// Number of matches before pagination.
+    pub total: usize,
+    pub results: Vec<SearchResult>,
+}
+
+/// A parsed query: free-text terms plus `keywords:a,b`, `author:name` and
+/// `not:deprecated` filters.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Query {
+    pub terms: Vec<String>,
+    pub keywords: Vec<String>,
+    pub author: Option<String>,
+    pub not_deprecated: bool,
+}
+
+impl Query {
+    pub fn parse(q: &str) -> Self {
+        let mut query = Query::default();
+        for word in q.split_whitespace() {
+            let word = word.to_lowercase();
+            match word.split_once(':') {
+                Some(("keywords" | "keyword", list)) => query.keywords.extend(
+                    list.split(',')
+                        .filter(|k| !k.is_empty())
+                        .map(str::to_string),
+                ),
+                Some(("author", name)) if !name.is_empty() => query.author = Some(name.to_string()),
+                Some(("not", "deprecated")) => query.not_deprecated = true,
+                _ => query.terms.extend(tokenize(&word)),
+            }
+        }
+        query
+    }
+
+    fn filters(&self, doc: &SearchResult) -> bool {
+        if self.not_deprecated && doc.deprecated {
+            return false;
+        }
+        if let Some(author) = &self.author {
+            let matches = doc
+                .author
+                .as_ref()
+                .is_some_and(|a| a.to_lowercase().contains(author.as_str()));
+            if !matches {
+                return false;
+            }
+        }
+        self.keywords.is_empty()
+            || doc
+                .keywords
+                .iter()
+                .any(|k| self.keywords.contains(&k.to_lowercase()))
+    }
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Token -> names of the packages containing it.
+type Postings = BTreeMap<String, BTreeSet<String>>;
+
+#[derive(Default)]
+pub struct SearchIndex {
+    docs: HashMap<String, SearchResult>,
+    /// Weekly downloads, kept apart from `docs` since they change separately.
+    downloads: HashMap<String, u64>,
+    /// Name tokens plus the full and unscoped names.
+    names: Postings,
+    keywords: Postings,
+    words: Postings,
+}
+
+impl SearchIndex {
+    pub fn build(packages: impl IntoIterator<Item = PackageMeta>) -> Self {
+        let mut index = SearchIndex::default();
+        for pkg in packages {
+            index.update(&pkg.name, Some(&pkg));
+        }
+        index
+    }
+
+    /// A package's downloads over the last week.
+    pub fn downloads(&self, name: &str) -> u64 {
+        self.downloads.get(name).copied().unwrap_or_default()
+    }
+
+    pub fn set_downloads(&mut self, name: &str, weekly: u64) {
+        if weekly == 0 {
+            self.downloads.remove(name);
+        } else {
+            self.downloads.insert(name.to_string(), weekly);
+        }
+    }
+
+    /// Re-index `name` after it changed, or drop it if it no longer exists.
+    pub fn update(&mut self, name: &str, meta: Option<&PackageMeta>) {
+        if let Some(old) = self.docs.remove(name) {
+            let (names, keywords, words) = tokens(&old);
+            for (postings, tokens) in [
+                (&mut self.names, names),
+                (&mut self.keywords, keywords),
+                (&mut self.words, words),
+            ] {
+                for token in tokens {
+                    if let Some(set) = postings.get_mut(&token) {
+                        set.remove(name);
+                        if set.is_empty() {
+                            postings.remove(&token);
+                        }
+                    }
+                }
+            }
+        }
+        let Some(doc) = meta.and_then(to_doc) else {
+            return;
+        };
+        let (names, keywords, words) = tokens(&doc);
+        for (postings, tokens) in [
+            (&mut self.names, names),
+            (&mut self.keywords, keywords),
+            (&mut self.words, words),
+        ] {
+            for token in tokens {
+                postings.entry(token).or_default().insert(name.to_string());
+            }
+        }
+        self.docs.insert(name.to_string(), doc);
+    }
+
+    /// Packages matching every term of `query` and all its filters, best
+    /// matches first.
+    pub fn search(&self, query: &Query, from: usize, size: usize) -> SearchPage {
+        let mut scores: HashMap<&str, u32> = HashMap::new();
+        for (i, term) in query.terms.iter().enumerate() {
+            let term_scores = self.score_term(term);
+            if i == 0 {
+                scores = term_scores;
+            } else {
+                scores.retain(|name, score| match term_scores.get(name) {
+                    Some(s) => {
+                        *score += s;
+                        true
+                    }
+                    None => false,
+                });
+            }
+        }
+        if query.terms.is_empty() {
+            scores = self.docs.keys().map(|name| (name.as_str(), 0)).collect();
+        }
+        let mut results: Vec<SearchResult> = scores
+            .into_iter()
+            .map(|(name, score)| (&self.docs[name], score))
+            .filter(|(doc, _)| query.filters(doc))
+            .map(|(doc, score)| SearchResult {
+                downloads: self.downloads(&doc.name),
+                score,
+                ..doc.clone()
+            })
+            .collect();
+        // Among equally good matches, the more used package comes first.
+        results.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| b.downloads.cmp(&a.downloads))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        SearchPage {
+            total: results.len(),
+            results: results
+                .into_iter()
+                .skip(from)
+                .take(size.min(MAX_PAGE_SIZE))
+                .collect(),
+        }
+    }
+
+    /// The best score each package gets for a single term.
+    fn score_term<'a>(&'a self, term: &str) -> HashMap<&'a str, u32> {
+        let mut scores = HashMap::new();
+        let mut add = |names: &'a BTreeSet<String>, score: u32| {
+            for name in names {
+                let best = scores.entry(name.as_str()).or_insert(0);
+                *best = (*best).max(score);
+            }
+        };
+        for (token, names) in self.names.range(term.to_string()..) {
+            if !token.starts_with(term) {
+                break;
+            }
+            add(names, NAME_PREFIX);
+        }
+        if let Some(names) = self.keywords.get(term) {
+            add(names, KEYWORD);
+        }
+        if let Some(names) = self.words.get(term) {
+            add(names, DESCRIPTION);
+        }
+        for (name, score) in scores.iter_mut() {
+            let name = name.to_lowercase();
+            if name == term || name.rsplit('/').next() == Some(term) {
+                *score = EXACT_NAME;
+            }
+        }
+        scores
+    }
+}
+
+fn tokens(doc: &SearchResult) -> (BTreeSet<String>, BTreeSet<String>, BTreeSet<String>) {
+    let name = doc.name.to_lowercase();
+    let mut names: BTreeSet<String> = tokenize(&name).collect();
+    names.insert(name.rsplit('/').next().unwrap_or(&name).to_string());
+    names.insert(name.clone());
+    let keywords = doc.keywords.iter().map(|k| k.to_lowercase()).collect();
+    let words = tokenize(&doc.description).collect();
+    (names, keywords, words)
+}
+
+/// Summarize a package by its `latest` version, or its highest version that
+/// isn't yanked. Packages with every version yanked aren't searchable.
+fn to_doc(meta: &PackageMeta) -> Option<SearchResult> {
+    let latest = meta
+        .latest_version()
+        .filter(|v| !meta.versions[*v].yanked)
+        .or_else(|| {
+            meta.versions
+                .iter()
+                .filter(|(_, v)| !v.yanked)
+                .filter_map(|(v, _)| Some((semver::Version::parse(v).ok()?, v.as_str())))
+                .max()
+                .map(|(_, v)| v)
+        })?;
+    let version = &meta.versions[latest];
+    Some(SearchResult {
+        name: meta.name.clone(),
+        version: latest.to_string(),
+        description: version.description.clone(),
+        keywords: version.keywords.clone(),
+        author: version.author.clone(),
+        deprecated: version.deprecated,
+        date: version.published_at,
+        downloads: 0,
+        score: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::VersionMeta;
+
+    fn package(name: &str, description: &str, keywords: &[&str], deprecated: bool) -> PackageMeta {
+        let mut meta = PackageMeta::new(name);
+        meta.versions.insert(
+            "1.0.0".to_string(),
+            VersionMeta {
+                description: description.to_string(),
+                keywords: keywords.iter().map(|k| k.to_string()).collect(),
+                author: Some("Jane Doe".to_string()),
+                deprecated,
+                ..Default::default()
+            },
+        );
+        meta
+    }
+
+    fn names(page: &SearchPage) -> Vec<&str> {
+        page.results.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    fn index() -> SearchIndex {
+        SearchIndex::build([
+            package("react", "A UI library", &["ui"], false),
+            package("react-dom", "DOM bindings for react", &["ui", "dom"], false),
+            package(
+                "preact",
+                "A tiny react alternative",
+                &["react", "ui"],
+                false,
+            ),
+            package("@acme/react-old", "Old react helpers", &[], true),
+            package("left-pad", "Pads strings", &["string"], false),
+        ])
+    }
+
+    #[test]
+    fn test_ranking() {
+        let index = index();
+        let page = index.search(&Query::parse("react"), 0, 20);
+        assert_eq!(
+            names(&page),
+            ["react", "@acme/react-old", "react-dom", "preact"]
+        );
+        assert_eq!(page.total, 4);
+        let page = index.search(&Query::parse("react dom"), 0, 20);
+        assert_eq!(names(&page), ["react-dom"]);
+        let page = index.search(&Query::parse("pad"), 0, 20);
+        assert_eq!(names(&page), ["left-pad"]);
+    }
+
+    #[test]
+    fn test_downloads_break_ties() {
+        let mut index = index();
+        index.set_downloads("preact", 500);
+        let page = index.search(&Query::parse("keywords:ui"), 0, 20);
+        assert_eq!(names(&page), ["preact", "react", "react-dom"]);
+        assert_eq!(page.results[0].downloads, 500);
+        // Re-indexing a package keeps its downloads.
+        index.update("preact", Some(&package("preact", "Tiny", &["ui"], false)));
+        assert_eq!(
+            index.search(&Query::parse("tiny"), 0, 20).results[0].downloads,
+            500
+        );
+    }
+
+    #[test]
+    fn test_filters_and_pagination() {
+        let index = index();
+        let page = index.search(&Query::parse("react not:deprecated"), 1, 2);
+        assert_eq!(page.total, 3);
+        assert_eq!(names(&page), ["react-dom", "preact"]);
+        let page = index.search(&Query::parse("keywords:dom,string"), 0, 20);
+        assert_eq!(names(&page), ["left-pad", "react-dom"]);
+        let page = index.search(&Query::parse("author:jane keywords:react"), 0, 20);
+        assert_eq!(names(&page), ["preact"]);
+        assert_eq!(index.search(&Query::parse("author:bob"), 0, 20).total, 0);
+    }
+
+    #[test]
+    fn test_update_replaces_and_removes() {
+        let mut index = index();
+        let renamed = package("left-pad", "Pads text on the left", &[], false);
+        index.update("left-pad", Some(&renamed));
+        assert_eq!(index.search(&Query::parse("strings"), 0, 20).total, 0);
+        assert_eq!(index.search(&Query::parse("text"), 0, 20).total, 1);
+        index.update("left-pad", None);
+        assert_eq!(index.search(&Query::parse("pad"), 0, 20).total, 0);
+        assert!(!index.names.contains_key("left"));
+    }
+}
@@ -0,0 +1,349 @@
+use crate::audit::AuditEntry;
+use crate::config::{StorageBackend, StorageConfig};
+use crate::downloads::DailyDownloads;
+use crate::meta::PackageMeta;
+use crate::webhooks::{Delivery, Webhook};
+use std::fmt;
+use std::sync::Arc;
+
+mod fs;
+mod sqlite;
+
+pub use fs::FsStorage;
+pub use sqlite::SqliteStorage;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+    Serialization(serde_json::Error),
+    NotFound(String),
+    Conflict(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "IO error: {e}"),
+            StorageError::Sqlite(e) => write!(f, "SQLite error: {e}"),
+            StorageError::Serialization(e) => write!(f, "Serialization error: {e}"),
+            StorageError::NotFound(what) => write!(f, "{what} not found"),
+            StorageError::Conflict(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        StorageError::Sqlite(err)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(err: serde_json::Error) -> Self {
+        StorageError::Serialization(err)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Callback passed to [`Storage::update_package`], called exactly once. It
+/// receives `None` when the package does not exist yet; leaving it `None`
+/// writes nothing.
+pub type UpdateFn<'a> = dyn FnMut(&mut Option<PackageMeta>) -> Result<(), StorageError> + 'a;
+
+/// A stored tarball opened for serving.
+pub enum TarballData {
+    /// Kept on disk, so it can be streamed rather than read into memory.
+    File(std::fs::File),
+    Bytes(Vec<u8>),
+}
+
+/// Persistence for package metadata and tarballs.
+///
+/// Implementations must make `update_package` atomic with respect to other
+/// writers of the same package, including other registry processes sharing
+/// the same storage.
+pub trait Storage: Send + Sync {
+    fn list_packages(&self) -> Result<Vec<PackageMeta>, StorageError>;
+    fn get_package(&self, name: &str) -> Result<Option<PackageMeta>, StorageError>;
+    fn update_package(
+        &self,
+        name: &str,
+        f: &mut UpdateFn<'_>,
+    ) -> Result<Option<PackageMeta>, StorageError>;
+    /// Store a version's tarball. Fails with [`StorageError::Conflict`] if one
+    /// already exists, since published versions are immutable.
+    fn put_tarball(&self, name: &str, version: &str, bytes: &[u8]) -> Result<(), StorageError>;
+    fn get_tarball(&self, name: &str, version: &str) -> Result<Option<Vec<u8>>, StorageError>;
+    /// Remove a version's tarball, if it has one.
+    fn delete_tarball(&self, name: &str, version: &str) -> Result<(), StorageError>;
+    /// Open a tarball for serving. Backends that keep tarballs as files
+    /// should return the open file.
+    fn open_tarball(&self, name: &str, version: &str) -> Result<Option<TarballData>, StorageError> {
+        Ok(self.get_tarball(name, version)?.map(TarballData::Bytes))
+    }
+    /// Add an entry to the audit log. Entries are never changed or removed.
+    fn append_audit(&self, entry: &AuditEntry) -> Result<(), StorageError>;
+    /// Audit log entries oldest first, only those for `package` if given.
+    fn list_audit(&self, package: Option<&str>) -> Result<Vec<AuditEntry>, StorageError>;
+    /// Add `counts` to a package's download counts.
+    fn add_downloads(&self, name: &str, counts: &DailyDownloads) -> Result<(), StorageError>;
+    fn get_downloads(&self, name: &str) -> Result<DailyDownloads, StorageError>;
+    fn list_webhooks(&self) -> Result<Vec<Webhook>, StorageError>;
+    /// Add a webhook, or replace the one with the same id.
+    fn put_webhook(&self, hook: &Webhook) -> Result<(), StorageError>;
+    /// Remove a webhook, returning whether it existed. Its delivery history
+    /// is kept.
+    fn delete_webhook(&self, id: &str) -> Result<bool, StorageError>;
+    /// Record an attempt to deliver an event to a webhook.
+    fn append_delivery(&self, delivery: &Delivery) -> Result<(), StorageError>;
+    /// A webhook's delivery attempts, oldest first.
+    fn list_deliveries(&self, hook_id: &str) -> Result<Vec<Delivery>, StorageError>;
+}
+
+pub fn open(config: &StorageConfig) -> Result<Arc<dyn Storage>, StorageError> {
+    Ok(match config.backend {
+        StorageBackend::Fs => Arc::new(FsStorage::open(&config.path)?),
+        StorageBackend::Sqlite => Arc::new(SqliteStorage::open(&config.path)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditAction;
+    use crate::meta::VersionMeta;
+    use crate::webhooks::tests::{delivery, webhook};
+
+    fn publish(storage: &dyn Storage, name: &str, version: &str) {
+        storage.put_tarball(name, version, b"tgz").unwrap();
+        storage
+            .update_package(name, &mut |meta| {
+                meta.get_or_insert_with(|| PackageMeta::new(name))
+                    .versions
+                    .insert(version.to_string(), VersionMeta::default());
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    fn check_roundtrip(storage: &dyn Storage) {
+        assert!(storage.get_package("left-pad").unwrap().is_none());
+        let mut calls = 0;
+        storage
+            .update_package("created", &mut |meta| {
+                calls += 1;
+                *meta = Some(PackageMeta::new("created"));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(calls, 1);
+        publish(storage, "left-pad", "1.0.0");
+        publish(storage, "@scope/pkg", "0.1.0");
+        let meta = storage.get_package("left-pad").unwrap().unwrap();
+        assert!(meta.versions.contains_key("1.0.0"));
+        assert_eq!(
+            storage
+                .get_tarball("@scope/pkg", "0.1.0")
+                .unwrap()
+                .as_deref(),
+            Some(&b"tgz"[..])
+        );
+        assert!(storage.get_tarball("left-pad", "9.9.9").unwrap().is_none());
+        let mut names: Vec<_> = storage
+            .list_packages()
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["@scope/pkg", "created", "left-pad"]);
+        assert!(matches!(
+            storage.put_tarball("left-pad", "1.0.0", b"other"),
+            Err(StorageError::Conflict(_))
+        ));
+        storage.delete_tarball("@scope/pkg", "0.1.0").unwrap();
+        assert!(storage
+            .get_tarball("@scope/pkg", "0.1.0")
+            .unwrap()
+            .is_none());
+        storage.delete_tarball("@scope/pkg", "0.1.0").unwrap();
+    }
+
+    fn check_audit_log(storage: &dyn Storage) {
+        let entry = |action, package: &str| AuditEntry {
+            timestamp: chrono::Utc::now(),
+            actor: "ci".to_string(),
+            token_id: None,
+            action,
+            package: package.to_string(),
+            version: Some("1.0.0".to_string()),
+            tag: None,
+            client_ip: None,
+            request_id: "1".to_string(),
+        };
+        let entries = [
+            entry(AuditAction::Publish, "left-pad"),
+            entry(AuditAction::Publish, "@scope/pkg"),
+            entry(AuditAction::Yank, "left-pad"),
+        ];
+        for e in &entries {
+            storage.append_audit(e).unwrap();
+        }
+        assert_eq!(storage.list_audit(None).unwrap(), entries);
+        assert_eq!(
+            storage.list_audit(Some("left-pad")).unwrap(),
+            [entries[0].clone(), entries[2].clone()]
+        );
+        assert!(storage.list_audit(Some("other")).unwrap().is_empty());
+    }
+
+    fn check_downloads(storage: &dyn Storage) {
+        let day = |d: &str| d.parse::<chrono::NaiveDate>().unwrap();
+        let counts = |entries: &[(&str, &str, u64)]| {
+            let mut counts = DailyDownloads::new();
+            for (d, version, count) in entries {
+                counts
+                    .entry(day(d))
+                    .or_default()
+                    .insert(version.to_string(), *count);
+            }
+            counts
+        };
+        assert!(storage.get_downloads("left-pad").unwrap().is_empty());
+        let first = counts(&[("2024-05-01", "1.0.0", 3), ("2024-05-02", "1.0.0", 1)]);
+        storage.add_downloads("left-pad", &first).unwrap();
+        let second = counts(&[("2024-05-02", "1.0.0", 2), ("2024-05-02", "1.1.0", 5)]);
+        storage.add_downloads("left-pad", &second).unwrap();
+        let mut expected = counts(&[("2024-05-01", "1.0.0", 3), ("2024-05-02", "1.0.0", 3)]);
+        expected
+            .get_mut(&day("2024-05-02"))
+            .unwrap()
+            .insert("1.1.0".to_string(), 5);
+        assert_eq!(storage.get_downloads("left-pad").unwrap(), expected);
+        assert!(storage.get_downloads("@scope/pkg").unwrap().is_empty());
+    }
+
+    fn check_webhooks(storage: &dyn Storage) {
+        assert!(storage.list_webhooks().unwrap().is_empty());
+        let first = webhook("a", "left-pad");
+        let second = webhook("b", "@scope/*");
+        storage.put_webhook(&first).unwrap();
+        storage.put_webhook(&second).unwrap();
+        let mut replaced = first.clone();
+        replaced.url = "http://ci.test/other".to_string();
+        storage.put_webhook(&replaced).unwrap();
+        let mut hooks = storage.list_webhooks().unwrap();
+        hooks.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(hooks, [replaced, second]);
+        assert!(storage.delete_webhook("a").unwrap());
+        assert!(!storage.delete_webhook("a").unwrap());
+        assert_eq!(storage.list_webhooks().unwrap().len(), 1);
+
+        let attempts = [delivery("a", 1), delivery("b", 1), delivery("a", 2)];
+        for attempt in &attempts {
+            storage.append_delivery(attempt).unwrap();
+        }
+        assert_eq!(
+            storage.list_deliveries("a").unwrap(),
+            [attempts[0].clone(), attempts[2].clone()]
+        );
+        assert!(storage.list_deliveries("c").unwrap().is_empty());
+    }
+
+    fn check_concurrent_updates(storage: Arc<dyn Storage>) {
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let storage = storage.clone();
+                std::thread::spawn(move || publish(&*storage, "busy", &format!("1.0.{i}")))
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let meta = storage.get_package("busy").unwrap().unwrap();
+        assert_eq!(meta.versions.len(), 8);
+    }
+
+    #[test]
+    fn test_fs_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        check_roundtrip(&FsStorage::open(dir.path()).unwrap());
+        check_audit_log(&FsStorage::open(dir.path()).unwrap());
+        check_downloads(&FsStorage::open(dir.path()).unwrap());
+        check_webhooks(&FsStorage::open(dir.path()).unwrap());
+        check_concurrent_updates(Arc::new(FsStorage::open(dir.path()).unwrap()));
+    }
+
+    #[test]
+    fn test_sqlite_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry.db");
+        check_roundtrip(&SqliteStorage::open(&path).unwrap());
+        check_audit_log(&SqliteStorage::open(&path).unwrap());
+        check_downloads(&SqliteStorage::open(&path).unwrap());
+        check_webhooks(&SqliteStorage::open(&path).unwrap());
+        check_concurrent_updates(Arc::new(SqliteStorage::open(&path).unwrap()));
+    }
+
+    #[test]
+    fn test_fs_storage_stays_inside_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("store");
+        let storage = FsStorage::open(&root).unwrap();
+        let not_found = |result: Result<Option<PackageMeta>, StorageError>| {
+            matches!(result, Err(StorageError::NotFound(_)))
+        };
+        for name in [
+            "../escaped",
+            "@../escaped",
+            "@scope/../../escaped",
+            ".lock",
+            "_audit.jsonl",
+        ] {
+            assert!(not_found(storage.get_package(name)), "{name}");
+            assert!(
+                not_found(storage.update_package(name, &mut |_| Ok(()))),
+                "{name}"
+            );
+        }
+        assert!(matches!(
+            storage.get_tarball("left-pad", "../../escaped"),
+            Err(StorageError::NotFound(_))
+        ));
+
+        // Looking up a package that doesn't exist creates nothing.
+        let missing = storage.update_package("missing", &mut |meta| match meta {
+            Some(_) => Ok(()),
+            None => Err(StorageError::NotFound("Package".to_string())),
+        });
+        assert!(matches!(missing, Err(StorageError::NotFound(_))));
+        assert!(storage
+            .update_package("missing", &mut |_| Ok(()))
+            .unwrap()
+            .is_none());
+        assert!(!root.join("missing").exists());
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn test_fs_storage_migrates_packages_json() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("packages.json"),
+            r#"{"somepkg": {"name": "somepkg", "versions": {"1.0.0": "Test package"}}}"#,
+        )
+        .unwrap();
+        let storage = FsStorage::open(dir.path()).unwrap();
+        let meta = storage.get_package("somepkg").unwrap().unwrap();
+        assert_eq!(meta.versions["1.0.0"].description, "Test package");
+        assert!(!dir.path().join("packages.json").exists());
+    }
+}
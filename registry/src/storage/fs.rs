@@ -0,0 +1,365 @@
+use super::{Storage, StorageError, TarballData, UpdateFn};
+use crate::audit::AuditEntry;
+use crate::downloads::DailyDownloads;
+use crate::meta::{migrate_meta, PackageMeta};
+use crate::webhooks::{Delivery, Webhook};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const META_FILE: &str = "meta.json";
+const LOCK_FILE: &str = ".lock";
+const DOWNLOADS_FILE: &str = "downloads.json";
+const TARBALL_FILE: &str = "package.tgz";
+/// Package names can't start with `_`, so this never clashes with a package.
+const AUDIT_FILE: &str = "_audit.jsonl";
+const WEBHOOKS_FILE: &str = "_webhooks.json";
+const DELIVERIES_FILE: &str = "_deliveries.jsonl";
+
+/// Filesystem storage laid out as
+/// `<root>/<pkg>/meta.json` and `<root>/<pkg>/<version>/package.tgz`, with
+/// download counts in `<root>/<pkg>/downloads.json`, the audit log as JSON
+/// lines in `<root>/_audit.jsonl`, webhooks in `<root>/_webhooks.json` and
+/// their deliveries as JSON lines in `<root>/_deliveries.jsonl`.
+///
+/// Writes go to a temporary file that is renamed into place, and metadata
+/// and download count updates hold an exclusive lock on
+/// `<root>/<pkg>/.lock` (webhook changes and package creation on
+/// `<root>/.lock`). Audit entries
+/// and deliveries are appended with a single write each, so concurrent
+/// writers don't interleave.
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl FsStorage {
+    pub fn open(root: &Path) -> Result<Self, StorageError> {
+        fs::create_dir_all(root)?;
+        let storage = FsStorage {
+            root: root.to_path_buf(),
+        };
+        storage.migrate_legacy_index()?;
+        Ok(storage)
+    }
+
+    /// Where `name` is stored. Names that aren't a plain `name` or
+    /// `@scope/name` could point outside the package's own directory, so
+    /// they are never found.
+    fn package_dir(&self, name: &str) -> Result<PathBuf, StorageError> {
+        let segments = match name.strip_prefix('@') {
+            Some(scoped) => scoped.split_once('/').map(|(s, n)| vec![s, n]),
+            None => Some(vec![name]),
+        };
+        let plain = segments.is_some_and(|segments| {
+            segments.iter().all(|segment| {
+                !segment.is_empty()
+                    && !segment.starts_with(['.', '_'])
+                    && !segment.contains(['/', '\\', '\0'])
+            })
+        });
+        if !plain {
+            return Err(StorageError::NotFound(format!("Package {name}")));
+        }
+        Ok(self.root.join(name))
+    }
+
+    /// Where a version's tarball is stored, for a version that is a plain
+    /// path segment.
+    fn version_dir(&self, name: &str, version: &str) -> Result<PathBuf, StorageError> {
+        if version.is_empty() || version.starts_with('.') || version.contains(['/', '\\', '\0']) {
+            return Err(StorageError::NotFound(format!("{name}@{version}")));
+        }
+        Ok(self.package_dir(name)?.join(version))
+    }
+
+    /// Split the old single `packages.json` index into per-package metadata files.
+    fn migrate_legacy_index(&self) -> Result<(), StorageError> {
+        let legacy = self.root.join("packages.json");
+        let raw = match fs::read(&legacy) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for (name, pkg) in migrate_meta(&raw)? {
+            self.update_package(&name, &mut |meta| {
+                if meta.is_none() {
+                    *meta = Some(pkg.clone());
+                }
+                Ok(())
+            })?;
+        }
+        fs::rename(&legacy, self.root.join("packages.json.migrated"))?;
+        log::info!("Migrated legacy packages.json into per-package metadata");
+        Ok(())
+    }
+
+    /// Take the package's lock, released when the returned file is dropped.
+    fn lock(dir: &Path) -> Result<fs::File, StorageError> {
+        fs::create_dir_all(dir)?;
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK_FILE))?;
+        lock.lock()?;
+        Ok(lock)
+    }
+
+    /// Append `value` to a JSON lines file as a single write.
+    fn append_line(&self, file: &str, value: &impl serde::Serialize) -> Result<(), StorageError> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join(file))?
+            .write_all(&line)?;
+        Ok(())
+    }
+
+    /// Read a JSON lines file, keeping the values `keep` accepts.
+    fn read_lines<T: serde::de::DeserializeOwned>(
+        &self,
+        name: &str,
+        keep: impl Fn(&T) -> bool,
+    ) -> Result<Vec<T>, StorageError> {
+        let file = match fs::File::open(self.root.join(name)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut values = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str::<T>(&line) {
+                Ok(value) if keep(&value) => values.push(value),
+                Ok(_) => {}
+                // Only a write cut short by a crash leaves a bad line.
+                Err(e) => log::warn!("Skipping unreadable line in {name}: {e}"),
+            }
+        }
+        Ok(values)
+    }
+
+    fn update_webhooks(
+        &self,
+        f: impl FnOnce(&mut Vec<Webhook>) -> bool,
+    ) -> Result<bool, StorageError> {
+        let _lock = Self::lock(&self.root)?;
+        let mut hooks = self.list_webhooks()?;
+        let changed = f(&mut hooks);
+        if changed {
+            write_atomic(&self.root.join(WEBHOOKS_FILE), &serde_json::to_vec(&hooks)?)?;
+        }
+        Ok(changed)
+    }
+
+    fn read_meta(path: &Path) -> Result<Option<PackageMeta>, StorageError> {
+        match fs::read(path) {
+            Ok(raw) => Ok(Some(serde_json::from_slice(&raw)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn collect_packages(dir: &Path, out: &mut Vec<PackageMeta>) -> Result<(), StorageError> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            if let Some(meta) = Self::read_meta(&path.join(META_FILE))? {
+                out.push(meta);
+            } else if path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with('@'))
+            {
+                Self::collect_packages(&path, out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_tmp(dir: &Path, bytes: &[u8]) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp, bytes)?;
+    Ok(tmp)
+}
+
+/// Write `bytes` to `path` via a sibling temporary file and an atomic rename.
+fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = write_tmp(path.parent().expect("storage paths have a parent"), bytes)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Like [`write_atomic`], but fails with `AlreadyExists` instead of replacing
+/// an existing file. Hard-linking the temporary file into place is atomic.
+fn write_new(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = write_tmp(path.parent().expect("storage paths have a parent"), bytes)?;
+    let result = fs::hard_link(&tmp, path);
+    let _ = fs::remove_file(&tmp);
+    result
+}
+
+impl Storage for FsStorage {
+    fn list_packages(&self) -> Result<Vec<PackageMeta>, StorageError> {
+        let mut packages = Vec::new();
+        Self::collect_packages(&self.root, &mut packages)?;
+        Ok(packages)
+    }
+
+    fn get_package(&self, name: &str) -> Result<Option<PackageMeta>, StorageError> {
+        Self::read_meta(&self.package_dir(name)?.join(META_FILE))
+    }
+
+    fn update_package(
+        &self,
+        name: &str,
+        f: &mut UpdateFn<'_>,
+    ) -> Result<Option<PackageMeta>, StorageError> {
+        let dir = self.package_dir(name)?;
+        let meta_path = dir.join(META_FILE);
+        if !meta_path.exists() {
+            // Packages are created under the root lock, so the package's
+            // directory and lock only appear once there is metadata to write.
+            // Metadata is never removed, so once it exists the package's own
+            // lock is enough.
+            let _create = Self::lock(&self.root)?;
+            if !meta_path.exists() {
+                let mut meta = None;
+                f(&mut meta)?;
+                if let Some(meta) = &meta {
+                    let _lock = Self::lock(&dir)?;
+                    write_atomic(&meta_path, &serde_json::to_vec_pretty(meta)?)?;
+                }
+                return Ok(meta);
+            }
+        }
+        let _lock = Self::lock(&dir)?;
+        let mut meta = Self::read_meta(&meta_path)?;
+        f(&mut meta)?;
+        if let Some(meta) = &meta {
+            write_atomic(&meta_path, &serde_json::to_vec_pretty(meta)?)?;
+        }
+        Ok(meta)
+    }
+
+    fn put_tarball(&self, name: &str, version: &str, bytes: &[u8]) -> Result<(), StorageError> {
+        let path = self.version_dir(name, version)?.join(TARBALL_FILE);
+        write_new(&path, bytes).map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => {
+                StorageError::Conflict(format!("{name}@{version} already exists"))
+            }
+            _ => e.into(),
+        })
+    }
+
+    fn get_tarball(&self, name: &str, version: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let path = self.version_dir(name, version)?.join(TARBALL_FILE);
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete_tarball(&self, name: &str, version: &str) -> Result<(), StorageError> {
+        let dir = self.version_dir(name, version)?;
+        match fs::remove_file(dir.join(TARBALL_FILE)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        // Leave the directory if anything else is still in it.
+        let _ = fs::remove_dir(dir);
+        Ok(())
+    }
+
+    fn open_tarball(&self, name: &str, version: &str) -> Result<Option<TarballData>, StorageError> {
+        let path = self.version_dir(name, version)?.join(TARBALL_FILE);
+        match fs::File::open(path) {
+            Ok(file) => Ok(Some(TarballData::File(file))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> Result<(), StorageError> {
+        self.append_line(AUDIT_FILE, entry)
+    }
+
+    fn list_audit(&self, package: Option<&str>) -> Result<Vec<AuditEntry>, StorageError> {
+        self.read_lines(AUDIT_FILE, |entry: &AuditEntry| {
+            package.is_none_or(|p| p == entry.package)
+        })
+    }
+
+    fn add_downloads(&self, name: &str, counts: &DailyDownloads) -> Result<(), StorageError> {
+        let dir = self.package_dir(name)?;
+        let _lock = Self::lock(&dir)?;
+        let mut downloads = self.get_downloads(name)?;
+        for (day, versions) in counts {
+            let day = downloads.entry(*day).or_default();
+            for (version, count) in versions {
+                *day.entry(version.clone()).or_default() += count;
+            }
+        }
+        write_atomic(&dir.join(DOWNLOADS_FILE), &serde_json::to_vec(&downloads)?)?;
+        Ok(())
+    }
+
+    fn get_downloads(&self, name: &str) -> Result<DailyDownloads, StorageError> {
+        match fs::read(self.package_dir(name)?.join(DOWNLOADS_FILE)) {
+            Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(DailyDownloads::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list_webhooks(&self) -> Result<Vec<Webhook>, StorageError> {
+        match fs::read(self.root.join(WEBHOOKS_FILE)) {
+            Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put_webhook(&self, hook: &Webhook) -> Result<(), StorageError> {
+        self.update_webhooks(|hooks| {
+            match hooks.iter_mut().find(|h| h.id == hook.id) {
+                Some(existing) => *existing = hook.clone(),
+                None => hooks.push(hook.clone()),
+            }
+            true
+        })?;
+        Ok(())
+    }
+
+    fn delete_webhook(&self, id: &str) -> Result<bool, StorageError> {
+        self.update_webhooks(|hooks| {
+            let before = hooks.len();
+            hooks.retain(|h| h.id != id);
+            hooks.len() != before
+        })
+    }
+
+    fn append_delivery(&self, delivery: &Delivery) -> Result<(), StorageError> {
+        self.append_line(DELIVERIES_FILE, delivery)
+    }
+
+    fn list_deliveries(&self, hook_id: &str) -> Result<Vec<Delivery>, StorageError> {
+        self.read_lines(DELIVERIES_FILE, |d: &Delivery| d.hook_id == hook_id)
+    }
+}
@@ -0,0 +1,262 @@
+use super::{Storage, StorageError, UpdateFn};
+use crate::audit::AuditEntry;
+use crate::downloads::DailyDownloads;
+use crate::meta::PackageMeta;
+use crate::webhooks::{Delivery, Webhook};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Embedded SQLite storage keeping metadata as JSON and tarballs as blobs in a
+/// single database file.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS packages (
+                 name TEXT PRIMARY KEY,
+                 meta TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS tarballs (
+                 name TEXT NOT NULL,
+                 version TEXT NOT NULL,
+                 data BLOB NOT NULL,
+                 PRIMARY KEY (name, version)
+             );
+             CREATE TABLE IF NOT EXISTS audit_log (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 package TEXT NOT NULL,
+                 entry TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS audit_log_package ON audit_log (package);
+             CREATE TABLE IF NOT EXISTS downloads (
+                 name TEXT NOT NULL,
+                 version TEXT NOT NULL,
+                 day TEXT NOT NULL,
+                 count INTEGER NOT NULL,
+                 PRIMARY KEY (name, version, day)
+             );
+             CREATE TABLE IF NOT EXISTS webhooks (
+                 id TEXT PRIMARY KEY,
+                 hook TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 hook_id TEXT NOT NULL,
+                 delivery TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS webhook_deliveries_hook
+                 ON webhook_deliveries (hook_id);",
+        )?;
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic while holding the lock cannot leave a transaction open, so
+        // the connection is still usable.
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn get_meta(conn: &Connection, name: &str) -> Result<Option<PackageMeta>, StorageError> {
+    let raw: Option<String> = conn
+        .query_row(
+            "SELECT meta FROM packages WHERE name = ?1",
+            params![name],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(match raw {
+        Some(raw) => Some(serde_json::from_str(&raw)?),
+        None => None,
+    })
+}
+
+impl Storage for SqliteStorage {
+    fn list_packages(&self) -> Result<Vec<PackageMeta>, StorageError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT meta FROM packages ORDER BY name")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut packages = Vec::new();
+        for raw in rows {
+            packages.push(serde_json::from_str(&raw?)?);
+        }
+        Ok(packages)
+    }
+
+    fn get_package(&self, name: &str) -> Result<Option<PackageMeta>, StorageError> {
+        get_meta(&self.conn(), name)
+    }
+
+    fn update_package(
+        &self,
+        name: &str,
+        f: &mut UpdateFn<'_>,
+    ) -> Result<Option<PackageMeta>, StorageError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut meta = get_meta(&tx, name)?;
+        f(&mut meta)?;
+        if let Some(meta) = &meta {
+            tx.execute(
+                "INSERT INTO packages (name, meta) VALUES (?1, ?2)
+                 ON CONFLICT(name) DO UPDATE SET meta = excluded.meta",
+                params![name, serde_json::to_string(meta)?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(meta)
+    }
+
+    fn put_tarball(&self, name: &str, version: &str, bytes: &[u8]) -> Result<(), StorageError> {
+        let result = self.conn().execute(
+            "INSERT INTO tarballs (name, version, data) VALUES (?1, ?2, ?3)",
+            params![name, version, bytes],
+        );
+        match result {
+            Ok(_) => Ok(()),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(StorageError::Conflict(format!(
+                    "{name}@{version} already exists"
+                )))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_tarball(&self, name: &str, version: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT data FROM tarballs WHERE name = ?1 AND version = ?2",
+                params![name, version],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn delete_tarball(&self, name: &str, version: &str) -> Result<(), StorageError> {
+        self.conn().execute(
+            "DELETE FROM tarballs WHERE name = ?1 AND version = ?2",
+            params![name, version],
+        )?;
+        Ok(())
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> Result<(), StorageError> {
+        self.conn().execute(
+            "INSERT INTO audit_log (package, entry) VALUES (?1, ?2)",
+            params![entry.package, serde_json::to_string(entry)?],
+        )?;
+        Ok(())
+    }
+
+    fn list_audit(&self, package: Option<&str>) -> Result<Vec<AuditEntry>, StorageError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT entry FROM audit_log WHERE ?1 IS NULL OR package = ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![package], |row| row.get::<_, String>(0))?;
+        let mut entries = Vec::new();
+        for raw in rows {
+            entries.push(serde_json::from_str(&raw?)?);
+        }
+        Ok(entries)
+    }
+
+    fn add_downloads(&self, name: &str, counts: &DailyDownloads) -> Result<(), StorageError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for (day, versions) in counts {
+            for (version, count) in versions {
+                tx.execute(
+                    "INSERT INTO downloads (name, version, day, count) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT(name, version, day) DO UPDATE SET count = count + excluded.count",
+                    params![name, version, day.to_string(), count],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_downloads(&self, name: &str) -> Result<DailyDownloads, StorageError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT day, version, count FROM downloads WHERE name = ?1")?;
+        let rows = stmt.query_map(params![name], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u64>(2)?,
+            ))
+        })?;
+        let mut downloads = DailyDownloads::new();
+        for row in rows {
+            let (day, version, count) = row?;
+            // Days are only ever written by `add_downloads`, as YYYY-MM-DD.
+            let Ok(day) = day.parse() else { continue };
+            downloads.entry(day).or_default().insert(version, count);
+        }
+        Ok(downloads)
+    }
+
+    fn list_webhooks(&self) -> Result<Vec<Webhook>, StorageError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT hook FROM webhooks ORDER BY id")?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        let mut hooks = Vec::new();
+        for raw in rows {
+            hooks.push(serde_json::from_str(&raw?)?);
+        }
+        Ok(hooks)
+    }
+
+    fn put_webhook(&self, hook: &Webhook) -> Result<(), StorageError> {
+        self.conn().execute(
+            "INSERT INTO webhooks (id, hook) VALUES (?1, ?2)
+             ON CONFLICT(id) DO UPDATE SET hook = excluded.hook",
+            params![hook.id, serde_json::to_string(hook)?],
+        )?;
+        Ok(())
+    }
+
+    fn delete_webhook(&self, id: &str) -> Result<bool, StorageError> {
+        let deleted = self
+            .conn()
+            .execute("DELETE FROM webhooks WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    fn append_delivery(&self, delivery: &Delivery) -> Result<(), StorageError> {
+        self.conn().execute(
+            "INSERT INTO webhook_deliveries (hook_id, delivery) VALUES (?1, ?2)",
+            params![delivery.hook_id, serde_json::to_string(delivery)?],
+        )?;
+        Ok(())
+    }
+
+    fn list_deliveries(&self, hook_id: &str) -> Result<Vec<Delivery>, StorageError> {
+        let conn = self.conn();
+        let mut stmt =
+            conn.prepare("SELECT delivery FROM webhook_deliveries WHERE hook_id = ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![hook_id], |row| row.get::<_, String>(0))?;
+        let mut deliveries = Vec::new();
+        for raw in rows {
+            deliveries.push(serde_json::from_str(&raw?)?);
+        }
+        Ok(deliveries)
+    }
+}
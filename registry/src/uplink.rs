@@ -0,0 +1,401 @@
+//! Proxying of packages not published here from an upstream npm-compatible
+//! registry, caching what is fetched in storage.
+
+use crate::config::UplinkConfig;
+use crate::error::ApiError;
+use crate::meta::{Dist, PackageMeta, UplinkCache, VersionMeta};
+use crate::storage::StorageError;
+use crate::{tarball_path, AppState};
+use axum::http::StatusCode;
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sha1::Sha1;
+use sha2::{Digest, Sha512};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum UplinkError {
+    Http(reqwest::Error),
+    Status(String, reqwest::StatusCode),
+    InvalidResponse(String),
+    Integrity(String),
+    /// The tarball at the URL is larger than the byte limit.
+    TooLarge(String, usize),
+}
+
+impl fmt::Display for UplinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UplinkError::Http(e) => write!(f, "Uplink request failed: {e}"),
+            UplinkError::Status(url, status) => write!(f, "Uplink returned {status} for {url}"),
+            UplinkError::InvalidResponse(msg) => write!(f, "Invalid uplink response: {msg}"),
+            UplinkError::Integrity(msg) => write!(f, "Uplink tarball failed verification: {msg}"),
+            UplinkError::TooLarge(url, limit) => {
+                write!(f, "Uplink tarball {url} is above the {limit} byte limit")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UplinkError {}
+
+impl From<reqwest::Error> for UplinkError {
+    fn from(err: reqwest::Error) -> Self {
+        UplinkError::Http(err)
+    }
+}
+
+impl From<UplinkError> for ApiError {
+    fn from(err: UplinkError) -> Self {
+        ApiError::new(StatusCode::BAD_GATEWAY, err.to_string())
+    }
+}
+
+pub struct Uplink {
+    pub config: UplinkConfig,
+    client: reqwest::Client,
+}
+
+impl Uplink {
+    pub fn new(config: UplinkConfig) -> Self {
+        Uplink {
+            config,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Fetch a package's packument, or `None` if the uplink doesn't have it.
+    async fn fetch_package(&self, pkg: &str) -> Result<Option<PackageMeta>, UplinkError> {
+        let url = format!("{}/{}", self.config.url, urlencoding::encode(pkg));
+        let resp = self.client.get(&url).send().await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(UplinkError::Status(url, resp.status()));
+        }
+        let packument: Value = resp.json().await?;
+        from_packument(pkg, &packument).map(Some)
+    }
+
+    /// Fetch a tarball, reading no more than `limit` bytes of it.
+    async fn fetch_tarball(
+        &self,
+        url: &str,
+        dist: Option<&Dist>,
+        limit: usize,
+    ) -> Result<Vec<u8>, UplinkError> {
+        let too_large = || UplinkError::TooLarge(url.to_string(), limit);
+        let mut resp = self.client.get(url).send().await?;
+        if !resp.status().is_success() {
+            return Err(UplinkError::Status(url.to_string(), resp.status()));
+        }
+        if resp.content_length().is_some_and(|len| len > limit as u64) {
+            return Err(too_large());
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if bytes.len() + chunk.len() > limit {
+                return Err(too_large());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        if let Some(dist) = dist {
+            verify(&bytes, dist)?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Convert an npm packument into the metadata we store, pointing tarballs at
+/// this registry and remembering where the uplink keeps them.
+fn from_packument(pkg: &str, packument: &Value) -> Result<PackageMeta, UplinkError> {
+    if packument["name"].as_str() != Some(pkg) {
+        return Err(UplinkError::InvalidResponse(format!(
+            "expected package {pkg}, got {}",
+            packument["name"]
+        )));
+    }
+    let versions = packument["versions"]
+        .as_object()
+        .ok_or_else(|| UplinkError::InvalidResponse(format!("{pkg} has no versions")))?;
+    let mut meta = PackageMeta::new(pkg);
+    let mut cache = UplinkCache {
+        fetched_at: Utc::now(),
+        ..Default::default()
+    };
+    for (version, doc) in versions {
+        let dist = &doc["dist"];
+        let Some(url) = dist["tarball"].as_str() else {
+            continue;
+        };
+        cache.tarballs.insert(version.clone(), url.to_string());
+        let text = |key: &str| dist[key].as_str().unwrap_or_default().to_string();
+        let mut ver_meta = VersionMeta::from_package_json(
+            doc,
+            Dist {
+                tarball: tarball_path(pkg, version),
+                shasum: text("shasum"),
+                integrity: text("integrity"),
+                unpacked_size: dist["unpackedSize"].as_u64().unwrap_or_default(),
+                file_count: dist["fileCount"].as_u64().unwrap_or_default(),
+            },
+        );
+        if let Some(message) = doc["deprecated"].as_str() {
+            ver_meta.deprecated = true;
+            ver_meta.deprecation_message = Some(message.to_string());
+        }
+        ver_meta.published_at = packument["time"][version]
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        meta.versions.insert(version.clone(), ver_meta);
+    }
+    if let Some(tags) = packument["dist-tags"].as_object() {
+        for (tag, version) in tags {
+            if let Some(version) = version.as_str().filter(|v| meta.versions.contains_key(*v)) {
+                meta.dist_tags.insert(tag.clone(), version.to_string());
+            }
+        }
+    }
+    meta.uplink = Some(cache);
+    Ok(meta)
+}
+
+/// Check a tarball against the strongest checksum the uplink gave us.
+fn verify(bytes: &[u8], dist: &Dist) -> Result<(), UplinkError> {
+    if let Some(expected) = dist.integrity.strip_prefix("sha512-") {
+        let actual = BASE64_STANDARD.encode(Sha512::digest(bytes));
+        if actual != expected {
+            return Err(UplinkError::Integrity(format!(
+                "sha512 {actual} does not match {expected}"
+            )));
+        }
+    } else if !dist.shasum.is_empty() {
+        let actual = format!("{:x}", Sha1::digest(bytes));
+        if !actual.eq_ignore_ascii_case(&dist.shasum) {
+            return Err(UplinkError::Integrity(format!(
+                "sha1 {actual} does not match {}",
+                dist.shasum
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Look up a package, consulting the uplink when it isn't published here and
+/// the cached copy is missing or older than the TTL. Packages published here
+/// always take precedence, and a stale copy is served if the uplink is down.
+pub(crate) async fn get_package(
+    state: &AppState,
+    pkg: &str,
+) -> Result<Option<PackageMeta>, ApiError> {
+    let local = state.storage.get_package(pkg)?;
+    let Some(uplink) = &state.uplink else {
+        return Ok(local);
+    };
+    let cached_at = match &local {
+        Some(meta) => match &meta.uplink {
+            Some(cache) => cache.fetched_at,
+            None => return Ok(local),
+        },
+        None => DateTime::<Utc>::MIN_UTC,
+    };
+    if !uplink.config.proxies(pkg) {
+        // The rules may have changed since this copy was cached.
+        return Ok(None);
+    }
+    let age = Utc::now().signed_duration_since(cached_at);
+    if age.to_std().is_ok_and(|age| age < uplink.config.ttl) {
+        return Ok(local);
+    }
+    let fetched = match uplink.fetch_package(pkg).await {
+        Ok(Some(fetched)) => fetched,
+        Ok(None) => return Ok(None),
+        Err(e) if local.is_some() => {
+            log::warn!("Serving stale {pkg}: {e}");
+            return Ok(local);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let stored = state.update_package(pkg, &mut |meta| {
+        // A local publish may have claimed the name while we were fetching.
+        if meta.as_ref().is_none_or(|m| m.uplink.is_some()) {
+            *meta = Some(fetched.clone());
+        }
+        Ok(())
+    })?;
+    Ok(stored)
+}
+
+/// Fetch a tarball of a proxied package from the uplink and cache it.
+/// Returns `None` if the package or version is unknown.
+pub(crate) async fn get_tarball(
+    state: &AppState,
+    pkg: &str,
+    version: &str,
+) -> Result<Option<Vec<u8>>, ApiError> {
+    let Some(uplink) = &state.uplink else {
+        return Ok(None);
+    };
+    let Some(meta) = get_package(state, pkg).await? else {
+        return Ok(None);
+    };
+    let Some(url) = meta.uplink.as_ref().and_then(|c| c.tarballs.get(version)) else {
+        return Ok(None);
+    };
+    let dist = meta.versions.get(version).and_then(|v| v.dist.as_ref());
+    // Proxied tarballs are held to the same limit as published ones.
+    let bytes = uplink
+        .fetch_tarball(url, dist, state.config.max_upload_size)
+        .await?;
+    match state.storage.put_tarball(pkg, version, &bytes) {
+        // Another request cached it first; the contents are the same.
+        Ok(()) | Err(StorageError::Conflict(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(Some(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::UplinkConfig;
+    use crate::tests::{
+        publish_request, send, serve, test_registry, test_registry_with, test_registry_with_uplink,
+    };
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use serde_json::Value;
+    use std::time::Duration;
+
+    fn uplink(url: &str, ttl: Duration, rules: &[&str]) -> Option<UplinkConfig> {
+        Some(UplinkConfig {
+            url: url.to_string(),
+            ttl,
+            rules: rules.iter().map(|r| r.parse().unwrap()).collect(),
+        })
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn manifest(name: &str, version: &str) -> String {
+        format!(r#"{{"name":"{name}","version":"{version}","description":"upstream"}}"#)
+    }
+
+    async fn versions(app: &axum::Router, pkg: &str) -> Vec<String> {
+        let (status, body) = send(app, get(&format!("/v1/{pkg}/"))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let meta: Value = serde_json::from_str(&body).unwrap();
+        let mut versions: Vec<String> = meta["versions"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        versions.sort();
+        versions
+    }
+
+    #[tokio::test]
+    async fn test_proxies_and_caches_uplink_packages() {
+        let upstream = test_registry();
+        for (pkg, version) in [("foo", "1.0.0"), ("@acme/widget", "1.0.0")] {
+            let req = publish_request(pkg, version, &manifest(pkg, version));
+            assert_eq!(send(&upstream.app, req).await.0, StatusCode::OK);
+        }
+        let url = serve(upstream.app.clone()).await;
+        let registry =
+            test_registry_with_uplink(uplink(&url, Duration::from_secs(3600), &["!@acme/*", "*"]));
+
+        assert_eq!(versions(&registry.app, "foo").await, ["1.0.0"]);
+        let (status, _) = send(&registry.app, get("/v1/foo/1.0.0/")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&registry.app, get("/foo/-/foo-1.0.0.tgz")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&registry.app, get("/v1/%40acme%2Fwidget/")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(&registry.app, get("/v1/missing/")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Within the TTL the cached copy is served.
+        let req = publish_request("foo", "1.1.0", &manifest("foo", "1.1.0"));
+        assert_eq!(send(&upstream.app, req).await.0, StatusCode::OK);
+        assert_eq!(versions(&registry.app, "foo").await, ["1.0.0"]);
+
+        let refreshing = test_registry_with_uplink(uplink(&url, Duration::ZERO, &["*"]));
+        assert_eq!(versions(&refreshing.app, "foo").await, ["1.0.0", "1.1.0"]);
+    }
+
+    #[tokio::test]
+    async fn test_uplink_tarballs_are_size_limited() {
+        let upstream = test_registry();
+        let req = publish_request("foo", "1.0.0", &manifest("foo", "1.0.0"));
+        assert_eq!(send(&upstream.app, req).await.0, StatusCode::OK);
+        let url = serve(upstream.app.clone()).await;
+        let registry = test_registry_with(|config| {
+            config.uplink = uplink(&url, Duration::from_secs(3600), &["*"]);
+            config.max_upload_size = 16;
+        });
+
+        let (status, body) = send(&registry.app, get("/foo/-/foo-1.0.0.tgz")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(body.contains("16 byte limit"), "{body}");
+        assert!(registry
+            .state
+            .storage
+            .get_tarball("foo", "1.0.0")
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_local_packages_take_precedence() {
+        let upstream = test_registry();
+        let req = publish_request("bar", "2.0.0", &manifest("bar", "2.0.0"));
+        assert_eq!(send(&upstream.app, req).await.0, StatusCode::OK);
+        let url = serve(upstream.app.clone()).await;
+        let registry = test_registry_with_uplink(uplink(&url, Duration::ZERO, &["*"]));
+
+        assert_eq!(versions(&registry.app, "bar").await, ["2.0.0"]);
+        let req = publish_request("bar", "1.0.0", &manifest("bar", "1.0.0"));
+        assert_eq!(send(&registry.app, req).await.0, StatusCode::OK);
+        assert_eq!(versions(&registry.app, "bar").await, ["1.0.0"]);
+        let (status, _) = send(&registry.app, get("/v1/bar/2.0.0/")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_publish_drops_cached_uplink_tarballs() {
+        let upstream = test_registry();
+        for version in ["1.0.0", "2.0.0"] {
+            let req = publish_request("bar", version, &manifest("bar", version));
+            assert_eq!(send(&upstream.app, req).await.0, StatusCode::OK);
+        }
+        let url = serve(upstream.app.clone()).await;
+        let registry = test_registry_with_uplink(uplink(&url, Duration::from_secs(3600), &["*"]));
+        for version in ["1.0.0", "2.0.0"] {
+            let (status, _) = send(&registry.app, get(&format!("/v1/bar/{version}/"))).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        // The version cached from upstream can be published here, and the
+        // other cached version isn't served under the now local name.
+        let req = publish_request("bar", "2.0.0", &manifest("bar", "2.0.0"));
+        let (status, body) = send(&registry.app, req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(versions(&registry.app, "bar").await, ["2.0.0"]);
+        let (status, _) = send(&registry.app, get("/v1/bar/1.0.0/")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(registry
+            .state
+            .storage
+            .get_tarball("bar", "1.0.0")
+            .unwrap()
+            .is_none());
+        let (status, _) = send(&registry.app, get("/v1/bar/2.0.0/")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,223 @@
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::path::Component;
+
+const MAX_NAME_LENGTH: usize = 214;
+const MAX_PACKAGE_JSON_SIZE: u64 = 1024 * 1024;
+
+/// Validate a package name against npm's rules for new packages: lowercase,
+/// URL-safe, at most 214 characters, optionally scoped as `@scope/name`.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Package name must not be empty".to_string());
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "Package name must be at most {MAX_NAME_LENGTH} characters"
+        ));
+    }
+    let parts: Vec<&str> = match name.strip_prefix('@') {
+        Some(scoped) => match scoped.split_once('/') {
+            Some((scope, pkg)) => vec![scope, pkg],
+            None => return Err(format!("Scoped package name '{name}' must be @scope/name")),
+        },
+        None => vec![name],
+    };
+    for part in parts {
+        if part.is_empty() {
+            return Err(format!("Package name '{name}' has an empty segment"));
+        }
+        if part.starts_with('.') || part.starts_with('_') {
+            return Err(format!(
+                "Package name '{name}' must not start with '.' or '_'"
+            ));
+        }
+        if let Some(c) = part
+            .chars()
+            .find(|c| !matches!(c, 'a'..='z' | '0'..='9' | '-' | '.' | '_'))
+        {
+            return Err(format!(
+                "Package name '{name}' contains invalid character '{c}'"
+            ));
+        }
+    }
+    if name == "node_modules" || name == "favicon.ico" {
+        return Err(format!("'{name}' is a reserved package name"));
+    }
+    Ok(())
+}
+
+pub fn validate_version(version: &str) -> Result<semver::Version, String> {
+    semver::Version::parse(version).map_err(|e| format!("Invalid version '{version}': {e}"))
+}
+
+/// Dist-tags must be URL-safe and, like npm's, must not themselves be valid
+/// semver ranges, or `foo@<tag>` would be ambiguous.
+pub fn validate_tag(tag: &str) -> Result<(), String> {
+    if tag.is_empty() {
+        return Err("Dist-tag must not be empty".to_string());
+    }
+    if let Some(c) = tag
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_')))
+    {
+        return Err(format!("Dist-tag '{tag}' contains invalid character '{c}'"));
+    }
+    if semver::VersionReq::parse(tag).is_ok() {
+        return Err(format!(
+            "Dist-tag '{tag}' is a valid semver range and cannot be used as a tag"
+        ));
+    }
+    Ok(())
+}
+
+/// What publishing learns from reading a tarball.
+pub struct TarballInfo {
+    pub package_json: serde_json::Value,
+    pub unpacked_size: u64,
+    pub file_count: u64,
+}
+
+/// Check that `bytes` is a gzipped tarball with no absolute or `..` entry paths,
+/// and return its parsed `package.json` along with its unpacked size.
+///
+/// Like npm, the manifest may live at the archive root or under a single
+/// top-level directory (usually `package/`).
+pub fn inspect_tarball(bytes: &[u8]) -> Result<TarballInfo, String> {
+    let mut archive = tar::Archive::new(GzDecoder::new(bytes));
+    let entries = archive
+        .entries()
+        .map_err(|e| format!("Tarball is not a valid gzip tar archive: {e}"))?;
+    let mut package_json = None;
+    let mut unpacked_size = 0;
+    let mut file_count = 0;
+    for entry in entries {
+        let mut entry =
+            entry.map_err(|e| format!("Tarball is not a valid gzip tar archive: {e}"))?;
+        let path = entry
+            .path()
+            .map_err(|e| format!("Tarball contains an invalid path: {e}"))?
+            .into_owned();
+        if path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(format!(
+                "Tarball entry '{}' escapes the package directory",
+                path.display()
+            ));
+        }
+        if entry.header().entry_type().is_file() {
+            unpacked_size += entry.size();
+            file_count += 1;
+        }
+        let depth = path
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .count();
+        let is_manifest = path.file_name().is_some_and(|n| n == "package.json") && depth <= 2;
+        // Prefer a root-level package.json over one nested in a directory.
+        if is_manifest && (package_json.is_none() || depth == 1) {
+            if entry.size() > MAX_PACKAGE_JSON_SIZE {
+                return Err("package.json in tarball is too large".to_string());
+            }
+            let mut raw = Vec::new();
+            entry
+                .read_to_end(&mut raw)
+                .map_err(|e| format!("Failed to read package.json from tarball: {e}"))?;
+            let value = serde_json::from_slice(&raw)
+                .map_err(|e| format!("package.json in tarball is invalid: {e}"))?;
+            package_json = Some(value);
+        }
+    }
+    let package_json =
+        package_json.ok_or_else(|| "Tarball does not contain a package.json".to_string())?;
+    Ok(TarballInfo {
+        package_json,
+        unpacked_size,
+        file_count,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build a gzipped tarball containing `files` as `(path, contents)` pairs.
+    pub(crate) fn make_tarball(files: &[(&str, &str)]) -> Vec<u8> {
+        let gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(gz);
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_validate_name() {
+        for ok in ["left-pad", "lodash.merge", "@scope/pkg", "a_b", "x1"] {
+            assert!(validate_name(ok).is_ok(), "{ok} should be valid");
+        }
+        for bad in [
+            "",
+            "../etc",
+            "..",
+            "@scope/../x",
+            "@scope",
+            "@/pkg",
+            "Upper",
+            ".hidden",
+            "_private",
+            "foo/bar",
+            "white space",
+            "node_modules",
+        ] {
+            assert!(validate_name(bad).is_err(), "{bad} should be rejected");
+        }
+        assert!(validate_name(&"a".repeat(215)).is_err());
+    }
+
+    #[test]
+    fn test_validate_version() {
+        assert!(validate_version("1.2.3").is_ok());
+        assert!(validate_version("1.0.0-beta.1").is_ok());
+        assert!(validate_version("1.0").is_err());
+        assert!(validate_version("latest").is_err());
+    }
+
+    #[test]
+    fn test_validate_tag() {
+        assert!(validate_tag("latest").is_ok());
+        assert!(validate_tag("next").is_ok());
+        assert!(validate_tag("beta-2").is_ok());
+        assert!(validate_tag("").is_err());
+        assert!(validate_tag("1.2.3").is_err());
+        assert!(validate_tag("../x").is_err());
+    }
+
+    #[test]
+    fn test_inspect_tarball() {
+        let manifest = r#"{"name":"foo","version":"1.0.0"}"#;
+        let tgz = make_tarball(&[
+            ("package/package.json", manifest),
+            ("package/index.js", "module.exports = 1;"),
+        ]);
+        let info = inspect_tarball(&tgz).unwrap();
+        assert_eq!(info.package_json["name"], "foo");
+        assert_eq!(info.file_count, 2);
+        assert_eq!(
+            info.unpacked_size,
+            (manifest.len() + "module.exports = 1;".len()) as u64
+        );
+
+        let missing = make_tarball(&[("package/index.js", "")]);
+        assert!(inspect_tarball(&missing).is_err());
+        assert!(inspect_tarball(b"not a tarball").is_err());
+    }
+}
@@ -0,0 +1,750 @@
+//! Webhooks: a signed JSON POST to registered URLs whenever a package
+//! changes, so downstream CI can rebuild when a dependency publishes.
+//!
+//! Events are the changes [`AppState::audit`] records. Each is delivered in
+//! the background, retried with exponential backoff, and every attempt is
+//! kept as the hook's delivery history.
+//!
+//! Only admins can point a hook at a loopback, link-local or private
+//! address. Other hooks are checked on every attempt against the addresses
+//! their host resolves to, which the request is then pinned to, and no hook
+//! follows redirects.
+
+use crate::audit::{AuditAction, AuditContext, AuditEntry};
+use crate::error::ApiError;
+use crate::storage::StorageError;
+use crate::{validate, AppState};
+use axum::extract::{Json, Path, Query, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// `sha256=<hex HMAC-SHA256 of the body, keyed with the hook's secret>`.
+pub const SIGNATURE_HEADER: &str = "x-boltpm-signature";
+pub const EVENT_HEADER: &str = "x-boltpm-event";
+/// The same for every attempt at delivering one event.
+pub const DELIVERY_HEADER: &str = "x-boltpm-delivery";
+
+/// Attempts at delivering an event before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubling for each one after.
+const RETRY_DELAY: Duration = if cfg!(test) {
+    Duration::from_millis(10)
+} else {
+    Duration::from_secs(2)
+};
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries returned by the history endpoint when no limit is given.
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Webhook {
+    pub id: String,
+    /// A package name, or `@scope/*` for every package in a scope.
+    pub package: String,
+    pub url: String,
+    /// Key for the payload signature. Never returned by the API.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    /// The events to deliver; empty for all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<AuditAction>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    /// Whether the URL may reach non-public addresses, which only admins
+    /// can allow.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub internal: bool,
+}
+
+impl Webhook {
+    fn wants(&self, entry: &AuditEntry) -> bool {
+        let package = match self.package.strip_suffix("/*") {
+            Some(scope) => entry
+                .package
+                .strip_prefix(scope)
+                .is_some_and(|rest| rest.starts_with('/')),
+            None => self.package == entry.package,
+        };
+        package && (self.events.is_empty() || self.events.contains(&entry.action))
+    }
+
+    /// Whether the request may see and remove the hook: the token that
+    /// created it, or an admin.
+    fn managed_by(&self, ctx: &AuditContext) -> bool {
+        ctx.is_admin() || ctx.actor() == Some(self.created_by.as_str())
+    }
+
+    /// The hook as shown by the API, without its secret.
+    fn redacted(self) -> Self {
+        Webhook {
+            secret: String::new(),
+            ..self
+        }
+    }
+}
+
+/// One attempt at delivering an event to a webhook.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Delivery {
+    /// Shared by every attempt at delivering the same event.
+    pub id: String,
+    pub hook_id: String,
+    pub event: AuditAction,
+    pub package: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub attempt: u32,
+    pub timestamp: DateTime<Utc>,
+    /// The receiver's response status, if it responded at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Why the attempt failed; unset when it succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// The JSON body POSTed to a webhook.
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: AuditAction,
+    package: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    actor: &'a str,
+    timestamp: DateTime<Utc>,
+    request_id: &'a str,
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Whether `ip` is reachable from the public internet, rather than loopback,
+/// link-local, private, shared or unspecified.
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // 100.64.0.0/10, shared by carrier-grade NAT.
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local fc00::/7 and link-local fe80::/10.
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// The addresses `url`'s host resolves to, failing if any of them isn't
+/// public.
+async fn public_addrs(url: &reqwest::Url) -> Result<Vec<SocketAddr>, String> {
+    let host = url.host_str().ok_or("Webhook URLs need a host")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Failed to resolve {host}: {e}"))?
+        .collect();
+    match addrs.iter().find(|addr| !is_public(addr.ip())) {
+        Some(addr) => Err(format!(
+            "{host} resolves to {}, which isn't a public address",
+            addr.ip()
+        )),
+        None if addrs.is_empty() => Err(format!("{host} doesn't resolve to any address")),
+        None => Ok(addrs),
+    }
+}
+
+/// The client to deliver to `hook` with. Unless the hook is internal, its
+/// host must resolve only to public addresses, and the client is pinned to
+/// them so a second lookup can't answer differently.
+async fn delivery_client(state: &AppState, hook: &Webhook) -> Result<reqwest::Client, String> {
+    if hook.internal {
+        return Ok(state.webhook_client.clone());
+    }
+    let url = reqwest::Url::parse(&hook.url).map_err(|e| e.to_string())?;
+    let addrs = public_addrs(&url).await?;
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve_to_addrs(url.host_str().unwrap_or_default(), &addrs)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// A short unique id, for hooks and deliveries.
+fn new_id(seed: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let digest = Sha256::digest(format!(
+        "{seed}:{}:{}:{}",
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    format!("{digest:x}")[..12].to_string()
+}
+
+impl AppState {
+    /// Start delivering `entry` to every webhook that wants it. Must be
+    /// called from within the runtime.
+    pub(crate) fn notify_webhooks(&self, entry: &AuditEntry) {
+        let hooks = match self.storage.list_webhooks() {
+            Ok(hooks) => hooks,
+            Err(e) => {
+                error!("Failed to list webhooks for {}: {e}", entry.package);
+                return;
+            }
+        };
+        let body = serde_json::to_vec(&Payload {
+            event: entry.action,
+            package: &entry.package,
+            version: entry.version.as_deref(),
+            tag: entry.tag.as_deref(),
+            actor: &entry.actor,
+            timestamp: entry.timestamp,
+            request_id: &entry.request_id,
+        })
+        .expect("payloads serialize");
+        for hook in hooks.into_iter().filter(|hook| hook.wants(entry)) {
+            let delivery = Delivery {
+                id: new_id(&hook.id),
+                hook_id: hook.id.clone(),
+                event: entry.action,
+                package: entry.package.clone(),
+                version: entry.version.clone(),
+                attempt: 0,
+                timestamp: Utc::now(),
+                status: None,
+                error: None,
+                duration_ms: 0,
+            };
+            tokio::spawn(deliver(self.clone(), hook, delivery, body.clone()));
+        }
+    }
+}
+
+/// POST `body` to the hook until it answers with a success status or
+/// [`MAX_ATTEMPTS`] run out, recording each attempt.
+async fn deliver(state: AppState, hook: Webhook, mut delivery: Delivery, body: Vec<u8>) {
+    let signature = sign(&hook.secret, &body);
+    let event = serde_json::to_value(delivery.event).expect("actions serialize");
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let started = Instant::now();
+        let result = match delivery_client(&state, &hook).await {
+            Ok(client) => client
+                .post(&hook.url)
+                .header("content-type", "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .header(EVENT_HEADER, event.as_str().unwrap_or_default())
+                .header(DELIVERY_HEADER, &delivery.id)
+                .timeout(DELIVERY_TIMEOUT)
+                .body(body.clone())
+                .send()
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        delivery.attempt = attempt;
+        delivery.timestamp = Utc::now();
+        delivery.duration_ms = started.elapsed().as_millis() as u64;
+        (delivery.status, delivery.error) = match result {
+            Ok(resp) if resp.status().is_success() => (Some(resp.status().as_u16()), None),
+            Ok(resp) => (
+                Some(resp.status().as_u16()),
+                Some(format!("Receiver returned {}", resp.status())),
+            ),
+            Err(e) => (None, Some(e)),
+        };
+        if let Err(e) = state.storage.append_delivery(&delivery) {
+            error!("Failed to record delivery to webhook {}: {e}", hook.id);
+        }
+        if delivery.error.is_none() {
+            return;
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    warn!(
+        "Giving up on delivering {} of {} to webhook {} after {MAX_ATTEMPTS} attempts",
+        event, delivery.package, hook.id
+    );
+}
+
+#[derive(Deserialize)]
+pub(crate) struct NewWebhook {
+    package: String,
+    url: String,
+    secret: String,
+    #[serde(default)]
+    events: Vec<AuditAction>,
+}
+
+/// Fail unless the hook `id` exists and the request may manage it. Other
+/// tokens' hooks are reported as missing rather than forbidden, so ids
+/// can't be probed.
+fn check_manages(state: &AppState, ctx: &AuditContext, id: &str) -> Result<(), ApiError> {
+    let hooks = state.storage.list_webhooks()?;
+    if !hooks
+        .iter()
+        .any(|hook| hook.id == id && hook.managed_by(ctx))
+    {
+        return Err(StorageError::NotFound(format!("Webhook {id}")).into());
+    }
+    Ok(())
+}
+
+/// `GET /v1/-/hooks`: the hooks this token created, or every hook for an
+/// admin.
+pub(crate) async fn list_webhooks(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+) -> Result<Json<Vec<Webhook>>, ApiError> {
+    let hooks = state.storage.list_webhooks()?;
+    Ok(Json(
+        hooks
+            .into_iter()
+            .filter(|hook| hook.managed_by(&ctx))
+            .map(Webhook::redacted)
+            .collect(),
+    ))
+}
+
+/// `POST /v1/-/hooks` with `{package, url, secret, events}`, where `package`
+/// is a name or `@scope/*`.
+pub(crate) async fn add_webhook(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Json(new): Json<NewWebhook>,
+) -> Result<(StatusCode, Json<Webhook>), ApiError> {
+    // A hook belongs to the token that created it, so it needs one.
+    let Some(actor) = ctx.actor() else {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "Registering a webhook needs a token",
+        ));
+    };
+    match new.package.strip_suffix("/*") {
+        Some(scope) if scope.starts_with('@') => {
+            validate::validate_name(&format!("{scope}/pkg")).map_err(ApiError::bad_request)?
+        }
+        _ => validate::validate_name(&new.package).map_err(ApiError::bad_request)?,
+    }
+    let url = reqwest::Url::parse(&new.url)
+        .map_err(|e| ApiError::bad_request(format!("Invalid webhook URL: {e}")))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ApiError::bad_request("Webhook URLs must be http or https"));
+    }
+    if !ctx.is_admin() {
+        public_addrs(&url).await.map_err(|e| {
+            ApiError::bad_request(format!(
+                "{e}; only admin tokens can register webhooks for internal addresses"
+            ))
+        })?;
+    }
+    if new.secret.is_empty() {
+        return Err(ApiError::bad_request(
+            "A webhook needs a secret to sign with",
+        ));
+    }
+    let hook = Webhook {
+        id: new_id(&new.url),
+        package: new.package,
+        url: new.url,
+        secret: new.secret,
+        events: new.events,
+        created_by: actor.to_string(),
+        created_at: Utc::now(),
+        internal: ctx.is_admin(),
+    };
+    state.storage.put_webhook(&hook)?;
+    Ok((StatusCode::CREATED, Json(hook.redacted())))
+}
+
+/// `DELETE /v1/-/hooks/:id`
+pub(crate) async fn delete_webhook(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Path(id): Path<String>,
+) -> Result<(StatusCode, String), ApiError> {
+    check_manages(&state, &ctx, &id)?;
+    if !state.storage.delete_webhook(&id)? {
+        return Err(StorageError::NotFound(format!("Webhook {id}")).into());
+    }
+    Ok((StatusCode::OK, format!("Deleted webhook {id}")))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeliveriesQuery {
+    limit: Option<usize>,
+}
+
+/// `GET /v1/-/hooks/:id/deliveries?limit=`: the most recent attempts first.
+/// Once a hook is deleted only admins can read its history.
+pub(crate) async fn list_deliveries(
+    State(state): State<AppState>,
+    ctx: AuditContext,
+    Path(id): Path<String>,
+    Query(query): Query<DeliveriesQuery>,
+) -> Result<Json<Vec<Delivery>>, ApiError> {
+    if !ctx.is_admin() {
+        check_manages(&state, &ctx, &id)?;
+    }
+    let mut deliveries = state.storage.list_deliveries(&id)?;
+    deliveries.reverse();
+    deliveries.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+    Ok(Json(deliveries))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::AuthToken;
+    use crate::tests::{
+        publish_request, send, serve, test_registry, test_registry_with, TestRegistry,
+    };
+    use axum::body::{Body, Bytes};
+    use axum::http::{HeaderMap, Request};
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::{Arc, Mutex};
+
+    pub(crate) fn webhook(id: &str, package: &str) -> Webhook {
+        Webhook {
+            id: id.to_string(),
+            package: package.to_string(),
+            url: "http://ci.test/hook".to_string(),
+            secret: "s3cret".to_string(),
+            events: vec![AuditAction::Publish],
+            created_by: "ci".to_string(),
+            created_at: Utc::now(),
+            internal: false,
+        }
+    }
+
+    /// A registry with `ci` (`s3cret`) and `dev` (`d3v`) tokens and an `ops`
+    /// admin token (`adm1n`).
+    fn registry_with_tokens() -> TestRegistry {
+        test_registry_with(|config| {
+            let admin = AuthToken {
+                admin: true,
+                .."ops:adm1n".parse::<AuthToken>().unwrap()
+            };
+            config.auth.tokens = vec![
+                "ci:s3cret".parse().unwrap(),
+                "dev:d3v".parse().unwrap(),
+                admin,
+            ];
+        })
+    }
+
+    fn with_token(mut req: Request<Body>, token: &str) -> Request<Body> {
+        req.headers_mut()
+            .insert("authorization", format!("Bearer {token}").parse().unwrap());
+        req
+    }
+
+    fn add(hook: serde_json::Value) -> Request<Body> {
+        Request::post("/v1/-/hooks")
+            .header("content-type", "application/json")
+            .body(Body::from(hook.to_string()))
+            .unwrap()
+    }
+
+    pub(crate) fn delivery(hook_id: &str, attempt: u32) -> Delivery {
+        Delivery {
+            id: "d1".to_string(),
+            hook_id: hook_id.to_string(),
+            event: AuditAction::Publish,
+            package: "left-pad".to_string(),
+            version: Some("1.0.0".to_string()),
+            attempt,
+            timestamp: Utc::now(),
+            status: Some(500),
+            error: Some("Receiver returned 500".to_string()),
+            duration_ms: 3,
+        }
+    }
+
+    #[test]
+    fn test_hooks_match_packages_and_events() {
+        let entry = |action, package: &str| AuditEntry {
+            timestamp: Utc::now(),
+            actor: "ci".to_string(),
+            token_id: None,
+            action,
+            package: package.to_string(),
+            version: None,
+            tag: None,
+            client_ip: None,
+            request_id: "1".to_string(),
+        };
+        let scoped = webhook("a", "@acme/*");
+        assert!(scoped.wants(&entry(AuditAction::Publish, "@acme/widget")));
+        assert!(!scoped.wants(&entry(AuditAction::Publish, "@acme-other/widget")));
+        assert!(!scoped.wants(&entry(AuditAction::Yank, "@acme/widget")));
+        let all_events = Webhook {
+            events: Vec::new(),
+            ..webhook("b", "left-pad")
+        };
+        assert!(all_events.wants(&entry(AuditAction::DistTagAdd, "left-pad")));
+        assert!(!all_events.wants(&entry(AuditAction::Publish, "left-pad-2")));
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_are_signed_and_retried() {
+        // A receiver that fails its first request.
+        let received: Arc<Mutex<Vec<(HeaderMap, Bytes)>>> = Arc::default();
+        let receiver = {
+            let received = received.clone();
+            Router::new().route(
+                "/hook",
+                post(move |headers: HeaderMap, body: Bytes| async move {
+                    let mut received = received.lock().unwrap();
+                    received.push((headers, body));
+                    if received.len() == 1 {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    } else {
+                        StatusCode::NO_CONTENT
+                    }
+                }),
+            )
+        };
+        let receiver_url = serve(receiver).await;
+
+        let registry = registry_with_tokens();
+        // The receiver is on loopback, which only admins can register.
+        let ops = |req| with_token(req, "adm1n");
+        let new_hook = serde_json::json!({
+            "package": "foo",
+            "url": format!("{receiver_url}/hook"),
+            "secret": "s3cret",
+            "events": ["publish"],
+        });
+        let (status, body) = send(&registry.app, ops(add(new_hook))).await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+        let hook: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(hook.get("secret").is_none());
+        let id = hook["id"].as_str().unwrap();
+
+        for (pkg, manifest) in [
+            ("foo", r#"{"name":"foo","version":"1.0.0"}"#),
+            ("bar", r#"{"name":"bar","version":"1.0.0"}"#),
+        ] {
+            send(&registry.app, ops(publish_request(pkg, "1.0.0", manifest))).await;
+        }
+        let yank = Request::post("/v1/foo/1.0.0/yank")
+            .body(Body::empty())
+            .unwrap();
+        send(&registry.app, ops(yank)).await;
+
+        for _ in 0..200 {
+            if received.lock().unwrap().len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // Give a stray third delivery a chance to show up.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2, "only foo's publish is delivered");
+        let (headers, body) = &received[1];
+        assert_eq!(headers[SIGNATURE_HEADER], sign("s3cret", body));
+        assert_eq!(headers[EVENT_HEADER], "publish");
+        assert_eq!(headers[DELIVERY_HEADER], received[0].0[DELIVERY_HEADER]);
+        let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload["package"], "foo");
+        assert_eq!(payload["version"], "1.0.0");
+
+        let get = |uri: String| ops(Request::get(uri).body(Body::empty()).unwrap());
+        let (_, body) = send(&registry.app, get(format!("/v1/-/hooks/{id}/deliveries"))).await;
+        let deliveries: Vec<Delivery> = serde_json::from_str(&body).unwrap();
+        let attempts: Vec<_> = deliveries
+            .iter()
+            .map(|d| (d.attempt, d.status, d.error.is_some()))
+            .collect();
+        assert_eq!(attempts, [(2, Some(204), false), (1, Some(500), true)]);
+
+        let delete = Request::delete(format!("/v1/-/hooks/{id}"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&registry.app, ops(delete)).await.0, StatusCode::OK);
+        let (_, body) = send(&registry.app, get("/v1/-/hooks".to_string())).await;
+        assert_eq!(body, "[]");
+    }
+
+    #[tokio::test]
+    async fn test_hooks_belong_to_their_creator() {
+        let registry = registry_with_tokens();
+        let hook = serde_json::json!({
+            "package": "foo", "url": "https://203.0.113.9/hook", "secret": "s"
+        });
+        let (status, body) = send(&registry.app, with_token(add(hook), "s3cret")).await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+        let hook: Webhook = serde_json::from_str(&body).unwrap();
+        assert_eq!(hook.created_by, "ci");
+
+        let get =
+            |uri: &str, token| with_token(Request::get(uri).body(Body::empty()).unwrap(), token);
+        let delete = |token| {
+            let req = Request::delete(format!("/v1/-/hooks/{}", hook.id))
+                .body(Body::empty())
+                .unwrap();
+            with_token(req, token)
+        };
+        let deliveries = format!("/v1/-/hooks/{}/deliveries", hook.id);
+        let listed = |body: &str| {
+            let hooks: Vec<Webhook> = serde_json::from_str(body).unwrap();
+            hooks.into_iter().map(|h| h.id).collect::<Vec<_>>()
+        };
+
+        // Another token can't see, read or remove it.
+        let (_, body) = send(&registry.app, get("/v1/-/hooks", "d3v")).await;
+        assert!(listed(&body).is_empty());
+        let (status, _) = send(&registry.app, get(&deliveries, "d3v")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            send(&registry.app, delete("d3v")).await.0,
+            StatusCode::NOT_FOUND
+        );
+
+        // Its creator and admins can.
+        for token in ["s3cret", "adm1n"] {
+            let (_, body) = send(&registry.app, get("/v1/-/hooks", token)).await;
+            assert_eq!(listed(&body), [hook.id.as_str()]);
+            let (status, _) = send(&registry.app, get(&deliveries, token)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_eq!(send(&registry.app, delete("adm1n")).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_add_webhook_validates() {
+        // With no tokens configured there is no one to own a hook.
+        let open = test_registry();
+        let hook = serde_json::json!({
+            "package": "foo", "url": "https://ci.test/hook", "secret": "s"
+        });
+        let (status, _) = send(&open.app, add(hook)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let registry = registry_with_tokens();
+        let add = |hook| with_token(add(hook), "adm1n");
+        for (package, url, secret) in [
+            ("foo", "ftp://ci.test/hook", "s"),
+            ("foo", "not a url", "s"),
+            ("foo", "http://ci.test/hook", ""),
+            ("@/*", "http://ci.test/hook", "s"),
+            ("Bad Name", "http://ci.test/hook", "s"),
+        ] {
+            let hook = serde_json::json!({"package": package, "url": url, "secret": secret});
+            let (status, _) = send(&registry.app, add(hook)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{package} {url}");
+        }
+        let hook = serde_json::json!({
+            "package": "@acme/*", "url": "https://ci.test/hook", "secret": "s"
+        });
+        assert_eq!(send(&registry.app, add(hook)).await.0, StatusCode::CREATED);
+
+        // Other tokens can't make the registry POST to internal addresses.
+        for url in [
+            "http://127.0.0.1:9000/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.1.2.3/hook",
+            "http://[::1]/hook",
+            "http://[::ffff:192.168.0.1]/hook",
+        ] {
+            let hook = serde_json::json!({"package": "foo", "url": url, "secret": "s"});
+            let (status, body) = send(&registry.app, with_token(self::add(hook), "s3cret")).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{url}");
+            assert!(body.contains("admin"), "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_check_addresses() {
+        let registry = registry_with_tokens();
+        // A hook whose host has since come to resolve to loopback.
+        let hook = Webhook {
+            url: "http://localhost:9/hook".to_string(),
+            ..webhook("h1", "foo")
+        };
+        registry.state.storage.put_webhook(&hook).unwrap();
+        let manifest = r#"{"name":"foo","version":"1.0.0"}"#;
+        let req = with_token(publish_request("foo", "1.0.0", manifest), "s3cret");
+        assert_eq!(send(&registry.app, req).await.0, StatusCode::OK);
+
+        let mut deliveries = Vec::new();
+        for _ in 0..200 {
+            deliveries = registry.state.storage.list_deliveries("h1").unwrap();
+            if !deliveries.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let error = deliveries[0].error.as_deref().unwrap_or_default();
+        assert!(error.contains("isn't a public address"), "{error}");
+        assert_eq!(deliveries[0].status, None);
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in [
+            "203.0.113.9",
+            "8.8.8.8",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -1,5 +1,8 @@
-#[no_mangle]
-pub extern "C" fn run(_ctx_json: *const u8, _ctx_len: usize) -> i32 {
+use plugin_api::{PluginContext, PluginResult};
+
+plugin_api::declare_plugin!(run_plugin);
+
+fn run_plugin(_ctx: PluginContext) -> PluginResult {
     // Immediately fail
-    1
+    Err("test plugin failure".to_string())
 }
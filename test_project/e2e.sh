@@ -18,7 +18,11 @@ REG_PID=$!
 sleep 2
 
 echo "Publishing package..."
-curl -s -X PUT -F version=1.0.0 -F description="E2E test package" -F tarball=@<(echo 'test tarball contents') http://localhost:4000/v1/e2eshpkg/ || { echo 'Publish failed'; exit 1; }
+PKG_DIR=$(mktemp -d)
+mkdir -p "$PKG_DIR/package"
+echo '{"name":"e2eshpkg","version":"1.0.0"}' > "$PKG_DIR/package/package.json"
+tar czf "$PKG_DIR/package.tgz" -C "$PKG_DIR" package
+curl -s -X PUT -F version=1.0.0 -F description="E2E test package" -F tarball=@"$PKG_DIR/package.tgz" http://localhost:4000/v1/e2eshpkg/ || { echo 'Publish failed'; exit 1; }
 
 echo "Yanking package..."
 curl -s -X POST http://localhost:4000/v1/e2eshpkg/1.0.0/yank || { echo 'Yank failed'; exit 1; }
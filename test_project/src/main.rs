@@ -35,11 +35,10 @@ mod e2e {
         let start = std::time::Instant::now();
         let timeout = std::time::Duration::from_secs(60);
         while start.elapsed() < timeout {
-            if let Ok(resp) = reqwest::blocking::get("http://localhost:4000/v1/index") {
-                if resp.status().is_success() {
+            if let Ok(resp) = reqwest::blocking::get("http://localhost:4000/v1/index")
+                && resp.status().is_success() {
                     return child;
                 }
-            }
             std::thread::sleep(std::time::Duration::from_millis(300));
         }
         // If failed, print captured logs
@@ -84,6 +83,22 @@ mod e2e {
         Ok(())
     }
 
+    /// The registry only accepts gzipped tarballs whose package.json matches
+    /// the published name and version.
+    fn make_tarball(name: &str, version: &str) -> Vec<u8> {
+        let package_json = format!(r#"{{"name":"{name}","version":"{version}"}}"#);
+        let gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(gz);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(package_json.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "package/package.json", package_json.as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
     #[test]
     fn test_end_to_end() {
         // Start registry
@@ -92,13 +107,13 @@ mod e2e {
         let pkg_name = "e2etestpkg";
         let version = "1.0.0";
         let desc = "E2E test package";
-        let tarball_bytes = b"test tarball contents";
+        let tarball_bytes = make_tarball(pkg_name, version);
         let form = reqwest::blocking::multipart::Form::new()
             .text("version", version)
             .text("description", desc)
             .part(
                 "tarball",
-                reqwest::blocking::multipart::Part::bytes(tarball_bytes.to_vec())
+                reqwest::blocking::multipart::Part::bytes(tarball_bytes)
                     .file_name("package.tgz"),
             );
         let publish_url = format!("http://localhost:4000/v1/{}/", pkg_name);
@@ -170,10 +185,8 @@ mod e2e {
             println!("[E2E] Listing directory: {}", path);
             match std::fs::read_dir(path) {
                 Ok(entries) => {
-                    for entry in entries {
-                        if let Ok(entry) = entry {
-                            println!("  {}", entry.path().display());
-                        }
+                    for entry in entries.flatten() {
+                        println!("  {}", entry.path().display());
                     }
                 }
                 Err(e) => println!("  [E2E] Failed to read dir: {}", e),
@@ -196,10 +209,8 @@ mod e2e {
             let plugins_output_dir = PathBuf::from(temp_project_dir).join(".boltpm/plugins_output");
             if let Ok(entries) = std::fs::read_dir(&plugins_output_dir) {
                 println!("[Test] Listing contents of plugins_output:");
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        println!(" - {}", entry.path().display());
-                    }
+                for entry in entries.flatten() {
+                    println!(" - {}", entry.path().display());
                 }
             } else {
                 println!("[Test] plugins_output directory not found or inaccessible.");